@@ -0,0 +1,97 @@
+use batched_iteration_mt_leaves::{append_leaves, append_leaves_into, HASH_LEN};
+use criterion::{black_box, criterion_group, criterion_main, BatchSize, Criterion};
+
+const NUM_LEAVES: usize = 100_000;
+const NUM_TREES: usize = 64;
+const BATCH_SIZE: usize = 128;
+
+fn input() -> (Vec<[u8; HASH_LEN]>, Vec<[u8; HASH_LEN]>) {
+    input_with(NUM_LEAVES, NUM_TREES)
+}
+
+fn input_with(num_leaves: usize, num_trees: usize) -> (Vec<[u8; HASH_LEN]>, Vec<[u8; HASH_LEN]>) {
+    let leaves = (0..num_leaves)
+        .map(|i| {
+            let mut leaf = [0_u8; HASH_LEN];
+            leaf[..8].copy_from_slice(&(i as u64).to_le_bytes());
+            leaf
+        })
+        .collect();
+    let merkle_trees = (0..num_leaves)
+        .map(|i| [(i * 7 % num_trees) as u8; HASH_LEN])
+        .collect();
+    (leaves, merkle_trees)
+}
+
+fn bench_append_leaves(c: &mut Criterion) {
+    let (leaves, merkle_trees) = input();
+
+    let mut group = c.benchmark_group("append_leaves");
+    group.bench_function("allocating", |b| {
+        b.iter_batched(
+            || (leaves.clone(), merkle_trees.clone()),
+            |(leaves, merkle_trees)| {
+                black_box(append_leaves(leaves, merkle_trees, BATCH_SIZE).unwrap())
+            },
+            BatchSize::LargeInput,
+        )
+    });
+    group.bench_function("into", |b| {
+        let mut out = Vec::new();
+        b.iter_batched(
+            || (leaves.clone(), merkle_trees.clone()),
+            |(leaves, merkle_trees)| {
+                append_leaves_into(leaves, merkle_trees, BATCH_SIZE, &mut out).unwrap();
+                black_box(out.len())
+            },
+            BatchSize::LargeInput,
+        )
+    });
+    #[cfg(feature = "smallvec")]
+    group.bench_function("small", |b| {
+        b.iter_batched(
+            || (leaves.clone(), merkle_trees.clone()),
+            |(leaves, merkle_trees)| {
+                black_box(
+                    batched_iteration_mt_leaves::append_leaves_small(
+                        leaves,
+                        merkle_trees,
+                        BATCH_SIZE,
+                    )
+                    .unwrap(),
+                )
+            },
+            BatchSize::LargeInput,
+        )
+    });
+    group.finish();
+}
+
+/// Grouping with and without interned Merkle tree keys, on 2M leaves over 16
+/// trees. Both must group identically.
+#[cfg(feature = "bench")]
+fn bench_grouping(c: &mut Criterion) {
+    use batched_iteration_mt_leaves::bench::{group_interned, group_plain};
+
+    let (leaves, merkle_trees) = input_with(2_000_000, 16);
+    assert_eq!(
+        group_plain(&leaves, &merkle_trees),
+        group_interned(&leaves, &merkle_trees)
+    );
+
+    let mut group = c.benchmark_group("grouping");
+    group.sample_size(10);
+    group.bench_function("plain", |b| {
+        b.iter(|| black_box(group_plain(&leaves, &merkle_trees)))
+    });
+    group.bench_function("interned", |b| {
+        b.iter(|| black_box(group_interned(&leaves, &merkle_trees)))
+    });
+    group.finish();
+}
+
+#[cfg(not(feature = "bench"))]
+criterion_group!(benches, bench_append_leaves);
+#[cfg(feature = "bench")]
+criterion_group!(benches, bench_append_leaves, bench_grouping);
+criterion_main!(benches);
@@ -0,0 +1,25 @@
+#![no_main]
+
+use batched_iteration_mt_leaves::{
+    append_leaves,
+    testing::{check_invariants, FuzzInput},
+};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|input: FuzzInput| {
+    let batches = append_leaves(
+        input.leaves.clone(),
+        input.merkle_trees.clone(),
+        input.batch_size,
+    )
+    .unwrap();
+
+    if let Err(violation) = check_invariants(
+        &input.leaves,
+        &input.merkle_trees,
+        input.batch_size,
+        &batches,
+    ) {
+        panic!("{violation}");
+    }
+});
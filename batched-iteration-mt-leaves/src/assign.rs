@@ -0,0 +1,161 @@
+use crate::{append_leaves, Changelogs, MyError, HASH_LEN};
+
+/// Batches leaves like [`append_leaves`], but instead of taking a Merkle
+/// tree for every leaf, picks one of `merkle_trees` with `assigner`, which
+/// returns an index into `merkle_trees`.
+///
+/// Returns [`MyError::TreeIndexOutOfRange`] if `assigner` returns an index
+/// outside of `merkle_trees`.
+pub fn assign_and_append(
+    leaves: &[[u8; HASH_LEN]],
+    merkle_trees: &[[u8; HASH_LEN]],
+    batch_size: usize,
+    assigner: impl Fn(&[u8; HASH_LEN]) -> usize,
+) -> Result<Vec<Changelogs>, MyError> {
+    let assigned_merkle_trees = leaves
+        .iter()
+        .enumerate()
+        .map(|(leaf_index, leaf)| {
+            let tree_index = assigner(leaf);
+            merkle_trees
+                .get(tree_index)
+                .copied()
+                .ok_or(MyError::TreeIndexOutOfRange {
+                    leaf_index,
+                    tree_index,
+                    num_trees: merkle_trees.len(),
+                })
+        })
+        .collect::<Result<_, _>>()?;
+
+    append_leaves(leaves.to_vec(), assigned_merkle_trees, batch_size)
+}
+
+/// Same as [`assign_and_append`], but assigns each leaf to the Merkle tree at
+/// index `leaf % merkle_trees.len()`, where the first 8 bytes of the leaf are
+/// read as a little-endian `u64`.
+pub fn assign_and_append_modulo(
+    leaves: &[[u8; HASH_LEN]],
+    merkle_trees: &[[u8; HASH_LEN]],
+    batch_size: usize,
+) -> Result<Vec<Changelogs>, MyError> {
+    let num_trees = merkle_trees.len() as u64;
+    assign_and_append(leaves, merkle_trees, batch_size, |leaf| {
+        // Without trees, the index 0 is out of range as well.
+        let leaf = u64::from_le_bytes(leaf[..8].try_into().unwrap());
+        leaf.checked_rem(num_trees).unwrap_or(0) as usize
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ChangelogEvent;
+
+    fn leaves() -> Vec<[u8; HASH_LEN]> {
+        (0..10_u64)
+            .map(|i| {
+                let mut leaf = [0xaa_u8; HASH_LEN];
+                leaf[..8].copy_from_slice(&i.to_le_bytes());
+                leaf
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_assign_and_append() {
+        let leaves = leaves();
+        let merkle_trees = [[1_u8; 32], [2_u8; 32]];
+
+        // Leaves with the first byte below 3 go to MT 2, the rest to MT 1.
+        let changelogs =
+            assign_and_append(&leaves, &merkle_trees, 4, |leaf| usize::from(leaf[0] < 3)).unwrap();
+        assert_eq!(
+            changelogs,
+            vec![
+                Changelogs {
+                    changelogs: vec![ChangelogEvent {
+                        merkle_tree_pubkey: [1_u8; 32],
+                        leaves: leaves[3..7].to_vec(),
+                    }]
+                },
+                Changelogs {
+                    changelogs: vec![
+                        ChangelogEvent {
+                            merkle_tree_pubkey: [1_u8; 32],
+                            leaves: leaves[7..].to_vec(),
+                        },
+                        ChangelogEvent {
+                            merkle_tree_pubkey: [2_u8; 32],
+                            leaves: leaves[..1].to_vec(),
+                        }
+                    ]
+                },
+                Changelogs {
+                    changelogs: vec![ChangelogEvent {
+                        merkle_tree_pubkey: [2_u8; 32],
+                        leaves: leaves[1..3].to_vec(),
+                    }]
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_assign_and_append_modulo() {
+        let leaves = leaves();
+        let merkle_trees = [[1_u8; 32], [2_u8; 32], [3_u8; 32]];
+
+        let changelogs = assign_and_append_modulo(&leaves, &merkle_trees, 100).unwrap();
+        assert_eq!(changelogs.len(), 1);
+
+        // Leaves 0, 3, 6, 9 go to MT 1, leaves 1, 4, 7 to MT 2 and leaves 2,
+        // 5, 8 to MT 3.
+        let distribution: Vec<([u8; 32], Vec<[u8; 32]>)> = changelogs[0]
+            .changelogs
+            .iter()
+            .map(|changelog_event| {
+                (
+                    changelog_event.merkle_tree_pubkey,
+                    changelog_event.leaves.clone(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            distribution,
+            vec![
+                ([1_u8; 32], vec![leaves[0], leaves[3], leaves[6], leaves[9]]),
+                ([2_u8; 32], vec![leaves[1], leaves[4], leaves[7]]),
+                ([3_u8; 32], vec![leaves[2], leaves[5], leaves[8]]),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_assign_and_append_out_of_range() {
+        let leaves = leaves();
+
+        let res = assign_and_append(&leaves, &[[1_u8; 32], [2_u8; 32]], 4, |leaf| {
+            usize::from(leaf[0])
+        });
+        assert!(matches!(
+            res,
+            Err(MyError::TreeIndexOutOfRange {
+                leaf_index: 2,
+                tree_index: 2,
+                num_trees: 2
+            })
+        ));
+
+        let res = assign_and_append_modulo(&leaves, &[], 4);
+        assert!(matches!(
+            res,
+            Err(MyError::TreeIndexOutOfRange {
+                leaf_index: 0,
+                tree_index: 0,
+                num_trees: 0
+            })
+        ));
+        assert!(assign_and_append_modulo(&[], &[], 4).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,180 @@
+//! Allocation counting for benchmarks.
+//!
+//! Counting requires [`CountingAllocator`] to be the global allocator of the
+//! binary:
+//!
+//! ```ignore
+//! use batched_iteration_mt_leaves::bench::CountingAllocator;
+//!
+//! #[global_allocator]
+//! static GLOBAL: CountingAllocator = CountingAllocator;
+//! ```
+//!
+//! Otherwise all the counts are 0.
+
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    cell::Cell,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use std::collections::BTreeMap;
+
+use crate::{
+    append_leaves, build_merkle_tree_map_plain, interned::build_merkle_tree_map_interned,
+    Changelogs, MyError, HASH_LEN,
+};
+
+/// Global allocator which forwards to [`System`] and counts the allocations
+/// (including reallocations) made by threads inside [`count_allocations`].
+pub struct CountingAllocator;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+    /// Whether the allocations of this thread are counted. Other threads,
+    /// e.g. of the test harness, don't disturb the count.
+    static COUNTING: Cell<bool> = const { Cell::new(false) };
+}
+
+fn count() {
+    if COUNTING.with(Cell::get) {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        count();
+        System.alloc(layout)
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        count();
+        System.alloc_zeroed(layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        count();
+        System.realloc(ptr, layout, new_size)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+/// Runs `f` and returns its result together with the number of allocations
+/// it made on the current thread.
+///
+/// Not reentrant and not meant to be called from several threads at once,
+/// they share the counter.
+pub fn count_allocations<T>(f: impl FnOnce() -> T) -> (T, usize) {
+    ALLOCATIONS.store(0, Ordering::Relaxed);
+    COUNTING.with(|counting| counting.set(true));
+    let res = f();
+    COUNTING.with(|counting| counting.set(false));
+    (res, ALLOCATIONS.load(Ordering::Relaxed))
+}
+
+/// Runs [`append_leaves`] and returns the batches together with the number
+/// of allocations it made. Copying the input is not counted.
+pub fn run_and_count(
+    leaves: &[[u8; HASH_LEN]],
+    merkle_trees: &[[u8; HASH_LEN]],
+    batch_size: usize,
+) -> (Result<Vec<Changelogs>, MyError>, usize) {
+    let (leaves, merkle_trees) = (leaves.to_vec(), merkle_trees.to_vec());
+    count_allocations(|| append_leaves(leaves, merkle_trees, batch_size))
+}
+
+/// Same as [`run_and_count`], but runs
+/// [`append_leaves_small`](crate::append_leaves_small).
+#[cfg(feature = "smallvec")]
+pub fn run_and_count_small(
+    leaves: &[[u8; HASH_LEN]],
+    merkle_trees: &[[u8; HASH_LEN]],
+    batch_size: usize,
+) -> (Result<Vec<crate::SmallChangelogs>, MyError>, usize) {
+    let (leaves, merkle_trees) = (leaves.to_vec(), merkle_trees.to_vec());
+    count_allocations(|| crate::append_leaves_small(leaves, merkle_trees, batch_size))
+}
+
+/// Grouped leaves of each Merkle tree.
+pub type MerkleTreeMap = BTreeMap<[u8; HASH_LEN], Vec<[u8; HASH_LEN]>>;
+
+/// Groups the leaves by looking up their Merkle tree in a map, whatever the
+/// input size. For comparing with [`group_interned`].
+pub fn group_plain(leaves: &[[u8; HASH_LEN]], merkle_trees: &[[u8; HASH_LEN]]) -> MerkleTreeMap {
+    assert_eq!(leaves.len(), merkle_trees.len());
+    build_merkle_tree_map_plain(leaves, merkle_trees, |_| Ok(())).unwrap()
+}
+
+/// Groups the leaves by interned Merkle tree keys, whatever the input size.
+/// [`build_merkle_tree_map`](crate::build_merkle_tree_map) does it above a
+/// threshold of leaves.
+pub fn group_interned(leaves: &[[u8; HASH_LEN]], merkle_trees: &[[u8; HASH_LEN]]) -> MerkleTreeMap {
+    assert_eq!(leaves.len(), merkle_trees.len());
+    build_merkle_tree_map_interned(leaves, merkle_trees, |_| Ok(())).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixture;
+
+    #[global_allocator]
+    static GLOBAL: CountingAllocator = CountingAllocator;
+
+    #[test]
+    fn test_run_and_count() {
+        let (leaves, merkle_trees) = fixture::leaves_and_merkle_trees();
+
+        let (batches, allocations) = run_and_count(&leaves, &merkle_trees, 10);
+        let batches = batches.unwrap();
+        assert_eq!(batches, append_leaves(leaves, merkle_trees, 10).unwrap());
+
+        // 4 trees, 3 batches and 6 events, plus the maps' nodes and the list
+        // of batches.
+        assert!(allocations > 0);
+        assert!(allocations <= 20, "{allocations} allocations");
+    }
+
+    #[test]
+    fn test_count_allocations() {
+        let (_, allocations) = count_allocations(|| ());
+        assert_eq!(allocations, 0);
+
+        let (v, allocations) = count_allocations(|| Vec::<u8>::with_capacity(8));
+        assert_eq!(v.capacity(), 8);
+        assert_eq!(allocations, 1);
+    }
+
+    #[cfg(feature = "smallvec")]
+    #[test]
+    fn test_run_and_count_small() {
+        // Single-tree-heavy: 990 leaves of MT 1 and 10 of MT 2, so every
+        // batch has one or two events.
+        let leaves: Vec<[u8; HASH_LEN]> = (0..1000_u32)
+            .map(|i| {
+                let mut leaf = [0_u8; HASH_LEN];
+                leaf[..4].copy_from_slice(&i.to_le_bytes());
+                leaf
+            })
+            .collect();
+        let merkle_trees: Vec<[u8; HASH_LEN]> = (0..1000)
+            .map(|i| if i % 100 == 0 { [2_u8; 32] } else { [1_u8; 32] })
+            .collect();
+
+        let (batches, allocations) = run_and_count(&leaves, &merkle_trees, 16);
+        let num_batches = batches.unwrap().len();
+        let (small_batches, small_allocations) = run_and_count_small(&leaves, &merkle_trees, 16);
+        assert_eq!(small_batches.unwrap().len(), num_batches);
+
+        // One allocation of the list of events saved per batch.
+        assert!(
+            small_allocations + num_batches <= allocations,
+            "{small_allocations} allocations with smallvec, {allocations} without"
+        );
+    }
+}
@@ -0,0 +1,995 @@
+use std::{
+    cmp::{self, Ordering},
+    collections::{BTreeMap, BTreeSet},
+    fmt, mem,
+    sync::Arc,
+};
+
+use crate::{
+    ensure_contiguous, split_into_batches, validate_trees, ChangelogEvent, Changelogs, Limits,
+    MyError, HASH_LEN,
+};
+
+/// Order in which the Merkle trees are processed while batching. The
+/// batching itself is the same for all of them, the order only decides which
+/// trees end up sharing a batch and which get split across batches.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TreeOrder {
+    /// Trees sorted by their pubkeys, as in [`append_leaves`](crate::append_leaves).
+    #[default]
+    PubkeyAsc,
+    /// Trees with the most leaves first, ties broken by pubkey. Large trees
+    /// take whole batches, so fewer trees span batch boundaries.
+    LeafCountDesc,
+    /// Trees in the order of their first appearance in the input.
+    Insertion,
+}
+
+impl TreeOrder {
+    fn arrange(
+        self,
+        merkle_tree_map: BTreeMap<[u8; HASH_LEN], Vec<[u8; HASH_LEN]>>,
+        merkle_trees: &[[u8; HASH_LEN]],
+    ) -> Vec<([u8; HASH_LEN], Vec<[u8; HASH_LEN]>)> {
+        let mut merkle_tree_leaves: Vec<_> = merkle_tree_map.into_iter().collect();
+        match self {
+            TreeOrder::PubkeyAsc => {}
+            TreeOrder::LeafCountDesc => {
+                // Stable sort, so trees with equal leaf counts stay sorted by
+                // pubkey.
+                merkle_tree_leaves.sort_by_key(|(_, leaves)| cmp::Reverse(leaves.len()));
+            }
+            TreeOrder::Insertion => {
+                let mut seen = BTreeSet::new();
+                let first_appearance: BTreeMap<_, _> = merkle_trees
+                    .iter()
+                    .filter(|merkle_tree| seen.insert(*merkle_tree))
+                    .enumerate()
+                    .map(|(i, merkle_tree)| (*merkle_tree, i))
+                    .collect();
+                merkle_tree_leaves.sort_by_key(|(merkle_tree, _)| first_appearance[merkle_tree]);
+            }
+        }
+        merkle_tree_leaves
+    }
+}
+
+/// Comparator of leaves used by [`LeafOrder::Custom`].
+pub type LeafComparator = Arc<dyn Fn(&[u8; HASH_LEN], &[u8; HASH_LEN]) -> Ordering + Send + Sync>;
+
+/// Order of the leaves within each Merkle tree.
+#[derive(Clone, Default)]
+pub enum LeafOrder {
+    /// Leaves in the order they were given in, as in
+    /// [`append_leaves`](crate::append_leaves).
+    #[default]
+    Insertion,
+    /// Leaves sorted by their bytes.
+    Natural,
+    /// Leaves sorted with the given comparator. The sort is stable, so leaves
+    /// comparing equal keep their order.
+    Custom(LeafComparator),
+}
+
+impl LeafOrder {
+    fn sort(&self, leaves: &mut [[u8; HASH_LEN]]) {
+        match self {
+            LeafOrder::Insertion => {}
+            LeafOrder::Natural => leaves.sort(),
+            LeafOrder::Custom(compare) => leaves.sort_by(|a, b| compare(a, b)),
+        }
+    }
+}
+
+impl fmt::Debug for LeafOrder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LeafOrder::Insertion => f.write_str("Insertion"),
+            LeafOrder::Natural => f.write_str("Natural"),
+            LeafOrder::Custom(_) => f.write_str("Custom(..)"),
+        }
+    }
+}
+
+/// Decides what happens with the last batch when it has fewer than
+/// `batch_size` leaves.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum FlushPolicy {
+    /// The under-full batch is returned as it is.
+    #[default]
+    Emit,
+    /// The under-full batch is dropped, so only full batches are returned.
+    Drop,
+    /// The under-full batch is padded to `batch_size` leaves with the given
+    /// sentinel leaf, appended to the last event of the batch.
+    Pad([u8; HASH_LEN]),
+}
+
+impl FlushPolicy {
+    fn apply(self, batches: &mut Vec<Changelogs>, batch_size: usize) {
+        let Some(last_batch) = batches.last_mut() else {
+            return;
+        };
+        let num_leaves: usize = last_batch
+            .changelogs
+            .iter()
+            .map(|changelog_event| changelog_event.leaves.len())
+            .sum();
+        if num_leaves == batch_size {
+            return;
+        }
+
+        match self {
+            FlushPolicy::Emit => {}
+            FlushPolicy::Drop => {
+                batches.pop();
+            }
+            FlushPolicy::Pad(sentinel) => {
+                // Batches are never empty, so the last event always exists.
+                let last_event = last_batch.changelogs.last_mut().unwrap();
+                // `num_leaves < batch_size`, subtract first so the sum
+                // can't overflow for huge batch sizes.
+                let padded_len = last_event.leaves.len() + (batch_size - num_leaves);
+                last_event.leaves.resize(padded_len, sentinel);
+            }
+        }
+    }
+}
+
+/// Splits the leaves like [`split_into_batches`], but when a Merkle tree
+/// doesn't fit in the rest of a batch, only the largest power of two of its
+/// leaves which fits is added and the batch is closed.
+fn split_into_aligned_batches(
+    merkle_tree_leaves: Vec<([u8; HASH_LEN], Vec<[u8; HASH_LEN]>)>,
+    batch_size: usize,
+) -> Vec<Changelogs> {
+    assert!(batch_size != 0, "batch size must be non-zero");
+
+    let mut batches_of_changelogs = Vec::new();
+    let mut batch_of_changelogs = Changelogs {
+        changelogs: Vec::new(),
+    };
+    let mut batch_len = 0;
+
+    for (merkle_tree_pubkey, leaves) in merkle_tree_leaves {
+        let mut leaves = leaves.as_slice();
+        while !leaves.is_empty() {
+            // Full batches are closed right away, so there is always space
+            // for at least one leaf.
+            let space = batch_size - batch_len;
+            let chunk_len = if leaves.len() <= space {
+                leaves.len()
+            } else {
+                1 << space.ilog2()
+            };
+
+            batch_of_changelogs.changelogs.push(ChangelogEvent {
+                merkle_tree_pubkey,
+                leaves: leaves[..chunk_len].to_vec(),
+            });
+            batch_len += chunk_len;
+            leaves = &leaves[chunk_len..];
+
+            if !leaves.is_empty() || batch_len == batch_size {
+                batches_of_changelogs.push(mem::replace(
+                    &mut batch_of_changelogs,
+                    Changelogs {
+                        changelogs: Vec::new(),
+                    },
+                ));
+                batch_len = 0;
+            }
+        }
+    }
+
+    if !batch_of_changelogs.changelogs.is_empty() {
+        batches_of_changelogs.push(batch_of_changelogs);
+    }
+
+    batches_of_changelogs
+}
+
+/// Batches leaves like [`append_leaves`], with additional options.
+#[derive(Clone, Debug)]
+pub struct BatchBuilder {
+    batch_size: usize,
+    reject_default_key: bool,
+    order: TreeOrder,
+    flush: FlushPolicy,
+    compact: bool,
+    align_to_pow2: bool,
+    limits: Limits,
+    require_full_batches: bool,
+    leaf_order: LeafOrder,
+    require_contiguous: bool,
+    max_leaves_per_event: Option<usize>,
+    priorities: Option<TreePriorities>,
+}
+
+/// Priorities of the Merkle trees set by [`BatchBuilder::tree_priorities`].
+#[derive(Clone, Debug)]
+struct TreePriorities {
+    priorities: BTreeMap<[u8; HASH_LEN], u8>,
+    default_priority: u8,
+}
+
+impl BatchBuilder {
+    /// Creates a builder of batches of `batch_size` leaves. A `batch_size` of
+    /// 0 is not rejected here, but by [`build`](Self::build).
+    pub fn new(batch_size: usize) -> Self {
+        Self {
+            batch_size,
+            reject_default_key: false,
+            order: TreeOrder::default(),
+            flush: FlushPolicy::default(),
+            compact: false,
+            align_to_pow2: false,
+            limits: Limits::default(),
+            require_full_batches: false,
+            leaf_order: LeafOrder::default(),
+            require_contiguous: false,
+            max_leaves_per_event: None,
+            priorities: None,
+        }
+    }
+
+    /// Makes [`build`](Self::build) fail with [`MyError::DefaultKey`] when any
+    /// of the Merkle tree pubkeys is `[0u8; HASH_LEN]`, which usually means that
+    /// it was never initialized (see [`validate_trees`]). Disabled by default.
+    pub fn reject_default_key(mut self, reject_default_key: bool) -> Self {
+        self.reject_default_key = reject_default_key;
+        self
+    }
+
+    /// Sets the order in which the Merkle trees are batched. Defaults to
+    /// [`TreeOrder::PubkeyAsc`].
+    pub fn order(mut self, order: TreeOrder) -> Self {
+        self.order = order;
+        self
+    }
+
+    /// Sets how the last, under-full batch is handled. Defaults to
+    /// [`FlushPolicy::Emit`].
+    pub fn flush(mut self, flush: FlushPolicy) -> Self {
+        self.flush = flush;
+        self
+    }
+
+    /// Releases the unused capacity of the batches before returning them, see
+    /// [`Changelogs::shrink_to_fit`]. Batches are allocated with exact
+    /// capacities, but dropping the last batch or padding it with
+    /// [`FlushPolicy`] can leave some unused. Disabled by default.
+    pub fn compact(mut self, compact: bool) -> Self {
+        self.compact = compact;
+        self
+    }
+
+    /// When a Merkle tree doesn't fit in the rest of a batch, splits it at
+    /// the largest power of two of leaves which fits, instead of filling the
+    /// batch, and closes the batch. Appends then align to subtree boundaries,
+    /// at the cost of more, smaller batches. Disabled by default.
+    pub fn align_to_pow2(mut self, align_to_pow2: bool) -> Self {
+        self.align_to_pow2 = align_to_pow2;
+        self
+    }
+
+    /// Sets the limits on the number of Merkle trees and leaves, checked
+    /// while grouping the leaves. No limits by default.
+    pub fn limits(mut self, limits: Limits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Makes [`build`](Self::build) fail with [`MyError::BatchSizeTooLarge`]
+    /// when there are fewer leaves than `batch_size`, i.e. when all of them
+    /// would end up in a single under-full batch. Disabled by default.
+    pub fn require_full_batches(mut self, require_full_batches: bool) -> Self {
+        self.require_full_batches = require_full_batches;
+        self
+    }
+
+    /// Sets the order of the leaves within each Merkle tree, applied before
+    /// splitting them into batches. Defaults to [`LeafOrder::Insertion`].
+    pub fn leaf_order(mut self, leaf_order: LeafOrder) -> Self {
+        self.leaf_order = leaf_order;
+        self
+    }
+
+    /// Makes [`build`](Self::build) fail with [`MyError::NonContiguousTree`]
+    /// when the leaves of a Merkle tree are interleaved with leaves of other
+    /// trees in the input (see [`ensure_contiguous`]), instead of silently
+    /// regrouping them. Disabled by default.
+    pub fn require_contiguous(mut self, require_contiguous: bool) -> Self {
+        self.require_contiguous = require_contiguous;
+        self
+    }
+
+    /// Splits events with more than `max_leaves_per_event` leaves into
+    /// several consecutive events of the same Merkle tree within the batch.
+    /// The batches themselves are not affected. Such batches are rejected by
+    /// [`Changelogs::validate`]. No limit by default.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `max_leaves_per_event` is 0.
+    pub fn max_leaves_per_event(mut self, max_leaves_per_event: usize) -> Self {
+        assert!(
+            max_leaves_per_event != 0,
+            "maximum number of leaves per event must be non-zero"
+        );
+        self.max_leaves_per_event = Some(max_leaves_per_event);
+        self
+    }
+
+    /// Processes the Merkle trees in the order of their priorities, lower
+    /// first, so the leaves of high-priority trees land in the earliest
+    /// batches. Trees missing in `priorities` get `default_priority`. Trees
+    /// with equal priorities keep the [`order`](Self::order), by default
+    /// sorted by pubkey.
+    pub fn tree_priorities(
+        mut self,
+        priorities: BTreeMap<[u8; HASH_LEN], u8>,
+        default_priority: u8,
+    ) -> Self {
+        self.priorities = Some(TreePriorities {
+            priorities,
+            default_priority,
+        });
+        self
+    }
+
+    /// Batches `leaves` appended to `merkle_trees` with the configured
+    /// options. Fails with [`MyError::InvalidBatchSize`] if the batch size is
+    /// 0, before validating or grouping the input.
+    pub fn build(
+        &self,
+        leaves: Vec<[u8; HASH_LEN]>,
+        merkle_trees: Vec<[u8; HASH_LEN]>,
+    ) -> Result<Vec<Changelogs>, MyError> {
+        if self.batch_size == 0 {
+            return Err(MyError::InvalidBatchSize);
+        }
+        if self.reject_default_key {
+            validate_trees(&merkle_trees)?;
+        }
+        if self.require_contiguous {
+            ensure_contiguous(&merkle_trees)?;
+        }
+
+        let mut merkle_tree_map = self.limits.build_merkle_tree_map(&leaves, &merkle_trees)?;
+        for tree_leaves in merkle_tree_map.values_mut() {
+            self.leaf_order.sort(tree_leaves);
+        }
+        if self.require_full_batches && !leaves.is_empty() && leaves.len() < self.batch_size {
+            return Err(MyError::BatchSizeTooLarge {
+                batch_size: self.batch_size,
+                total: leaves.len(),
+            });
+        }
+        let mut merkle_tree_leaves = self.order.arrange(merkle_tree_map, &merkle_trees);
+        if let Some(TreePriorities {
+            priorities,
+            default_priority,
+        }) = &self.priorities
+        {
+            // Stable sort, so trees with equal priorities stay in `order`.
+            merkle_tree_leaves.sort_by_key(|(merkle_tree, _)| {
+                priorities
+                    .get(merkle_tree)
+                    .copied()
+                    .unwrap_or(*default_priority)
+            });
+        }
+
+        let mut batches = if self.align_to_pow2 {
+            split_into_aligned_batches(merkle_tree_leaves, self.batch_size)
+        } else {
+            split_into_batches(merkle_tree_leaves, self.batch_size)
+        };
+        self.flush.apply(&mut batches, self.batch_size);
+        if let Some(max_leaves_per_event) = self.max_leaves_per_event {
+            for batch in batches.iter_mut() {
+                split_events(batch, max_leaves_per_event);
+            }
+        }
+        if self.compact {
+            for batch in batches.iter_mut() {
+                batch.shrink_to_fit();
+            }
+            batches.shrink_to_fit();
+        }
+
+        Ok(batches)
+    }
+}
+
+/// Splits the events of `batch` into events of at most `max_leaves_per_event`
+/// leaves, keeping the order of events and leaves.
+fn split_events(batch: &mut Changelogs, max_leaves_per_event: usize) {
+    if batch
+        .changelogs
+        .iter()
+        .all(|changelog_event| changelog_event.leaves.len() <= max_leaves_per_event)
+    {
+        return;
+    }
+
+    let num_events = batch
+        .changelogs
+        .iter()
+        .map(|changelog_event| changelog_event.leaves.len().div_ceil(max_leaves_per_event))
+        .sum();
+    let mut changelogs = Vec::with_capacity(num_events);
+    for changelog_event in batch.changelogs.drain(..) {
+        if changelog_event.leaves.len() <= max_leaves_per_event {
+            changelogs.push(changelog_event);
+            continue;
+        }
+        changelogs.extend(
+            changelog_event
+                .leaves
+                .chunks(max_leaves_per_event)
+                .map(|leaves| ChangelogEvent {
+                    merkle_tree_pubkey: changelog_event.merkle_tree_pubkey,
+                    leaves: leaves.to_vec(),
+                }),
+        );
+    }
+    batch.changelogs = changelogs;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{append_leaves, fixture};
+
+    #[test]
+    fn test_reject_default_key() {
+        // The fixture uses `[0u8; 32]` as the first Merkle tree.
+        let (leaves, merkle_trees) = fixture::leaves_and_merkle_trees();
+
+        let res = BatchBuilder::new(10)
+            .reject_default_key(true)
+            .build(leaves, merkle_trees);
+        assert!(matches!(res, Err(MyError::DefaultKey { first_index: 0 })));
+    }
+
+    #[test]
+    fn test_zero_batch_size() {
+        let (leaves, merkle_trees) = fixture::leaves_and_merkle_trees();
+
+        assert!(matches!(
+            BatchBuilder::new(0).build(Vec::new(), Vec::new()),
+            Err(MyError::InvalidBatchSize)
+        ));
+        assert!(matches!(
+            BatchBuilder::new(0).build(leaves.clone(), merkle_trees.clone()),
+            Err(MyError::InvalidBatchSize)
+        ));
+        // Checked before the Merkle trees are validated.
+        assert!(matches!(
+            BatchBuilder::new(0)
+                .reject_default_key(true)
+                .align_to_pow2(true)
+                .build(leaves, merkle_trees),
+            Err(MyError::InvalidBatchSize)
+        ));
+    }
+
+    #[test]
+    fn test_default_key_allowed_by_default() {
+        let (leaves, merkle_trees) = fixture::leaves_and_merkle_trees();
+
+        let changelogs = BatchBuilder::new(10)
+            .build(leaves.clone(), merkle_trees.clone())
+            .unwrap();
+        assert_eq!(changelogs, append_leaves(leaves, merkle_trees, 10).unwrap());
+    }
+
+    #[test]
+    fn test_reject_default_key_without_default_key() {
+        let leaves = vec![[1_u8; 32], [2_u8; 32]];
+        let merkle_trees = vec![[1_u8; 32], [2_u8; 32]];
+
+        let changelogs = BatchBuilder::new(10)
+            .reject_default_key(true)
+            .build(leaves.clone(), merkle_trees.clone())
+            .unwrap();
+        assert_eq!(changelogs, append_leaves(leaves, merkle_trees, 10).unwrap());
+    }
+
+    #[test]
+    fn test_require_contiguous() {
+        let (leaves, merkle_trees) = fixture::leaves_and_merkle_trees();
+        let changelogs = BatchBuilder::new(10)
+            .require_contiguous(true)
+            .build(leaves.clone(), merkle_trees.clone())
+            .unwrap();
+        assert_eq!(
+            changelogs,
+            append_leaves(leaves.clone(), merkle_trees.clone(), 10).unwrap()
+        );
+
+        // MT 3 comes first and then again after the other trees.
+        let mut interleaved = merkle_trees;
+        interleaved.swap(0, 24);
+        let res = BatchBuilder::new(10)
+            .require_contiguous(true)
+            .build(leaves.clone(), interleaved.clone());
+        assert!(matches!(
+            res,
+            Err(MyError::NonContiguousTree(merkle_tree)) if merkle_tree == [3_u8; 32]
+        ));
+
+        // Interleaved trees are regrouped by default.
+        assert!(BatchBuilder::new(10).build(leaves, interleaved).is_ok());
+    }
+
+    #[test]
+    fn test_max_leaves_per_event() {
+        let leaves: Vec<[u8; 32]> = (0..10).map(|i| [i; 32]).collect();
+        let mut merkle_trees = vec![[1_u8; 32]; 8];
+        merkle_trees.extend([[2_u8; 32]; 2]);
+
+        let changelogs = BatchBuilder::new(10)
+            .max_leaves_per_event(3)
+            .build(leaves.clone(), merkle_trees.clone())
+            .unwrap();
+        assert_eq!(
+            changelogs,
+            vec![Changelogs {
+                changelogs: vec![
+                    ChangelogEvent {
+                        merkle_tree_pubkey: [1_u8; 32],
+                        leaves: leaves[0..3].to_vec(),
+                    },
+                    ChangelogEvent {
+                        merkle_tree_pubkey: [1_u8; 32],
+                        leaves: leaves[3..6].to_vec(),
+                    },
+                    ChangelogEvent {
+                        merkle_tree_pubkey: [1_u8; 32],
+                        leaves: leaves[6..8].to_vec(),
+                    },
+                    ChangelogEvent {
+                        merkle_tree_pubkey: [2_u8; 32],
+                        leaves: leaves[8..10].to_vec(),
+                    },
+                ],
+            }]
+        );
+
+        // A limit larger than any event changes nothing.
+        assert_eq!(
+            BatchBuilder::new(10)
+                .max_leaves_per_event(8)
+                .build(leaves.clone(), merkle_trees.clone())
+                .unwrap(),
+            append_leaves(leaves, merkle_trees, 10).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_tree_priorities() {
+        // MT 0 has many leaves and sorts first, MT 9 is latency-critical.
+        let mut leaves: Vec<[u8; 32]> = (0..25).map(|i| [i; 32]).collect();
+        let mut merkle_trees = vec![[0_u8; 32]; 25];
+        leaves.extend([[100_u8; 32], [101_u8; 32], [102_u8; 32]]);
+        merkle_trees.extend([[9_u8; 32]; 3]);
+        leaves.push([200_u8; 32]);
+        merkle_trees.push([5_u8; 32]);
+
+        let changelogs = BatchBuilder::new(10)
+            .tree_priorities(BTreeMap::from([([9_u8; 32], 0), ([0_u8; 32], 5)]), 1)
+            .build(leaves.clone(), merkle_trees.clone())
+            .unwrap();
+
+        // MT 9 first, then MT 5 with the default priority, then MT 0.
+        assert_eq!(
+            changelogs[0].changelogs[0],
+            ChangelogEvent {
+                merkle_tree_pubkey: [9_u8; 32],
+                leaves: vec![[100_u8; 32], [101_u8; 32], [102_u8; 32]],
+            }
+        );
+        assert_eq!(
+            changelogs[0]
+                .changelogs
+                .iter()
+                .map(|changelog_event| changelog_event.merkle_tree_pubkey)
+                .collect::<Vec<_>>(),
+            vec![[9_u8; 32], [5_u8; 32], [0_u8; 32]]
+        );
+        assert_eq!(changelogs.len(), 3);
+        crate::testing::check_invariants(&leaves, &merkle_trees, 10, &changelogs).unwrap();
+
+        // Equal priorities keep the pubkey order.
+        assert_eq!(
+            BatchBuilder::new(10)
+                .tree_priorities(BTreeMap::new(), 0)
+                .build(leaves.clone(), merkle_trees.clone())
+                .unwrap(),
+            append_leaves(leaves, merkle_trees, 10).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_order_pubkey_asc() {
+        let (leaves, merkle_trees) = fixture::leaves_and_merkle_trees();
+
+        let changelogs = BatchBuilder::new(10)
+            .order(TreeOrder::PubkeyAsc)
+            .build(leaves.clone(), merkle_trees.clone())
+            .unwrap();
+        assert_eq!(changelogs, append_leaves(leaves, merkle_trees, 10).unwrap());
+    }
+
+    #[test]
+    fn test_order_leaf_count_desc() {
+        // MT 0 has 12 leaves, MT 3 has 6, MT 2 has 4 and MT 1 has 3.
+        let (leaves, merkle_trees) = fixture::leaves_and_merkle_trees();
+
+        let changelogs = BatchBuilder::new(10)
+            .order(TreeOrder::LeafCountDesc)
+            .build(leaves, merkle_trees)
+            .unwrap();
+        assert_eq!(
+            changelogs,
+            vec![
+                Changelogs {
+                    changelogs: vec![ChangelogEvent {
+                        merkle_tree_pubkey: [0_u8; 32],
+                        leaves: vec![
+                            [0_u8; 32], [1_u8; 32], [2_u8; 32], [3_u8; 32], [4_u8; 32], [5_u8; 32],
+                            [6_u8; 32], [7_u8; 32], [8_u8; 32], [9_u8; 32],
+                        ]
+                    }]
+                },
+                Changelogs {
+                    changelogs: vec![
+                        ChangelogEvent {
+                            merkle_tree_pubkey: [0_u8; 32],
+                            leaves: vec![[10_u8; 32], [11_u8; 32]]
+                        },
+                        ChangelogEvent {
+                            merkle_tree_pubkey: [3_u8; 32],
+                            leaves: vec![
+                                [19_u8; 32],
+                                [20_u8; 32],
+                                [21_u8; 32],
+                                [22_u8; 32],
+                                [23_u8; 32],
+                                [24_u8; 32]
+                            ]
+                        },
+                        ChangelogEvent {
+                            merkle_tree_pubkey: [2_u8; 32],
+                            leaves: vec![[15_u8; 32], [16_u8; 32]]
+                        },
+                    ]
+                },
+                Changelogs {
+                    changelogs: vec![
+                        ChangelogEvent {
+                            merkle_tree_pubkey: [2_u8; 32],
+                            leaves: vec![[17_u8; 32], [18_u8; 32]]
+                        },
+                        ChangelogEvent {
+                            merkle_tree_pubkey: [1_u8; 32],
+                            leaves: vec![[12_u8; 32], [13_u8; 32], [14_u8; 32]]
+                        },
+                    ]
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_order_insertion() {
+        // MT 2 shows up first, then MT 0, then MT 1.
+        let leaves = vec![
+            [0_u8; 32], [1_u8; 32], [2_u8; 32], [3_u8; 32], [4_u8; 32], [5_u8; 32],
+        ];
+        let merkle_trees = vec![
+            [2_u8; 32], [0_u8; 32], [2_u8; 32], [1_u8; 32], [0_u8; 32], [2_u8; 32],
+        ];
+
+        let changelogs = BatchBuilder::new(4)
+            .order(TreeOrder::Insertion)
+            .build(leaves, merkle_trees)
+            .unwrap();
+        assert_eq!(
+            changelogs,
+            vec![
+                Changelogs {
+                    changelogs: vec![
+                        ChangelogEvent {
+                            merkle_tree_pubkey: [2_u8; 32],
+                            leaves: vec![[0_u8; 32], [2_u8; 32], [5_u8; 32]]
+                        },
+                        ChangelogEvent {
+                            merkle_tree_pubkey: [0_u8; 32],
+                            leaves: vec![[1_u8; 32]]
+                        },
+                    ]
+                },
+                Changelogs {
+                    changelogs: vec![
+                        ChangelogEvent {
+                            merkle_tree_pubkey: [0_u8; 32],
+                            leaves: vec![[4_u8; 32]]
+                        },
+                        ChangelogEvent {
+                            merkle_tree_pubkey: [1_u8; 32],
+                            leaves: vec![[3_u8; 32]]
+                        },
+                    ]
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_flush_emit() {
+        let (leaves, merkle_trees) = fixture::leaves_and_merkle_trees();
+
+        let changelogs = BatchBuilder::new(10)
+            .flush(FlushPolicy::Emit)
+            .build(leaves.clone(), merkle_trees.clone())
+            .unwrap();
+        assert_eq!(changelogs.len(), 3);
+        assert_eq!(changelogs, append_leaves(leaves, merkle_trees, 10).unwrap());
+    }
+
+    #[test]
+    fn test_flush_drop() {
+        let (leaves, merkle_trees) = fixture::leaves_and_merkle_trees();
+        let mut expected = append_leaves(leaves.clone(), merkle_trees.clone(), 10).unwrap();
+        expected.pop();
+
+        let changelogs = BatchBuilder::new(10)
+            .flush(FlushPolicy::Drop)
+            .build(leaves.clone(), merkle_trees.clone())
+            .unwrap();
+        assert_eq!(changelogs, expected);
+
+        // A full last batch is kept.
+        let changelogs = BatchBuilder::new(5)
+            .flush(FlushPolicy::Drop)
+            .build(leaves.clone(), merkle_trees.clone())
+            .unwrap();
+        assert_eq!(changelogs, append_leaves(leaves, merkle_trees, 5).unwrap());
+    }
+
+    #[test]
+    fn test_flush_pad() {
+        // The last batch has 5 leaves of MT 3.
+        let (leaves, merkle_trees) = fixture::leaves_and_merkle_trees();
+        let sentinel = [0xff_u8; 32];
+
+        let changelogs = BatchBuilder::new(10)
+            .flush(FlushPolicy::Pad(sentinel))
+            .build(leaves.clone(), merkle_trees.clone())
+            .unwrap();
+        assert_eq!(changelogs.len(), 3);
+        assert_eq!(
+            changelogs[..2],
+            append_leaves(leaves, merkle_trees, 10).unwrap()[..2]
+        );
+        assert_eq!(
+            changelogs[2],
+            Changelogs {
+                changelogs: vec![ChangelogEvent {
+                    merkle_tree_pubkey: [3_u8; 32],
+                    leaves: vec![
+                        [20_u8; 32],
+                        [21_u8; 32],
+                        [22_u8; 32],
+                        [23_u8; 32],
+                        [24_u8; 32],
+                        sentinel,
+                        sentinel,
+                        sentinel,
+                        sentinel,
+                        sentinel,
+                    ]
+                }]
+            }
+        );
+    }
+
+    #[test]
+    fn test_compact() {
+        // One large tree and many single-leaf trees.
+        let mut leaves = Vec::new();
+        let mut merkle_trees = Vec::new();
+        for i in 0..10_000_u32 {
+            let mut leaf = [0_u8; 32];
+            leaf[..4].copy_from_slice(&i.to_le_bytes());
+            leaves.push(leaf);
+            merkle_trees.push(if i % 100 == 0 {
+                [(i / 100) as u8; 32]
+            } else {
+                [255_u8; 32]
+            });
+        }
+
+        let changelogs = BatchBuilder::new(256)
+            .compact(true)
+            .build(leaves.clone(), merkle_trees.clone())
+            .unwrap();
+        assert_eq!(changelogs.capacity(), changelogs.len());
+        for batch in changelogs.iter() {
+            assert_eq!(batch.changelogs.capacity(), batch.changelogs.len());
+            for changelog_event in batch.changelogs.iter() {
+                assert_eq!(
+                    changelog_event.leaves.capacity(),
+                    changelog_event.leaves.len()
+                );
+            }
+        }
+        assert_eq!(
+            changelogs,
+            append_leaves(leaves, merkle_trees, 256).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_align_to_pow2() {
+        let leaves: Vec<[u8; 32]> = (0..12).map(|i| [i; 32]).collect();
+        let merkle_trees = vec![[0_u8; 32]; 12];
+
+        // 12 leaves don't fit in 10, so the tree is split after 8 of them.
+        let changelogs = BatchBuilder::new(10)
+            .align_to_pow2(true)
+            .build(leaves.clone(), merkle_trees)
+            .unwrap();
+        assert_eq!(
+            changelogs,
+            vec![
+                Changelogs {
+                    changelogs: vec![ChangelogEvent {
+                        merkle_tree_pubkey: [0_u8; 32],
+                        leaves: leaves[..8].to_vec(),
+                    }]
+                },
+                Changelogs {
+                    changelogs: vec![ChangelogEvent {
+                        merkle_tree_pubkey: [0_u8; 32],
+                        leaves: leaves[8..].to_vec(),
+                    }]
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_align_to_pow2_fixture() {
+        // MT 0 has 12 leaves, MT 1 has 3, MT 2 has 4 and MT 3 has 6.
+        let (leaves, merkle_trees) = fixture::leaves_and_merkle_trees();
+
+        let changelogs = BatchBuilder::new(10)
+            .align_to_pow2(true)
+            .build(leaves, merkle_trees)
+            .unwrap();
+        let event_lens: Vec<Vec<(u8, usize)>> = changelogs
+            .iter()
+            .map(|batch| {
+                batch
+                    .changelogs
+                    .iter()
+                    .map(|changelog_event| {
+                        (
+                            changelog_event.merkle_tree_pubkey[0],
+                            changelog_event.leaves.len(),
+                        )
+                    })
+                    .collect()
+            })
+            .collect();
+        // MT 2 doesn't fit in the 3 leaves left in the second batch, so only 2
+        // of its leaves are added there.
+        assert_eq!(
+            event_lens,
+            vec![
+                vec![(0, 8)],
+                vec![(0, 4), (1, 3), (2, 2)],
+                vec![(2, 2), (3, 6)],
+            ]
+        );
+    }
+
+    #[test]
+    fn test_limits() {
+        let (leaves, merkle_trees) = fixture::leaves_and_merkle_trees();
+
+        let res = BatchBuilder::new(10)
+            .limits(Limits {
+                max_distinct_trees: Some(3),
+                max_total_leaves: None,
+            })
+            .build(leaves, merkle_trees);
+        assert!(matches!(res, Err(MyError::TooManyTrees { got: 4, max: 3 })));
+    }
+
+    #[test]
+    fn test_require_full_batches() {
+        let (leaves, merkle_trees) = fixture::leaves_and_merkle_trees();
+
+        let res = BatchBuilder::new(26)
+            .require_full_batches(true)
+            .build(leaves.clone(), merkle_trees.clone());
+        assert!(matches!(
+            res,
+            Err(MyError::BatchSizeTooLarge {
+                batch_size: 26,
+                total: 25
+            })
+        ));
+
+        // Allowed by default.
+        let changelogs = BatchBuilder::new(26)
+            .build(leaves.clone(), merkle_trees.clone())
+            .unwrap();
+        assert_eq!(changelogs.len(), 1);
+
+        // All leaves fit exactly, or there are several batches.
+        for batch_size in [25, 10] {
+            let changelogs = BatchBuilder::new(batch_size)
+                .require_full_batches(true)
+                .build(leaves.clone(), merkle_trees.clone())
+                .unwrap();
+            assert_eq!(
+                changelogs,
+                append_leaves(leaves.clone(), merkle_trees.clone(), batch_size).unwrap()
+            );
+        }
+    }
+
+    #[test]
+    fn test_leaf_order() {
+        let leaves = vec![
+            [5_u8; 32], [3_u8; 32], [9_u8; 32], [1_u8; 32], [4_u8; 32], [2_u8; 32],
+        ];
+        let merkle_trees = vec![
+            [0_u8; 32], [1_u8; 32], [0_u8; 32], [1_u8; 32], [0_u8; 32], [1_u8; 32],
+        ];
+
+        let tree_leaves = |leaf_order: LeafOrder| -> Vec<Vec<[u8; 32]>> {
+            BatchBuilder::new(2)
+                .leaf_order(leaf_order)
+                .build(leaves.clone(), merkle_trees.clone())
+                .unwrap()
+                .into_iter()
+                .flat_map(|batch| batch.changelogs)
+                .fold(Vec::new(), |mut tree_leaves, changelog_event| {
+                    let i = usize::from(changelog_event.merkle_tree_pubkey[0]);
+                    tree_leaves.resize(i + 1, Vec::new());
+                    tree_leaves[i].extend(changelog_event.leaves);
+                    tree_leaves
+                })
+        };
+
+        assert_eq!(
+            tree_leaves(LeafOrder::Insertion),
+            vec![
+                vec![[5_u8; 32], [9_u8; 32], [4_u8; 32]],
+                vec![[3_u8; 32], [1_u8; 32], [2_u8; 32]],
+            ]
+        );
+        assert_eq!(
+            tree_leaves(LeafOrder::Natural),
+            vec![
+                vec![[4_u8; 32], [5_u8; 32], [9_u8; 32]],
+                vec![[1_u8; 32], [2_u8; 32], [3_u8; 32]],
+            ]
+        );
+        assert_eq!(
+            tree_leaves(LeafOrder::Custom(Arc::new(|a, b| b.cmp(a)))),
+            vec![
+                vec![[9_u8; 32], [5_u8; 32], [4_u8; 32]],
+                vec![[3_u8; 32], [2_u8; 32], [1_u8; 32]],
+            ]
+        );
+    }
+}
@@ -0,0 +1,116 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use crate::{append_leaves_iter, Changelogs, MyError, HASH_LEN};
+
+/// Batches leaves like [`append_leaves`](crate::append_leaves), but stops
+/// as soon as `cancel` is set, failing with [`MyError::Cancelled`].
+///
+/// The flag is checked before each batch is produced, so after it's set at
+/// most one more batch is built. Once all batches are produced, the flag is
+/// ignored.
+pub fn append_leaves_cancellable(
+    leaves: Vec<[u8; HASH_LEN]>,
+    merkle_trees: Vec<[u8; HASH_LEN]>,
+    batch_size: usize,
+    cancel: &AtomicBool,
+) -> Result<Vec<Changelogs>, MyError> {
+    if batch_size == 0 {
+        return Err(MyError::InvalidBatchSize);
+    }
+
+    collect_cancellable(
+        append_leaves_iter(leaves, merkle_trees, batch_size)?,
+        cancel,
+    )
+}
+
+fn collect_cancellable<I>(mut batches: I, cancel: &AtomicBool) -> Result<Vec<Changelogs>, MyError>
+where
+    I: ExactSizeIterator<Item = Changelogs>,
+{
+    let mut batches_of_changelogs = Vec::with_capacity(batches.len());
+    while batches.len() > 0 {
+        if cancel.load(Ordering::Relaxed) {
+            return Err(MyError::Cancelled {
+                batches_completed: batches_of_changelogs.len(),
+            });
+        }
+        // Can't fail, the iterator has batches left.
+        batches_of_changelogs.push(batches.next().unwrap());
+    }
+    Ok(batches_of_changelogs)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::mpsc, thread};
+
+    use super::*;
+    use crate::{append_leaves, fixture};
+
+    #[test]
+    fn test_append_leaves_cancellable() {
+        let (leaves, merkle_trees) = fixture::leaves_and_merkle_trees();
+        let cancel = AtomicBool::new(false);
+
+        assert_eq!(
+            append_leaves_cancellable(leaves.clone(), merkle_trees.clone(), 10, &cancel).unwrap(),
+            append_leaves(leaves.clone(), merkle_trees.clone(), 10).unwrap()
+        );
+
+        cancel.store(true, Ordering::Relaxed);
+        assert!(matches!(
+            append_leaves_cancellable(leaves, merkle_trees, 10, &cancel),
+            Err(MyError::Cancelled {
+                batches_completed: 0
+            })
+        ));
+
+        assert!(matches!(
+            append_leaves_cancellable(vec![], vec![], 0, &cancel),
+            Err(MyError::InvalidBatchSize)
+        ));
+        // Nothing to cancel.
+        assert!(append_leaves_cancellable(vec![], vec![], 10, &cancel)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn test_append_leaves_cancellable_from_another_thread() {
+        let (leaves, merkle_trees) = fixture::leaves_and_merkle_trees();
+        let cancel = AtomicBool::new(false);
+        let (first_batch_tx, first_batch_rx) = mpsc::channel();
+
+        thread::scope(|s| {
+            let cancel = &cancel;
+            let canceller = s.spawn(move || {
+                first_batch_rx.recv().unwrap();
+                cancel.store(true, Ordering::Relaxed);
+            });
+
+            // Wait for the other thread to set the flag right after the
+            // first batch is produced.
+            let mut batch_index = 0;
+            let batches = append_leaves_iter(leaves, merkle_trees, 10)
+                .unwrap()
+                .inspect(|_| {
+                    if batch_index == 0 {
+                        first_batch_tx.send(()).unwrap();
+                        while !cancel.load(Ordering::Relaxed) {
+                            thread::yield_now();
+                        }
+                    }
+                    batch_index += 1;
+                });
+
+            assert!(matches!(
+                collect_cancellable(batches, cancel),
+                Err(MyError::Cancelled {
+                    batches_completed: 1
+                })
+            ));
+            canceller.join().unwrap();
+        });
+    }
+}
@@ -0,0 +1,337 @@
+use std::{collections::BTreeMap, fmt};
+
+use crate::{hex::write_hex, ChangelogEventVersioned, ChangelogsVersioned, HASH_LEN};
+
+/// Caps checked by [`check_batch_sequence`]. `None` means no cap.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SequenceConfig {
+    /// Maximum number of leaves in a batch.
+    pub max_batch_leaves: Option<usize>,
+    /// Maximum number of leaves in an event.
+    pub max_event_leaves: Option<usize>,
+}
+
+/// Kind of a [`SequenceViolation`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ViolationKind {
+    /// The batch has no events.
+    EmptyBatch,
+    /// The batch has more leaves than allowed.
+    BatchTooLarge { leaves: usize, max: usize },
+    /// The event has more leaves than allowed.
+    EventTooLarge { leaves: usize, max: usize },
+    /// The event starts after the end of the previous event of its tree.
+    IndexGap { expected: u64, got: u64 },
+    /// The event starts before the end of the previous event of its tree.
+    IndexOverlap { expected: u64, got: u64 },
+    /// The sequence number is not greater than the one of the previous event
+    /// of its tree.
+    SeqNotIncreasing { previous: u64, got: u64 },
+}
+
+impl fmt::Display for ViolationKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ViolationKind::EmptyBatch => write!(f, "batch has no events"),
+            ViolationKind::BatchTooLarge { leaves, max } => {
+                write!(f, "batch has {leaves} leaves, the maximum is {max}")
+            }
+            ViolationKind::EventTooLarge { leaves, max } => {
+                write!(f, "event has {leaves} leaves, the maximum is {max}")
+            }
+            ViolationKind::IndexGap { expected, got } => {
+                write!(
+                    f,
+                    "expected start index {expected}, got {got}, leaves are missing"
+                )
+            }
+            ViolationKind::IndexOverlap { expected, got } => {
+                write!(
+                    f,
+                    "expected start index {expected}, got {got}, leaves are repeated"
+                )
+            }
+            ViolationKind::SeqNotIncreasing { previous, got } => {
+                write!(f, "sequence number {got} doesn't follow {previous}")
+            }
+        }
+    }
+}
+
+/// Inconsistency found by [`check_batch_sequence`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SequenceViolation {
+    pub batch_index: usize,
+    /// Merkle tree of the offending event, `None` for violations of the whole
+    /// batch.
+    pub merkle_tree_pubkey: Option<[u8; HASH_LEN]>,
+    pub kind: ViolationKind,
+}
+
+impl fmt::Display for SequenceViolation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "batch {}: ", self.batch_index)?;
+        if let Some(merkle_tree_pubkey) = self.merkle_tree_pubkey {
+            write_hex(f, &merkle_tree_pubkey)?;
+            f.write_str(": ")?;
+        }
+        write!(f, "{}", self.kind)
+    }
+}
+
+/// Checks that the batches can be submitted one after another:
+///
+/// * No batch is empty and no batch or event exceeds the caps in `config`.
+/// * Each V2 event starts right where the previous V2 event of its tree
+///   ended and has a greater sequence number. The first event of each tree
+///   can start anywhere. V1 events carry no positions, so only the caps are
+///   checked for them.
+///
+/// Returns all violations, in the order of batches and events.
+pub fn check_batch_sequence(
+    batches: &[ChangelogsVersioned],
+    config: &SequenceConfig,
+) -> Result<(), Vec<SequenceViolation>> {
+    let mut violations = Vec::new();
+    // End index and sequence number of the last V2 event of each tree.
+    let mut last_events: BTreeMap<[u8; HASH_LEN], (u64, u64)> = BTreeMap::new();
+
+    for (batch_index, batch) in batches.iter().enumerate() {
+        let batch_violation = |kind| SequenceViolation {
+            batch_index,
+            merkle_tree_pubkey: None,
+            kind,
+        };
+
+        if batch.changelogs.is_empty() {
+            violations.push(batch_violation(ViolationKind::EmptyBatch));
+        }
+        let batch_leaves: usize = batch
+            .changelogs
+            .iter()
+            .map(|changelog_event| changelog_event.leaves().len())
+            .sum();
+        if let Some(max) = config.max_batch_leaves {
+            if batch_leaves > max {
+                violations.push(batch_violation(ViolationKind::BatchTooLarge {
+                    leaves: batch_leaves,
+                    max,
+                }));
+            }
+        }
+
+        for changelog_event in batch.changelogs.iter() {
+            let merkle_tree_pubkey = *changelog_event.merkle_tree_pubkey();
+            let mut event_violation = |kind| {
+                violations.push(SequenceViolation {
+                    batch_index,
+                    merkle_tree_pubkey: Some(merkle_tree_pubkey),
+                    kind,
+                })
+            };
+
+            let leaves = changelog_event.leaves().len();
+            if let Some(max) = config.max_event_leaves {
+                if leaves > max {
+                    event_violation(ViolationKind::EventTooLarge { leaves, max });
+                }
+            }
+
+            let ChangelogEventVersioned::V2(changelog_event) = changelog_event else {
+                continue;
+            };
+            if let Some((expected, previous)) = last_events.get(&merkle_tree_pubkey).copied() {
+                let got = changelog_event.start_index;
+                if got > expected {
+                    event_violation(ViolationKind::IndexGap { expected, got });
+                } else if got < expected {
+                    event_violation(ViolationKind::IndexOverlap { expected, got });
+                }
+                if changelog_event.seq <= previous {
+                    event_violation(ViolationKind::SeqNotIncreasing {
+                        previous,
+                        got: changelog_event.seq,
+                    });
+                }
+            }
+            last_events.insert(
+                merkle_tree_pubkey,
+                (
+                    changelog_event.start_index + leaves as u64,
+                    changelog_event.seq,
+                ),
+            );
+        }
+    }
+
+    if violations.is_empty() {
+        Ok(())
+    } else {
+        Err(violations)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{append_leaves_versioned, fixture, ChangelogEventV2, EventVersion};
+
+    fn event(
+        merkle_tree: u8,
+        start_index: u64,
+        seq: u64,
+        leaves: usize,
+    ) -> ChangelogEventVersioned {
+        ChangelogEventVersioned::V2(ChangelogEventV2 {
+            merkle_tree_pubkey: [merkle_tree; 32],
+            start_index,
+            seq,
+            leaves: vec![[merkle_tree; 32]; leaves],
+        })
+    }
+
+    fn batch(changelogs: Vec<ChangelogEventVersioned>) -> ChangelogsVersioned {
+        ChangelogsVersioned { changelogs }
+    }
+
+    #[test]
+    fn test_check_batch_sequence_valid() {
+        let (leaves, merkle_trees) = fixture::leaves_and_merkle_trees();
+        let config = SequenceConfig {
+            max_batch_leaves: Some(10),
+            max_event_leaves: Some(10),
+        };
+
+        for version in [EventVersion::V1, EventVersion::V2] {
+            let batches =
+                append_leaves_versioned(leaves.clone(), merkle_trees.clone(), 10, version).unwrap();
+            assert_eq!(check_batch_sequence(&batches, &config), Ok(()));
+        }
+    }
+
+    #[test]
+    fn test_check_batch_sequence_gap() {
+        let batches = [
+            batch(vec![event(1, 0, 0, 4), event(2, 0, 0, 2)]),
+            batch(vec![event(1, 5, 1, 2)]),
+        ];
+
+        assert_eq!(
+            check_batch_sequence(&batches, &SequenceConfig::default()),
+            Err(vec![SequenceViolation {
+                batch_index: 1,
+                merkle_tree_pubkey: Some([1_u8; 32]),
+                kind: ViolationKind::IndexGap {
+                    expected: 4,
+                    got: 5
+                },
+            }])
+        );
+    }
+
+    #[test]
+    fn test_check_batch_sequence_duplicate() {
+        let batches = [
+            batch(vec![event(1, 0, 0, 4)]),
+            batch(vec![event(1, 0, 0, 4)]),
+        ];
+
+        let violations = check_batch_sequence(&batches, &SequenceConfig::default()).unwrap_err();
+        assert_eq!(
+            violations
+                .iter()
+                .map(|violation| violation.kind)
+                .collect::<Vec<_>>(),
+            vec![
+                ViolationKind::IndexOverlap {
+                    expected: 4,
+                    got: 0
+                },
+                ViolationKind::SeqNotIncreasing {
+                    previous: 0,
+                    got: 0
+                },
+            ]
+        );
+        assert_eq!(
+            violations[1].to_string(),
+            format!(
+                "batch 1: {}: sequence number 0 doesn't follow 0",
+                "01".repeat(32)
+            )
+        );
+    }
+
+    #[test]
+    fn test_check_batch_sequence_out_of_order() {
+        // The events of MT 1 are swapped.
+        let batches = [
+            batch(vec![event(1, 0, 0, 2)]),
+            batch(vec![event(1, 4, 2, 2)]),
+            batch(vec![event(1, 2, 1, 2)]),
+        ];
+
+        assert_eq!(
+            check_batch_sequence(&batches, &SequenceConfig::default()),
+            Err(vec![
+                SequenceViolation {
+                    batch_index: 1,
+                    merkle_tree_pubkey: Some([1_u8; 32]),
+                    kind: ViolationKind::IndexGap {
+                        expected: 2,
+                        got: 4
+                    },
+                },
+                SequenceViolation {
+                    batch_index: 2,
+                    merkle_tree_pubkey: Some([1_u8; 32]),
+                    kind: ViolationKind::IndexOverlap {
+                        expected: 6,
+                        got: 2
+                    },
+                },
+                SequenceViolation {
+                    batch_index: 2,
+                    merkle_tree_pubkey: Some([1_u8; 32]),
+                    kind: ViolationKind::SeqNotIncreasing {
+                        previous: 2,
+                        got: 1
+                    },
+                },
+            ])
+        );
+    }
+
+    #[test]
+    fn test_check_batch_sequence_caps() {
+        let batches = [
+            batch(vec![event(1, 0, 0, 3), event(2, 0, 0, 2)]),
+            batch(Vec::new()),
+        ];
+        let config = SequenceConfig {
+            max_batch_leaves: Some(4),
+            max_event_leaves: Some(2),
+        };
+
+        assert_eq!(
+            check_batch_sequence(&batches, &config),
+            Err(vec![
+                SequenceViolation {
+                    batch_index: 0,
+                    merkle_tree_pubkey: None,
+                    kind: ViolationKind::BatchTooLarge { leaves: 5, max: 4 },
+                },
+                SequenceViolation {
+                    batch_index: 0,
+                    merkle_tree_pubkey: Some([1_u8; 32]),
+                    kind: ViolationKind::EventTooLarge { leaves: 3, max: 2 },
+                },
+                SequenceViolation {
+                    batch_index: 1,
+                    merkle_tree_pubkey: None,
+                    kind: ViolationKind::EmptyBatch,
+                },
+            ])
+        );
+    }
+}
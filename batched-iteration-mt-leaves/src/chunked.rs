@@ -0,0 +1,320 @@
+use crate::{build_merkle_tree_map, BatchCursor, ChangelogEvent, Changelogs, MyError, HASH_LEN};
+
+/// Same as [`append_leaves`](crate::append_leaves), but groups the input in
+/// chunks of `chunk_size` `(merkle_tree, leaf)` pairs, so the grouping map
+/// never holds more than `chunk_size` leaves at once.
+///
+/// The last, not yet full batch of a chunk is carried over and filled with
+/// the leaves of the next one, see [`extend_batches`]. If the input is already grouped per Merkle
+/// tree, in ascending order of the pubkeys (e.g. sorted by tree), the output
+/// is identical to the unchunked call.
+///
+/// Otherwise the leaves are only grouped within each chunk. A Merkle tree
+/// whose leaves are spread over several chunks can then appear in more
+/// batches than necessary, but never more than once in the same batch, and
+/// its leaves keep their original order.
+///
+/// Fails with [`MyError::InvalidBatchSize`] if `batch_size` is 0 and with
+/// [`MyError::InvalidChunkSize`] if `chunk_size` is 0.
+pub fn append_leaves_chunked(
+    leaves: Vec<[u8; HASH_LEN]>,
+    merkle_trees: Vec<[u8; HASH_LEN]>,
+    batch_size: usize,
+    chunk_size: usize,
+) -> Result<Vec<Changelogs>, MyError> {
+    if batch_size == 0 {
+        return Err(MyError::InvalidBatchSize);
+    }
+    if chunk_size == 0 {
+        return Err(MyError::InvalidChunkSize);
+    }
+    if leaves.len() != merkle_trees.len() {
+        // Let the map builder report the mismatch for the whole input.
+        build_merkle_tree_map(&leaves, &merkle_trees)?;
+    }
+
+    let mut batches = Vec::new();
+    for (leaves, merkle_trees) in leaves
+        .chunks(chunk_size)
+        .zip(merkle_trees.chunks(chunk_size))
+    {
+        extend_batches(&mut batches, leaves, merkle_trees, batch_size)?;
+    }
+
+    Ok(batches)
+}
+
+/// Appends `leaves` of `merkle_trees` to already batched leaves. The last
+/// batch of `batches` is filled up to `batch_size` leaves first, the rest of
+/// the leaves is split into new batches like in
+/// [`append_leaves`](crate::append_leaves).
+///
+/// Batches other than the last one are never modified. If a Merkle tree
+/// already has an event in the last batch, that event is extended, so the
+/// tree still has at most one event per batch. A last batch with
+/// `batch_size` or more leaves is considered full.
+pub fn extend_batches(
+    batches: &mut Vec<Changelogs>,
+    leaves: &[[u8; HASH_LEN]],
+    merkle_trees: &[[u8; HASH_LEN]],
+    batch_size: usize,
+) -> Result<(), MyError> {
+    if batch_size == 0 {
+        return Err(MyError::InvalidBatchSize);
+    }
+    let merkle_tree_map = build_merkle_tree_map(leaves, merkle_trees)?;
+
+    let merkle_tree_leaves: Vec<_> = merkle_tree_map.into_iter().collect();
+    let tree_len = |tree_index: usize| {
+        merkle_tree_leaves
+            .get(tree_index)
+            .map(|(_, tree_leaves)| tree_leaves.len())
+    };
+    let mut cursor = BatchCursor::default();
+
+    // Fill up the last batch first, as a batch of the space left in it.
+    if let Some(batch) = batches.last_mut() {
+        let leaves_in_batch: usize = batch
+            .changelogs
+            .iter()
+            .map(|changelog_event| changelog_event.leaves.len())
+            .sum();
+        if leaves_in_batch < batch_size {
+            cursor.next_batch(
+                batch_size - leaves_in_batch,
+                tree_len,
+                |tree_index, range| {
+                    let (merkle_tree_pubkey, tree_leaves) = &merkle_tree_leaves[tree_index];
+                    let head = &tree_leaves[range];
+
+                    // The Merkle tree can already have an event in the last
+                    // batch, either because its leaves continue here or because
+                    // the input is not grouped.
+                    match batch.changelogs.iter_mut().find(|changelog_event| {
+                        changelog_event.merkle_tree_pubkey == *merkle_tree_pubkey
+                    }) {
+                        Some(changelog_event) => changelog_event.leaves.extend_from_slice(head),
+                        None => batch.changelogs.push(ChangelogEvent {
+                            merkle_tree_pubkey: *merkle_tree_pubkey,
+                            leaves: head.to_vec(),
+                        }),
+                    }
+                },
+            );
+        }
+    }
+
+    loop {
+        let mut batch = Changelogs {
+            changelogs: Vec::new(),
+        };
+        let leaves_in_batch = cursor.next_batch(batch_size, tree_len, |tree_index, range| {
+            let (merkle_tree_pubkey, tree_leaves) = &merkle_tree_leaves[tree_index];
+            batch.changelogs.push(ChangelogEvent {
+                merkle_tree_pubkey: *merkle_tree_pubkey,
+                leaves: tree_leaves[range].to_vec(),
+            });
+        });
+        if leaves_in_batch == 0 {
+            break;
+        }
+        batches.push(batch);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{append_leaves, fixture, testing::check_invariants};
+
+    #[test]
+    fn test_chunked_grouped_input() {
+        // The fixture is grouped per tree, in ascending order.
+        let (leaves, merkle_trees) = fixture::leaves_and_merkle_trees();
+
+        for batch_size in [1, 3, 7, 10, 25, 100] {
+            let expected = append_leaves(leaves.clone(), merkle_trees.clone(), batch_size).unwrap();
+            for chunk_size in [1, 2, 5, 10, 12, 25, 100] {
+                assert_eq!(
+                    append_leaves_chunked(
+                        leaves.clone(),
+                        merkle_trees.clone(),
+                        batch_size,
+                        chunk_size
+                    )
+                    .unwrap(),
+                    expected,
+                    "batch size {batch_size}, chunk size {chunk_size}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_chunked_interleaved_input() {
+        let leaves: Vec<[u8; 32]> = (0..6).map(|i| [i; 32]).collect();
+        let merkle_trees = vec![
+            [1_u8; 32], [2_u8; 32], [1_u8; 32], [2_u8; 32], [1_u8; 32], [2_u8; 32],
+        ];
+
+        // Unchunked, both trees get a single event.
+        let unchunked = append_leaves(leaves.clone(), merkle_trees.clone(), 3).unwrap();
+        assert_eq!(unchunked.len(), 2);
+        assert_eq!(unchunked[0].changelogs.len(), 1);
+
+        // Chunked by 2, every chunk contains both trees, so both of them end
+        // up in both batches.
+        let chunked = append_leaves_chunked(leaves.clone(), merkle_trees.clone(), 3, 2).unwrap();
+        assert_eq!(
+            chunked,
+            vec![
+                Changelogs {
+                    changelogs: vec![
+                        ChangelogEvent {
+                            merkle_tree_pubkey: [1_u8; 32],
+                            leaves: vec![[0_u8; 32], [2_u8; 32]],
+                        },
+                        ChangelogEvent {
+                            merkle_tree_pubkey: [2_u8; 32],
+                            leaves: vec![[1_u8; 32]],
+                        },
+                    ],
+                },
+                Changelogs {
+                    changelogs: vec![
+                        ChangelogEvent {
+                            merkle_tree_pubkey: [2_u8; 32],
+                            leaves: vec![[3_u8; 32], [5_u8; 32]],
+                        },
+                        ChangelogEvent {
+                            merkle_tree_pubkey: [1_u8; 32],
+                            leaves: vec![[4_u8; 32]],
+                        },
+                    ],
+                },
+            ]
+        );
+        check_invariants(&leaves, &merkle_trees, 3, &chunked).unwrap();
+    }
+
+    #[test]
+    fn test_extend_batches() {
+        let (leaves, merkle_trees) = fixture::leaves_and_merkle_trees();
+
+        for batch_size in [1, 3, 7, 10, 25, 100] {
+            // The fixture is grouped per tree, so extending by consecutive
+            // slices gives the same batches as a single run.
+            let expected = append_leaves(leaves.clone(), merkle_trees.clone(), batch_size).unwrap();
+
+            let mut batches = Vec::new();
+            for (start, end) in [(0, 1), (1, 4), (4, 4), (4, 13), (13, 25)] {
+                let before = batches.clone();
+                extend_batches(
+                    &mut batches,
+                    &leaves[start..end],
+                    &merkle_trees[start..end],
+                    batch_size,
+                )
+                .unwrap();
+                // Only the last batch can change.
+                if let Some((_, earlier)) = before.split_last() {
+                    assert_eq!(&batches[..earlier.len()], earlier);
+                }
+            }
+            assert_eq!(batches, expected, "batch size {batch_size}");
+        }
+    }
+
+    #[test]
+    fn test_extend_batches_interleaved() {
+        // MT 1 and MT 2 alternate, so every call touches both of them.
+        let leaves: Vec<[u8; 32]> = (0..9).map(|i| [i; 32]).collect();
+        let merkle_trees: Vec<[u8; 32]> = (0..9).map(|i| [1 + i % 2; 32]).collect();
+
+        let mut batches =
+            append_leaves(leaves[..1].to_vec(), merkle_trees[..1].to_vec(), 4).unwrap();
+        extend_batches(&mut batches, &leaves[1..4], &merkle_trees[1..4], 4).unwrap();
+        extend_batches(&mut batches, &leaves[4..9], &merkle_trees[4..9], 4).unwrap();
+
+        // The existing events of the last batch were extended.
+        assert_eq!(
+            batches[0],
+            Changelogs {
+                changelogs: vec![
+                    ChangelogEvent {
+                        merkle_tree_pubkey: [1_u8; 32],
+                        leaves: vec![[0_u8; 32], [2_u8; 32]],
+                    },
+                    ChangelogEvent {
+                        merkle_tree_pubkey: [2_u8; 32],
+                        leaves: vec![[1_u8; 32], [3_u8; 32]],
+                    },
+                ],
+            }
+        );
+        check_invariants(&leaves, &merkle_trees, 4, &batches).unwrap();
+        assert_eq!(
+            batches.len(),
+            append_leaves(leaves, merkle_trees, 4).unwrap().len()
+        );
+    }
+
+    #[test]
+    fn test_extend_batches_full_last_batch() {
+        let mut batches = append_leaves(vec![[0_u8; 32]; 3], vec![[1_u8; 32]; 3], 3).unwrap();
+
+        // A smaller batch size than the one used before leaves the last batch
+        // untouched.
+        extend_batches(&mut batches, &[[1_u8; 32]], &[[1_u8; 32]], 2).unwrap();
+        assert_eq!(batches.len(), 2);
+        assert_eq!(batches[0].changelogs[0].leaves, vec![[0_u8; 32]; 3]);
+        assert_eq!(batches[1].changelogs[0].leaves, vec![[1_u8; 32]]);
+
+        assert!(matches!(
+            extend_batches(&mut batches, &[[1_u8; 32]], &[[1_u8; 32]], 0),
+            Err(MyError::InvalidBatchSize)
+        ));
+        assert!(matches!(
+            extend_batches(&mut batches, &[[1_u8; 32]], &[], 2),
+            Err(MyError::LeavesTreesNotEqual { .. })
+        ));
+        assert_eq!(batches.len(), 2);
+    }
+
+    #[test]
+    fn test_chunked_errors() {
+        assert!(matches!(
+            append_leaves_chunked(vec![[0_u8; 32]; 3], vec![[0_u8; 32]; 2], 2, 1),
+            Err(MyError::LeavesTreesNotEqual {
+                leaves: 3,
+                trees: 2,
+                ..
+            })
+        ));
+        assert!(matches!(
+            append_leaves_chunked(vec![[0_u8; 32]], vec![[0_u8; 32]], 0, 1),
+            Err(MyError::InvalidBatchSize)
+        ));
+        assert!(matches!(
+            append_leaves_chunked(vec![[0_u8; 32]], vec![[0_u8; 32]], 2, 0),
+            Err(MyError::InvalidChunkSize)
+        ));
+        // The batch size is checked first, then the chunk size and then the
+        // lengths, like in `append_leaves`.
+        assert!(matches!(
+            append_leaves_chunked(vec![[0_u8; 32]; 3], vec![[0_u8; 32]; 2], 0, 0),
+            Err(MyError::InvalidBatchSize)
+        ));
+        assert!(matches!(
+            append_leaves_chunked(vec![[0_u8; 32]; 3], vec![[0_u8; 32]; 2], 2, 0),
+            Err(MyError::InvalidChunkSize)
+        ));
+        assert!(matches!(
+            extend_batches(&mut Vec::new(), &[[1_u8; 32]], &[], 0),
+            Err(MyError::InvalidBatchSize)
+        ));
+        assert_eq!(append_leaves_chunked(vec![], vec![], 2, 1).unwrap(), vec![]);
+    }
+}
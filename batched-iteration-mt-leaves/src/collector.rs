@@ -0,0 +1,262 @@
+use std::{
+    collections::VecDeque,
+    mem,
+    sync::{Condvar, Mutex},
+};
+
+use crate::{ChangelogEvent, Changelogs, MyError, HASH_LEN};
+
+/// Collects leaves pushed from multiple threads into batches of `batch_size`
+/// leaves.
+///
+/// Producers call [`push`](Self::push), a consumer calls
+/// [`recv_batch`](Self::recv_batch) to receive full batches. After
+/// [`close`](Self::close), the last partial batch is emitted as well.
+///
+/// Within a batch, the leaves of each Merkle tree are grouped in one event,
+/// in the order in which they were pushed. Events are ordered by the first
+/// leaf pushed for their tree.
+#[derive(Debug)]
+pub struct BatchCollector {
+    batch_size: usize,
+    state: Mutex<CollectorState>,
+    batch_ready: Condvar,
+}
+
+#[derive(Debug)]
+struct CollectorState {
+    batch_of_changelogs: Changelogs,
+    leaves_in_batch: usize,
+    full_batches: VecDeque<Changelogs>,
+    closed: bool,
+}
+
+impl BatchCollector {
+    /// # Panics
+    ///
+    /// Panics if `batch_size` is 0.
+    pub fn new(batch_size: usize) -> Self {
+        assert!(batch_size != 0, "batch size must be non-zero");
+
+        Self {
+            batch_size,
+            state: Mutex::new(CollectorState {
+                batch_of_changelogs: Changelogs {
+                    changelogs: Vec::new(),
+                },
+                leaves_in_batch: 0,
+                full_batches: VecDeque::new(),
+                closed: false,
+            }),
+            batch_ready: Condvar::new(),
+        }
+    }
+
+    /// Adds a leaf appended to `merkle_tree` to the current batch. Fails with
+    /// [`MyError::CollectorClosed`] after [`close`](Self::close).
+    pub fn push(&self, merkle_tree: [u8; HASH_LEN], leaf: [u8; HASH_LEN]) -> Result<(), MyError> {
+        let mut state = self.state.lock().unwrap();
+        if state.closed {
+            return Err(MyError::CollectorClosed);
+        }
+
+        let changelogs = &mut state.batch_of_changelogs.changelogs;
+        match changelogs
+            .iter_mut()
+            .find(|changelog_event| changelog_event.merkle_tree_pubkey == merkle_tree)
+        {
+            Some(changelog_event) => changelog_event.leaves.push(leaf),
+            None => changelogs.push(ChangelogEvent {
+                merkle_tree_pubkey: merkle_tree,
+                leaves: vec![leaf],
+            }),
+        }
+        state.leaves_in_batch += 1;
+
+        if state.leaves_in_batch == self.batch_size {
+            // We reached the batch limit.
+            let batch_of_changelogs = mem::replace(
+                &mut state.batch_of_changelogs,
+                Changelogs {
+                    changelogs: Vec::new(),
+                },
+            );
+            state.full_batches.push_back(batch_of_changelogs);
+            state.leaves_in_batch = 0;
+            self.batch_ready.notify_one();
+        }
+
+        Ok(())
+    }
+
+    /// Blocks until a full batch is available and returns it. After
+    /// [`close`](Self::close), returns the remaining batches and then `None`.
+    pub fn recv_batch(&self) -> Option<Changelogs> {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if let Some(batch_of_changelogs) = state.full_batches.pop_front() {
+                return Some(batch_of_changelogs);
+            }
+            if state.closed {
+                return None;
+            }
+            state = self.batch_ready.wait(state).unwrap();
+        }
+    }
+
+    /// Stops accepting leaves and makes the last, partial batch available to
+    /// [`recv_batch`](Self::recv_batch).
+    pub fn close(&self) {
+        let mut state = self.state.lock().unwrap();
+        if state.closed {
+            return;
+        }
+        state.closed = true;
+
+        if state.leaves_in_batch > 0 {
+            let batch_of_changelogs = mem::replace(
+                &mut state.batch_of_changelogs,
+                Changelogs {
+                    changelogs: Vec::new(),
+                },
+            );
+            state.full_batches.push_back(batch_of_changelogs);
+            state.leaves_in_batch = 0;
+        }
+        self.batch_ready.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+
+    const NUM_PRODUCERS: u32 = 8;
+    const LEAVES_PER_PRODUCER: u32 = 500;
+    const BATCH_SIZE: usize = 7;
+
+    /// xorshift32, to randomize the interleaving of producers.
+    fn next_random(state: &mut u32) -> u32 {
+        *state ^= *state << 13;
+        *state ^= *state >> 17;
+        *state ^= *state << 5;
+        *state
+    }
+
+    #[test]
+    fn test_batch_collector_concurrent() {
+        let collector = BatchCollector::new(BATCH_SIZE);
+
+        let mut expected = Vec::new();
+        for producer in 0..NUM_PRODUCERS {
+            for i in 0..LEAVES_PER_PRODUCER {
+                let mut leaf = [0_u8; 32];
+                leaf[..4].copy_from_slice(&producer.to_le_bytes());
+                leaf[4..8].copy_from_slice(&i.to_le_bytes());
+                expected.push(([(i % 5) as u8; 32], leaf));
+            }
+        }
+
+        let batches = thread::scope(|s| {
+            let consumer = s.spawn(|| {
+                let mut batches = Vec::new();
+                while let Some(batch) = collector.recv_batch() {
+                    batches.push(batch);
+                }
+                batches
+            });
+
+            let producers: Vec<_> = expected
+                .chunks(LEAVES_PER_PRODUCER as usize)
+                .enumerate()
+                .map(|(producer, pairs)| {
+                    let collector = &collector;
+                    s.spawn(move || {
+                        let mut random = producer as u32 + 1;
+                        for (merkle_tree, leaf) in pairs {
+                            for _ in 0..next_random(&mut random) % 4 {
+                                thread::yield_now();
+                            }
+                            collector.push(*merkle_tree, *leaf).unwrap();
+                        }
+                    })
+                })
+                .collect();
+            for producer in producers {
+                producer.join().unwrap();
+            }
+
+            collector.close();
+            consumer.join().unwrap()
+        });
+
+        let num_leaves = (NUM_PRODUCERS * LEAVES_PER_PRODUCER) as usize;
+        assert_eq!(batches.len(), num_leaves.div_ceil(BATCH_SIZE));
+
+        let mut actual = Vec::with_capacity(num_leaves);
+        for (i, batch) in batches.iter().enumerate() {
+            let leaves_in_batch: usize = batch
+                .changelogs
+                .iter()
+                .map(|changelog_event| changelog_event.leaves.len())
+                .sum();
+            if i + 1 < batches.len() {
+                assert_eq!(leaves_in_batch, BATCH_SIZE);
+            } else {
+                assert!(leaves_in_batch <= BATCH_SIZE);
+            }
+
+            for changelog_event in batch.changelogs.iter() {
+                for leaf in changelog_event.leaves.iter() {
+                    actual.push((changelog_event.merkle_tree_pubkey, *leaf));
+                }
+            }
+        }
+
+        actual.sort();
+        expected.sort();
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn test_batch_collector_close() {
+        let collector = BatchCollector::new(3);
+
+        for i in 0..4_u8 {
+            collector.push([i % 2; 32], [i; 32]).unwrap();
+        }
+        collector.close();
+        assert!(matches!(
+            collector.push([0_u8; 32], [9_u8; 32]),
+            Err(MyError::CollectorClosed)
+        ));
+
+        assert_eq!(
+            collector.recv_batch(),
+            Some(Changelogs {
+                changelogs: vec![
+                    ChangelogEvent {
+                        merkle_tree_pubkey: [0_u8; 32],
+                        leaves: vec![[0_u8; 32], [2_u8; 32]],
+                    },
+                    ChangelogEvent {
+                        merkle_tree_pubkey: [1_u8; 32],
+                        leaves: vec![[1_u8; 32]],
+                    },
+                ]
+            })
+        );
+        assert_eq!(
+            collector.recv_batch(),
+            Some(Changelogs {
+                changelogs: vec![ChangelogEvent {
+                    merkle_tree_pubkey: [1_u8; 32],
+                    leaves: vec![[3_u8; 32]],
+                }]
+            })
+        );
+        assert_eq!(collector.recv_batch(), None);
+    }
+}
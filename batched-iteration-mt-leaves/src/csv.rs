@@ -0,0 +1,288 @@
+use std::io::{BufRead, BufReader, Read, Write};
+
+use crate::{hex, ChangelogEvent, Changelogs, MyError};
+
+/// Header of the CSV written by [`to_csv`].
+pub const CSV_HEADER: &str = "batch_index,event_index,position,tree_hex,leaf_hex";
+
+/// Writes every leaf of `batches` as a CSV row, after the [`CSV_HEADER`]:
+///
+/// ```text
+/// batch_index,event_index,position,tree_hex,leaf_hex
+/// 0,0,0,<hex>,<hex>
+/// ```
+///
+/// `position` is the index of the leaf within its event. Pubkeys and leaves
+/// are lowercase hex without the `0x` prefix.
+pub fn to_csv<W: Write>(batches: &[Changelogs], mut writer: W) -> Result<(), MyError> {
+    writeln!(writer, "{CSV_HEADER}")?;
+
+    for (batch_index, batch) in batches.iter().enumerate() {
+        for (event_index, changelog_event) in batch.changelogs.iter().enumerate() {
+            let tree = hex::encode(&changelog_event.merkle_tree_pubkey);
+            for (position, leaf) in changelog_event.leaves.iter().enumerate() {
+                writeln!(
+                    writer,
+                    "{batch_index},{event_index},{position},{tree},{}",
+                    hex::encode(leaf)
+                )?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Reads batches written by [`to_csv`]. Blank lines are skipped.
+///
+/// Batches and the events within a batch have to be numbered consecutively
+/// from 0, and so do the positions of leaves within an event. All rows of an
+/// event have to name the same Merkle tree. Errors name the line of the
+/// offending row, counting the header as line 1.
+pub fn from_csv<R: Read>(reader: R) -> Result<Vec<Changelogs>, MyError> {
+    let mut lines = BufReader::new(reader).lines();
+
+    match lines.next().transpose()? {
+        Some(header) if header.trim_end() == CSV_HEADER => {}
+        _ => {
+            return Err(MyError::InvalidCsvRow {
+                line: 1,
+                reason: format!("expected the header {CSV_HEADER:?}"),
+            })
+        }
+    }
+
+    let mut batches: Vec<Changelogs> = Vec::new();
+
+    for (i, line) in lines.enumerate() {
+        let line_number = i + 2;
+        let line = line?;
+        let line = line.trim_end();
+        if line.is_empty() {
+            continue;
+        }
+
+        let invalid_row = |reason: String| MyError::InvalidCsvRow {
+            line: line_number,
+            reason,
+        };
+        let unexpected_index =
+            |field: &'static str, expected: usize, got: usize| MyError::UnexpectedIndex {
+                line: line_number,
+                field,
+                expected,
+                got,
+            };
+
+        let columns: Vec<&str> = line.split(',').collect();
+        let [batch_index, event_index, position, tree, leaf] = columns[..] else {
+            return Err(invalid_row(format!(
+                "expected 5 columns, got {}",
+                columns.len()
+            )));
+        };
+        let parse_index = |field: &'static str, value: &str| {
+            value
+                .parse::<usize>()
+                .map_err(|_| invalid_row(format!("invalid {field} {value:?}")))
+        };
+        let batch_index = parse_index("batch_index", batch_index)?;
+        let event_index = parse_index("event_index", event_index)?;
+        let position = parse_index("position", position)?;
+        let decode = |field: &'static str, value: &str| {
+            hex::decode_hash(value).ok_or_else(|| MyError::InvalidHex {
+                line: line_number,
+                field,
+                value: value.to_owned(),
+            })
+        };
+        let merkle_tree_pubkey = decode("tree", tree)?;
+        let leaf = decode("leaf", leaf)?;
+
+        // A row either continues the last batch or starts the next one.
+        let last_batch_index = batches.len().checked_sub(1);
+        if Some(batch_index) != last_batch_index {
+            if batch_index != batches.len() {
+                return Err(MyError::UnexpectedBatch {
+                    line: line_number,
+                    expected: batches.len(),
+                    got: batch_index,
+                });
+            }
+            batches.push(Changelogs {
+                changelogs: Vec::new(),
+            });
+        }
+        // Can't fail, a batch was pushed above if there was none.
+        let batch = batches.last_mut().unwrap();
+
+        // Same for the events of the batch.
+        let last_event_index = batch.changelogs.len().checked_sub(1);
+        if Some(event_index) != last_event_index {
+            if event_index != batch.changelogs.len() {
+                return Err(unexpected_index(
+                    "event_index",
+                    batch.changelogs.len(),
+                    event_index,
+                ));
+            }
+            batch.changelogs.push(ChangelogEvent {
+                merkle_tree_pubkey,
+                leaves: Vec::new(),
+            });
+        }
+        // Can't fail, an event was pushed above if there was none.
+        let changelog_event = batch.changelogs.last_mut().unwrap();
+
+        if changelog_event.merkle_tree_pubkey != merkle_tree_pubkey {
+            return Err(invalid_row(format!(
+                "tree {tree} differs from the tree of the event"
+            )));
+        }
+        if position != changelog_event.leaves.len() {
+            return Err(unexpected_index(
+                "position",
+                changelog_event.leaves.len(),
+                position,
+            ));
+        }
+        changelog_event.leaves.push(leaf);
+    }
+
+    Ok(batches)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{append_leaves, fixture};
+
+    fn row(batch_index: usize, event_index: usize, position: usize, tree: u8, leaf: u8) -> String {
+        format!(
+            "{batch_index},{event_index},{position},{},{}\n",
+            "0".repeat(63) + &tree.to_string(),
+            "0".repeat(63) + &leaf.to_string()
+        )
+    }
+
+    #[test]
+    fn test_csv_round_trip() {
+        let (leaves, merkle_trees) = fixture::leaves_and_merkle_trees();
+
+        for batch_size in [1, 10, 100] {
+            let batches = append_leaves(leaves.clone(), merkle_trees.clone(), batch_size).unwrap();
+
+            let mut csv = Vec::new();
+            to_csv(&batches, &mut csv).unwrap();
+            // Header and one row per leaf.
+            assert_eq!(csv.iter().filter(|byte| **byte == b'\n').count(), 26);
+
+            assert_eq!(from_csv(csv.as_slice()).unwrap(), batches);
+        }
+
+        let mut csv = Vec::new();
+        to_csv(&[], &mut csv).unwrap();
+        assert_eq!(csv, format!("{CSV_HEADER}\n").into_bytes());
+        assert!(from_csv(csv.as_slice()).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_to_csv() {
+        let batches = append_leaves(
+            vec![[1_u8; 32], [2_u8; 32], [3_u8; 32]],
+            vec![[0_u8; 32], [1_u8; 32], [1_u8; 32]],
+            2,
+        )
+        .unwrap();
+
+        let mut csv = Vec::new();
+        to_csv(&batches, &mut csv).unwrap();
+
+        let (mt0, mt1) = ("00".repeat(32), "01".repeat(32));
+        let (leaf1, leaf2, leaf3) = ("01".repeat(32), "02".repeat(32), "03".repeat(32));
+        assert_eq!(
+            String::from_utf8(csv).unwrap(),
+            format!(
+                "{CSV_HEADER}\n0,0,0,{mt0},{leaf1}\n0,1,0,{mt1},{leaf2}\n1,0,0,{mt1},{leaf3}\n"
+            )
+        );
+    }
+
+    #[test]
+    fn test_from_csv_malformed() {
+        let header = format!("{CSV_HEADER}\n");
+
+        // Gap in the positions, on line 3.
+        let csv = header.clone() + &row(0, 0, 0, 1, 1) + &row(0, 0, 2, 1, 2);
+        assert!(matches!(
+            from_csv(csv.as_bytes()),
+            Err(MyError::UnexpectedIndex {
+                line: 3,
+                field: "position",
+                expected: 1,
+                got: 2
+            })
+        ));
+
+        // Malformed hex of the leaf, on line 4.
+        let mut csv =
+            header.clone() + &row(0, 0, 0, 1, 1) + &row(0, 0, 1, 1, 2) + &row(0, 1, 0, 2, 3);
+        csv.replace_range(csv.len() - 2.., "x\n");
+        let err = from_csv(csv.as_bytes()).unwrap_err();
+        assert!(matches!(
+            err,
+            MyError::InvalidHex {
+                line: 4,
+                field: "leaf",
+                ..
+            }
+        ));
+        assert!(err.to_string().starts_with("Line 4: invalid leaf"));
+
+        // Skipped event and batch.
+        let csv = header.clone() + &row(0, 0, 0, 1, 1) + &row(0, 2, 0, 2, 2);
+        assert!(matches!(
+            from_csv(csv.as_bytes()),
+            Err(MyError::UnexpectedIndex {
+                line: 3,
+                field: "event_index",
+                expected: 1,
+                got: 2
+            })
+        ));
+        let csv = header.clone() + &row(1, 0, 0, 1, 1);
+        assert!(matches!(
+            from_csv(csv.as_bytes()),
+            Err(MyError::UnexpectedBatch {
+                line: 2,
+                expected: 0,
+                got: 1
+            })
+        ));
+
+        // Tree changing within an event.
+        let csv = header.clone() + &row(0, 0, 0, 1, 1) + &row(0, 0, 1, 2, 2);
+        assert!(matches!(
+            from_csv(csv.as_bytes()),
+            Err(MyError::InvalidCsvRow { line: 3, .. })
+        ));
+
+        // Missing column and non-numeric index.
+        let csv = header.clone() + "0,0,0\n";
+        assert_eq!(
+            from_csv(csv.as_bytes()).unwrap_err().to_string(),
+            "Line 2: expected 5 columns, got 3"
+        );
+        let csv = header + &row(0, 0, 0, 1, 1).replacen('0', "a", 1);
+        assert_eq!(
+            from_csv(csv.as_bytes()).unwrap_err().to_string(),
+            "Line 2: invalid batch_index \"a\""
+        );
+
+        // Missing header.
+        assert!(matches!(
+            from_csv(row(0, 0, 0, 1, 1).as_bytes()),
+            Err(MyError::InvalidCsvRow { line: 1, .. })
+        ));
+    }
+}
@@ -0,0 +1,216 @@
+use std::{cmp, collections::BTreeMap, ops::Range};
+
+use crate::{Batches, Changelogs, MyError, HASH_LEN};
+
+/// Position in the leaves of a Merkle tree map, from which
+/// [`append_leaves_resumable`] continues batching.
+///
+/// The default cursor points at the first leaf.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct BatchCursor {
+    /// Index of the Merkle tree in the map, in ascending order of pubkeys.
+    pub tree_index: usize,
+    /// Number of leaves of that tree which were already batched.
+    pub leaves_consumed: usize,
+}
+
+impl BatchCursor {
+    /// Advances the cursor over the next batch of at most `batch_size`
+    /// leaves and passes each of its events to `emit`, as the index of the
+    /// Merkle tree and the range of its leaves. `tree_len` returns the number
+    /// of leaves of the Merkle tree with the given index, `None` past the
+    /// last one. It's called with consecutive indices, starting at the
+    /// cursor's tree and ending at most one past the last tree of the batch. Returns the number of leaves in the batch, 0 once all of
+    /// them were batched.
+    ///
+    /// This is the greedy split shared by all the batching functions: every
+    /// batch except the last one has exactly `batch_size` leaves, and Merkle
+    /// trees without leaves produce no events. `batch_size` must not be 0.
+    pub(crate) fn next_batch(
+        &mut self,
+        batch_size: usize,
+        mut tree_len: impl FnMut(usize) -> Option<usize>,
+        mut emit: impl FnMut(usize, Range<usize>),
+    ) -> usize {
+        debug_assert!(batch_size != 0);
+        let mut leaves_in_batch = 0;
+
+        while let Some(len) = tree_len(self.tree_index) {
+            // The arithmetic below can't overflow: `leaves_consumed` never
+            // exceeds the number of leaves of the current Merkle tree and
+            // `leaves_in_batch` never exceeds `batch_size`, so both
+            // subtractions are non-negative and both sums are bounded by
+            // `len` and `batch_size` respectively.
+            debug_assert!(self.leaves_consumed <= len);
+            debug_assert!(leaves_in_batch <= batch_size);
+            let leaves_to_process =
+                cmp::min(len - self.leaves_consumed, batch_size - leaves_in_batch);
+            let leaves_end = self.leaves_consumed + leaves_to_process;
+
+            if leaves_to_process > 0 {
+                emit(self.tree_index, self.leaves_consumed..leaves_end);
+            }
+
+            leaves_in_batch += leaves_to_process;
+            self.leaves_consumed = leaves_end;
+
+            if self.leaves_consumed == len {
+                // We processed all the leaves from the current Merkle tree.
+                // Move to the next one.
+                self.leaves_consumed = 0;
+                self.tree_index += 1;
+            }
+
+            if leaves_in_batch == batch_size {
+                // We reached the batch limit.
+                break;
+            }
+        }
+
+        leaves_in_batch
+    }
+}
+
+/// Produces at most `max_batches` batches of `merkle_tree_map` (see
+/// [`build_merkle_tree_map`](crate::build_merkle_tree_map)), starting at
+/// `cursor`, and returns them together with the cursor to continue from.
+///
+/// Batches are the same as the corresponding ones of
+/// [`batch_map`](crate::batch_map), so resuming from the returned cursor
+/// with the same map and batch size continues exactly where the previous
+/// call stopped. Once all leaves are batched, no more batches are produced.
+///
+/// Fails with [`MyError::InvalidCursor`] if `cursor` doesn't point into the
+/// map.
+pub fn append_leaves_resumable(
+    merkle_tree_map: &BTreeMap<[u8; HASH_LEN], Vec<[u8; HASH_LEN]>>,
+    batch_size: usize,
+    cursor: BatchCursor,
+    max_batches: usize,
+) -> Result<(Vec<Changelogs>, BatchCursor), MyError> {
+    if batch_size == 0 {
+        return Err(MyError::InvalidBatchSize);
+    }
+    let tree_len = merkle_tree_map
+        .values()
+        .nth(cursor.tree_index)
+        .map_or(0, Vec::len);
+    if cursor.tree_index > merkle_tree_map.len() || cursor.leaves_consumed > tree_len {
+        return Err(MyError::InvalidCursor {
+            tree_index: cursor.tree_index,
+            leaves_consumed: cursor.leaves_consumed,
+        });
+    }
+
+    let mut batches = Batches::resume(
+        merkle_tree_map
+            .iter()
+            .map(|(merkle_tree, leaves)| (*merkle_tree, leaves.as_slice()))
+            .collect(),
+        batch_size,
+        cursor,
+    );
+    let batches_of_changelogs = batches.by_ref().take(max_batches).collect();
+
+    Ok((batches_of_changelogs, batches.cursor()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{batch_map, build_merkle_tree_map, fixture};
+
+    #[test]
+    fn test_append_leaves_resumable() {
+        let (leaves, merkle_trees) = fixture::leaves_and_merkle_trees();
+        let merkle_tree_map = build_merkle_tree_map(&leaves, &merkle_trees).unwrap();
+
+        for batch_size in [1, 3, 7, 10, 25] {
+            let expected = batch_map(merkle_tree_map.clone(), batch_size).unwrap();
+            let half = expected.len() / 2;
+
+            let (mut batches, cursor) =
+                append_leaves_resumable(&merkle_tree_map, batch_size, BatchCursor::default(), half)
+                    .unwrap();
+            assert_eq!(batches.len(), half);
+            let (rest, end) =
+                append_leaves_resumable(&merkle_tree_map, batch_size, cursor, usize::MAX).unwrap();
+            batches.extend(rest);
+            assert_eq!(batches, expected, "batch size {batch_size}");
+
+            // Nothing left after the end.
+            assert_eq!(
+                append_leaves_resumable(&merkle_tree_map, batch_size, end, 1).unwrap(),
+                (vec![], end)
+            );
+        }
+    }
+
+    #[test]
+    fn test_append_leaves_resumable_cursor() {
+        let (leaves, merkle_trees) = fixture::leaves_and_merkle_trees();
+        let merkle_tree_map = build_merkle_tree_map(&leaves, &merkle_trees).unwrap();
+
+        // The second batch ends in the middle of MT 3.
+        let (_, cursor) =
+            append_leaves_resumable(&merkle_tree_map, 10, BatchCursor::default(), 2).unwrap();
+        assert_eq!(
+            cursor,
+            BatchCursor {
+                tree_index: 3,
+                leaves_consumed: 1,
+            }
+        );
+
+        // A cursor at the end of a tree is the same as at the start of the
+        // next one.
+        let at_end_of_mt0 = BatchCursor {
+            tree_index: 0,
+            leaves_consumed: 12,
+        };
+        let at_start_of_mt1 = BatchCursor {
+            tree_index: 1,
+            leaves_consumed: 0,
+        };
+        assert_eq!(
+            append_leaves_resumable(&merkle_tree_map, 10, at_end_of_mt0, 1)
+                .unwrap()
+                .0,
+            append_leaves_resumable(&merkle_tree_map, 10, at_start_of_mt1, 1)
+                .unwrap()
+                .0,
+        );
+
+        for (tree_index, leaves_consumed) in [(0, 13), (4, 1), (5, 0)] {
+            assert!(matches!(
+                append_leaves_resumable(
+                    &merkle_tree_map,
+                    10,
+                    BatchCursor {
+                        tree_index,
+                        leaves_consumed
+                    },
+                    1
+                ),
+                Err(MyError::InvalidCursor { .. })
+            ));
+        }
+        assert!(matches!(
+            append_leaves_resumable(&merkle_tree_map, 0, BatchCursor::default(), 1),
+            Err(MyError::InvalidBatchSize)
+        ));
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_batch_cursor_serde() {
+        let cursor = BatchCursor {
+            tree_index: 3,
+            leaves_consumed: 1,
+        };
+        let json = serde_json::to_string(&cursor).unwrap();
+        assert_eq!(json, r#"{"tree_index":3,"leaves_consumed":1}"#);
+        assert_eq!(serde_json::from_str::<BatchCursor>(&json).unwrap(), cursor);
+    }
+}
@@ -0,0 +1,260 @@
+use std::{collections::BTreeMap, mem};
+
+use crate::{ChangelogEvent, Changelogs, MyError, HASH_LEN};
+
+/// Leaf with an opaque payload attached to it (e.g. an owner hash).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LeafWithData {
+    pub leaf: [u8; HASH_LEN],
+    pub data: Vec<u8>,
+}
+
+impl LeafWithData {
+    /// Returns the number of bytes the leaf takes in a batch, which is the
+    /// length of the leaf and its data.
+    pub fn byte_len(&self) -> u64 {
+        (HASH_LEN + self.data.len()) as u64
+    }
+}
+
+/// Same as [`ChangelogEvent`], but each leaf carries its data.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ChangelogEventWithData {
+    pub merkle_tree_pubkey: [u8; HASH_LEN],
+    pub leaves: Vec<LeafWithData>,
+}
+
+/// Set of changelogs with data for different Merkle trees.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ChangelogsWithData {
+    pub changelogs: Vec<ChangelogEventWithData>,
+}
+
+impl From<ChangelogEventWithData> for ChangelogEvent {
+    /// Strips the data from the leaves.
+    fn from(changelog_event: ChangelogEventWithData) -> Self {
+        Self {
+            merkle_tree_pubkey: changelog_event.merkle_tree_pubkey,
+            leaves: changelog_event
+                .leaves
+                .into_iter()
+                .map(|leaf_with_data| leaf_with_data.leaf)
+                .collect(),
+        }
+    }
+}
+
+impl From<ChangelogsWithData> for Changelogs {
+    /// Strips the data from the leaves.
+    fn from(changelogs: ChangelogsWithData) -> Self {
+        Self {
+            changelogs: changelogs
+                .changelogs
+                .into_iter()
+                .map(ChangelogEvent::from)
+                .collect(),
+        }
+    }
+}
+
+/// Batches leaves with data like [`append_leaves`](crate::append_leaves).
+/// Each item is a `(merkle_tree, leaf, data)` triple.
+pub fn append_leaves_with_data(
+    items: &[([u8; HASH_LEN], [u8; HASH_LEN], Vec<u8>)],
+    batch_size: usize,
+) -> Result<Vec<ChangelogsWithData>, MyError> {
+    batch_leaves_with_data(items, batch_size, u64::MAX)
+}
+
+/// Same as [`append_leaves_with_data`], but additionally closes a batch
+/// before the next leaf would push its size over `max_batch_bytes`. The size
+/// of a leaf is given by [`LeafWithData::byte_len`].
+pub fn append_leaves_with_data_by_bytes(
+    items: &[([u8; HASH_LEN], [u8; HASH_LEN], Vec<u8>)],
+    batch_size: usize,
+    max_batch_bytes: u64,
+) -> Result<Vec<ChangelogsWithData>, MyError> {
+    batch_leaves_with_data(items, batch_size, max_batch_bytes)
+}
+
+fn batch_leaves_with_data(
+    items: &[([u8; HASH_LEN], [u8; HASH_LEN], Vec<u8>)],
+    batch_size: usize,
+    max_batch_bytes: u64,
+) -> Result<Vec<ChangelogsWithData>, MyError> {
+    if batch_size == 0 {
+        return Err(MyError::InvalidBatchSize);
+    }
+
+    let mut merkle_tree_map: BTreeMap<[u8; HASH_LEN], Vec<LeafWithData>> = BTreeMap::new();
+    for (merkle_tree, leaf, data) in items {
+        let leaf_with_data = LeafWithData {
+            leaf: *leaf,
+            data: data.clone(),
+        };
+        if leaf_with_data.byte_len() > max_batch_bytes {
+            return Err(MyError::LeafWeightExceeded(
+                leaf_with_data.byte_len(),
+                max_batch_bytes,
+            ));
+        }
+        merkle_tree_map
+            .entry(*merkle_tree)
+            .or_default()
+            .push(leaf_with_data);
+    }
+
+    let mut batches_of_changelogs = Vec::new();
+
+    let mut batch_of_changelogs = ChangelogsWithData {
+        changelogs: Vec::new(),
+    };
+    let mut batch_len = 0;
+    let mut batch_bytes = 0;
+
+    for (merkle_tree_pubkey, leaves) in merkle_tree_map {
+        for leaf_with_data in leaves {
+            let byte_len = leaf_with_data.byte_len();
+            // `batch_bytes` never exceeds `max_batch_bytes`, so the
+            // subtraction can't underflow.
+            if batch_len == batch_size || byte_len > max_batch_bytes - batch_bytes {
+                // The leaf doesn't fit. Close the batch.
+                batches_of_changelogs.push(mem::replace(
+                    &mut batch_of_changelogs,
+                    ChangelogsWithData {
+                        changelogs: Vec::new(),
+                    },
+                ));
+                batch_len = 0;
+                batch_bytes = 0;
+            }
+
+            match batch_of_changelogs.changelogs.last_mut() {
+                Some(changelog_event)
+                    if changelog_event.merkle_tree_pubkey == merkle_tree_pubkey =>
+                {
+                    changelog_event.leaves.push(leaf_with_data)
+                }
+                _ => batch_of_changelogs.changelogs.push(ChangelogEventWithData {
+                    merkle_tree_pubkey,
+                    leaves: vec![leaf_with_data],
+                }),
+            }
+            batch_len += 1;
+            batch_bytes += byte_len;
+        }
+    }
+
+    if !batch_of_changelogs.changelogs.is_empty() {
+        batches_of_changelogs.push(batch_of_changelogs);
+    }
+
+    Ok(batches_of_changelogs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{append_leaves, fixture};
+
+    fn data_lens(batches: &[ChangelogsWithData]) -> Vec<Vec<usize>> {
+        batches
+            .iter()
+            .map(|batch| {
+                batch
+                    .changelogs
+                    .iter()
+                    .flat_map(|changelog_event| &changelog_event.leaves)
+                    .map(|leaf_with_data| leaf_with_data.data.len())
+                    .collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_append_leaves_with_data() {
+        let (leaves, merkle_trees) = fixture::leaves_and_merkle_trees();
+        // Mixed data lengths, every third payload is empty.
+        let items: Vec<_> = merkle_trees
+            .iter()
+            .zip(&leaves)
+            .enumerate()
+            .map(|(i, (merkle_tree, leaf))| (*merkle_tree, *leaf, vec![i as u8; i % 3 * 8]))
+            .collect();
+
+        let batches = append_leaves_with_data(&items, 10).unwrap();
+
+        // The data stays with its leaf.
+        for leaf_with_data in batches
+            .iter()
+            .flat_map(|batch| &batch.changelogs)
+            .flat_map(|changelog_event| &changelog_event.leaves)
+        {
+            let i = leaves
+                .iter()
+                .position(|leaf| *leaf == leaf_with_data.leaf)
+                .unwrap();
+            assert_eq!(leaf_with_data.data, items[i].2);
+        }
+
+        // Without the data, the batches are the same as without the data.
+        let stripped: Vec<Changelogs> = batches.into_iter().map(Changelogs::from).collect();
+        assert_eq!(stripped, append_leaves(leaves, merkle_trees, 10).unwrap());
+    }
+
+    #[test]
+    fn test_append_leaves_with_data_by_bytes() {
+        let items = [
+            ([0_u8; 32], [0_u8; 32], vec![0; 40]),
+            ([1_u8; 32], [1_u8; 32], Vec::new()),
+            ([0_u8; 32], [2_u8; 32], vec![2; 8]),
+            ([1_u8; 32], [3_u8; 32], vec![3; 100]),
+            ([1_u8; 32], [4_u8; 32], Vec::new()),
+            ([2_u8; 32], [5_u8; 32], Vec::new()),
+        ];
+
+        // Leaf sizes in the batching order: 72, 40 | 32 | 132 | 32, 32 | 32.
+        let batches = append_leaves_with_data_by_bytes(&items, 2, 132).unwrap();
+        assert_eq!(
+            data_lens(&batches),
+            vec![vec![40, 8], vec![0], vec![100], vec![0, 0]]
+        );
+        assert_eq!(
+            batches[3].changelogs,
+            vec![
+                ChangelogEventWithData {
+                    merkle_tree_pubkey: [1_u8; 32],
+                    leaves: vec![LeafWithData {
+                        leaf: [4_u8; 32],
+                        data: Vec::new(),
+                    }],
+                },
+                ChangelogEventWithData {
+                    merkle_tree_pubkey: [2_u8; 32],
+                    leaves: vec![LeafWithData {
+                        leaf: [5_u8; 32],
+                        data: Vec::new(),
+                    }],
+                },
+            ]
+        );
+
+        // Only the leaf count limits the batches when the byte limit is large.
+        let batches = append_leaves_with_data_by_bytes(&items, 2, 1024).unwrap();
+        assert_eq!(
+            data_lens(&batches),
+            append_leaves_with_data(&items, 2)
+                .map(|batches| data_lens(&batches))
+                .unwrap()
+        );
+
+        assert!(matches!(
+            append_leaves_with_data_by_bytes(&items, 2, 100),
+            Err(MyError::LeafWeightExceeded(132, 100))
+        ));
+        assert!(matches!(
+            append_leaves_with_data(&items, 0),
+            Err(MyError::InvalidBatchSize)
+        ));
+    }
+}
@@ -0,0 +1,157 @@
+use std::fmt::{self, Write};
+
+use crate::{hex::write_hex, ChangelogEvent, Changelogs, HASH_LEN};
+
+/// Number of leading bytes printed for abbreviated leaves.
+pub(crate) const ABBREVIATED_LEN: usize = 4;
+
+/// Writes the Merkle tree pubkey as hex or, with the `solana` feature, as
+/// base58.
+fn write_merkle_tree_pubkey(f: &mut fmt::Formatter<'_>, pubkey: &[u8; HASH_LEN]) -> fmt::Result {
+    #[cfg(feature = "solana")]
+    {
+        write!(f, "{}", solana_pubkey::Pubkey::new_from_array(*pubkey))
+    }
+    #[cfg(not(feature = "solana"))]
+    {
+        write_hex(f, pubkey)
+    }
+}
+
+fn write_abbreviated(f: &mut fmt::Formatter<'_>, leaf: &[u8; HASH_LEN]) -> fmt::Result {
+    write_hex(f, &leaf[..ABBREVIATED_LEN])?;
+    f.write_str("..")
+}
+
+impl fmt::Display for ChangelogEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write_merkle_tree_pubkey(f, &self.merkle_tree_pubkey)?;
+        write!(f, ": {} leaves", self.leaves.len())?;
+        if let (Some(first), Some(last)) = (self.leaves.first(), self.leaves.last()) {
+            f.write_str(", first ")?;
+            write_abbreviated(f, first)?;
+            f.write_str(", last ")?;
+            write_abbreviated(f, last)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Changelogs {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let leaves: usize = self
+            .changelogs
+            .iter()
+            .map(|changelog_event| changelog_event.leaves.len())
+            .sum();
+        write!(f, "{} events, {} leaves", self.changelogs.len(), leaves)
+    }
+}
+
+/// Returns a table with one row per batch: its index, the number of leaves,
+/// the number of trees and how full the batch is.
+///
+/// The batch size isn't stored in the batches, so the fill percentage is
+/// relative to the largest batch. That's the batch size as long as at least
+/// one batch is full.
+pub fn summarize(batches: &[Changelogs]) -> String {
+    let leaves_per_batch = |batch: &Changelogs| -> usize {
+        batch
+            .changelogs
+            .iter()
+            .map(|changelog_event| changelog_event.leaves.len())
+            .sum()
+    };
+    let max_leaves = batches.iter().map(leaves_per_batch).max().unwrap_or(0);
+
+    let mut summary = String::new();
+    // Writing to a `String` never fails.
+    writeln!(
+        summary,
+        "{:>5} {:>8} {:>6} {:>7}",
+        "batch", "leaves", "trees", "fill"
+    )
+    .unwrap();
+    for (i, batch) in batches.iter().enumerate() {
+        let leaves = leaves_per_batch(batch);
+        let fill = if max_leaves == 0 {
+            0.0
+        } else {
+            leaves as f64 * 100.0 / max_leaves as f64
+        };
+        writeln!(
+            summary,
+            "{:>5} {:>8} {:>6} {:>6.1}%",
+            i,
+            leaves,
+            batch.changelogs.len(),
+            fill
+        )
+        .unwrap();
+    }
+
+    summary
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixture;
+
+    #[cfg(not(feature = "solana"))]
+    #[test]
+    fn test_changelog_event_display() {
+        let batches = fixture::batches();
+
+        assert_eq!(
+            batches[1].changelogs[0].to_string(),
+            "0000000000000000000000000000000000000000000000000000000000000000: \
+             2 leaves, first 0a0a0a0a.., last 0b0b0b0b.."
+        );
+        assert_eq!(
+            batches[1].changelogs[3].to_string(),
+            "0303030303030303030303030303030303030303030303030303030303030303: \
+             1 leaves, first 13131313.., last 13131313.."
+        );
+
+        let empty = ChangelogEvent {
+            merkle_tree_pubkey: [255_u8; 32],
+            leaves: Vec::new(),
+        };
+        assert_eq!(
+            empty.to_string(),
+            "ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff: 0 leaves"
+        );
+    }
+
+    #[test]
+    fn test_changelogs_display() {
+        let batches = fixture::batches();
+
+        let displayed: Vec<String> = batches.iter().map(ToString::to_string).collect();
+        assert_eq!(
+            displayed,
+            [
+                "1 events, 10 leaves",
+                "4 events, 10 leaves",
+                "1 events, 5 leaves"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_summarize() {
+        let batches = fixture::batches();
+
+        assert_eq!(
+            summarize(&batches),
+            "\
+batch   leaves  trees    fill
+    0       10      1  100.0%
+    1       10      4  100.0%
+    2        5      1   50.0%
+"
+        );
+        assert_eq!(summarize(&[]), "batch   leaves  trees    fill\n");
+    }
+}
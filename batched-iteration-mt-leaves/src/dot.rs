@@ -0,0 +1,102 @@
+use std::fmt::Write;
+
+use crate::{display::ABBREVIATED_LEN, hex::write_hex, Changelogs};
+
+/// Escapes `s` for use inside a double-quoted DOT string.
+fn escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Returns a Graphviz DOT graph of the batches.
+///
+/// Every batch is a cluster and every event is a node labeled with the
+/// abbreviated Merkle tree pubkey and the number of leaves. Events of the
+/// same Merkle tree in consecutive batches are connected with an edge, which
+/// shows where a tree was split.
+pub fn to_dot(batches: &[Changelogs]) -> String {
+    let mut dot = String::new();
+    // Writing to a `String` never fails.
+    writeln!(dot, "digraph batches {{").unwrap();
+    writeln!(dot, "    node [shape=box];").unwrap();
+
+    for (i, batch) in batches.iter().enumerate() {
+        writeln!(dot, "    subgraph cluster_{i} {{").unwrap();
+        writeln!(dot, "        label=\"{}\";", escape(&format!("batch {i}"))).unwrap();
+        for (j, changelog_event) in batch.changelogs.iter().enumerate() {
+            let mut label = String::new();
+            write_hex(
+                &mut label,
+                &changelog_event.merkle_tree_pubkey[..ABBREVIATED_LEN],
+            )
+            .unwrap();
+            write!(label, "..\n{} leaves", changelog_event.leaves.len()).unwrap();
+            writeln!(dot, "        b{i}_e{j} [label=\"{}\"];", escape(&label)).unwrap();
+        }
+        writeln!(dot, "    }}").unwrap();
+    }
+
+    for (i, pair) in batches.windows(2).enumerate() {
+        for (j, changelog_event) in pair[0].changelogs.iter().enumerate() {
+            if let Some(k) = pair[1].changelogs.iter().position(|next_event| {
+                next_event.merkle_tree_pubkey == changelog_event.merkle_tree_pubkey
+            }) {
+                writeln!(dot, "    b{i}_e{j} -> b{}_e{k};", i + 1).unwrap();
+            }
+        }
+    }
+
+    writeln!(dot, "}}").unwrap();
+    dot
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{append_leaves, fixture};
+
+    #[test]
+    fn test_to_dot() {
+        let (leaves, merkle_trees) = fixture::leaves_and_merkle_trees();
+        let batches = append_leaves(leaves, merkle_trees, 10).unwrap();
+
+        assert_eq!(
+            to_dot(&batches),
+            r#"digraph batches {
+    node [shape=box];
+    subgraph cluster_0 {
+        label="batch 0";
+        b0_e0 [label="00000000..\n10 leaves"];
+    }
+    subgraph cluster_1 {
+        label="batch 1";
+        b1_e0 [label="00000000..\n2 leaves"];
+        b1_e1 [label="01010101..\n3 leaves"];
+        b1_e2 [label="02020202..\n4 leaves"];
+        b1_e3 [label="03030303..\n1 leaves"];
+    }
+    subgraph cluster_2 {
+        label="batch 2";
+        b2_e0 [label="03030303..\n5 leaves"];
+    }
+    b0_e0 -> b1_e0;
+    b1_e3 -> b2_e0;
+}
+"#
+        );
+        assert_eq!(to_dot(&[]), "digraph batches {\n    node [shape=box];\n}\n");
+    }
+
+    #[test]
+    fn test_escape() {
+        assert_eq!(escape("a\"b\\c\nd"), "a\\\"b\\\\c\\nd");
+    }
+}
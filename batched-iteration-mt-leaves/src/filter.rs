@@ -0,0 +1,166 @@
+use crate::{Changelogs, HASH_LEN};
+
+impl Changelogs {
+    /// Returns a batch with only the events of the Merkle trees in `keep`.
+    pub fn filter_trees(&self, keep: &[[u8; HASH_LEN]]) -> Changelogs {
+        Changelogs {
+            changelogs: self
+                .changelogs
+                .iter()
+                .filter(|changelog_event| keep.contains(&changelog_event.merkle_tree_pubkey))
+                .cloned()
+                .collect(),
+        }
+    }
+
+    /// Keeps only the events of Merkle trees matching `f`, in place. Unlike
+    /// [`filter_trees`](Self::filter_trees), nothing is cloned nor allocated.
+    pub fn retain_trees<F: Fn(&[u8; HASH_LEN]) -> bool>(&mut self, f: F) {
+        self.changelogs
+            .retain(|changelog_event| f(&changelog_event.merkle_tree_pubkey));
+    }
+}
+
+/// Keeps only the events of Merkle trees matching `pred`. Batches left
+/// without events are dropped.
+///
+/// The remaining batches keep their order, but they can have fewer leaves
+/// than the batch size they were created with.
+pub fn filter_batches_by_tree(
+    batches: &[Changelogs],
+    pred: impl Fn(&[u8; HASH_LEN]) -> bool,
+) -> Vec<Changelogs> {
+    partition_by_tree(batches, pred).0
+}
+
+/// Splits the events into those of Merkle trees matching `pred` and the
+/// rest, keeping the batches they belong to. Batches left without events on
+/// either side are dropped.
+///
+/// Like with [`filter_batches_by_tree`], the batches on both sides can have
+/// fewer leaves than the batch size they were created with.
+pub fn partition_by_tree(
+    batches: &[Changelogs],
+    pred: impl Fn(&[u8; HASH_LEN]) -> bool,
+) -> (Vec<Changelogs>, Vec<Changelogs>) {
+    let mut matching = Vec::new();
+    let mut rest = Vec::new();
+
+    for batch in batches {
+        let (matching_events, rest_events): (Vec<_>, Vec<_>) = batch
+            .changelogs
+            .iter()
+            .cloned()
+            .partition(|changelog_event| pred(&changelog_event.merkle_tree_pubkey));
+        if !matching_events.is_empty() {
+            matching.push(Changelogs {
+                changelogs: matching_events,
+            });
+        }
+        if !rest_events.is_empty() {
+            rest.push(Changelogs {
+                changelogs: rest_events,
+            });
+        }
+    }
+
+    (matching, rest)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixture;
+
+    #[test]
+    fn test_filter_batches_by_tree() {
+        let batches = fixture::batches();
+
+        // MT 3 is in batches 1 and 2.
+        let filtered = filter_batches_by_tree(&batches, |merkle_tree| *merkle_tree == [3_u8; 32]);
+        assert_eq!(
+            filtered,
+            vec![
+                Changelogs {
+                    changelogs: vec![batches[1].changelogs[3].clone()]
+                },
+                batches[2].clone(),
+            ]
+        );
+
+        assert!(filter_batches_by_tree(&batches, |_| false).is_empty());
+        assert_eq!(filter_batches_by_tree(&batches, |_| true), batches);
+    }
+
+    #[test]
+    fn test_filter_trees() {
+        // Batch 1 has events of MT 0, MT 1, MT 2 and MT 3.
+        let batch = fixture::batches().swap_remove(1);
+
+        assert_eq!(
+            batch.filter_trees(&[[2_u8; 32], [9_u8; 32]]),
+            Changelogs {
+                changelogs: vec![batch.changelogs[2].clone()]
+            }
+        );
+        assert!(batch.filter_trees(&[]).is_empty());
+    }
+
+    #[test]
+    fn test_retain_trees() {
+        // Batch 1 has events of MT 0, MT 1, MT 2 and MT 3.
+        let original = fixture::batches().swap_remove(1);
+
+        let mut batch = original.clone();
+        batch.retain_trees(|merkle_tree| merkle_tree[0] % 2 == 1);
+        assert_eq!(
+            batch,
+            Changelogs {
+                changelogs: vec![
+                    original.changelogs[1].clone(),
+                    original.changelogs[3].clone(),
+                ]
+            }
+        );
+
+        batch.retain_trees(|_| true);
+        assert_eq!(batch.changelogs.len(), 2);
+        batch.retain_trees(|_| false);
+        assert!(batch.is_empty());
+    }
+
+    #[test]
+    fn test_partition_by_tree() {
+        let batches = fixture::batches();
+
+        // MT 0 is in batches 0 and 1.
+        let (matching, rest) =
+            partition_by_tree(&batches, |merkle_tree| *merkle_tree == [0_u8; 32]);
+        assert_eq!(
+            matching,
+            vec![
+                batches[0].clone(),
+                Changelogs {
+                    changelogs: vec![batches[1].changelogs[0].clone()]
+                },
+            ]
+        );
+        assert_eq!(
+            rest,
+            vec![
+                Changelogs {
+                    changelogs: batches[1].changelogs[1..].to_vec()
+                },
+                batches[2].clone(),
+            ]
+        );
+
+        let (matching, rest) = partition_by_tree(&batches, |_| false);
+        assert!(matching.is_empty());
+        assert_eq!(rest, batches);
+
+        let (matching, rest) = partition_by_tree(&batches, |_| true);
+        assert_eq!(matching, batches);
+        assert!(rest.is_empty());
+    }
+}
@@ -0,0 +1,155 @@
+use sha2::{Digest, Sha256};
+
+use crate::{ChangelogEvent, Changelogs};
+
+/// Version of the encoding hashed by [`fingerprint`]. Bump it whenever the
+/// encoding changes.
+const FINGERPRINT_VERSION: u8 = 1;
+
+/// Returns a SHA-256 fingerprint of the batches, which can be compared to
+/// check whether two sets of batches are the same.
+///
+/// The hashed encoding is the version byte and the number of batches,
+/// followed by the number of events in each batch and, for each event, the
+/// Merkle tree pubkey, the number of leaves and the leaves. All numbers are
+/// little-endian `u64`s. Therefore the fingerprint depends on the order of
+/// events and leaves and on the batch boundaries, but not on anything not
+/// visible in the batches themselves, like capacities of the vectors.
+pub fn fingerprint(batches: &[Changelogs]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+
+    hasher.update([FINGERPRINT_VERSION]);
+    hasher.update((batches.len() as u64).to_le_bytes());
+    for batch in batches {
+        hasher.update((batch.changelogs.len() as u64).to_le_bytes());
+        for changelog_event in batch.changelogs.iter() {
+            hasher.update(changelog_event.merkle_tree_pubkey);
+            hasher.update((changelog_event.leaves.len() as u64).to_le_bytes());
+            for leaf in changelog_event.leaves.iter() {
+                hasher.update(leaf);
+            }
+        }
+    }
+
+    hasher.finalize().into()
+}
+
+impl Changelogs {
+    /// Returns a SHA-256 commitment to the batch, which doesn't depend on the
+    /// order of events. Batches equal according to
+    /// [`canonical_eq`](Changelogs::canonical_eq) have the same commitment.
+    ///
+    /// The hashed encoding is the number of events followed by, for each
+    /// event in the order of Merkle tree pubkeys, the pubkey, the number of
+    /// leaves and the leaves. All numbers are little-endian `u64`s.
+    pub fn commitment(&self) -> [u8; 32] {
+        let mut changelog_events: Vec<&ChangelogEvent> = self.changelogs.iter().collect();
+        changelog_events.sort_by_key(|changelog_event| changelog_event.merkle_tree_pubkey);
+
+        let mut hasher = Sha256::new();
+
+        hasher.update((changelog_events.len() as u64).to_le_bytes());
+        for changelog_event in changelog_events {
+            hasher.update(changelog_event.merkle_tree_pubkey);
+            hasher.update((changelog_event.leaves.len() as u64).to_le_bytes());
+            for leaf in changelog_event.leaves.iter() {
+                hasher.update(leaf);
+            }
+        }
+
+        hasher.finalize().into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixture;
+
+    #[test]
+    fn test_fingerprint_deterministic() {
+        assert_eq!(
+            fingerprint(&fixture::batches()),
+            fingerprint(&fixture::batches())
+        );
+        assert_ne!(fingerprint(&fixture::batches()), fingerprint(&[]));
+    }
+
+    #[test]
+    fn test_fingerprint_ignores_capacity() {
+        let batches = fixture::batches();
+
+        let mut reallocated = batches.clone();
+        reallocated.reserve(100);
+        for batch in reallocated.iter_mut() {
+            batch.changelogs.shrink_to_fit();
+            for changelog_event in batch.changelogs.iter_mut() {
+                changelog_event.leaves.reserve(100);
+            }
+        }
+
+        assert_eq!(fingerprint(&batches), fingerprint(&reallocated));
+    }
+
+    #[test]
+    fn test_fingerprint_event_order() {
+        let batches = fixture::batches();
+
+        let mut reordered = batches.clone();
+        reordered[1].changelogs.swap(1, 2);
+
+        assert_ne!(fingerprint(&batches), fingerprint(&reordered));
+    }
+
+    #[test]
+    fn test_fingerprint_leaf_order() {
+        let batches = fixture::batches();
+
+        let mut reordered = batches.clone();
+        reordered[2].changelogs[0].leaves.swap(0, 1);
+
+        assert_ne!(fingerprint(&batches), fingerprint(&reordered));
+    }
+
+    #[test]
+    fn test_fingerprint_batch_boundaries() {
+        let batches = fixture::batches();
+
+        // Same events in the same order, but the last event of the second
+        // batch moves to the third batch.
+        let mut moved = batches.clone();
+        let changelog_event = moved[1].changelogs.pop().unwrap();
+        moved[2].changelogs.insert(0, changelog_event);
+
+        assert_ne!(fingerprint(&batches), fingerprint(&moved));
+    }
+
+    #[test]
+    fn test_commitment_event_order() {
+        let batches = fixture::batches();
+
+        let mut shuffled = batches[1].clone();
+        shuffled.changelogs.swap(0, 3);
+        shuffled.changelogs.swap(1, 2);
+        assert!(shuffled.canonical_eq(&batches[1]));
+
+        assert_eq!(shuffled.commitment(), batches[1].commitment());
+    }
+
+    #[test]
+    fn test_commitment_contents() {
+        let batches = fixture::batches();
+
+        assert_ne!(batches[0].commitment(), batches[1].commitment());
+
+        let mut reordered = batches[1].clone();
+        reordered.changelogs[2].leaves.swap(0, 1);
+        assert!(!reordered.canonical_eq(&batches[1]));
+        assert_ne!(reordered.commitment(), batches[1].commitment());
+
+        let mut moved = batches[1].clone();
+        let leaf = moved.changelogs[0].leaves.pop().unwrap();
+        moved.changelogs[1].leaves.insert(0, leaf);
+        assert_ne!(moved.commitment(), batches[1].commitment());
+    }
+}
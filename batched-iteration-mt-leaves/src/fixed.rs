@@ -0,0 +1,189 @@
+use crate::{build_merkle_tree_map, ChangelogEvent, Changelogs, MyError, HASH_LEN};
+
+/// Batch of at most `B` leaves, stored inline instead of in heap-allocated
+/// vectors.
+///
+/// Leaves of all events are stored in one array, events keep only their
+/// Merkle tree pubkey and number of leaves.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ChangelogsFixed<const B: usize> {
+    leaves: [[u8; HASH_LEN]; B],
+    len: usize,
+    events: [([u8; HASH_LEN], usize); B],
+    num_events: usize,
+}
+
+impl<const B: usize> ChangelogsFixed<B> {
+    fn new() -> Self {
+        Self {
+            leaves: [[0_u8; HASH_LEN]; B],
+            len: 0,
+            events: [([0_u8; HASH_LEN], 0); B],
+            num_events: 0,
+        }
+    }
+
+    /// Appends a leaf, extending the last event if it belongs to the same
+    /// Merkle tree. The caller has to make sure the batch isn't full.
+    fn push(&mut self, merkle_tree_pubkey: [u8; HASH_LEN], leaf: [u8; HASH_LEN]) {
+        self.leaves[self.len] = leaf;
+        self.len += 1;
+
+        match self.events[..self.num_events].last_mut() {
+            Some((last_merkle_tree_pubkey, leaves))
+                if *last_merkle_tree_pubkey == merkle_tree_pubkey =>
+            {
+                *leaves += 1;
+            }
+            _ => {
+                self.events[self.num_events] = (merkle_tree_pubkey, 1);
+                self.num_events += 1;
+            }
+        }
+    }
+
+    /// Returns the number of leaves in the batch.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Checks whether the batch contains `B` leaves.
+    pub fn is_full(&self) -> bool {
+        self.len == B
+    }
+
+    /// Returns the leaves of all events.
+    pub fn leaves(&self) -> &[[u8; HASH_LEN]] {
+        &self.leaves[..self.len]
+    }
+
+    /// Iterates over the events, yielding the Merkle tree pubkey and leaves
+    /// of each of them.
+    pub fn events(&self) -> impl Iterator<Item = (&[u8; HASH_LEN], &[[u8; HASH_LEN]])> {
+        let mut leaves_start = 0;
+        self.events[..self.num_events]
+            .iter()
+            .map(move |(merkle_tree_pubkey, num_leaves)| {
+                let leaves = &self.leaves[leaves_start..leaves_start + num_leaves];
+                leaves_start += num_leaves;
+                (merkle_tree_pubkey, leaves)
+            })
+    }
+}
+
+impl<const B: usize> From<ChangelogsFixed<B>> for Changelogs {
+    fn from(changelogs: ChangelogsFixed<B>) -> Self {
+        Self::from(&changelogs)
+    }
+}
+
+impl<const B: usize> From<&ChangelogsFixed<B>> for Changelogs {
+    fn from(changelogs: &ChangelogsFixed<B>) -> Self {
+        Self {
+            changelogs: changelogs
+                .events()
+                .map(|(merkle_tree_pubkey, leaves)| ChangelogEvent {
+                    merkle_tree_pubkey: *merkle_tree_pubkey,
+                    leaves: leaves.to_vec(),
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Batches leaves like [`append_leaves`](crate::append_leaves) with a batch
+/// size known at compile time, storing the leaves of each batch inline.
+pub fn append_leaves_const<const B: usize>(
+    leaves: Vec<[u8; HASH_LEN]>,
+    merkle_trees: Vec<[u8; HASH_LEN]>,
+) -> Result<Vec<ChangelogsFixed<B>>, MyError> {
+    const { assert!(B > 0, "batch size must be non-zero") };
+
+    let merkle_tree_map = build_merkle_tree_map(&leaves, &merkle_trees)?;
+
+    let mut batches_of_changelogs = Vec::with_capacity(leaves.len().div_ceil(B));
+    let mut batch_of_changelogs = ChangelogsFixed::new();
+
+    for (merkle_tree_pubkey, leaves) in merkle_tree_map {
+        for leaf in leaves {
+            if batch_of_changelogs.is_full() {
+                batches_of_changelogs.push(batch_of_changelogs);
+                batch_of_changelogs = ChangelogsFixed::new();
+            }
+            batch_of_changelogs.push(merkle_tree_pubkey, leaf);
+        }
+    }
+
+    if !batch_of_changelogs.is_empty() {
+        batches_of_changelogs.push(batch_of_changelogs);
+    }
+
+    Ok(batches_of_changelogs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{append_leaves, fixture};
+
+    #[test]
+    fn test_append_leaves_const() {
+        let (leaves, merkle_trees) = fixture::leaves_and_merkle_trees();
+
+        let batches = append_leaves_const::<10>(leaves.clone(), merkle_trees.clone()).unwrap();
+        assert_eq!(batches.len(), 3);
+
+        // Full batches.
+        assert!(batches[0].is_full());
+        assert_eq!(batches[0].leaves(), &leaves[..10]);
+        assert!(batches[1].is_full());
+        assert_eq!(
+            batches[1]
+                .events()
+                .map(|(merkle_tree_pubkey, leaves)| (merkle_tree_pubkey[0], leaves.len()))
+                .collect::<Vec<_>>(),
+            vec![(0, 2), (1, 3), (2, 4), (3, 1)]
+        );
+
+        // Partially-filled batch.
+        assert!(!batches[2].is_full());
+        assert_eq!(batches[2].len(), 5);
+        assert_eq!(batches[2].leaves(), &leaves[20..]);
+
+        let converted: Vec<Changelogs> = batches.into_iter().map(Changelogs::from).collect();
+        assert_eq!(converted, append_leaves(leaves, merkle_trees, 10).unwrap());
+    }
+
+    #[test]
+    fn test_append_leaves_const_conversion() {
+        let (leaves, merkle_trees) = fixture::leaves_and_merkle_trees();
+
+        fn convert<const B: usize>(batches: Vec<ChangelogsFixed<B>>) -> Vec<Changelogs> {
+            batches.iter().map(Changelogs::from).collect()
+        }
+
+        assert_eq!(
+            convert(append_leaves_const::<1>(leaves.clone(), merkle_trees.clone()).unwrap()),
+            append_leaves(leaves.clone(), merkle_trees.clone(), 1).unwrap()
+        );
+        assert_eq!(
+            convert(append_leaves_const::<8>(leaves.clone(), merkle_trees.clone()).unwrap()),
+            append_leaves(leaves.clone(), merkle_trees.clone(), 8).unwrap()
+        );
+        assert_eq!(
+            convert(append_leaves_const::<32>(leaves.clone(), merkle_trees.clone()).unwrap()),
+            append_leaves(leaves, merkle_trees, 32).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_append_leaves_const_empty() {
+        assert!(append_leaves_const::<8>(Vec::new(), Vec::new())
+            .unwrap()
+            .is_empty());
+    }
+}
@@ -0,0 +1,55 @@
+//! Input shared by the unit tests: 25 leaves spread over 4 Merkle trees,
+//! which with a batch size of 10 produces 3 batches.
+
+use crate::{append_leaves, Changelogs, HASH_LEN};
+
+/// Returns the leaves and the Merkle trees they should be appended to.
+pub(crate) fn leaves_and_merkle_trees() -> (Vec<[u8; HASH_LEN]>, Vec<[u8; HASH_LEN]>) {
+    let leaves = vec![
+        // MT 0
+        [0_u8; 32],
+        [1_u8; 32],
+        [2_u8; 32],
+        [3_u8; 32],
+        [4_u8; 32],
+        [5_u8; 32],
+        [6_u8; 32],
+        [7_u8; 32],
+        [8_u8; 32],
+        [9_u8; 32],
+        [10_u8; 32],
+        [11_u8; 32],
+        // MT 1
+        [12_u8; 32],
+        [13_u8; 32],
+        [14_u8; 32],
+        // MT 2
+        [15_u8; 32],
+        [16_u8; 32],
+        [17_u8; 32],
+        [18_u8; 32],
+        // MT 3
+        [19_u8; 32],
+        [20_u8; 32],
+        [21_u8; 32],
+        [22_u8; 32],
+        [23_u8; 32],
+        [24_u8; 32],
+    ];
+    let merkle_trees = vec![
+        // MT 0
+        [0_u8; 32], [0_u8; 32], [0_u8; 32], [0_u8; 32], [0_u8; 32], [0_u8; 32], [0_u8; 32],
+        [0_u8; 32], [0_u8; 32], [0_u8; 32], [0_u8; 32], [0_u8; 32], // MT 1
+        [1_u8; 32], [1_u8; 32], [1_u8; 32], // MT 2
+        [2_u8; 32], [2_u8; 32], [2_u8; 32], [2_u8; 32], // MT 3
+        [3_u8; 32], [3_u8; 32], [3_u8; 32], [3_u8; 32], [3_u8; 32], [3_u8; 32],
+    ];
+
+    (leaves, merkle_trees)
+}
+
+/// Returns the 3 batches of the leaves, with a batch size of 10.
+pub(crate) fn batches() -> Vec<Changelogs> {
+    let (leaves, merkle_trees) = leaves_and_merkle_trees();
+    append_leaves(leaves, merkle_trees, 10).unwrap()
+}
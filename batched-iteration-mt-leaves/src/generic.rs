@@ -0,0 +1,49 @@
+//! Batch types generic over the Merkle tree identifier and the leaf type.
+//!
+//! [`ChangelogEvent`](crate::ChangelogEvent) and [`Changelogs`](crate::Changelogs)
+//! are aliases of these types with 32-byte pubkeys and leaves. Other
+//! combinations, e.g. `u64` nullifier indices keyed by a `u32` tree index, are
+//! produced by [`append_leaves`](crate::append_leaves) as well.
+
+/// Changelog event for one Merkle tree.
+///
+/// Events are ordered by their pubkey first and then by their leaves,
+/// lexicographically.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ChangelogEvent<K, L> {
+    pub merkle_tree_pubkey: K,
+    pub leaves: Vec<L>,
+}
+
+/// Set of changelogs for different Merkle trees.
+/// The number of changelogs it contains is batched.
+///
+/// Batches are ordered by their events, lexicographically: the first
+/// differing event decides, and a batch which is a prefix of another one
+/// (e.g. an empty batch) comes first.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Changelogs<K, L> {
+    pub changelogs: Vec<ChangelogEvent<K, L>>,
+}
+
+impl<K, L> Changelogs<K, L> {
+    /// Creates an empty batch with space for at least `capacity` events.
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self {
+            changelogs: Vec::with_capacity(capacity),
+        }
+    }
+
+    /// Appends an event to the batch.
+    pub fn push(&mut self, changelog_event: ChangelogEvent<K, L>) {
+        self.changelogs.push(changelog_event);
+    }
+}
+
+impl<K, L> Default for Changelogs<K, L> {
+    fn default() -> Self {
+        Self {
+            changelogs: Vec::new(),
+        }
+    }
+}
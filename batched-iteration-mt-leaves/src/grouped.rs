@@ -0,0 +1,307 @@
+use std::{collections::BTreeMap, ops::Range};
+
+use crate::{
+    build_merkle_tree_map, div_ceil_checked, generic, split_into_batches, BatchCursor, MyError,
+    HASH_LEN,
+};
+
+/// Events of one batch, as ranges of the leaves of each Merkle tree.
+pub type BatchRanges<K = [u8; HASH_LEN]> = Vec<(K, Range<usize>)>;
+
+/// Leaves grouped per Merkle tree, sorted by the Merkle tree keys.
+///
+/// The grouping can be done once and then batched with different batch
+/// sizes. [`append_leaves`](crate::append_leaves) groups into it and batches
+/// it once.
+///
+/// Like [`append_leaves`](crate::append_leaves), it works with any leaf type
+/// and any ordered Merkle tree identifier, 32-byte pubkeys and leaves by
+/// default.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct GroupedLeaves<K = [u8; HASH_LEN], L = [u8; HASH_LEN]>(BTreeMap<K, Vec<L>>);
+
+// Not derived, an empty grouping doesn't need `K: Default` and `L: Default`.
+impl<K, L> Default for GroupedLeaves<K, L> {
+    fn default() -> Self {
+        Self(BTreeMap::new())
+    }
+}
+
+impl<K: Ord + Copy, L: Copy> GroupedLeaves<K, L> {
+    /// Groups `leaves` by the Merkle trees they are appended to.
+    pub fn new(leaves: &[L], merkle_trees: &[K]) -> Result<Self, MyError> {
+        build_merkle_tree_map(leaves, merkle_trees).map(Self)
+    }
+
+    /// Returns the number of all leaves.
+    pub fn total_leaves(&self) -> usize {
+        self.0.values().map(Vec::len).sum()
+    }
+
+    /// Returns the number of distinct Merkle trees.
+    pub fn tree_count(&self) -> usize {
+        self.0.len()
+    }
+
+    /// Returns the Merkle tree with the most leaves and the number of its
+    /// leaves. If several trees have the same number of leaves, the one with
+    /// the lowest key is returned.
+    pub fn largest_tree(&self) -> Option<(&K, usize)> {
+        let mut largest_tree: Option<(&K, usize)> = None;
+        for (merkle_tree, leaves) in self.0.iter() {
+            match largest_tree {
+                Some((_, largest_len)) if largest_len >= leaves.len() => {}
+                _ => largest_tree = Some((merkle_tree, leaves.len())),
+            }
+        }
+        largest_tree
+    }
+
+    /// Iterates over the Merkle trees and their leaves, in the order of the
+    /// Merkle tree keys.
+    pub fn iter(&self) -> impl Iterator<Item = (&K, &[L])> {
+        self.0
+            .iter()
+            .map(|(merkle_tree, leaves)| (merkle_tree, leaves.as_slice()))
+    }
+
+    pub fn into_inner(self) -> BTreeMap<K, Vec<L>> {
+        self.0
+    }
+
+    /// Returns the number of batches [`split_into_batches`](Self::split_into_batches)
+    /// produces for the given `batch_size`. Fails with
+    /// [`MyError::InvalidBatchSize`] if `batch_size` is 0.
+    pub fn num_batches(&self, batch_size: usize) -> Result<usize, MyError> {
+        div_ceil_checked(self.total_leaves(), batch_size).ok_or(MyError::InvalidBatchSize)
+    }
+
+    /// Splits the leaves into batches of `batch_size` leaves, processing the
+    /// Merkle trees in the order of their keys. Fails with
+    /// [`MyError::InvalidBatchSize`] if `batch_size` is 0.
+    pub fn split_into_batches(
+        &self,
+        batch_size: usize,
+    ) -> Result<Vec<generic::Changelogs<K, L>>, MyError> {
+        let num_batches = self.num_batches(batch_size)?;
+        let batches = split_into_batches(
+            self.0
+                .iter()
+                .map(|(merkle_tree, leaves)| (*merkle_tree, leaves)),
+            batch_size,
+        );
+        debug_assert_eq!(batches.len(), num_batches);
+        Ok(batches)
+    }
+
+    /// Builds the batches described by `ranges`, e.g. returned by
+    /// [`batch_ranges`], by copying the leaves they cover.
+    ///
+    /// # Panics
+    ///
+    /// Panics if a range refers to a Merkle tree which isn't grouped, or
+    /// exceeds its leaves.
+    pub fn materialize(&self, ranges: &[BatchRanges<K>]) -> Vec<generic::Changelogs<K, L>> {
+        ranges
+            .iter()
+            .map(|batch_ranges| generic::Changelogs {
+                changelogs: batch_ranges
+                    .iter()
+                    .map(|(merkle_tree, range)| generic::ChangelogEvent {
+                        merkle_tree_pubkey: *merkle_tree,
+                        leaves: self.0[merkle_tree][range.clone()].to_vec(),
+                    })
+                    .collect(),
+            })
+            .collect()
+    }
+}
+
+/// Describes the batches of `batch_size` leaves without copying any leaf.
+/// Each batch is a list of events, each event is the Merkle tree and the
+/// half-open range of its leaves (indices into the leaves of that tree) it
+/// takes.
+///
+/// Merkle trees are taken in the order of their pubkeys, so
+/// [`GroupedLeaves::materialize`] of the ranges gives the same batches as
+/// [`GroupedLeaves::split_into_batches`].
+///
+/// Fails with [`MyError::InvalidBatchSize`] if `batch_size` is 0.
+pub fn batch_ranges<K: Ord + Copy, L: Copy>(
+    grouped: &GroupedLeaves<K, L>,
+    batch_size: usize,
+) -> Result<Vec<BatchRanges<K>>, MyError> {
+    let num_batches = grouped.num_batches(batch_size)?;
+    let tree_lens: Vec<_> = grouped
+        .iter()
+        .map(|(merkle_tree, leaves)| (*merkle_tree, leaves.len()))
+        .collect();
+
+    let mut cursor = BatchCursor::default();
+    let batches = (0..num_batches)
+        .map(|_| {
+            let mut batch = Vec::new();
+            cursor.next_batch(
+                batch_size,
+                |tree_index| tree_lens.get(tree_index).map(|(_, len)| *len),
+                |tree_index, range| batch.push((tree_lens[tree_index].0, range)),
+            );
+            batch
+        })
+        .collect();
+
+    Ok(batches)
+}
+
+impl<K, L> From<BTreeMap<K, Vec<L>>> for GroupedLeaves<K, L> {
+    fn from(merkle_tree_map: BTreeMap<K, Vec<L>>) -> Self {
+        Self(merkle_tree_map)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{append_leaves, fixture};
+
+    fn fixture_grouped_leaves() -> GroupedLeaves {
+        let (leaves, merkle_trees) = fixture::leaves_and_merkle_trees();
+        GroupedLeaves::new(&leaves, &merkle_trees).unwrap()
+    }
+
+    #[test]
+    fn test_total_leaves() {
+        assert_eq!(fixture_grouped_leaves().total_leaves(), 25);
+        assert_eq!(<GroupedLeaves>::default().total_leaves(), 0);
+    }
+
+    #[test]
+    fn test_tree_count() {
+        assert_eq!(fixture_grouped_leaves().tree_count(), 4);
+        assert_eq!(<GroupedLeaves>::default().tree_count(), 0);
+    }
+
+    #[test]
+    fn test_largest_tree() {
+        assert_eq!(
+            fixture_grouped_leaves().largest_tree(),
+            Some((&[0_u8; 32], 12))
+        );
+        assert_eq!(<GroupedLeaves>::default().largest_tree(), None);
+
+        // Ties are broken by the lowest pubkey.
+        let grouped_leaves =
+            GroupedLeaves::new(&[[0_u8; 32], [1_u8; 32]], &[[9_u8; 32], [8_u8; 32]]).unwrap();
+        assert_eq!(grouped_leaves.largest_tree(), Some((&[8_u8; 32], 1)));
+    }
+
+    #[test]
+    fn test_iter() {
+        let grouped_leaves = fixture_grouped_leaves();
+
+        let leaf_counts: Vec<([u8; HASH_LEN], usize)> = grouped_leaves
+            .iter()
+            .map(|(merkle_tree, leaves)| (*merkle_tree, leaves.len()))
+            .collect();
+        assert_eq!(
+            leaf_counts,
+            vec![
+                ([0_u8; 32], 12),
+                ([1_u8; 32], 3),
+                ([2_u8; 32], 4),
+                ([3_u8; 32], 6)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_into_inner() {
+        let (leaves, merkle_trees) = fixture::leaves_and_merkle_trees();
+
+        assert_eq!(
+            fixture_grouped_leaves().into_inner(),
+            build_merkle_tree_map(&leaves, &merkle_trees).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_num_batches() {
+        let grouped_leaves = fixture_grouped_leaves();
+
+        for (batch_size, num_batches) in [(1, 25), (10, 3), (12, 3), (13, 2), (25, 1), (26, 1)] {
+            assert_eq!(grouped_leaves.num_batches(batch_size).unwrap(), num_batches);
+            assert_eq!(
+                grouped_leaves.split_into_batches(batch_size).unwrap().len(),
+                num_batches
+            );
+        }
+        assert!(matches!(
+            grouped_leaves.num_batches(0),
+            Err(MyError::InvalidBatchSize)
+        ));
+    }
+
+    #[test]
+    fn test_split_into_batches() {
+        let (leaves, merkle_trees) = fixture::leaves_and_merkle_trees();
+        let grouped_leaves = fixture_grouped_leaves();
+
+        // The same grouping batched with several batch sizes.
+        for batch_size in [3, 10, 25] {
+            assert_eq!(
+                grouped_leaves.split_into_batches(batch_size).unwrap(),
+                append_leaves(leaves.clone(), merkle_trees.clone(), batch_size).unwrap()
+            );
+        }
+    }
+
+    #[test]
+    fn test_batch_ranges() {
+        let grouped_leaves = fixture_grouped_leaves();
+
+        assert_eq!(
+            batch_ranges(&grouped_leaves, 10).unwrap(),
+            vec![
+                vec![([0_u8; 32], 0..10)],
+                vec![
+                    ([0_u8; 32], 10..12),
+                    ([1_u8; 32], 0..3),
+                    ([2_u8; 32], 0..4),
+                    ([3_u8; 32], 0..1),
+                ],
+                vec![([3_u8; 32], 1..6)],
+            ]
+        );
+        assert!(batch_ranges(&<GroupedLeaves>::default(), 10)
+            .unwrap()
+            .is_empty());
+        assert!(matches!(
+            batch_ranges(&grouped_leaves, 0),
+            Err(MyError::InvalidBatchSize)
+        ));
+    }
+
+    #[test]
+    fn test_materialize_batch_ranges() {
+        let (leaves, merkle_trees) = fixture::leaves_and_merkle_trees();
+        let grouped_leaves = fixture_grouped_leaves();
+
+        for batch_size in [1, 3, 10, 12, 25, 26] {
+            assert_eq!(
+                grouped_leaves.materialize(&batch_ranges(&grouped_leaves, batch_size).unwrap()),
+                append_leaves(leaves.clone(), merkle_trees.clone(), batch_size).unwrap()
+            );
+        }
+    }
+
+    #[test]
+    fn test_generic_grouped_leaves() {
+        // `u64` nullifier indices keyed by a `u32` tree index.
+        let grouped_leaves = GroupedLeaves::new(&[10_u64, 20, 11], &[1_u32, 2, 1]).unwrap();
+        assert_eq!(grouped_leaves.largest_tree(), Some((&1, 2)));
+        assert_eq!(
+            grouped_leaves.split_into_batches(2).unwrap(),
+            append_leaves(vec![10_u64, 20, 11], vec![1_u32, 2, 1], 2).unwrap()
+        );
+    }
+}
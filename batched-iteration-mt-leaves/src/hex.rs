@@ -0,0 +1,75 @@
+//! Lowercase hex encoding of hashes, without the `0x` prefix.
+
+use std::fmt::{self, Write};
+
+use crate::HASH_LEN;
+
+/// Length of a hex-encoded hash.
+pub(crate) const HASH_HEX_LEN: usize = 2 * HASH_LEN;
+
+pub(crate) fn write_hex<W: Write>(w: &mut W, bytes: &[u8]) -> fmt::Result {
+    for byte in bytes {
+        write!(w, "{byte:02x}")?;
+    }
+    Ok(())
+}
+
+pub(crate) fn encode(bytes: &[u8]) -> String {
+    let mut encoded = String::with_capacity(bytes.len() * 2);
+    // Writing to a `String` never fails.
+    write_hex(&mut encoded, bytes).unwrap();
+    encoded
+}
+
+/// Decodes a hash from exactly [`HASH_HEX_LEN`] lowercase hex
+/// characters.
+pub(crate) fn decode_hash(encoded: &str) -> Option<[u8; HASH_LEN]> {
+    let encoded = encoded.as_bytes();
+    if encoded.len() != HASH_HEX_LEN {
+        return None;
+    }
+
+    let mut hash = [0_u8; HASH_LEN];
+    for (byte, pair) in hash.iter_mut().zip(encoded.chunks_exact(2)) {
+        *byte = (decode_nibble(pair[0])? << 4) | decode_nibble(pair[1])?;
+    }
+    Some(hash)
+}
+
+fn decode_nibble(c: u8) -> Option<u8> {
+    match c {
+        b'0'..=b'9' => Some(c - b'0'),
+        b'a'..=b'f' => Some(c - b'a' + 10),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hex_round_trip() {
+        let mut hash = [0_u8; 32];
+        for (i, byte) in hash.iter_mut().enumerate() {
+            *byte = (i * 9) as u8;
+        }
+
+        let encoded = encode(&hash);
+        assert_eq!(
+            encoded,
+            "0009121b242d363f48515a636c757e879099a2abb4bdc6cfd8e1eaf3fc050e17"
+        );
+        assert_eq!(decode_hash(&encoded), Some(hash));
+    }
+
+    #[test]
+    fn test_decode_hash_invalid() {
+        // Too short, too long, uppercase, prefixed, non-hex.
+        assert_eq!(decode_hash("00"), None);
+        assert_eq!(decode_hash(&"0".repeat(66)), None);
+        assert_eq!(decode_hash(&"A".repeat(64)), None);
+        assert_eq!(decode_hash(&format!("0x{}", "0".repeat(62))), None);
+        assert_eq!(decode_hash(&"g".repeat(64)), None);
+    }
+}
@@ -0,0 +1,217 @@
+use std::collections::BTreeMap;
+
+use crate::{
+    build_merkle_tree_map_from, generic, split_into_batches, weighted::split_by_cost,
+    ChangelogEvent, Changelogs, MyError, HASH_LEN,
+};
+
+/// Size of a queue index in bytes.
+const QUEUE_INDEX_LEN: usize = 8;
+
+/// Leaf together with its index in the output queue.
+pub type IndexedLeaf = (u64, [u8; HASH_LEN]);
+
+/// Changelog event whose leaves carry their output queue indices.
+pub type IndexedChangelogEvent = generic::ChangelogEvent<[u8; HASH_LEN], IndexedLeaf>;
+
+/// Batch of [`IndexedChangelogEvent`]s.
+pub type IndexedChangelogs = generic::Changelogs<[u8; HASH_LEN], IndexedLeaf>;
+
+impl IndexedChangelogEvent {
+    /// Drops the queue indices.
+    pub fn into_plain(self) -> ChangelogEvent {
+        ChangelogEvent {
+            merkle_tree_pubkey: self.merkle_tree_pubkey,
+            leaves: self.leaves.into_iter().map(|(_, leaf)| leaf).collect(),
+        }
+    }
+}
+
+impl From<IndexedChangelogEvent> for ChangelogEvent {
+    fn from(changelog_event: IndexedChangelogEvent) -> Self {
+        changelog_event.into_plain()
+    }
+}
+
+impl IndexedChangelogs {
+    /// Drops the queue indices of all events.
+    pub fn into_plain(self) -> Changelogs {
+        Changelogs {
+            changelogs: self
+                .changelogs
+                .into_iter()
+                .map(IndexedChangelogEvent::into_plain)
+                .collect(),
+        }
+    }
+}
+
+impl From<IndexedChangelogs> for Changelogs {
+    fn from(batch: IndexedChangelogs) -> Self {
+        batch.into_plain()
+    }
+}
+
+/// Groups `(merkle_tree, leaf, queue_index)` items per Merkle tree, with the
+/// leaves of every Merkle tree sorted by queue index. The sort is stable, so
+/// leaves with equal indices keep their input order.
+fn indexed_merkle_tree_map(
+    items: &[([u8; HASH_LEN], [u8; HASH_LEN], u64)],
+) -> BTreeMap<[u8; HASH_LEN], Vec<IndexedLeaf>> {
+    let mut merkle_tree_map = build_merkle_tree_map_from(
+        items
+            .iter()
+            .map(|(merkle_tree, leaf, queue_index)| (*merkle_tree, (*queue_index, *leaf))),
+    );
+    for leaves in merkle_tree_map.values_mut() {
+        leaves.sort_by_key(|(queue_index, _)| *queue_index);
+    }
+    merkle_tree_map
+}
+
+/// Batches leaves like [`append_leaves`](crate::append_leaves), keeping the
+/// output queue index of every leaf. Each item is a
+/// `(merkle_tree, leaf, queue_index)` triple.
+///
+/// Within each event, leaves are ordered by queue index, so a Merkle tree
+/// split over several batches continues where the previous batch stopped.
+pub fn append_indexed_leaves(
+    items: &[([u8; HASH_LEN], [u8; HASH_LEN], u64)],
+    batch_size: usize,
+) -> Result<Vec<IndexedChangelogs>, MyError> {
+    if batch_size == 0 {
+        return Err(MyError::InvalidBatchSize);
+    }
+
+    let merkle_tree_map = indexed_merkle_tree_map(items);
+
+    Ok(split_into_batches(
+        merkle_tree_map
+            .iter()
+            .map(|(merkle_tree, leaves)| (*merkle_tree, leaves)),
+        batch_size,
+    ))
+}
+
+/// Same as [`append_indexed_leaves`], but batches by serialized size like
+/// [`append_leaves_by_bytes`](crate::append_leaves_by_bytes). Every leaf
+/// costs [`HASH_LEN`] bytes plus 8 bytes of its queue index.
+pub fn append_indexed_leaves_by_bytes(
+    items: &[([u8; HASH_LEN], [u8; HASH_LEN], u64)],
+    max_bytes: u64,
+    per_event_overhead: u64,
+) -> Result<Vec<IndexedChangelogs>, MyError> {
+    split_by_cost(
+        indexed_merkle_tree_map(items),
+        max_bytes,
+        per_event_overhead,
+        (QUEUE_INDEX_LEN + HASH_LEN) as u64,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::append_leaves;
+
+    /// Leaves of MT 1 with queue indices in reverse input order, interleaved
+    /// with two leaves of MT 2.
+    fn items() -> Vec<([u8; HASH_LEN], [u8; HASH_LEN], u64)> {
+        vec![
+            ([1_u8; 32], [14_u8; 32], 104),
+            ([2_u8; 32], [20_u8; 32], 7),
+            ([1_u8; 32], [13_u8; 32], 103),
+            ([1_u8; 32], [12_u8; 32], 102),
+            ([2_u8; 32], [21_u8; 32], 8),
+            ([1_u8; 32], [11_u8; 32], 101),
+            ([1_u8; 32], [10_u8; 32], 100),
+        ]
+    }
+
+    #[test]
+    fn test_append_indexed_leaves() {
+        let batches = append_indexed_leaves(&items(), 4).unwrap();
+
+        // MT 1 is split over both batches and its indices continue in the
+        // second one.
+        assert!(
+            batches
+                == vec![
+                    IndexedChangelogs {
+                        changelogs: vec![IndexedChangelogEvent {
+                            merkle_tree_pubkey: [1_u8; 32],
+                            leaves: vec![
+                                (100, [10_u8; 32]),
+                                (101, [11_u8; 32]),
+                                (102, [12_u8; 32]),
+                                (103, [13_u8; 32]),
+                            ],
+                        }],
+                    },
+                    IndexedChangelogs {
+                        changelogs: vec![
+                            IndexedChangelogEvent {
+                                merkle_tree_pubkey: [1_u8; 32],
+                                leaves: vec![(104, [14_u8; 32])],
+                            },
+                            IndexedChangelogEvent {
+                                merkle_tree_pubkey: [2_u8; 32],
+                                leaves: vec![(7, [20_u8; 32]), (8, [21_u8; 32])],
+                            },
+                        ],
+                    },
+                ]
+        );
+
+        assert!(matches!(
+            append_indexed_leaves(&items(), 0),
+            Err(MyError::InvalidBatchSize)
+        ));
+        assert!(append_indexed_leaves(&[], 4).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_indexed_into_plain() {
+        let items = items();
+        let batches = append_indexed_leaves(&items, 4).unwrap();
+
+        // Same as batching the leaves in queue order without the indices.
+        let mut sorted = items;
+        sorted.sort_by_key(|(merkle_tree, _, queue_index)| (*merkle_tree, *queue_index));
+        let (merkle_trees, leaves): (Vec<_>, Vec<_>) = sorted
+            .into_iter()
+            .map(|(merkle_tree, leaf, _)| (merkle_tree, leaf))
+            .unzip();
+
+        assert_eq!(
+            batches
+                .into_iter()
+                .map(Changelogs::from)
+                .collect::<Vec<_>>(),
+            append_leaves(leaves, merkle_trees, 4).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_append_indexed_leaves_by_bytes() {
+        // Room for an event with two 40-byte leaves, but not three.
+        let batches = append_indexed_leaves_by_bytes(&items(), 10 + 2 * 40 + 39, 10).unwrap();
+        let event_lens: Vec<Vec<usize>> = batches
+            .iter()
+            .map(|batch| {
+                batch
+                    .changelogs
+                    .iter()
+                    .map(|changelog_event| changelog_event.leaves.len())
+                    .collect()
+            })
+            .collect();
+        assert_eq!(event_lens, vec![vec![2], vec![2], vec![1, 1], vec![1]]);
+        assert_eq!(batches[1].changelogs[0].leaves[0], (102, [12_u8; 32]));
+
+        assert!(matches!(
+            append_indexed_leaves_by_bytes(&items(), 49, 10),
+            Err(MyError::LeafWeightExceeded(50, 49))
+        ));
+    }
+}
@@ -0,0 +1,190 @@
+//! Instruction data layout of a batch:
+//!
+//! ```text
+//! u8 number of events
+//! per event:
+//!     [u8; 32] Merkle tree pubkey
+//!     u8 number of leaves
+//!     [[u8; 32]; number of leaves] leaves
+//! ```
+
+use crate::{versioned::Decoder, ChangelogEvent, Changelogs, MyError, HASH_LEN};
+
+impl ChangelogEvent {
+    /// Appends the event in the instruction data layout to `out`. Fails with
+    /// [`MyError::TooManyLeaves`], without writing anything, if the event has
+    /// more than `u8::MAX` leaves.
+    pub fn encode_instruction_data(&self, out: &mut Vec<u8>) -> Result<(), MyError> {
+        let num_leaves = u8::try_from(self.leaves.len()).map_err(|_| MyError::TooManyLeaves {
+            got: self.leaves.len(),
+            max: u8::MAX.into(),
+        })?;
+
+        out.reserve(HASH_LEN + 1 + self.leaves.len() * HASH_LEN);
+        out.extend_from_slice(&self.merkle_tree_pubkey);
+        out.push(num_leaves);
+        for leaf in self.leaves.iter() {
+            out.extend_from_slice(leaf);
+        }
+        Ok(())
+    }
+}
+
+impl Changelogs {
+    /// Encodes the batch in the instruction data layout. Fails with
+    /// [`MyError::TooManyEvents`] if the batch has more than `u8::MAX` events
+    /// and with [`MyError::TooManyLeaves`] if any of them has more than
+    /// `u8::MAX` leaves.
+    pub fn encode_instruction_data(&self) -> Result<Vec<u8>, MyError> {
+        let num_events =
+            u8::try_from(self.changelogs.len()).map_err(|_| MyError::TooManyEvents {
+                got: self.changelogs.len(),
+                max: u8::MAX.into(),
+            })?;
+
+        let mut out = vec![num_events];
+        for changelog_event in self.changelogs.iter() {
+            changelog_event.encode_instruction_data(&mut out)?;
+        }
+        Ok(out)
+    }
+
+    /// Decodes a batch encoded with
+    /// [`encode_instruction_data`](Self::encode_instruction_data). Fails with
+    /// [`MyError::TruncatedInput`] if `bytes` end early and with
+    /// [`MyError::TrailingBytes`] if they don't end after the last event.
+    pub fn decode_instruction_data(bytes: &[u8]) -> Result<Self, MyError> {
+        let mut decoder = Decoder::new(bytes);
+
+        let num_events = decoder.take::<1>()?[0];
+        let mut changelogs = Vec::with_capacity(num_events.into());
+        for _ in 0..num_events {
+            let merkle_tree_pubkey = decoder.take::<HASH_LEN>()?;
+            let num_leaves = decoder.take::<1>()?[0];
+            let leaves = (0..num_leaves)
+                .map(|_| decoder.take::<HASH_LEN>())
+                .collect::<Result<_, _>>()?;
+            changelogs.push(ChangelogEvent {
+                merkle_tree_pubkey,
+                leaves,
+            });
+        }
+
+        if decoder.remaining() != 0 {
+            return Err(MyError::TrailingBytes(decoder.remaining()));
+        }
+        Ok(Self { changelogs })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn two_event_batch() -> Changelogs {
+        Changelogs {
+            changelogs: vec![
+                ChangelogEvent {
+                    merkle_tree_pubkey: [1_u8; 32],
+                    leaves: vec![[0xaa_u8; 32], [0xbb_u8; 32]],
+                },
+                ChangelogEvent {
+                    merkle_tree_pubkey: [2_u8; 32],
+                    leaves: vec![[0xcc_u8; 32]],
+                },
+            ],
+        }
+    }
+
+    #[test]
+    fn test_encode_instruction_data() {
+        let batch = two_event_batch();
+
+        let mut expected = vec![2];
+        expected.extend_from_slice(&[1_u8; 32]);
+        expected.push(2);
+        expected.extend_from_slice(&[0xaa_u8; 32]);
+        expected.extend_from_slice(&[0xbb_u8; 32]);
+        expected.extend_from_slice(&[2_u8; 32]);
+        expected.push(1);
+        expected.extend_from_slice(&[0xcc_u8; 32]);
+
+        let encoded = batch.encode_instruction_data().unwrap();
+        assert_eq!(encoded.len(), 1 + 2 * (32 + 1) + 3 * 32);
+        assert_eq!(encoded, expected);
+        assert_eq!(
+            Changelogs::decode_instruction_data(&encoded).unwrap(),
+            batch
+        );
+
+        let empty = Changelogs::default();
+        assert_eq!(empty.encode_instruction_data().unwrap(), vec![0]);
+        assert_eq!(Changelogs::decode_instruction_data(&[0]).unwrap(), empty);
+    }
+
+    #[test]
+    fn test_encode_instruction_data_too_many_leaves() {
+        let mut batch = two_event_batch();
+        batch.changelogs[1].leaves = vec![[0_u8; 32]; 256];
+
+        assert!(matches!(
+            batch.encode_instruction_data(),
+            Err(MyError::TooManyLeaves { got: 256, max: 255 })
+        ));
+        let mut out = vec![7];
+        assert!(batch.changelogs[1]
+            .encode_instruction_data(&mut out)
+            .is_err());
+        assert_eq!(out, vec![7]);
+
+        // 255 leaves still fit.
+        batch.changelogs[1].leaves.pop();
+        let encoded = batch.encode_instruction_data().unwrap();
+        assert_eq!(
+            Changelogs::decode_instruction_data(&encoded).unwrap(),
+            batch
+        );
+    }
+
+    #[test]
+    fn test_encode_instruction_data_too_many_events() {
+        let batch = Changelogs {
+            changelogs: (0..=255)
+                .map(|i| ChangelogEvent {
+                    merkle_tree_pubkey: [i; 32],
+                    leaves: vec![[0_u8; 32]],
+                })
+                .chain([ChangelogEvent {
+                    merkle_tree_pubkey: [0_u8; 32],
+                    leaves: vec![[1_u8; 32]],
+                }])
+                .collect(),
+        };
+        assert!(matches!(
+            batch.encode_instruction_data(),
+            Err(MyError::TooManyEvents { got: 257, max: 255 })
+        ));
+    }
+
+    #[test]
+    fn test_decode_instruction_data_invalid() {
+        let encoded = two_event_batch().encode_instruction_data().unwrap();
+
+        // Cut in the middle of the last leaf.
+        assert!(matches!(
+            Changelogs::decode_instruction_data(&encoded[..encoded.len() - 10]),
+            Err(MyError::TruncatedInput(22))
+        ));
+        assert!(matches!(
+            Changelogs::decode_instruction_data(&[]),
+            Err(MyError::TruncatedInput(0))
+        ));
+
+        let mut trailing = encoded;
+        trailing.extend_from_slice(&[0, 0]);
+        assert!(matches!(
+            Changelogs::decode_instruction_data(&trailing),
+            Err(MyError::TrailingBytes(2))
+        ));
+    }
+}
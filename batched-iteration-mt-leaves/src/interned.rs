@@ -0,0 +1,211 @@
+use std::collections::BTreeMap;
+
+use crate::MyError;
+
+/// Number of leaves above which [`build_merkle_tree_map`](crate::build_merkle_tree_map)
+/// groups them by interned keys. Below it, the extra vector of ids costs more
+/// than the map lookups it saves.
+pub(crate) const INTERNING_THRESHOLD: usize = 4096;
+
+/// Maps each distinct Merkle tree identifier to a `u32` id, in order of first
+/// sight, so the leaves can be grouped by indexing a vector instead of
+/// looking up every leaf in a map twice.
+pub(crate) struct InternedKeys<K> {
+    ids: BTreeMap<K, u32>,
+    keys: Vec<K>,
+    /// Last interned key and its id. Leaves of one Merkle tree usually come
+    /// in runs, which skip the map lookup.
+    last: Option<(K, u32)>,
+}
+
+impl<K> InternedKeys<K>
+where
+    K: Ord + Copy,
+{
+    pub(crate) fn new() -> Self {
+        Self {
+            ids: BTreeMap::new(),
+            keys: Vec::new(),
+            last: None,
+        }
+    }
+
+    /// Returns the id of `key`, assigning the next one on first sight.
+    ///
+    /// # Panics
+    ///
+    /// Panics if more than `u32::MAX` distinct keys are interned.
+    pub(crate) fn intern(&mut self, key: K) -> u32 {
+        if let Some((last_key, last_id)) = self.last {
+            if last_key == key {
+                return last_id;
+            }
+        }
+        let next_id = u32::try_from(self.keys.len()).expect("too many distinct Merkle trees");
+        let id = *self.ids.entry(key).or_insert(next_id);
+        if id == next_id {
+            self.keys.push(key);
+        }
+        self.last = Some((key, id));
+        id
+    }
+
+    /// Returns the key with the given `id`.
+    pub(crate) fn resolve(&self, id: u32) -> K {
+        self.keys[id as usize]
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.keys.len()
+    }
+}
+
+/// Groups the leaves like [`build_merkle_tree_map`](crate::build_merkle_tree_map),
+/// but every Merkle tree is looked up only once, to intern it. The leaves are
+/// counted and grouped by id, and the keys are rehydrated only when building
+/// the map, once per Merkle tree. The result is identical.
+///
+/// The caller checks that `leaves` and `merkle_trees` have equal lengths and
+/// that there are at most `u32::MAX` of them, so every id fits.
+pub(crate) fn build_merkle_tree_map_interned<K, L, F>(
+    leaves: &[L],
+    merkle_trees: &[K],
+    validate: F,
+) -> Result<BTreeMap<K, Vec<L>>, MyError>
+where
+    K: Ord + Copy,
+    L: Copy,
+    F: Fn(&L) -> Result<(), MyError>,
+{
+    let mut interned = InternedKeys::new();
+    let ids: Vec<u32> = merkle_trees
+        .iter()
+        .map(|merkle_tree| interned.intern(*merkle_tree))
+        .collect();
+
+    let mut leaf_counts = vec![0_usize; interned.len()];
+    for id in ids.iter() {
+        leaf_counts[*id as usize] += 1;
+    }
+    let mut groups: Vec<Vec<L>> = leaf_counts.into_iter().map(Vec::with_capacity).collect();
+
+    for (id, leaf) in ids.iter().zip(leaves) {
+        validate(leaf)?;
+        groups[*id as usize].push(*leaf);
+    }
+
+    Ok(groups
+        .into_iter()
+        .enumerate()
+        .map(|(id, leaves)| (interned.resolve(id as u32), leaves))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cmp;
+
+    use proptest::prelude::*;
+
+    use super::*;
+    use crate::{
+        build_merkle_tree_map, build_merkle_tree_map_plain, fixture::leaves_and_merkle_trees,
+        reference, split_into_batches, HASH_LEN,
+    };
+
+    /// Generates more leaves than [`INTERNING_THRESHOLD`], so
+    /// [`build_merkle_tree_map`] groups them by interned keys, appended to a
+    /// single Merkle tree, to a random number of Merkle trees or each to a
+    /// distinct one, in random order, and a batch size.
+    fn interning_input() -> impl Strategy<Value = (Vec<[u8; HASH_LEN]>, Vec<[u8; HASH_LEN]>, usize)>
+    {
+        (
+            INTERNING_THRESHOLD + 1..INTERNING_THRESHOLD + 1024,
+            prop_oneof![
+                Just(1),
+                2..64_usize,
+                64..INTERNING_THRESHOLD,
+                Just(usize::MAX)
+            ],
+            1..300_usize,
+        )
+            .prop_flat_map(|(num_leaves, num_trees, batch_size)| {
+                let num_trees = cmp::min(num_trees, num_leaves);
+                let tree_indices: Vec<usize> = (0..num_leaves).map(|i| i % num_trees).collect();
+                (Just(tree_indices).prop_shuffle(), Just(batch_size))
+            })
+            .prop_map(|(tree_indices, batch_size)| {
+                let to_hash = |i: usize| {
+                    let mut hash = [0_u8; HASH_LEN];
+                    hash[..8].copy_from_slice(&(i as u64).to_le_bytes());
+                    hash
+                };
+                let leaves = (0..tree_indices.len()).map(to_hash).collect();
+                let merkle_trees = tree_indices.into_iter().map(to_hash).collect();
+                (leaves, merkle_trees, batch_size)
+            })
+    }
+
+    proptest! {
+        // Every case batches thousands of leaves, and the reference is
+        // quadratic in the number of Merkle trees.
+        #![proptest_config(ProptestConfig::with_cases(16))]
+
+        #[test]
+        fn prop_interned_equivalent_to_plain(
+            (leaves, merkle_trees, batch_size) in interning_input()
+        ) {
+            let interned = build_merkle_tree_map_interned(&leaves, &merkle_trees, |_| Ok(())).unwrap();
+            prop_assert_eq!(
+                &interned,
+                &build_merkle_tree_map_plain(&leaves, &merkle_trees, |_| Ok(())).unwrap()
+            );
+            prop_assert_eq!(
+                split_into_batches(interned, batch_size),
+                reference::append_leaves_reference(&leaves, &merkle_trees, batch_size).unwrap()
+            );
+            reference::assert_equivalent(&leaves, &merkle_trees, batch_size);
+        }
+    }
+
+    #[test]
+    fn test_interned_keys() {
+        let mut interned = InternedKeys::new();
+        assert_eq!(interned.intern([3_u8; 32]), 0);
+        assert_eq!(interned.intern([3_u8; 32]), 0);
+        assert_eq!(interned.intern([1_u8; 32]), 1);
+        assert_eq!(interned.intern([3_u8; 32]), 0);
+        assert_eq!(interned.len(), 2);
+        assert_eq!(interned.resolve(1), [1_u8; 32]);
+    }
+
+    #[test]
+    fn test_build_merkle_tree_map_interned() {
+        let (leaves, merkle_trees) = leaves_and_merkle_trees();
+        assert_eq!(
+            build_merkle_tree_map_interned(&leaves, &merkle_trees, |_| Ok(())).unwrap(),
+            build_merkle_tree_map(&leaves, &merkle_trees).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_interning_identical_above_threshold() {
+        // Large enough to be grouped by interned keys, with the Merkle trees
+        // interleaved.
+        let num_leaves = INTERNING_THRESHOLD * 4 + 3;
+        let leaves: Vec<[u8; 32]> = (0..num_leaves)
+            .map(|i| {
+                let mut leaf = [0_u8; 32];
+                leaf[..8].copy_from_slice(&(i as u64).to_le_bytes());
+                leaf
+            })
+            .collect();
+        let merkle_trees: Vec<[u8; 32]> =
+            (0..num_leaves).map(|i| [(i * 7 % 16) as u8; 32]).collect();
+
+        assert_eq!(
+            build_merkle_tree_map(&leaves, &merkle_trees).unwrap(),
+            build_merkle_tree_map_plain(&leaves, &merkle_trees, |_| Ok(())).unwrap()
+        );
+    }
+}
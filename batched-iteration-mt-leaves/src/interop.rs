@@ -0,0 +1,230 @@
+//! Conversions between our events and the `ChangelogEvent` emitted by the
+//! [Light Protocol](https://github.com/Lightprotocol/light-protocol) Merkle tree
+//! programs.
+//!
+//! Light events carry a full Merkle path for every appended leaf, together with
+//! the sequence number of the tree and the index in its changelog buffer. We
+//! only model the leaves, so converting into a Light event produces one-node
+//! paths with just the leaf, and converting back drops everything except the
+//! first node of each path.
+//!
+//! Changelogs of other Merkle tree implementations, which carry the new root
+//! and the appended leaves, can be converted with
+//! [`ChangelogEvent::from_light`] by implementing [`LightChangelog`].
+
+use light_merkle_tree_metadata::events::concurrent::{
+    ChangelogEvent as LightChangelogEvent, PathNode,
+};
+
+use crate::{ChangelogEvent, MyError, HASH_LEN};
+
+/// Changelog of a Merkle tree which records the root after an append and the
+/// appended leaves, like the one of `light-merkle-tree`. It doesn't know the
+/// pubkey of its tree.
+pub trait LightChangelog {
+    /// Root of the Merkle tree after appending the leaves.
+    fn root(&self) -> [u8; HASH_LEN];
+
+    /// Appended leaves, in order.
+    fn leaves(&self) -> &[[u8; HASH_LEN]];
+}
+
+impl ChangelogEvent {
+    /// Converts `changelog` of the Merkle tree `merkle_tree_pubkey`, keeping
+    /// its leaves in order. The root is dropped.
+    pub fn from_light<C: LightChangelog>(
+        merkle_tree_pubkey: [u8; HASH_LEN],
+        changelog: &C,
+    ) -> Self {
+        Self {
+            merkle_tree_pubkey,
+            leaves: changelog.leaves().to_vec(),
+        }
+    }
+
+    /// Converts the event into a Light [`ChangelogEvent`](LightChangelogEvent)
+    /// with the given `seq` and changelog `index`. Each leaf becomes a path
+    /// containing only the leaf node, whose index is `first_leaf_index` plus
+    /// the position of the leaf in the event.
+    pub fn to_light(&self, seq: u64, index: u32, first_leaf_index: u32) -> LightChangelogEvent {
+        LightChangelogEvent {
+            id: self.merkle_tree_pubkey,
+            paths: self
+                .leaves
+                .iter()
+                .zip(first_leaf_index..)
+                .map(|(leaf, leaf_index)| {
+                    vec![PathNode {
+                        node: *leaf,
+                        index: leaf_index,
+                    }]
+                })
+                .collect(),
+            seq,
+            index,
+        }
+    }
+}
+
+impl From<&ChangelogEvent> for LightChangelogEvent {
+    /// Same as [`ChangelogEvent::to_light`] with `seq`, `index` and the first
+    /// leaf index all set to 0.
+    fn from(changelog_event: &ChangelogEvent) -> Self {
+        changelog_event.to_light(0, 0, 0)
+    }
+}
+
+impl TryFrom<&LightChangelogEvent> for ChangelogEvent {
+    type Error = MyError;
+
+    /// Takes the first node of every path as the appended leaf. The rest of
+    /// the paths, `seq` and `index` are dropped.
+    fn try_from(light_event: &LightChangelogEvent) -> Result<Self, Self::Error> {
+        let leaves = light_event
+            .paths
+            .iter()
+            .enumerate()
+            .map(|(path_index, path)| {
+                path.first()
+                    .map(|path_node| path_node.node)
+                    .ok_or(MyError::EmptyMerklePath { path_index })
+            })
+            .collect::<Result<_, _>>()?;
+
+        Ok(Self {
+            merkle_tree_pubkey: light_event.id,
+            leaves,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{append_leaves, fixture};
+
+    struct MockChangelog {
+        root: [u8; 32],
+        leaf: Vec<[u8; 32]>,
+    }
+
+    impl LightChangelog for MockChangelog {
+        fn root(&self) -> [u8; 32] {
+            self.root
+        }
+
+        fn leaves(&self) -> &[[u8; 32]] {
+            &self.leaf
+        }
+    }
+
+    #[test]
+    fn test_from_light_changelog() {
+        let changelog = MockChangelog {
+            root: [9_u8; 32],
+            leaf: vec![[3_u8; 32], [2_u8; 32]],
+        };
+        assert_eq!(changelog.root(), [9_u8; 32]);
+
+        assert_eq!(
+            ChangelogEvent::from_light([1_u8; 32], &changelog),
+            ChangelogEvent {
+                merkle_tree_pubkey: [1_u8; 32],
+                leaves: vec![[3_u8; 32], [2_u8; 32]],
+            }
+        );
+    }
+
+    #[test]
+    fn test_light_round_trip() {
+        let (leaves, merkle_trees) = fixture::leaves_and_merkle_trees();
+        let batches = append_leaves(leaves, merkle_trees, 10).unwrap();
+
+        for changelog_event in batches.iter().flat_map(|batch| &batch.changelogs) {
+            let light_event = LightChangelogEvent::from(changelog_event);
+            assert_eq!(light_event.id, changelog_event.merkle_tree_pubkey);
+            assert_eq!(light_event.paths.len(), changelog_event.leaves.len());
+            assert_eq!((light_event.seq, light_event.index), (0, 0));
+
+            let back = ChangelogEvent::try_from(&light_event).unwrap();
+            assert_eq!(&back, changelog_event);
+        }
+    }
+
+    #[test]
+    fn test_to_light_indices() {
+        let changelog_event = ChangelogEvent {
+            merkle_tree_pubkey: [1_u8; 32],
+            leaves: vec![[2_u8; 32], [3_u8; 32]],
+        };
+
+        let light_event = changelog_event.to_light(7, 3, 10);
+        assert_eq!(
+            light_event,
+            LightChangelogEvent {
+                id: [1_u8; 32],
+                paths: vec![
+                    vec![PathNode {
+                        node: [2_u8; 32],
+                        index: 10,
+                    }],
+                    vec![PathNode {
+                        node: [3_u8; 32],
+                        index: 11,
+                    }],
+                ],
+                seq: 7,
+                index: 3,
+            }
+        );
+    }
+
+    #[test]
+    fn test_from_light_drops_paths() {
+        let light_event = LightChangelogEvent {
+            id: [1_u8; 32],
+            paths: vec![vec![
+                PathNode {
+                    node: [2_u8; 32],
+                    index: 4,
+                },
+                PathNode {
+                    node: [9_u8; 32],
+                    index: 2,
+                },
+            ]],
+            seq: 5,
+            index: 1,
+        };
+
+        let changelog_event = ChangelogEvent::try_from(&light_event).unwrap();
+        assert_eq!(
+            changelog_event,
+            ChangelogEvent {
+                merkle_tree_pubkey: [1_u8; 32],
+                leaves: vec![[2_u8; 32]],
+            }
+        );
+    }
+
+    #[test]
+    fn test_from_light_empty_path() {
+        let light_event = LightChangelogEvent {
+            id: [1_u8; 32],
+            paths: vec![
+                vec![PathNode {
+                    node: [2_u8; 32],
+                    index: 0,
+                }],
+                vec![],
+            ],
+            seq: 0,
+            index: 0,
+        };
+
+        assert!(matches!(
+            ChangelogEvent::try_from(&light_event),
+            Err(MyError::EmptyMerklePath { path_index: 1 })
+        ));
+    }
+}
@@ -0,0 +1,414 @@
+use std::io::{self, BufRead, Write};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    hex, ChangelogEvent, ChangelogEventV2, ChangelogEventVersioned, Changelogs, MyError, HASH_LEN,
+};
+
+#[derive(Serialize, Deserialize)]
+struct JsonBatch {
+    batch: usize,
+    events: Vec<JsonEvent>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct JsonEvent {
+    tree: String,
+    leaves: Vec<String>,
+}
+
+/// Versioned event, tagged with the version:
+///
+/// ```json
+/// {"version":"v1","tree":"<hex>","leaves":["<hex>",...]}
+/// {"version":"v2","tree":"<hex>","start_index":0,"seq":0,"leaves":["<hex>",...]}
+/// ```
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "version", rename_all = "lowercase")]
+enum JsonVersionedEvent {
+    V1 {
+        tree: String,
+        leaves: Vec<String>,
+    },
+    V2 {
+        tree: String,
+        start_index: u64,
+        seq: u64,
+        leaves: Vec<String>,
+    },
+}
+
+/// Maps an error of writing line `line` with `serde_json` to
+/// [`MyError::Io`] if the writer failed, to [`MyError::Json`] otherwise.
+fn write_error(line: usize, source: serde_json::Error) -> MyError {
+    if source.is_io() {
+        MyError::Io(io::Error::from(source))
+    } else {
+        MyError::Json { line, source }
+    }
+}
+
+/// Writes the batches as JSON Lines, one batch per line:
+///
+/// ```json
+/// {"batch":0,"events":[{"tree":"<hex>","leaves":["<hex>",...]}]}
+/// ```
+///
+/// Pubkeys and leaves are lowercase hex without the `0x` prefix.
+pub fn to_jsonl<W: Write>(batches: &[Changelogs], mut writer: W) -> Result<(), MyError> {
+    for (i, batch) in batches.iter().enumerate() {
+        let json_batch = JsonBatch {
+            batch: i,
+            events: batch
+                .changelogs
+                .iter()
+                .map(|changelog_event| JsonEvent {
+                    tree: hex::encode(&changelog_event.merkle_tree_pubkey),
+                    leaves: changelog_event
+                        .leaves
+                        .iter()
+                        .map(|leaf| hex::encode(leaf))
+                        .collect(),
+                })
+                .collect(),
+        };
+
+        serde_json::to_writer(&mut writer, &json_batch).map_err(|err| write_error(i + 1, err))?;
+        writer.write_all(b"\n")?;
+    }
+
+    Ok(())
+}
+
+/// Reads batches written by [`to_jsonl`]. Blank lines are skipped and the
+/// batches have to be numbered consecutively from 0.
+pub fn from_jsonl<R: BufRead>(reader: R) -> Result<Vec<Changelogs>, MyError> {
+    let mut batches = Vec::new();
+
+    for (i, line) in reader.lines().enumerate() {
+        let line_number = i + 1;
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let json_batch: JsonBatch =
+            serde_json::from_str(&line).map_err(|source| MyError::Json {
+                line: line_number,
+                source,
+            })?;
+        if json_batch.batch != batches.len() {
+            return Err(MyError::UnexpectedBatch {
+                line: line_number,
+                expected: batches.len(),
+                got: json_batch.batch,
+            });
+        }
+
+        let decode = |field: &'static str, value: String| {
+            hex::decode_hash(&value).ok_or(MyError::InvalidHex {
+                line: line_number,
+                field,
+                value,
+            })
+        };
+        let changelogs = json_batch
+            .events
+            .into_iter()
+            .map(|json_event| {
+                Ok(ChangelogEvent {
+                    merkle_tree_pubkey: decode("tree", json_event.tree)?,
+                    leaves: json_event
+                        .leaves
+                        .into_iter()
+                        .map(|leaf| decode("leaf", leaf))
+                        .collect::<Result<_, _>>()?,
+                })
+            })
+            .collect::<Result<_, MyError>>()?;
+
+        batches.push(Changelogs { changelogs });
+    }
+
+    Ok(batches)
+}
+
+/// Writes versioned events as JSON Lines, one event per line, tagged with
+/// their version.
+pub fn to_jsonl_versioned<W: Write>(
+    changelog_events: &[ChangelogEventVersioned],
+    mut writer: W,
+) -> Result<(), MyError> {
+    let encode_leaves =
+        |leaves: &[[u8; HASH_LEN]]| leaves.iter().map(|leaf| hex::encode(leaf)).collect();
+
+    for (i, changelog_event) in changelog_events.iter().enumerate() {
+        let json_event = match changelog_event {
+            ChangelogEventVersioned::V1(changelog_event) => JsonVersionedEvent::V1 {
+                tree: hex::encode(&changelog_event.merkle_tree_pubkey),
+                leaves: encode_leaves(&changelog_event.leaves),
+            },
+            ChangelogEventVersioned::V2(changelog_event) => JsonVersionedEvent::V2 {
+                tree: hex::encode(&changelog_event.merkle_tree_pubkey),
+                start_index: changelog_event.start_index,
+                seq: changelog_event.seq,
+                leaves: encode_leaves(&changelog_event.leaves),
+            },
+        };
+
+        serde_json::to_writer(&mut writer, &json_event).map_err(|err| write_error(i + 1, err))?;
+        writer.write_all(b"\n")?;
+    }
+
+    Ok(())
+}
+
+/// Reads versioned events written by [`to_jsonl_versioned`]. Events of
+/// different versions can be mixed. Blank lines are skipped.
+pub fn from_jsonl_versioned<R: BufRead>(
+    reader: R,
+) -> Result<Vec<ChangelogEventVersioned>, MyError> {
+    let mut changelog_events = Vec::new();
+
+    for (i, line) in reader.lines().enumerate() {
+        let line_number = i + 1;
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let json_event: JsonVersionedEvent =
+            serde_json::from_str(&line).map_err(|source| MyError::Json {
+                line: line_number,
+                source,
+            })?;
+
+        let decode = |field: &'static str, value: String| {
+            hex::decode_hash(&value).ok_or(MyError::InvalidHex {
+                line: line_number,
+                field,
+                value,
+            })
+        };
+        let decode_leaves = |leaves: Vec<String>| {
+            leaves
+                .into_iter()
+                .map(|leaf| decode("leaf", leaf))
+                .collect::<Result<_, _>>()
+        };
+        let changelog_event = match json_event {
+            JsonVersionedEvent::V1 { tree, leaves } => {
+                ChangelogEventVersioned::V1(ChangelogEvent {
+                    merkle_tree_pubkey: decode("tree", tree)?,
+                    leaves: decode_leaves(leaves)?,
+                })
+            }
+            JsonVersionedEvent::V2 {
+                tree,
+                start_index,
+                seq,
+                leaves,
+            } => ChangelogEventVersioned::V2(ChangelogEventV2 {
+                merkle_tree_pubkey: decode("tree", tree)?,
+                start_index,
+                seq,
+                leaves: decode_leaves(leaves)?,
+            }),
+        };
+
+        changelog_events.push(changelog_event);
+    }
+
+    Ok(changelog_events)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::fixture;
+
+    #[test]
+    fn test_jsonl_round_trip() {
+        let batches = fixture::batches();
+
+        let mut jsonl = Vec::new();
+        to_jsonl(&batches, &mut jsonl).unwrap();
+
+        let jsonl = String::from_utf8(jsonl).unwrap();
+        let lines: Vec<&str> = jsonl.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(
+            lines[2],
+            format!(
+                r#"{{"batch":2,"events":[{{"tree":"{}","leaves":["{}","{}","{}","{}","{}"]}}]}}"#,
+                "03".repeat(32),
+                "14".repeat(32),
+                "15".repeat(32),
+                "16".repeat(32),
+                "17".repeat(32),
+                "18".repeat(32),
+            )
+        );
+
+        assert_eq!(from_jsonl(Cursor::new(jsonl)).unwrap(), batches);
+    }
+
+    #[cfg(feature = "sha2")]
+    #[test]
+    fn test_jsonl_round_trip_fingerprint() {
+        use crate::fingerprint;
+
+        let batches = fixture::batches();
+
+        let mut jsonl = Vec::new();
+        to_jsonl(&batches, &mut jsonl).unwrap();
+        let parsed = from_jsonl(Cursor::new(jsonl)).unwrap();
+
+        assert_eq!(fingerprint(&parsed), fingerprint(&batches));
+    }
+
+    #[test]
+    fn test_from_jsonl_empty() {
+        assert!(from_jsonl(Cursor::new("")).unwrap().is_empty());
+        assert!(from_jsonl(Cursor::new("\n\n")).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_from_jsonl_short_leaf() {
+        let jsonl = format!(
+            "{{\"batch\":0,\"events\":[{{\"tree\":\"{}\",\"leaves\":[\"{}\"]}}]}}\n\
+             {{\"batch\":1,\"events\":[{{\"tree\":\"{}\",\"leaves\":[\"{}\"]}}]}}\n",
+            "00".repeat(32),
+            "01".repeat(32),
+            "00".repeat(32),
+            "01".repeat(31),
+        );
+
+        let err = from_jsonl(Cursor::new(jsonl)).unwrap_err();
+        assert!(matches!(
+            err,
+            MyError::InvalidHex {
+                line: 2,
+                field: "leaf",
+                ..
+            }
+        ));
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "Line 2: invalid leaf \"{}\", expected 64 lowercase hex characters",
+                "01".repeat(31)
+            )
+        );
+    }
+
+    #[test]
+    fn test_from_jsonl_prefixed_tree() {
+        let jsonl = format!(
+            "{{\"batch\":0,\"events\":[{{\"tree\":\"0x{}\",\"leaves\":[]}}]}}",
+            "00".repeat(31),
+        );
+
+        let err = from_jsonl(Cursor::new(jsonl)).unwrap_err();
+        assert!(matches!(
+            err,
+            MyError::InvalidHex {
+                line: 1,
+                field: "tree",
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn test_from_jsonl_malformed_json() {
+        let err = from_jsonl(Cursor::new("{\"batch\":0,\"events\":[")).unwrap_err();
+        assert!(matches!(err, MyError::Json { line: 1, .. }));
+    }
+
+    #[test]
+    fn test_from_jsonl_unexpected_batch() {
+        let err = from_jsonl(Cursor::new("{\"batch\":1,\"events\":[]}")).unwrap_err();
+        assert!(matches!(
+            err,
+            MyError::UnexpectedBatch {
+                line: 1,
+                expected: 0,
+                got: 1
+            }
+        ));
+    }
+
+    #[test]
+    fn test_jsonl_versioned_mixed() {
+        let changelog_events = vec![
+            ChangelogEventVersioned::V1(ChangelogEvent {
+                merkle_tree_pubkey: [1_u8; 32],
+                leaves: vec![[2_u8; 32]],
+            }),
+            ChangelogEventVersioned::V2(ChangelogEventV2 {
+                merkle_tree_pubkey: [3_u8; 32],
+                start_index: 10,
+                seq: 2,
+                leaves: vec![[4_u8; 32], [5_u8; 32]],
+            }),
+        ];
+
+        let mut jsonl = Vec::new();
+        to_jsonl_versioned(&changelog_events, &mut jsonl).unwrap();
+
+        let jsonl = String::from_utf8(jsonl).unwrap();
+        let lines: Vec<&str> = jsonl.lines().collect();
+        assert_eq!(
+            lines,
+            [
+                format!(
+                    r#"{{"version":"v1","tree":"{}","leaves":["{}"]}}"#,
+                    "01".repeat(32),
+                    "02".repeat(32),
+                ),
+                format!(
+                    r#"{{"version":"v2","tree":"{}","start_index":10,"seq":2,"leaves":["{}","{}"]}}"#,
+                    "03".repeat(32),
+                    "04".repeat(32),
+                    "05".repeat(32),
+                ),
+            ]
+        );
+
+        assert_eq!(
+            from_jsonl_versioned(Cursor::new(jsonl)).unwrap(),
+            changelog_events
+        );
+    }
+
+    #[test]
+    fn test_from_jsonl_versioned_unknown_version() {
+        let jsonl = format!(
+            r#"{{"version":"v3","tree":"{}","leaves":[]}}"#,
+            "00".repeat(32)
+        );
+
+        let err = from_jsonl_versioned(Cursor::new(jsonl)).unwrap_err();
+        assert!(matches!(err, MyError::Json { line: 1, .. }));
+    }
+
+    #[test]
+    fn test_to_jsonl_writer_error() {
+        // Writing to an empty slice fails with `WriteZero`.
+        let err = to_jsonl(&fixture::batches(), &mut [0_u8; 0][..]).unwrap_err();
+        assert!(matches!(err, MyError::Io(err) if err.kind() == io::ErrorKind::WriteZero));
+
+        let changelog_events: Vec<_> = fixture::batches()[0]
+            .changelogs
+            .iter()
+            .cloned()
+            .map(ChangelogEventVersioned::V1)
+            .collect();
+        let err = to_jsonl_versioned(&changelog_events, &mut [0_u8; 0][..]).unwrap_err();
+        assert!(matches!(err, MyError::Io(err) if err.kind() == io::ErrorKind::WriteZero));
+    }
+}
@@ -1,179 +1,1155 @@
-use std::{cmp, collections::BTreeMap};
+use std::{
+    borrow::Cow,
+    cmp,
+    collections::{BTreeMap, BTreeSet},
+    marker::PhantomData,
+};
 
-use num_integer::div_ceil;
+use interned::{build_merkle_tree_map_interned, INTERNING_THRESHOLD};
 use thiserror::Error;
 
+mod assign;
+#[cfg(feature = "bench")]
+pub mod bench;
+mod builder;
+mod cancel;
+mod check;
+mod chunked;
+mod collector;
+mod csv;
+mod cursor;
+mod data;
+mod display;
+mod dot;
+mod filter;
+#[cfg(feature = "sha2")]
+mod fingerprint;
+mod fixed;
+#[cfg(test)]
+mod fixture;
+pub mod generic;
+mod grouped;
+mod hex;
+mod indexed;
+mod instruction;
+mod interned;
+#[cfg(feature = "light")]
+mod interop;
+#[cfg(feature = "serde")]
+mod jsonl;
+mod limits;
+mod memory;
+mod paging;
+mod pipeline;
+mod plan;
+mod records;
+pub mod reference;
+mod registered;
+mod registry;
+mod retry;
+mod sequence;
+mod sharded;
+#[cfg(feature = "smallvec")]
+mod small;
+#[cfg(feature = "solana")]
+mod solana;
+mod stats;
+#[cfg(feature = "tokio")]
+mod stream;
+pub mod testing;
+pub mod tree;
+mod versioned;
+mod weighted;
+
+pub use assign::{assign_and_append, assign_and_append_modulo};
+pub use builder::{BatchBuilder, FlushPolicy, LeafComparator, LeafOrder, TreeOrder};
+pub use cancel::append_leaves_cancellable;
+pub use check::{check_batch_sequence, SequenceConfig, SequenceViolation, ViolationKind};
+pub use chunked::{append_leaves_chunked, extend_batches};
+pub use collector::BatchCollector;
+pub use csv::{from_csv, to_csv, CSV_HEADER};
+pub use cursor::{append_leaves_resumable, BatchCursor};
+pub use data::{
+    append_leaves_with_data, append_leaves_with_data_by_bytes, ChangelogEventWithData,
+    ChangelogsWithData, LeafWithData,
+};
+pub use display::summarize;
+pub use dot::to_dot;
+pub use filter::{filter_batches_by_tree, partition_by_tree};
+#[cfg(feature = "sha2")]
+pub use fingerprint::fingerprint;
+pub use fixed::{append_leaves_const, ChangelogsFixed};
+pub use grouped::{batch_ranges, BatchRanges, GroupedLeaves};
+pub use indexed::{
+    append_indexed_leaves, append_indexed_leaves_by_bytes, IndexedChangelogEvent,
+    IndexedChangelogs, IndexedLeaf,
+};
+#[cfg(feature = "light")]
+pub use interop::LightChangelog;
+#[cfg(feature = "serde")]
+pub use jsonl::{from_jsonl, from_jsonl_versioned, to_jsonl, to_jsonl_versioned};
+pub use limits::Limits;
+pub use memory::{estimate_grouping_memory, heap_size_of_batches};
+pub use paging::{page_batches, page_leaves};
+pub use pipeline::spawn_batcher;
+pub use plan::{plan, plan_batches, BatchPlan, BatchShape, SplitStrategy};
+pub use records::{append_leaves_from_reader, write_leaf_records, RECORD_LEN};
+pub use registered::{append_leaves_registered, Registered, UnknownTreePolicy};
+pub use registry::{
+    compact_batches, expand_batches, CompactChangelogEvent, CompactChangelogs, TreeRegistry,
+};
+pub use retry::{rebatch_failed, subtract_submitted, RetryPolicy, Unsubmitted};
+pub use sequence::{
+    append_leaves_sequenced, SequencedChangelogEvent, SequencedChangelogs, TreeSequences,
+};
+pub use sharded::append_leaves_sharded;
+#[cfg(feature = "smallvec")]
+pub use small::{append_leaves_small, SmallChangelogEvents, SmallChangelogs};
+#[cfg(feature = "solana")]
+pub use solana::{append_leaves_pubkeys, MerkleTreePubkey};
+pub use stats::{
+    batch_span_per_tree, first_batch_index_per_tree, top_k_trees, tree_histogram,
+    writable_trees_per_batch,
+};
+#[cfg(feature = "tokio")]
+pub use stream::append_leaves_stream;
+pub use versioned::{
+    append_leaves_versioned, decode_versioned, encode_versioned, ChangelogEventV2,
+    ChangelogEventVersioned, ChangelogsVersioned, EventVersion,
+};
+pub use weighted::{
+    append_leaves_by_bytes, append_leaves_by_compute, append_weighted_leaves, ComputeBudget,
+};
+
+/// Length of leaves and Merkle tree pubkeys, in bytes.
+pub const HASH_LEN: usize = 32;
+
 #[derive(Debug, Error)]
 pub enum MyError {
-    #[error("Number of leaves and Merkle trees should be equal, got {0} leaves and {1} trees")]
-    LeavesTreesNotEqual(usize, usize),
+    #[error(
+        "Number of leaves and Merkle trees should be equal, got {leaves} leaves and {trees} \
+         trees ({}), the first unpaired index is {first_unpaired}",
+        describe_excess(*.leaves, *.trees)
+    )]
+    LeavesTreesNotEqual {
+        leaves: usize,
+        trees: usize,
+        /// Index of the first element of the longer input without a pair
+        /// in the shorter one.
+        first_unpaired: usize,
+    },
+    #[error("Merkle tree pubkey at index {first_index} is the default (all-zero) key")]
+    DefaultKey { first_index: usize },
+    #[error("Leaf weight {0} exceeds the maximum batch weight {1}")]
+    LeafWeightExceeded(u64, u64),
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+    #[cfg(feature = "serde")]
+    #[error("Line {line}: invalid JSON: {source}")]
+    Json {
+        line: usize,
+        source: serde_json::Error,
+    },
+    #[error("Line {line}: invalid {field} {value:?}, expected 64 lowercase hex characters")]
+    InvalidHex {
+        line: usize,
+        field: &'static str,
+        value: String,
+    },
+    #[error("Line {line}: expected batch {expected}, got {got}")]
+    UnexpectedBatch {
+        line: usize,
+        expected: usize,
+        got: usize,
+    },
+    #[error("Batch collector is closed")]
+    CollectorClosed,
+    #[error("Batch size should be greater than 0")]
+    InvalidBatchSize,
+    #[error("Can't retry with a smaller batch size than {0}, the minimum is {1}")]
+    RetriesExhausted(usize, usize),
+    #[error("Input ends with a partial record of {0} bytes")]
+    TruncatedInput(usize),
+    #[error("Merkle tree {tree:?} got {got} leaves, the maximum is {max}")]
+    TreeCapacityExceeded {
+        tree: [u8; HASH_LEN],
+        got: usize,
+        max: usize,
+    },
+    #[error("Unknown event version {0}")]
+    UnknownVersion(u8),
+    #[error(
+        "Leaf {leaf_index} was assigned to Merkle tree {tree_index}, but there are only \
+         {num_trees} trees"
+    )]
+    TreeIndexOutOfRange {
+        leaf_index: usize,
+        tree_index: usize,
+        num_trees: usize,
+    },
+    #[error("Got at least {got} distinct Merkle trees, the maximum is {max}")]
+    TooManyTrees { got: usize, max: usize },
+    #[error("Got {got} leaves, the maximum is {max}")]
+    TooManyLeaves { got: usize, max: usize },
+    #[error("Batch size {batch_size} is larger than the number of leaves {total}")]
+    BatchSizeTooLarge { batch_size: usize, total: usize },
+    #[error("Merkle tree is full, its capacity is {capacity} leaves")]
+    TreeFull { capacity: usize },
+    #[error("Expected an event of Merkle tree {expected:?}, got {got:?}")]
+    TreeMismatch {
+        expected: [u8; HASH_LEN],
+        got: [u8; HASH_LEN],
+    },
+    #[error("Leaf index {leaf_index} is out of range, the tree has {len} leaves")]
+    LeafIndexOutOfRange { leaf_index: u64, len: usize },
+    #[error("Merkle tree {0:?} has more than one event in the batch")]
+    DuplicateTree([u8; HASH_LEN]),
+    #[error("Merkle path {path_index} of the event is empty")]
+    EmptyMerklePath { path_index: usize },
+    #[error("No leaves and Merkle trees were given")]
+    EmptyInput,
+    #[error("Merkle tree id {0} is not registered")]
+    UnknownTreeId(u32),
+    #[error("Receiver of the batches is disconnected")]
+    BatchReceiverDisconnected,
+    #[error("Leaves of Merkle tree {0:?} are interleaved with leaves of other trees")]
+    NonContiguousTree([u8; HASH_LEN]),
+    #[error("Line {line}: {reason}")]
+    InvalidCsvRow { line: usize, reason: String },
+    #[error("Line {line}: expected {field} {expected}, got {got}")]
+    UnexpectedIndex {
+        line: usize,
+        field: &'static str,
+        expected: usize,
+        got: usize,
+    },
+    #[error("Batching was cancelled after {batches_completed} batches")]
+    Cancelled { batches_completed: usize },
+    #[error(
+        "Cursor at leaf {leaves_consumed} of Merkle tree {tree_index} is out of range of the \
+         leaves"
+    )]
+    InvalidCursor {
+        tree_index: usize,
+        leaves_consumed: usize,
+    },
+    #[error("Invalid leaf: {0}")]
+    InvalidLeaf(String),
+    #[error("Got {got} events, the maximum is {max}")]
+    TooManyEvents { got: usize, max: usize },
+    #[error("Input has {0} unexpected bytes after the end")]
+    TrailingBytes(usize),
+    #[error("Merkle tree {pubkey:?} of the leaf at index {index} is not registered")]
+    UnregisteredTree {
+        pubkey: [u8; HASH_LEN],
+        index: usize,
+    },
+    #[error("Sequence number of Merkle tree {0:?} overflowed")]
+    SequenceOverflow([u8; HASH_LEN]),
+    #[error("Chunk size should be greater than 0")]
+    InvalidChunkSize,
+}
+
+/// Describes which side of [`MyError::LeavesTreesNotEqual`] is longer and by
+/// how much.
+fn describe_excess(leaves: usize, trees: usize) -> String {
+    if leaves > trees {
+        format!("{} more leaves", leaves - trees)
+    } else {
+        format!("{} more trees", trees - leaves)
+    }
 }
 
 /// Set of changelogs for different Merkle trees.
 /// The number of changelogs it contains is batched.
-#[derive(Clone, Debug, PartialEq, Eq)]
-pub struct Changelogs {
-    pub changelogs: Vec<ChangelogEvent>,
+pub type Changelogs = generic::Changelogs<[u8; HASH_LEN], [u8; HASH_LEN]>;
+
+impl Changelogs {
+    /// Returns the number of events.
+    pub fn len(&self) -> usize {
+        self.changelogs.len()
+    }
+
+    /// Returns `true` if there are no events.
+    pub fn is_empty(&self) -> bool {
+        self.changelogs.is_empty()
+    }
+
+    /// Sorts the events by Merkle tree pubkey. Leaves within the events keep
+    /// their order.
+    pub fn canonicalize(&mut self) {
+        self.changelogs
+            .sort_by_key(|changelog_event| changelog_event.merkle_tree_pubkey);
+    }
+
+    /// Merges events of the same Merkle tree into the first of them, appending
+    /// their leaves in the order of the events. The other events keep their
+    /// order.
+    pub fn merge_duplicate_trees(&mut self) {
+        let mut first_events: BTreeMap<[u8; HASH_LEN], usize> = BTreeMap::new();
+        let mut merged: Vec<ChangelogEvent> = Vec::with_capacity(self.changelogs.len());
+        for changelog_event in self.changelogs.drain(..) {
+            match first_events.get(&changelog_event.merkle_tree_pubkey) {
+                Some(i) => merged[*i].leaves.extend(changelog_event.leaves),
+                None => {
+                    first_events.insert(changelog_event.merkle_tree_pubkey, merged.len());
+                    merged.push(changelog_event);
+                }
+            }
+        }
+        self.changelogs = merged;
+    }
+
+    /// Checks that no Merkle tree has more than one event in the batch, which
+    /// programs consuming the batches reject. Fails with
+    /// [`MyError::DuplicateTree`] for the first repeated tree. Such batches
+    /// can be fixed with [`merge_duplicate_trees`](Self::merge_duplicate_trees).
+    pub fn validate(&self) -> Result<(), MyError> {
+        let mut seen = BTreeSet::new();
+        for changelog_event in self.changelogs.iter() {
+            if !seen.insert(changelog_event.merkle_tree_pubkey) {
+                return Err(MyError::DuplicateTree(changelog_event.merkle_tree_pubkey));
+            }
+        }
+        Ok(())
+    }
+
+    /// Flattens the batch into a list of Merkle trees and a parallel list of
+    /// leaves, with the `i`-th leaf appended to the `i`-th tree. Leaves are in
+    /// the same order as in the events.
+    pub fn into_parallel_vecs(self) -> (Vec<[u8; HASH_LEN]>, Vec<[u8; HASH_LEN]>) {
+        let num_leaves = self
+            .changelogs
+            .iter()
+            .map(|changelog_event| changelog_event.leaves.len())
+            .sum();
+        let mut merkle_trees = Vec::with_capacity(num_leaves);
+        let mut leaves = Vec::with_capacity(num_leaves);
+        for changelog_event in self.changelogs {
+            merkle_trees.resize(
+                merkle_trees.len() + changelog_event.leaves.len(),
+                changelog_event.merkle_tree_pubkey,
+            );
+            leaves.extend(changelog_event.leaves);
+        }
+        (merkle_trees, leaves)
+    }
+
+    /// Flattens the leaves of the batch into a single stream, in the same
+    /// order as in the events, with a `None` marker between the leaves of
+    /// consecutive events, i.e. wherever the Merkle tree changes. There is no
+    /// marker before the first or after the last leaf, so a batch with `n`
+    /// events has `n - 1` markers.
+    pub fn to_flat_with_boundaries(&self) -> Vec<Option<[u8; HASH_LEN]>> {
+        let num_leaves: usize = self
+            .changelogs
+            .iter()
+            .map(|changelog_event| changelog_event.leaves.len())
+            .sum();
+        let mut stream = Vec::with_capacity(num_leaves + self.changelogs.len().saturating_sub(1));
+        for (i, changelog_event) in self.changelogs.iter().enumerate() {
+            if i > 0 {
+                stream.push(None);
+            }
+            stream.extend(changelog_event.leaves.iter().copied().map(Some));
+        }
+        stream
+    }
+
+    /// Converts the batch into the map produced by [`build_merkle_tree_map`].
+    /// Leaves of events of the same Merkle tree are concatenated in the order
+    /// of the events.
+    pub fn into_map(self) -> BTreeMap<[u8; HASH_LEN], Vec<[u8; HASH_LEN]>> {
+        let mut merkle_tree_map: BTreeMap<_, Vec<_>> = BTreeMap::new();
+        for changelog_event in self.changelogs {
+            merkle_tree_map
+                .entry(changelog_event.merkle_tree_pubkey)
+                .or_default()
+                .extend(changelog_event.leaves);
+        }
+        merkle_tree_map
+    }
+
+    /// Creates a batch with one event per Merkle tree in `merkle_tree_map`,
+    /// ordered by the pubkeys. The batch size is not checked.
+    pub fn from_map(merkle_tree_map: BTreeMap<[u8; HASH_LEN], Vec<[u8; HASH_LEN]>>) -> Self {
+        Self {
+            changelogs: merkle_tree_map
+                .into_iter()
+                .map(|(merkle_tree_pubkey, leaves)| ChangelogEvent {
+                    merkle_tree_pubkey,
+                    leaves,
+                })
+                .collect(),
+        }
+    }
+
+    /// Shrinks the capacity of the event list and of the leaves of every
+    /// event to their lengths.
+    pub fn shrink_to_fit(&mut self) {
+        for changelog_event in self.changelogs.iter_mut() {
+            changelog_event.leaves.shrink_to_fit();
+        }
+        self.changelogs.shrink_to_fit();
+    }
+
+    /// Checks whether both batches contain the same events, regardless of
+    /// the order of events. The order of leaves within events still matters.
+    pub fn canonical_eq(&self, other: &Self) -> bool {
+        if self.changelogs.len() != other.changelogs.len() {
+            return false;
+        }
+
+        fn sorted(changelogs: &Changelogs) -> Vec<&ChangelogEvent> {
+            let mut sorted: Vec<_> = changelogs.changelogs.iter().collect();
+            sorted.sort_by_key(|changelog_event| changelog_event.merkle_tree_pubkey);
+            sorted
+        }
+        sorted(self) == sorted(other)
+    }
+}
+
+impl From<Changelogs> for BTreeMap<[u8; HASH_LEN], Vec<[u8; HASH_LEN]>> {
+    fn from(changelogs: Changelogs) -> Self {
+        changelogs.into_map()
+    }
+}
+
+/// Merges all `batches` into the map produced by [`build_merkle_tree_map`].
+/// Leaves of each Merkle tree are concatenated in the order of batches and
+/// events. [`batch_map`] splits the map into batches again.
+pub fn batches_to_map(batches: &[Changelogs]) -> BTreeMap<[u8; HASH_LEN], Vec<[u8; HASH_LEN]>> {
+    let mut merkle_tree_map: BTreeMap<_, Vec<_>> = BTreeMap::new();
+    for changelog_event in batches.iter().flat_map(|batch| &batch.changelogs) {
+        merkle_tree_map
+            .entry(changelog_event.merkle_tree_pubkey)
+            .or_default()
+            .extend_from_slice(&changelog_event.leaves);
+    }
+    merkle_tree_map
+}
+
+/// Checks whether both sets of batches are equal, ignoring the order of
+/// events within each batch (see [`Changelogs::canonical_eq`]). The order of
+/// batches matters.
+pub fn canonical_eq_batches(a: &[Changelogs], b: &[Changelogs]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).all(|(a, b)| a.canonical_eq(b))
 }
 
 /// Changelog event for one Merkle tree.
+pub type ChangelogEvent = generic::ChangelogEvent<[u8; HASH_LEN], [u8; HASH_LEN]>;
+
+impl ChangelogEvent {
+    /// Splits the leaves into consecutive chunks of at most `max` leaves, e.g.
+    /// to append them with several CPIs limited to `max` leaves each. Only the
+    /// last chunk can be shorter.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `max` is 0.
+    pub fn cpi_chunks(&self, max: usize) -> impl Iterator<Item = &[[u8; HASH_LEN]]> {
+        assert!(
+            max != 0,
+            "maximum number of leaves per CPI must be non-zero"
+        );
+        self.leaves.chunks(max)
+    }
+}
+
+/// Changelog event for one Merkle tree, which either borrows or owns its
+/// leaves.
 #[derive(Clone, Debug, PartialEq, Eq)]
-pub struct ChangelogEvent {
-    pub merkle_tree_pubkey: [u8; 32],
-    pub leaves: Vec<[u8; 32]>,
+pub struct ChangelogEventCow<'a> {
+    pub merkle_tree_pubkey: [u8; HASH_LEN],
+    pub leaves: Cow<'a, [[u8; HASH_LEN]]>,
+}
+
+impl ChangelogEventCow<'_> {
+    /// Converts into an owned [`ChangelogEvent`], copying the leaves only if
+    /// they are borrowed.
+    pub fn into_owned(self) -> ChangelogEvent {
+        ChangelogEvent {
+            merkle_tree_pubkey: self.merkle_tree_pubkey,
+            leaves: self.leaves.into_owned(),
+        }
+    }
+}
+
+impl<'a> From<&'a ChangelogEvent> for ChangelogEventCow<'a> {
+    fn from(changelog_event: &'a ChangelogEvent) -> Self {
+        Self {
+            merkle_tree_pubkey: changelog_event.merkle_tree_pubkey,
+            leaves: Cow::Borrowed(&changelog_event.leaves),
+        }
+    }
+}
+
+impl From<ChangelogEvent> for ChangelogEventCow<'_> {
+    fn from(changelog_event: ChangelogEvent) -> Self {
+        Self {
+            merkle_tree_pubkey: changelog_event.merkle_tree_pubkey,
+            leaves: Cow::Owned(changelog_event.leaves),
+        }
+    }
+}
+
+#[cfg_attr(
+    feature = "tracing",
+    tracing::instrument(level = "debug", skip_all, fields(leaves = leaves.len()))
+)]
+pub fn build_merkle_tree_map<K: Ord + Copy, L: Copy>(
+    leaves: &[L],
+    merkle_trees: &[K],
+) -> Result<BTreeMap<K, Vec<L>>, MyError> {
+    build_merkle_tree_map_validated(leaves, merkle_trees, |_| Ok(()))
 }
 
-pub fn build_merkle_tree_map(
-    leaves: &Vec<[u8; 32]>,
-    merkle_trees: &Vec<[u8; 32]>,
-) -> Result<BTreeMap<[u8; 32], Vec<[u8; 32]>>, MyError> {
+/// Same as [`build_merkle_tree_map`], but calls `validate` on every leaf
+/// before grouping it, failing with its first error.
+fn build_merkle_tree_map_validated<K, L, F>(
+    leaves: &[L],
+    merkle_trees: &[K],
+    validate: F,
+) -> Result<BTreeMap<K, Vec<L>>, MyError>
+where
+    K: Ord + Copy,
+    L: Copy,
+    F: Fn(&L) -> Result<(), MyError>,
+{
     if leaves.len() != merkle_trees.len() {
-        return Err(MyError::LeavesTreesNotEqual(
-            leaves.len(),
-            merkle_trees.len(),
-        ));
+        return Err(MyError::LeavesTreesNotEqual {
+            leaves: leaves.len(),
+            trees: merkle_trees.len(),
+            first_unpaired: cmp::min(leaves.len(), merkle_trees.len()),
+        });
     }
-    let mut merkle_tree_map = BTreeMap::new();
+    // Interned ids are `u32`, inputs with more leaves than that are grouped
+    // without interning.
+    let merkle_tree_map =
+        if merkle_trees.len() > INTERNING_THRESHOLD && u32::try_from(merkle_trees.len()).is_ok() {
+            build_merkle_tree_map_interned(leaves, merkle_trees, validate)?
+        } else {
+            build_merkle_tree_map_plain(leaves, merkle_trees, validate)?
+        };
 
-    for (i, merkle_tree) in merkle_trees.iter().enumerate() {
-        merkle_tree_map
-            .entry(merkle_tree.to_owned())
-            .or_insert_with(|| Vec::new())
-            .push(leaves[i]);
+    #[cfg(feature = "tracing")]
+    tracing::debug!(trees = merkle_tree_map.len(), "grouped leaves");
+
+    Ok(merkle_tree_map)
+}
+
+/// Groups the leaves by looking up their Merkle tree in a map. The caller
+/// checks that `leaves` and `merkle_trees` have equal lengths.
+fn build_merkle_tree_map_plain<K, L, F>(
+    leaves: &[L],
+    merkle_trees: &[K],
+    validate: F,
+) -> Result<BTreeMap<K, Vec<L>>, MyError>
+where
+    K: Ord + Copy,
+    L: Copy,
+    F: Fn(&L) -> Result<(), MyError>,
+{
+    // Count the leaves first, so the leaves of every Merkle tree are
+    // allocated once, with the exact capacity.
+    let mut leaf_counts: BTreeMap<K, usize> = BTreeMap::new();
+    for merkle_tree in merkle_trees {
+        *leaf_counts.entry(*merkle_tree).or_default() += 1;
+    }
+    let mut merkle_tree_map: BTreeMap<K, Vec<L>> = leaf_counts
+        .into_iter()
+        .map(|(merkle_tree, count)| (merkle_tree, Vec::with_capacity(count)))
+        .collect();
+
+    for (merkle_tree, leaf) in merkle_trees.iter().zip(leaves) {
+        validate(leaf)?;
+        // Every Merkle tree was inserted above.
+        merkle_tree_map.get_mut(merkle_tree).unwrap().push(*leaf);
     }
 
     Ok(merkle_tree_map)
 }
 
-pub fn append_leaves(
-    leaves: Vec<[u8; 32]>,
-    merkle_trees: Vec<[u8; 32]>,
+/// Same as [`build_merkle_tree_map`], but takes `(merkle_tree, leaf)` pairs,
+/// so no intermediate vectors are needed. Pairs can't be unbalanced, so it
+/// can't fail.
+pub fn build_merkle_tree_map_from<K, L, I>(pairs: I) -> BTreeMap<K, Vec<L>>
+where
+    K: Ord,
+    I: IntoIterator<Item = (K, L)>,
+{
+    let mut merkle_tree_map: BTreeMap<K, Vec<L>> = BTreeMap::new();
+
+    for (merkle_tree, leaf) in pairs {
+        merkle_tree_map.entry(merkle_tree).or_default().push(leaf);
+    }
+
+    merkle_tree_map
+}
+
+/// Checks that none of `merkle_trees` is `[0u8; HASH_LEN]`, which usually
+/// means that the pubkey was never initialized. Fails with
+/// [`MyError::DefaultKey`] pointing at the first such pubkey.
+pub fn validate_trees(merkle_trees: &[[u8; HASH_LEN]]) -> Result<(), MyError> {
+    match merkle_trees
+        .iter()
+        .position(|merkle_tree| *merkle_tree == [0u8; HASH_LEN])
+    {
+        Some(first_index) => Err(MyError::DefaultKey { first_index }),
+        None => Ok(()),
+    }
+}
+
+/// Checks that the leaves of every Merkle tree are contiguous in the input,
+/// i.e. that no Merkle tree in `merkle_trees` appears again after another
+/// tree. Fails with [`MyError::NonContiguousTree`] for the first tree which
+/// does.
+pub fn ensure_contiguous(merkle_trees: &[[u8; HASH_LEN]]) -> Result<(), MyError> {
+    let mut finished = BTreeSet::new();
+    for pair in merkle_trees.windows(2) {
+        let (previous, current) = (&pair[0], &pair[1]);
+        if previous == current {
+            continue;
+        }
+        finished.insert(previous);
+        if finished.contains(current) {
+            return Err(MyError::NonContiguousTree(*current));
+        }
+    }
+    Ok(())
+}
+
+/// Checks that no Merkle tree in `merkle_trees` appears more than
+/// `max_per_tree` times, i.e. that none of them gets more than `max_per_tree`
+/// leaves. If several trees exceed it, the one with the lowest pubkey is
+/// reported.
+pub fn ensure_per_tree_capacity(
+    merkle_trees: &[[u8; HASH_LEN]],
+    max_per_tree: usize,
+) -> Result<(), MyError> {
+    let mut leaf_counts: BTreeMap<&[u8; HASH_LEN], usize> = BTreeMap::new();
+    for merkle_tree in merkle_trees {
+        *leaf_counts.entry(merkle_tree).or_default() += 1;
+    }
+
+    match leaf_counts
+        .into_iter()
+        .find(|(_, count)| *count > max_per_tree)
+    {
+        Some((merkle_tree, count)) => Err(MyError::TreeCapacityExceeded {
+            tree: *merkle_tree,
+            got: count,
+            max: max_per_tree,
+        }),
+        None => Ok(()),
+    }
+}
+
+/// Groups `leaves` by the Merkle trees they are appended to and splits them
+/// into batches of `batch_size` leaves. Merkle trees are processed in the
+/// order of their keys.
+///
+/// Works with any leaf type and any ordered Merkle tree identifier. With
+/// 32-byte pubkeys and leaves, the result is a list of [`Changelogs`].
+///
+/// It's a shorthand for [`GroupedLeaves::new`] followed by
+/// [`GroupedLeaves::split_into_batches`]. To batch the same leaves with
+/// several batch sizes, group them once and split the [`GroupedLeaves`]
+/// instead.
+pub fn append_leaves<K: Ord + Copy, L: Copy>(
+    leaves: Vec<L>,
+    merkle_trees: Vec<K>,
+    batch_size: usize,
+) -> Result<Vec<generic::Changelogs<K, L>>, MyError> {
+    // `split_into_batches` rejects it as well, but only after grouping.
+    if batch_size == 0 {
+        return Err(MyError::InvalidBatchSize);
+    }
+
+    GroupedLeaves::new(&leaves, &merkle_trees)?.split_into_batches(batch_size)
+}
+
+/// Same as [`append_leaves`], but fails with [`MyError::EmptyInput`] if both
+/// `leaves` and `merkle_trees` are empty, instead of returning no batches.
+pub fn append_leaves_non_empty<K: Ord + Copy, L: Copy>(
+    leaves: Vec<L>,
+    merkle_trees: Vec<K>,
+    batch_size: usize,
+) -> Result<Vec<generic::Changelogs<K, L>>, MyError> {
+    if leaves.is_empty() && merkle_trees.is_empty() {
+        return Err(MyError::EmptyInput);
+    }
+
+    append_leaves(leaves, merkle_trees, batch_size)
+}
+
+/// Same as [`append_leaves`], but calls `validate` on every leaf while
+/// grouping them, so malformed leaves are rejected without a separate pass
+/// over the input. The first error returned by `validate`, e.g.
+/// [`MyError::InvalidLeaf`], fails the whole call.
+pub fn append_leaves_validated<F>(
+    leaves: Vec<[u8; HASH_LEN]>,
+    merkle_trees: Vec<[u8; HASH_LEN]>,
     batch_size: usize,
+    validate: F,
+) -> Result<Vec<Changelogs>, MyError>
+where
+    F: Fn(&[u8; HASH_LEN]) -> Result<(), MyError>,
+{
+    if batch_size == 0 {
+        return Err(MyError::InvalidBatchSize);
+    }
+    let merkle_tree_map = build_merkle_tree_map_validated(&leaves, &merkle_trees, validate)?;
+
+    Ok(split_into_batches(
+        merkle_tree_map
+            .iter()
+            .map(|(merkle_tree, leaves)| (*merkle_tree, leaves)),
+        batch_size,
+    ))
+}
+
+/// Groups `leaves` by the Merkle trees they are appended to, without
+/// splitting them: returns one batch per Merkle tree, with a single event
+/// holding all of its leaves, in the order of the Merkle tree pubkeys.
+///
+/// Useful for debugging, as there's no limit on the size of the batches.
+pub fn group_by_tree(
+    leaves: &[[u8; HASH_LEN]],
+    merkle_trees: &[[u8; HASH_LEN]],
 ) -> Result<Vec<Changelogs>, MyError> {
-    let mut merkle_tree_map = build_merkle_tree_map(&leaves, &merkle_trees)?;
+    Ok(build_merkle_tree_map(leaves, merkle_trees)?
+        .into_iter()
+        .map(|(merkle_tree_pubkey, leaves)| Changelogs {
+            changelogs: vec![ChangelogEvent {
+                merkle_tree_pubkey,
+                leaves,
+            }],
+        })
+        .collect())
+}
+
+/// Same as [`append_leaves`], but writes the batches to `out`, so its
+/// allocation can be reused across calls. `out` is cleared first, also when
+/// an error is returned.
+pub fn append_leaves_into(
+    leaves: Vec<[u8; HASH_LEN]>,
+    merkle_trees: Vec<[u8; HASH_LEN]>,
+    batch_size: usize,
+    out: &mut Vec<Changelogs>,
+) -> Result<(), MyError> {
+    out.clear();
+    if batch_size == 0 {
+        return Err(MyError::InvalidBatchSize);
+    }
+    let merkle_tree_map = build_merkle_tree_map(&leaves, &merkle_trees)?;
 
-    let num_batches = div_ceil(leaves.len(), batch_size);
-    let mut leaves_start = 0;
-    let mut batches_of_changelogs = Vec::with_capacity(num_batches);
+    split_into_batches_into(merkle_tree_map, batch_size, out);
 
-    while !merkle_tree_map.is_empty() {
-        let batch_of_changelogs =
-            process_batch(&mut leaves_start, &mut merkle_tree_map, batch_size);
-        batches_of_changelogs.push(batch_of_changelogs);
+    Ok(())
+}
+
+/// Splits a map of Merkle trees and their leaves, e.g. built by
+/// [`build_merkle_tree_map`], into batches of `batch_size` leaves. Merkle
+/// trees are processed in the order of their pubkeys.
+pub fn batch_map(
+    merkle_tree_map: BTreeMap<[u8; HASH_LEN], Vec<[u8; HASH_LEN]>>,
+    batch_size: usize,
+) -> Result<Vec<Changelogs>, MyError> {
+    if batch_size == 0 {
+        return Err(MyError::InvalidBatchSize);
     }
 
-    Ok(batches_of_changelogs)
+    Ok(split_into_batches(merkle_tree_map, batch_size))
 }
 
-pub fn process_batch(
-    leaves_start: &mut usize,
-    merkle_tree_map: &mut BTreeMap<[u8; 32], Vec<[u8; 32]>>,
+/// Splits leaves, already grouped per Merkle tree, into batches of
+/// `batch_size` leaves. Merkle trees are processed in the order in which
+/// `merkle_tree_leaves` yields them.
+#[cfg_attr(
+    feature = "tracing",
+    tracing::instrument(level = "debug", skip_all, fields(batch_size = batch_size))
+)]
+pub fn split_into_batches<I, K, L, T>(
+    merkle_tree_leaves: I,
     batch_size: usize,
-) -> Changelogs {
-    let mut leaves_in_batch = 0;
-    let mut batch_of_changelogs = Changelogs {
-        changelogs: Vec::with_capacity(batch_size),
-    };
+) -> Vec<generic::Changelogs<K, T>>
+where
+    I: IntoIterator<Item = (K, L)>,
+    K: Copy,
+    L: AsRef<[T]>,
+    T: Copy,
+{
+    let mut batches_of_changelogs = Vec::new();
+    split_into_batches_into(merkle_tree_leaves, batch_size, &mut batches_of_changelogs);
 
-    // A vector of trees which become fully processed and should be removed
-    // from the `merkle_tree_map`.
-    let mut processed_merkle_trees = Vec::new();
+    #[cfg(feature = "tracing")]
+    tracing::debug!(batches = batches_of_changelogs.len(), "split leaves");
 
-    {
-        let mut merkle_tree_map_iter = merkle_tree_map.iter();
-        let mut merkle_tree_map_pair = merkle_tree_map_iter.next();
+    batches_of_changelogs
+}
+
+/// Same as [`split_into_batches`], but appends the batches to
+/// `batches_of_changelogs`.
+fn split_into_batches_into<I, K, L, T>(
+    merkle_tree_leaves: I,
+    batch_size: usize,
+    batches_of_changelogs: &mut Vec<generic::Changelogs<K, T>>,
+) where
+    I: IntoIterator<Item = (K, L)>,
+    K: Copy,
+    L: AsRef<[T]>,
+    T: Copy,
+{
+    let batches = Batches::new(merkle_tree_leaves.into_iter().collect(), batch_size);
+
+    // The number of batches is exact, don't let `reserve` round it up.
+    batches_of_changelogs.reserve_exact(batches.len());
+    batches_of_changelogs.extend(batches);
+}
+
+/// Batches leaves like [`append_leaves`], but produces the batches lazily,
+/// one at a time.
+pub fn append_leaves_iter(
+    leaves: Vec<[u8; HASH_LEN]>,
+    merkle_trees: Vec<[u8; HASH_LEN]>,
+    batch_size: usize,
+) -> Result<Batches, MyError> {
+    if batch_size == 0 {
+        return Err(MyError::InvalidBatchSize);
+    }
+    let merkle_tree_map = build_merkle_tree_map(&leaves, &merkle_trees)?;
+
+    Ok(Batches::new(
+        merkle_tree_map.into_iter().collect(),
+        batch_size,
+    ))
+}
+
+/// Same as [`append_leaves`], but returns at most `max_batches` first
+/// batches. The leaves which didn't fit in them are ignored.
+pub fn append_leaves_take(
+    leaves: Vec<[u8; HASH_LEN]>,
+    merkle_trees: Vec<[u8; HASH_LEN]>,
+    batch_size: usize,
+    max_batches: usize,
+) -> Result<Vec<Changelogs>, MyError> {
+    Ok(append_leaves_iter(leaves, merkle_trees, batch_size)?
+        .take(max_batches)
+        .collect())
+}
 
-        while let Some((merkle_tree_pubkey, leaves)) = merkle_tree_map_pair {
-            let leaves_to_process =
-                cmp::min(leaves.len() - *leaves_start, batch_size - leaves_in_batch);
-            let leaves_end = *leaves_start + leaves_to_process;
+/// Result of [`append_leaves_capped`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CappedResult {
+    /// At most `max_batches` first batches.
+    pub batches: Vec<Changelogs>,
+    /// Merkle trees and leaves, which didn't fit in `batches`, in the order
+    /// in which they would have been batched.
+    pub remaining: Vec<([u8; HASH_LEN], [u8; HASH_LEN])>,
+}
 
-            let mut changelog_event = ChangelogEvent {
-                merkle_tree_pubkey: merkle_tree_pubkey.to_owned(),
-                leaves: Vec::with_capacity(cmp::min(leaves.len(), batch_size)),
-            };
+/// Same as [`append_leaves_take`], but returns the leaves which didn't fit
+/// in the first `max_batches` batches as well.
+///
+/// Feeding `remaining` back (unzipped into Merkle trees and leaves)
+/// produces the batches which would have followed.
+pub fn append_leaves_capped(
+    leaves: Vec<[u8; HASH_LEN]>,
+    merkle_trees: Vec<[u8; HASH_LEN]>,
+    batch_size: usize,
+    max_batches: usize,
+) -> Result<CappedResult, MyError> {
+    let mut batches = append_leaves_iter(leaves, merkle_trees, batch_size)?;
 
+    let capped = batches.by_ref().take(max_batches).collect();
+    let remaining = batches
+        .flat_map(|batch| batch.changelogs)
+        .flat_map(|changelog_event| {
+            let merkle_tree = changelog_event.merkle_tree_pubkey;
             changelog_event
                 .leaves
-                .extend_from_slice(&leaves[*leaves_start..leaves_end]);
+                .into_iter()
+                .map(move |leaf| (merkle_tree, leaf))
+        })
+        .collect();
 
-            batch_of_changelogs.changelogs.push(changelog_event);
+    Ok(CappedResult {
+        batches: capped,
+        remaining,
+    })
+}
 
-            leaves_in_batch += leaves_to_process;
-            *leaves_start += leaves_to_process;
+/// Batches of leaves or Merkle trees, each being a flat list.
+pub type FlatBatches = Vec<Vec<[u8; HASH_LEN]>>;
 
-            if *leaves_start == leaves.len() {
-                // We processed all the leaves from the current Merkle tree.
-                // Move to the next one.
-                *leaves_start = 0;
-                merkle_tree_map_pair = merkle_tree_map_iter.next();
-                processed_merkle_trees.push(merkle_tree_pubkey.to_owned());
-            }
+/// Batches leaves like [`append_leaves`], but returns each batch as a flat
+/// list of leaves, in the same order as in its events, together with a
+/// parallel list of their Merkle trees.
+pub fn append_leaves_flat(
+    leaves: Vec<[u8; HASH_LEN]>,
+    merkle_trees: Vec<[u8; HASH_LEN]>,
+    batch_size: usize,
+) -> Result<(FlatBatches, FlatBatches), MyError> {
+    let batches = append_leaves_iter(leaves, merkle_trees, batch_size)?;
 
-            if leaves_in_batch == batch_size {
-                // We reached the batch limit.
-                break;
-            }
+    let mut flat_leaves = Vec::with_capacity(batches.len());
+    let mut flat_merkle_trees = Vec::with_capacity(batches.len());
+    for batch in batches {
+        let (batch_merkle_trees, batch_leaves) = batch.into_parallel_vecs();
+        flat_leaves.push(batch_leaves);
+        flat_merkle_trees.push(batch_merkle_trees);
+    }
+
+    Ok((flat_leaves, flat_merkle_trees))
+}
+
+/// Batches of leaves, each flattened with tree boundary markers, see
+/// [`Changelogs::to_flat_with_boundaries`].
+pub type FlatMarkedBatches = Vec<Vec<Option<[u8; HASH_LEN]>>>;
+
+/// Batches leaves like [`append_leaves`], but returns each batch as a single
+/// stream of leaves with `None` markers at the boundaries between Merkle
+/// trees, see [`Changelogs::to_flat_with_boundaries`].
+pub fn append_leaves_flat_with_boundaries(
+    leaves: Vec<[u8; HASH_LEN]>,
+    merkle_trees: Vec<[u8; HASH_LEN]>,
+    batch_size: usize,
+) -> Result<FlatMarkedBatches, MyError> {
+    Ok(append_leaves_iter(leaves, merkle_trees, batch_size)?
+        .map(|batch| batch.to_flat_with_boundaries())
+        .collect())
+}
+
+/// Iterator over batches of leaves, already grouped per Merkle tree.
+///
+/// Every batch except the last one contains exactly `batch_size` leaves.
+/// Merkle trees are processed in the order they were given in.
+///
+/// `K` is the Merkle tree identifier and `T` the leaf type, `L` holds the
+/// leaves of one tree.
+#[derive(Clone, Debug)]
+pub struct Batches<L = Vec<[u8; HASH_LEN]>, K = [u8; HASH_LEN], T = [u8; HASH_LEN]> {
+    merkle_tree_leaves: Vec<(K, L)>,
+    batch_size: usize,
+    /// Position of the next batch.
+    cursor: BatchCursor,
+    /// Number of leaves which weren't emitted yet.
+    leaves_remaining: usize,
+    _leaf: PhantomData<T>,
+}
+
+impl<L: AsRef<[T]>, K, T> Batches<L, K, T> {
+    /// # Panics
+    ///
+    /// Panics if `batch_size` is 0.
+    pub fn new(merkle_tree_leaves: Vec<(K, L)>, batch_size: usize) -> Self {
+        assert!(batch_size != 0, "batch size must be non-zero");
+
+        let leaves_remaining = merkle_tree_leaves
+            .iter()
+            .map(|(_, leaves)| leaves.as_ref().len())
+            .sum();
+
+        Self {
+            merkle_tree_leaves,
+            batch_size,
+            cursor: BatchCursor::default(),
+            leaves_remaining,
+            _leaf: PhantomData,
+        }
+    }
+
+    /// Same as [`new`](Self::new), but skips the leaves before `cursor`, so
+    /// the first batch starts at the cursor.
+    pub(crate) fn resume(
+        merkle_tree_leaves: Vec<(K, L)>,
+        batch_size: usize,
+        cursor: BatchCursor,
+    ) -> Self {
+        let mut batches = Self::new(merkle_tree_leaves, batch_size);
+        let skipped: usize = batches.merkle_tree_leaves[..cursor.tree_index]
+            .iter()
+            .map(|(_, leaves)| leaves.as_ref().len())
+            .sum();
+        batches.cursor = cursor;
+        batches.leaves_remaining -= skipped + cursor.leaves_consumed;
+        batches
+    }
+
+    /// Returns the position of the next batch.
+    pub(crate) fn cursor(&self) -> BatchCursor {
+        self.cursor
+    }
+
+    /// Returns the number of leaves of the Merkle tree with the given index.
+    fn tree_len(&self, tree_index: usize) -> Option<usize> {
+        self.merkle_tree_leaves
+            .get(tree_index)
+            .map(|(_, leaves)| leaves.as_ref().len())
+    }
+
+    /// Returns the number of events in the next batch, i.e. the number of
+    /// Merkle trees whose leaves it contains.
+    fn num_events_in_next_batch(&self) -> usize {
+        let mut cursor = self.cursor;
+        let mut num_events = 0;
+        cursor.next_batch(
+            self.batch_size,
+            |tree_index| self.tree_len(tree_index),
+            |_, _| num_events += 1,
+        );
+        num_events
+    }
+}
+
+impl<L: AsRef<[T]>, K: Copy, T: Copy> Iterator for Batches<L, K, T> {
+    type Item = generic::Changelogs<K, T>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.leaves_remaining == 0 {
+            return None;
+        }
+
+        let mut batch_of_changelogs =
+            generic::Changelogs::with_capacity(self.num_events_in_next_batch());
+
+        let mut cursor = self.cursor;
+        let leaves_in_batch = cursor.next_batch(
+            self.batch_size,
+            |tree_index| self.tree_len(tree_index),
+            |tree_index, range| {
+                let (merkle_tree_pubkey, leaves) = &self.merkle_tree_leaves[tree_index];
+                batch_of_changelogs.push(generic::ChangelogEvent {
+                    merkle_tree_pubkey: *merkle_tree_pubkey,
+                    leaves: leaves.as_ref()[range].to_vec(),
+                });
+            },
+        );
+        self.cursor = cursor;
+
+        debug_assert!(leaves_in_batch <= self.leaves_remaining);
+        self.leaves_remaining -= leaves_in_batch;
+        Some(batch_of_changelogs)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        // `batch_size` is non-zero, see `new`.
+        let num_batches = self.leaves_remaining.div_ceil(self.batch_size);
+        (num_batches, Some(num_batches))
+    }
+}
+
+impl<L: AsRef<[T]>, K: Copy, T: Copy> ExactSizeIterator for Batches<L, K, T> {}
+
+/// Returns `a / b` rounded up, or `None` if `b` is 0. Unlike
+/// `(a + b - 1) / b`, it can't overflow.
+fn div_ceil_checked(a: usize, b: usize) -> Option<usize> {
+    (b != 0).then(|| a.div_ceil(b))
+}
+
+/// Takes the next batch of `batch_size` leaves out of `merkle_tree_map`,
+/// starting at `leaves_start` in its first Merkle tree, and removes the
+/// Merkle trees it exhausts.
+///
+/// Fails with [`MyError::InvalidCursor`] if `leaves_start` is past the
+/// leaves of the first Merkle tree, and with [`MyError::InvalidBatchSize`]
+/// if `batch_size` is 0. Nothing is taken out of the map then.
+pub fn process_batch(
+    leaves_start: &mut usize,
+    merkle_tree_map: &mut BTreeMap<[u8; HASH_LEN], Vec<[u8; HASH_LEN]>>,
+    batch_size: usize,
+) -> Result<Changelogs, MyError> {
+    if batch_size == 0 {
+        return Err(MyError::InvalidBatchSize);
+    }
+    if let Some(leaves) = merkle_tree_map.values().next() {
+        if *leaves_start > leaves.len() {
+            return Err(MyError::InvalidCursor {
+                tree_index: 0,
+                leaves_consumed: *leaves_start,
+            });
         }
     }
 
+    // A batch has at most one event per Merkle tree, don't preallocate
+    // `batch_size` events, which can be arbitrarily large.
+    let mut batch_of_changelogs = Changelogs {
+        changelogs: Vec::with_capacity(cmp::min(batch_size, merkle_tree_map.len())),
+    };
+
+    // Only the Merkle trees the batch reaches are taken from the map, so
+    // draining it batch by batch walks every tree once.
+    let mut merkle_tree_map_iter = merkle_tree_map.iter();
+    let mut merkle_tree_leaves = Vec::new();
+    let mut ranges = Vec::new();
+    let mut cursor = BatchCursor {
+        tree_index: 0,
+        leaves_consumed: *leaves_start,
+    };
+    cursor.next_batch(
+        batch_size,
+        |tree_index| {
+            if tree_index == merkle_tree_leaves.len() {
+                merkle_tree_leaves.extend(merkle_tree_map_iter.next());
+            }
+            merkle_tree_leaves
+                .get(tree_index)
+                .map(|(_, leaves): &(_, &Vec<_>)| leaves.len())
+        },
+        |tree_index, range| ranges.push((tree_index, range)),
+    );
+    for (tree_index, range) in ranges {
+        let (merkle_tree_pubkey, leaves) = merkle_tree_leaves[tree_index];
+        batch_of_changelogs.changelogs.push(ChangelogEvent {
+            merkle_tree_pubkey: *merkle_tree_pubkey,
+            leaves: leaves[range].to_vec(),
+        });
+    }
+
+    // The Merkle trees before the cursor were fully processed, remove them
+    // from the `merkle_tree_map`.
+    let processed_merkle_trees: Vec<_> = merkle_tree_leaves[..cursor.tree_index]
+        .iter()
+        .map(|(merkle_tree_pubkey, _)| **merkle_tree_pubkey)
+        .collect();
     for processed_merkle_tree in processed_merkle_trees {
         merkle_tree_map.remove(&processed_merkle_tree);
     }
+    *leaves_start = cursor.leaves_consumed;
 
-    batch_of_changelogs
+    Ok(batch_of_changelogs)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::fixture;
+
+    // Leaves and Merkle tree pubkeys are 32-byte hashes and Ed25519 keys.
+    const _: () = assert!(HASH_LEN == 32);
+
+    #[cfg(feature = "tracing")]
+    #[tracing_test::traced_test]
+    #[test]
+    fn test_tracing_spans() {
+        let (leaves, merkle_trees) = fixture::leaves_and_merkle_trees();
+        append_leaves(leaves, merkle_trees, 10).unwrap();
+
+        assert!(logs_contain("build_merkle_tree_map{leaves=25}"));
+        assert!(logs_contain("grouped leaves trees=4"));
+        assert!(logs_contain("split_into_batches{batch_size=10}"));
+        assert!(logs_contain("split leaves batches=3"));
+    }
 
     #[test]
     fn test_append_leaves() {
-        let leaves = vec![
-            // MT 0
-            [0_u8; 32],
-            [1_u8; 32],
-            [2_u8; 32],
-            [3_u8; 32],
-            [4_u8; 32],
-            [5_u8; 32],
-            [6_u8; 32],
-            [7_u8; 32],
-            [8_u8; 32],
-            [9_u8; 32],
-            [10_u8; 32],
-            [11_u8; 32],
-            // MT 1
-            [12_u8; 32],
-            [13_u8; 32],
-            [14_u8; 32],
-            // MT 2
-            [15_u8; 32],
-            [16_u8; 32],
-            [17_u8; 32],
-            [18_u8; 32],
-            // MT 3
-            [19_u8; 32],
-            [20_u8; 32],
-            [21_u8; 32],
-            [22_u8; 32],
-            [23_u8; 32],
-            [24_u8; 32],
-        ];
-        let merkle_trees = vec![
-            // MT 0
-            [0_u8; 32], [0_u8; 32], [0_u8; 32], [0_u8; 32], [0_u8; 32], [0_u8; 32], [0_u8; 32],
-            [0_u8; 32], [0_u8; 32], [0_u8; 32], [0_u8; 32], [0_u8; 32], // MT 1
-            [1_u8; 32], [1_u8; 32], [1_u8; 32], // MT 2
-            [2_u8; 32], [2_u8; 32], [2_u8; 32], [2_u8; 32], // MT 3
-            [3_u8; 32], [3_u8; 32], [3_u8; 32], [3_u8; 32], [3_u8; 32], [3_u8; 32],
-        ];
+        let (leaves, merkle_trees) = fixture::leaves_and_merkle_trees();
 
         let merkle_tree_map = build_merkle_tree_map(&leaves, &merkle_trees).unwrap();
         assert_eq!(
             merkle_tree_map,
-            BTreeMap::<[u8; 32], Vec<[u8; 32]>>::from([
+            BTreeMap::<[u8; HASH_LEN], Vec<[u8; HASH_LEN]>>::from([
                 (
                     [0_u8; 32],
                     vec![
@@ -270,4 +1246,984 @@ mod tests {
             ]
         );
     }
+
+    #[test]
+    fn test_append_leaves_generic() {
+        // Nullifier indices keyed by tree indices.
+        let leaves: Vec<u64> = vec![100, 200, 101, 300, 102, 201];
+        let merkle_trees: Vec<u32> = vec![1, 2, 1, 3, 1, 2];
+
+        let batches = append_leaves(leaves, merkle_trees, 4).unwrap();
+        assert_eq!(
+            batches,
+            vec![
+                generic::Changelogs {
+                    changelogs: vec![
+                        generic::ChangelogEvent {
+                            merkle_tree_pubkey: 1_u32,
+                            leaves: vec![100_u64, 101, 102],
+                        },
+                        generic::ChangelogEvent {
+                            merkle_tree_pubkey: 2,
+                            leaves: vec![200],
+                        },
+                    ],
+                },
+                generic::Changelogs {
+                    changelogs: vec![
+                        generic::ChangelogEvent {
+                            merkle_tree_pubkey: 2,
+                            leaves: vec![201],
+                        },
+                        generic::ChangelogEvent {
+                            merkle_tree_pubkey: 3,
+                            leaves: vec![300],
+                        },
+                    ],
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_append_leaves_into() {
+        let (leaves, merkle_trees) = fixture::leaves_and_merkle_trees();
+
+        let mut out = Vec::new();
+        for batch_size in [10, 3, 25] {
+            append_leaves_into(leaves.clone(), merkle_trees.clone(), batch_size, &mut out).unwrap();
+            assert_eq!(
+                out,
+                append_leaves(leaves.clone(), merkle_trees.clone(), batch_size).unwrap()
+            );
+        }
+
+        // The buffer is cleared even when the input is invalid.
+        let res = append_leaves_into(leaves.clone(), Vec::new(), 10, &mut out);
+        assert!(matches!(
+            res,
+            Err(MyError::LeavesTreesNotEqual {
+                leaves: 25,
+                trees: 0,
+                first_unpaired: 0
+            })
+        ));
+        assert!(out.is_empty());
+
+        append_leaves_into(leaves.clone(), merkle_trees.clone(), 10, &mut out).unwrap();
+        assert!(matches!(
+            append_leaves_into(leaves, merkle_trees, 0, &mut out),
+            Err(MyError::InvalidBatchSize)
+        ));
+        assert!(out.is_empty());
+    }
+
+    #[test]
+    fn test_build_merkle_tree_map_from() {
+        let (leaves, merkle_trees) = fixture::leaves_and_merkle_trees();
+
+        let merkle_tree_map =
+            build_merkle_tree_map_from(merkle_trees.iter().copied().zip(leaves.iter().copied()));
+        assert_eq!(
+            merkle_tree_map,
+            build_merkle_tree_map(&leaves, &merkle_trees).unwrap()
+        );
+        assert_eq!(merkle_tree_map[&[2_u8; 32]], leaves[15..19]);
+    }
+
+    #[test]
+    fn test_validate_trees() {
+        // The fixture uses `[0u8; 32]` as the first Merkle tree.
+        let (_, merkle_trees) = fixture::leaves_and_merkle_trees();
+        assert!(matches!(
+            validate_trees(&merkle_trees),
+            Err(MyError::DefaultKey { first_index: 0 })
+        ));
+
+        assert!(validate_trees(&merkle_trees[12..]).is_ok());
+        assert!(validate_trees(&[]).is_ok());
+
+        let mixed = [[1_u8; 32], [2_u8; 32], [1_u8; 32], [0_u8; 32], [0_u8; 32]];
+        assert!(matches!(
+            validate_trees(&mixed),
+            Err(MyError::DefaultKey { first_index: 3 })
+        ));
+    }
+
+    #[test]
+    fn test_ensure_contiguous() {
+        let (_, merkle_trees) = fixture::leaves_and_merkle_trees();
+        assert!(ensure_contiguous(&merkle_trees).is_ok());
+        assert!(ensure_contiguous(&[]).is_ok());
+
+        // Contiguous, but not sorted.
+        let unsorted = [[2_u8; 32], [2_u8; 32], [1_u8; 32], [3_u8; 32], [3_u8; 32]];
+        assert!(ensure_contiguous(&unsorted).is_ok());
+
+        let interleaved = [[1_u8; 32], [2_u8; 32], [2_u8; 32], [3_u8; 32], [2_u8; 32]];
+        assert!(matches!(
+            ensure_contiguous(&interleaved),
+            Err(MyError::NonContiguousTree(merkle_tree)) if merkle_tree == [2_u8; 32]
+        ));
+    }
+
+    #[test]
+    fn test_ensure_per_tree_capacity() {
+        // MT 0 has 12 leaves, the other trees at most 6.
+        let (_, merkle_trees) = fixture::leaves_and_merkle_trees();
+
+        ensure_per_tree_capacity(&merkle_trees, 12).unwrap();
+        ensure_per_tree_capacity(&[], 0).unwrap();
+
+        assert!(matches!(
+            ensure_per_tree_capacity(&merkle_trees, 11),
+            Err(MyError::TreeCapacityExceeded {
+                tree,
+                got: 12,
+                max: 11
+            }) if tree == [0_u8; 32]
+        ));
+        // MT 0 and MT 3 exceed the cap, the lower pubkey is reported.
+        assert!(matches!(
+            ensure_per_tree_capacity(&merkle_trees, 5),
+            Err(MyError::TreeCapacityExceeded {
+                tree,
+                got: 12,
+                max: 5
+            }) if tree == [0_u8; 32]
+        ));
+        assert!(matches!(
+            ensure_per_tree_capacity(&merkle_trees[12..], 5),
+            Err(MyError::TreeCapacityExceeded {
+                tree,
+                got: 6,
+                max: 5
+            }) if tree == [3_u8; 32]
+        ));
+    }
+
+    #[test]
+    fn test_batch_map() {
+        let (leaves, merkle_trees) = fixture::leaves_and_merkle_trees();
+        let merkle_tree_map = build_merkle_tree_map(&leaves, &merkle_trees).unwrap();
+
+        for batch_size in [10, 3, 25] {
+            assert_eq!(
+                batch_map(merkle_tree_map.clone(), batch_size).unwrap(),
+                append_leaves(leaves.clone(), merkle_trees.clone(), batch_size).unwrap()
+            );
+        }
+
+        assert!(matches!(
+            batch_map(merkle_tree_map, 0),
+            Err(MyError::InvalidBatchSize)
+        ));
+        assert!(batch_map(BTreeMap::new(), 10).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_changelogs_len() {
+        let (leaves, merkle_trees) = fixture::leaves_and_merkle_trees();
+        let batches = append_leaves(leaves, merkle_trees, 10).unwrap();
+
+        let lens: Vec<usize> = batches.iter().map(Changelogs::len).collect();
+        assert_eq!(lens, [1, 4, 1]);
+        assert!(batches.iter().all(|batch| !batch.is_empty()));
+
+        let empty = Changelogs {
+            changelogs: Vec::new(),
+        };
+        assert_eq!(empty.len(), 0);
+        assert!(empty.is_empty());
+    }
+
+    #[test]
+    fn test_changelogs_with_capacity_push() {
+        let mut changelogs = Changelogs::with_capacity(4);
+        assert!(changelogs.is_empty());
+        assert!(changelogs.changelogs.capacity() >= 4);
+
+        changelogs.push(ChangelogEvent {
+            merkle_tree_pubkey: [1_u8; 32],
+            leaves: vec![[2_u8; 32]],
+        });
+        changelogs.push(ChangelogEvent {
+            merkle_tree_pubkey: [3_u8; 32],
+            leaves: vec![[4_u8; 32], [5_u8; 32]],
+        });
+        assert_eq!(changelogs.len(), 2);
+        assert_eq!(changelogs.changelogs[1].merkle_tree_pubkey, [3_u8; 32]);
+        assert!(changelogs.changelogs.capacity() >= 4);
+    }
+
+    #[test]
+    fn test_into_parallel_vecs() {
+        let changelogs = Changelogs {
+            changelogs: vec![
+                ChangelogEvent {
+                    merkle_tree_pubkey: [1_u8; 32],
+                    leaves: vec![[10_u8; 32], [11_u8; 32]],
+                },
+                ChangelogEvent {
+                    merkle_tree_pubkey: [2_u8; 32],
+                    leaves: vec![[20_u8; 32]],
+                },
+            ],
+        };
+
+        let (merkle_trees, leaves) = changelogs.into_parallel_vecs();
+        assert_eq!(merkle_trees, vec![[1_u8; 32], [1_u8; 32], [2_u8; 32]]);
+        assert_eq!(leaves, vec![[10_u8; 32], [11_u8; 32], [20_u8; 32]]);
+
+        assert_eq!(
+            Changelogs::default().into_parallel_vecs(),
+            (Vec::new(), Vec::new())
+        );
+    }
+
+    #[test]
+    fn test_changelogs_map_round_trip() {
+        let (leaves, merkle_trees) = fixture::leaves_and_merkle_trees();
+        let batches = append_leaves(leaves, merkle_trees, 10).unwrap();
+
+        for batch in batches.iter() {
+            let mut reversed = batch.clone();
+            reversed.changelogs.reverse();
+
+            let merkle_tree_map: BTreeMap<_, _> = reversed.clone().into();
+            assert_eq!(merkle_tree_map.len(), batch.changelogs.len());
+            let round_trip = Changelogs::from_map(merkle_tree_map);
+            assert!(round_trip.canonical_eq(&reversed));
+            // Events come back ordered by the pubkeys.
+            assert_eq!(&round_trip, batch);
+        }
+    }
+
+    #[test]
+    fn test_batches_to_map() {
+        let (leaves, merkle_trees) = fixture::leaves_and_merkle_trees();
+        let batches = append_leaves(leaves.clone(), merkle_trees.clone(), 10).unwrap();
+
+        // MT 0 and MT 3 are split over two batches, their leaves are
+        // concatenated in batch order.
+        let merkle_tree_map = batches_to_map(&batches);
+        assert_eq!(merkle_tree_map[&[0_u8; 32]], leaves[..12]);
+        assert_eq!(merkle_tree_map[&[3_u8; 32]], leaves[19..]);
+        assert_eq!(
+            merkle_tree_map,
+            build_merkle_tree_map(&leaves, &merkle_trees).unwrap()
+        );
+        assert_eq!(batch_map(merkle_tree_map, 10).unwrap(), batches);
+
+        assert!(batches_to_map(&[]).is_empty());
+    }
+
+    #[test]
+    fn test_changelogs_default() {
+        let changelogs = Changelogs::default();
+        assert!(changelogs.is_empty());
+        assert_eq!(
+            changelogs,
+            Changelogs {
+                changelogs: Vec::new()
+            }
+        );
+    }
+
+    fn event(merkle_tree: u8, leaves: &[u8]) -> ChangelogEvent {
+        ChangelogEvent {
+            merkle_tree_pubkey: [merkle_tree; 32],
+            leaves: leaves.iter().map(|leaf| [*leaf; 32]).collect(),
+        }
+    }
+
+    #[test]
+    fn test_merge_duplicate_trees_none() {
+        let (leaves, merkle_trees) = fixture::leaves_and_merkle_trees();
+
+        for batch in append_leaves(leaves, merkle_trees, 10).unwrap() {
+            batch.validate().unwrap();
+
+            let mut merged = batch.clone();
+            merged.merge_duplicate_trees();
+            assert_eq!(merged, batch);
+        }
+    }
+
+    #[test]
+    fn test_merge_duplicate_trees_one_group() {
+        let mut batch = Changelogs {
+            changelogs: vec![event(2, &[1]), event(1, &[2, 3]), event(2, &[4, 5])],
+        };
+        assert!(matches!(
+            batch.validate(),
+            Err(MyError::DuplicateTree(merkle_tree)) if merkle_tree == [2_u8; 32]
+        ));
+
+        batch.merge_duplicate_trees();
+        assert_eq!(
+            batch,
+            Changelogs {
+                changelogs: vec![event(2, &[1, 4, 5]), event(1, &[2, 3])],
+            }
+        );
+        batch.validate().unwrap();
+    }
+
+    #[test]
+    fn test_merge_duplicate_trees_multiple_groups() {
+        let mut batch = Changelogs {
+            changelogs: vec![
+                event(3, &[1]),
+                event(1, &[2]),
+                event(4, &[3]),
+                event(1, &[4]),
+                event(3, &[5, 6]),
+                event(1, &[7]),
+            ],
+        };
+        assert!(matches!(
+            batch.validate(),
+            Err(MyError::DuplicateTree(merkle_tree)) if merkle_tree == [1_u8; 32]
+        ));
+
+        batch.merge_duplicate_trees();
+        assert_eq!(
+            batch,
+            Changelogs {
+                changelogs: vec![event(3, &[1, 5, 6]), event(1, &[2, 4, 7]), event(4, &[3])],
+            }
+        );
+    }
+
+    #[test]
+    fn test_canonicalize() {
+        let (leaves, merkle_trees) = fixture::leaves_and_merkle_trees();
+        let batches = append_leaves(leaves, merkle_trees, 10).unwrap();
+
+        let mut shuffled = batches[1].clone();
+        shuffled.changelogs.reverse();
+        assert_ne!(shuffled, batches[1]);
+
+        shuffled.canonicalize();
+        assert_eq!(shuffled, batches[1]);
+    }
+
+    #[test]
+    fn test_canonical_eq() {
+        let (leaves, merkle_trees) = fixture::leaves_and_merkle_trees();
+        let batches = append_leaves(leaves, merkle_trees, 10).unwrap();
+
+        let mut shuffled = batches.clone();
+        shuffled[1].changelogs.swap(0, 3);
+        shuffled[1].changelogs.swap(1, 2);
+        assert!(shuffled[1].canonical_eq(&batches[1]));
+        assert!(canonical_eq_batches(&shuffled, &batches));
+
+        // Different order of leaves.
+        let mut reordered = shuffled.clone();
+        reordered[1].changelogs[1].leaves.swap(0, 1);
+        assert!(!reordered[1].canonical_eq(&batches[1]));
+        assert!(!canonical_eq_batches(&reordered, &batches));
+
+        // Different order of batches.
+        let mut swapped = batches.clone();
+        swapped.swap(0, 2);
+        assert!(!canonical_eq_batches(&swapped, &batches));
+
+        // Missing batch.
+        assert!(!canonical_eq_batches(&batches[..2], &batches));
+    }
+
+    #[test]
+    fn test_changelog_event_cow() {
+        let leaves = [[1_u8; 32], [2_u8; 32], [3_u8; 32]];
+        let changelog_event = ChangelogEvent {
+            merkle_tree_pubkey: [7_u8; 32],
+            leaves: leaves.to_vec(),
+        };
+
+        let borrowed = ChangelogEventCow {
+            merkle_tree_pubkey: [7_u8; 32],
+            leaves: Cow::Borrowed(&leaves),
+        };
+        assert!(matches!(borrowed.leaves, Cow::Borrowed(_)));
+        assert_eq!(borrowed, ChangelogEventCow::from(&changelog_event));
+
+        let owned = ChangelogEventCow::from(changelog_event.clone());
+        assert!(matches!(owned.leaves, Cow::Owned(_)));
+        // Equality doesn't depend on whether the leaves are borrowed.
+        assert_eq!(owned, borrowed);
+
+        assert_eq!(borrowed.into_owned(), changelog_event);
+        assert_eq!(owned.into_owned(), changelog_event);
+    }
+
+    #[test]
+    fn test_append_leaves_iter() {
+        let (leaves, merkle_trees) = fixture::leaves_and_merkle_trees();
+
+        for batch_size in [1, 3, 10, 25, 26] {
+            let mut batches =
+                append_leaves_iter(leaves.clone(), merkle_trees.clone(), batch_size).unwrap();
+            let expected = append_leaves(leaves.clone(), merkle_trees.clone(), batch_size).unwrap();
+
+            assert_eq!(batches.len(), expected.len());
+            assert_eq!(batches.next().as_ref(), expected.first());
+            assert_eq!(batches.len(), expected.len() - 1);
+            assert_eq!(batches.collect::<Vec<_>>(), expected[1..]);
+        }
+    }
+
+    #[test]
+    fn test_append_leaves_take() {
+        let (leaves, merkle_trees) = fixture::leaves_and_merkle_trees();
+        let all_batches = append_leaves(leaves.clone(), merkle_trees.clone(), 10).unwrap();
+
+        for max_batches in 0..=2 {
+            let batches =
+                append_leaves_take(leaves.clone(), merkle_trees.clone(), 10, max_batches).unwrap();
+            assert_eq!(batches.len(), max_batches);
+            assert_eq!(batches, all_batches[..max_batches]);
+        }
+
+        // Asking for more batches than there are returns all of them.
+        let batches = append_leaves_take(leaves, merkle_trees, 10, 5).unwrap();
+        assert_eq!(batches, all_batches);
+    }
+
+    #[test]
+    fn test_append_leaves_capped() {
+        let (leaves, merkle_trees) = fixture::leaves_and_merkle_trees();
+
+        for (batch_size, max_batches) in [(10, 1), (3, 2), (1, 7), (25, 1), (10, 5)] {
+            let mut all_batches = Vec::new();
+            let (mut next_merkle_trees, mut next_leaves) = (merkle_trees.clone(), leaves.clone());
+            loop {
+                let res =
+                    append_leaves_capped(next_leaves, next_merkle_trees, batch_size, max_batches)
+                        .unwrap();
+                assert!(res.batches.len() <= max_batches);
+
+                all_batches.extend(res.batches);
+                if res.remaining.is_empty() {
+                    break;
+                }
+                (next_merkle_trees, next_leaves) = res.remaining.into_iter().unzip();
+            }
+
+            assert_eq!(
+                all_batches,
+                append_leaves(leaves.clone(), merkle_trees.clone(), batch_size).unwrap()
+            );
+        }
+
+        let res = append_leaves_capped(leaves, merkle_trees, 10, 2).unwrap();
+        assert_eq!(res.batches.len(), 2);
+        assert_eq!(res.remaining.len(), 5);
+        assert!(res
+            .remaining
+            .iter()
+            .all(|(merkle_tree, _)| *merkle_tree == [3_u8; 32]));
+    }
+
+    #[test]
+    fn test_append_leaves_flat() {
+        let (leaves, merkle_trees) = fixture::leaves_and_merkle_trees();
+
+        let (flat_leaves, flat_merkle_trees) =
+            append_leaves_flat(leaves.clone(), merkle_trees.clone(), 10).unwrap();
+
+        // The fixture leaves are already sorted by their Merkle trees, so the
+        // flat batches are consecutive chunks of the input.
+        let expected_leaves: Vec<Vec<[u8; 32]>> = leaves.chunks(10).map(<[_]>::to_vec).collect();
+        let expected_merkle_trees: Vec<Vec<[u8; 32]>> =
+            merkle_trees.chunks(10).map(<[_]>::to_vec).collect();
+        assert_eq!(flat_leaves, expected_leaves);
+        assert_eq!(flat_merkle_trees, expected_merkle_trees);
+
+        // Unsorted input is grouped the same way as in `append_leaves`.
+        let (flat_leaves, flat_merkle_trees) = append_leaves_flat(
+            vec![[0_u8; 32], [1_u8; 32], [2_u8; 32]],
+            vec![[5_u8; 32], [4_u8; 32], [5_u8; 32]],
+            2,
+        )
+        .unwrap();
+        assert_eq!(
+            flat_leaves,
+            vec![vec![[1_u8; 32], [0_u8; 32]], vec![[2_u8; 32]]]
+        );
+        assert_eq!(
+            flat_merkle_trees,
+            vec![vec![[4_u8; 32], [5_u8; 32]], vec![[5_u8; 32]]]
+        );
+    }
+
+    #[test]
+    fn test_append_leaves_flat_with_boundaries() {
+        let (leaves, merkle_trees) = fixture::leaves_and_merkle_trees();
+
+        let batches =
+            append_leaves_flat_with_boundaries(leaves.clone(), merkle_trees.clone(), 10).unwrap();
+        assert_eq!(batches.len(), 3);
+        assert_eq!(
+            batches[0],
+            leaves[..10].iter().copied().map(Some).collect::<Vec<_>>()
+        );
+
+        // MT 0 (2 leaves) | MT 1 (3) | MT 2 (4) | MT 3 (1).
+        let boundaries: Vec<usize> = batches[1]
+            .iter()
+            .enumerate()
+            .filter(|(_, leaf)| leaf.is_none())
+            .map(|(i, _)| i)
+            .collect();
+        assert_eq!(boundaries, vec![2, 6, 11]);
+        assert_eq!(
+            batches[1].iter().flatten().copied().collect::<Vec<_>>(),
+            leaves[10..20]
+        );
+
+        assert!(Changelogs::default().to_flat_with_boundaries().is_empty());
+    }
+
+    #[test]
+    fn test_leaves_trees_not_equal() {
+        let (leaves, merkle_trees) = fixture::leaves_and_merkle_trees();
+
+        let res = build_merkle_tree_map(&leaves[..20], &merkle_trees);
+        assert!(matches!(
+            res,
+            Err(MyError::LeavesTreesNotEqual {
+                leaves: 20,
+                trees: 25,
+                first_unpaired: 20
+            })
+        ));
+
+        let err = append_leaves(leaves, merkle_trees[..23].to_vec(), 10).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Number of leaves and Merkle trees should be equal, got 25 leaves and 23 trees (2 \
+             more leaves), the first unpaired index is 23"
+        );
+
+        let err =
+            append_leaves(Vec::<[u8; 32]>::new(), merkle_trees[..3].to_vec(), 10).unwrap_err();
+        assert!(matches!(
+            err,
+            MyError::LeavesTreesNotEqual {
+                leaves: 0,
+                trees: 3,
+                first_unpaired: 0
+            }
+        ));
+        assert_eq!(
+            err.to_string(),
+            "Number of leaves and Merkle trees should be equal, got 0 leaves and 3 trees (3 more \
+             trees), the first unpaired index is 0"
+        );
+    }
+
+    #[test]
+    fn test_append_leaves_empty() {
+        // An empty input is not an error, there is just nothing to batch.
+        let batches = append_leaves(Vec::<[u8; 32]>::new(), Vec::<[u8; 32]>::new(), 10).unwrap();
+        assert!(batches.is_empty());
+    }
+
+    #[test]
+    fn test_append_leaves_non_empty() {
+        let (leaves, merkle_trees) = fixture::leaves_and_merkle_trees();
+
+        assert!(matches!(
+            append_leaves_non_empty(Vec::<[u8; 32]>::new(), Vec::<[u8; 32]>::new(), 10),
+            Err(MyError::EmptyInput)
+        ));
+        assert!(matches!(
+            append_leaves_non_empty(leaves.clone(), Vec::<[u8; 32]>::new(), 10),
+            Err(MyError::LeavesTreesNotEqual {
+                leaves: 25,
+                trees: 0,
+                ..
+            })
+        ));
+        assert_eq!(
+            append_leaves_non_empty(leaves.clone(), merkle_trees.clone(), 10).unwrap(),
+            append_leaves(leaves, merkle_trees, 10).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_append_leaves_exact_capacity() {
+        let (leaves, merkle_trees) = fixture::leaves_and_merkle_trees();
+
+        for batch_size in [1, 3, 10, 25, 100] {
+            let batches = append_leaves(leaves.clone(), merkle_trees.clone(), batch_size).unwrap();
+            assert_eq!(batches.len(), batches.capacity());
+            for batch in batches.iter() {
+                assert_eq!(batch.changelogs.len(), batch.changelogs.capacity());
+            }
+            for changelog_event in batches.iter().flat_map(|batch| batch.changelogs.iter()) {
+                assert_eq!(
+                    changelog_event.leaves.len(),
+                    changelog_event.leaves.capacity()
+                );
+            }
+        }
+
+        let merkle_tree_map = build_merkle_tree_map(&leaves, &merkle_trees).unwrap();
+        for tree_leaves in merkle_tree_map.values() {
+            assert_eq!(tree_leaves.len(), tree_leaves.capacity());
+        }
+    }
+
+    #[test]
+    fn test_changelogs_ord() {
+        let event = |merkle_tree: u8, leaf: u8| ChangelogEvent {
+            merkle_tree_pubkey: [merkle_tree; 32],
+            leaves: vec![[leaf; 32]],
+        };
+        let batch = |changelogs| Changelogs { changelogs };
+
+        // All of them start with the same event of MT 1.
+        let mut batches = vec![
+            batch(vec![event(1, 0), event(3, 0)]),
+            batch(vec![event(1, 0), event(2, 1)]),
+            batch(vec![event(1, 0)]),
+            batch(vec![event(1, 0), event(2, 0)]),
+            batch(vec![]),
+            batch(vec![event(1, 0), event(2, 0)]),
+        ];
+        batches.sort();
+
+        assert_eq!(
+            batches,
+            vec![
+                batch(vec![]),
+                batch(vec![event(1, 0)]),
+                batch(vec![event(1, 0), event(2, 0)]),
+                batch(vec![event(1, 0), event(2, 0)]),
+                batch(vec![event(1, 0), event(2, 1)]),
+                batch(vec![event(1, 0), event(3, 0)]),
+            ]
+        );
+
+        // Consistent with `Eq`.
+        assert_eq!(batches[2].cmp(&batches[3]), cmp::Ordering::Equal);
+        assert!(batches[2] == batches[3]);
+        // The leaves decide when the pubkeys are equal.
+        assert!(event(1, 0) < event(1, 1));
+        assert!(event(1, 1) < event(2, 0));
+    }
+
+    #[test]
+    fn test_append_leaves_huge_batch_size() {
+        let (leaves, merkle_trees) = fixture::leaves_and_merkle_trees();
+        let expected = append_leaves(leaves.clone(), merkle_trees.clone(), 25).unwrap();
+        assert_eq!(expected.len(), 1);
+
+        for batch_size in [usize::MAX, usize::MAX - 1, usize::MAX / 2 + 1] {
+            assert_eq!(
+                append_leaves(leaves.clone(), merkle_trees.clone(), batch_size).unwrap(),
+                expected
+            );
+
+            let batches =
+                append_leaves_iter(leaves.clone(), merkle_trees.clone(), batch_size).unwrap();
+            assert_eq!(batches.len(), 1);
+            assert_eq!(batches.collect::<Vec<_>>(), expected);
+
+            let mut merkle_tree_map = build_merkle_tree_map(&leaves, &merkle_trees).unwrap();
+            let mut leaves_start = 0;
+            assert_eq!(
+                process_batch(&mut leaves_start, &mut merkle_tree_map, batch_size).unwrap(),
+                expected[0]
+            );
+            assert!(merkle_tree_map.is_empty());
+            assert_eq!(leaves_start, 0);
+        }
+    }
+
+    #[test]
+    fn test_append_leaves_huge_batch_size_tail() {
+        // One leaf less than the batch size, so the only batch is not full.
+        let leaves = vec![[1_u8; 32]; 3];
+        let merkle_trees = vec![[0_u8; 32], [0_u8; 32], [1_u8; 32]];
+
+        let batches = append_leaves(leaves, merkle_trees, usize::MAX).unwrap();
+        assert_eq!(batches.len(), 1);
+        assert_eq!(batches[0].changelogs.len(), 2);
+        assert_eq!(batches[0].changelogs[0].leaves.len(), 2);
+        assert_eq!(batches[0].changelogs[1].leaves.len(), 1);
+    }
+
+    #[test]
+    fn test_cpi_chunks() {
+        let changelog_event = ChangelogEvent {
+            merkle_tree_pubkey: [1_u8; 32],
+            leaves: (0..7).map(|i| [i; 32]).collect(),
+        };
+
+        let chunks: Vec<_> = changelog_event.cpi_chunks(3).collect();
+        assert_eq!(
+            chunks.iter().map(|chunk| chunk.len()).collect::<Vec<_>>(),
+            vec![3, 3, 1]
+        );
+        assert_eq!(chunks.concat(), changelog_event.leaves);
+
+        assert_eq!(changelog_event.cpi_chunks(7).count(), 1);
+        assert_eq!(changelog_event.cpi_chunks(100).count(), 1);
+        let empty = ChangelogEvent {
+            merkle_tree_pubkey: [1_u8; 32],
+            leaves: vec![],
+        };
+        assert_eq!(empty.cpi_chunks(3).count(), 0);
+    }
+
+    #[test]
+    fn test_append_leaves_single_large_tree() {
+        for (num_leaves, expected_lens) in [
+            (25, vec![10, 10, 5]),
+            // Exact multiple of the batch size, no trailing empty batch.
+            (20, vec![10, 10]),
+            (21, vec![10, 10, 1]),
+            (19, vec![10, 9]),
+        ] {
+            let leaves: Vec<[u8; 32]> = (0..num_leaves).map(|i| [i; 32]).collect();
+            let merkle_trees = vec![[1_u8; 32]; leaves.len()];
+
+            let batches = append_leaves(leaves.clone(), merkle_trees.clone(), 10).unwrap();
+
+            // Every batch is a single full event of the tree, except the last
+            // one, which gets the remainder.
+            assert_eq!(batches.len(), expected_lens.len());
+            for (batch, expected_len) in batches.iter().zip(&expected_lens) {
+                assert_eq!(batch.changelogs.len(), 1);
+                assert_eq!(batch.changelogs[0].merkle_tree_pubkey, [1_u8; 32]);
+                assert_eq!(batch.changelogs[0].leaves.len(), *expected_len);
+            }
+            let flat: Vec<_> = batches
+                .iter()
+                .flat_map(|batch| batch.changelogs[0].leaves.clone())
+                .collect();
+            assert_eq!(flat, leaves);
+
+            assert_eq!(
+                append_leaves_iter(leaves.clone(), merkle_trees.clone(), 10)
+                    .unwrap()
+                    .collect::<Vec<_>>(),
+                batches
+            );
+
+            let mut merkle_tree_map = build_merkle_tree_map(&leaves, &merkle_trees).unwrap();
+            let mut leaves_start = 0;
+            for batch in batches.iter() {
+                assert_eq!(
+                    &process_batch(&mut leaves_start, &mut merkle_tree_map, 10).unwrap(),
+                    batch
+                );
+            }
+            assert!(merkle_tree_map.is_empty());
+        }
+    }
+
+    #[test]
+    fn test_process_batch_empty_trees() {
+        let (leaves, merkle_trees) = fixture::leaves_and_merkle_trees();
+        let batches = append_leaves(leaves.clone(), merkle_trees.clone(), 10).unwrap();
+
+        // Empty Merkle trees between the fixture trees, e.g. MT 0..9 sorts
+        // between MT 0 and MT 1, don't count towards the batch and produce no
+        // events.
+        let mut merkle_tree_map = build_merkle_tree_map(&leaves, &merkle_trees).unwrap();
+        for i in 0..3 {
+            let mut merkle_tree = [i; 32];
+            merkle_tree[31] = 9;
+            merkle_tree_map.insert(merkle_tree, Vec::new());
+        }
+
+        let mut leaves_start = 0;
+        for batch in batches.iter() {
+            assert_eq!(
+                &process_batch(&mut leaves_start, &mut merkle_tree_map, 10).unwrap(),
+                batch
+            );
+        }
+        assert!(merkle_tree_map.is_empty());
+    }
+
+    #[test]
+    fn test_div_ceil_checked() {
+        assert_eq!(div_ceil_checked(10, 0), None);
+        assert_eq!(div_ceil_checked(0, 0), None);
+        assert_eq!(div_ceil_checked(0, 3), Some(0));
+        // Exact division.
+        assert_eq!(div_ceil_checked(12, 4), Some(3));
+        assert_eq!(div_ceil_checked(7, 1), Some(7));
+        // Remainder.
+        assert_eq!(div_ceil_checked(13, 4), Some(4));
+        assert_eq!(div_ceil_checked(3, 10), Some(1));
+        // `a + b - 1` would overflow.
+        assert_eq!(div_ceil_checked(usize::MAX, 2), Some(usize::MAX / 2 + 1));
+        assert_eq!(div_ceil_checked(usize::MAX, usize::MAX), Some(1));
+        assert_eq!(div_ceil_checked(usize::MAX - 1, usize::MAX), Some(1));
+        assert_eq!(div_ceil_checked(usize::MAX, 1), Some(usize::MAX));
+    }
+
+    #[test]
+    fn test_append_leaves_zero_batch_size() {
+        let (leaves, merkle_trees) = fixture::leaves_and_merkle_trees();
+        assert!(matches!(
+            append_leaves(leaves, merkle_trees, 0),
+            Err(MyError::InvalidBatchSize)
+        ));
+        assert!(matches!(
+            append_leaves(Vec::<[u8; 32]>::new(), Vec::<[u8; 32]>::new(), 0),
+            Err(MyError::InvalidBatchSize)
+        ));
+    }
+
+    #[test]
+    fn test_append_leaves_iter_zero_batch_size() {
+        let (leaves, merkle_trees) = fixture::leaves_and_merkle_trees();
+
+        // The wrappers of `append_leaves_iter` fail the same way.
+        assert!(matches!(
+            append_leaves_iter(leaves.clone(), merkle_trees.clone(), 0),
+            Err(MyError::InvalidBatchSize)
+        ));
+        assert!(matches!(
+            append_leaves_take(leaves.clone(), merkle_trees.clone(), 0, 1),
+            Err(MyError::InvalidBatchSize)
+        ));
+        assert!(matches!(
+            append_leaves_capped(leaves.clone(), merkle_trees.clone(), 0, 1),
+            Err(MyError::InvalidBatchSize)
+        ));
+        assert!(matches!(
+            append_leaves_flat(leaves.clone(), merkle_trees.clone(), 0),
+            Err(MyError::InvalidBatchSize)
+        ));
+        assert!(matches!(
+            append_leaves_flat_with_boundaries(leaves, merkle_trees, 0),
+            Err(MyError::InvalidBatchSize)
+        ));
+    }
+
+    #[test]
+    fn test_process_batch_invalid_leaves_start() {
+        let (leaves, merkle_trees) = fixture::leaves_and_merkle_trees();
+        let mut merkle_tree_map = build_merkle_tree_map(&leaves, &merkle_trees).unwrap();
+
+        // MT 0 has 12 leaves, starting right after them is still valid.
+        for leaves_start in [13, usize::MAX] {
+            let mut start = leaves_start;
+            assert!(matches!(
+                process_batch(&mut start, &mut merkle_tree_map, 10),
+                Err(MyError::InvalidCursor {
+                    tree_index: 0,
+                    leaves_consumed,
+                }) if leaves_consumed == leaves_start
+            ));
+            assert_eq!(start, leaves_start);
+        }
+        let mut leaves_start = 0;
+        assert!(matches!(
+            process_batch(&mut leaves_start, &mut merkle_tree_map, 0),
+            Err(MyError::InvalidBatchSize)
+        ));
+        assert_eq!(merkle_tree_map.len(), 4);
+
+        let mut leaves_start = 12;
+        assert!(process_batch(&mut leaves_start, &mut merkle_tree_map, 10).is_ok());
+    }
+
+    #[test]
+    fn test_group_by_tree() {
+        let (leaves, merkle_trees) = fixture::leaves_and_merkle_trees();
+        let batches = group_by_tree(&leaves, &merkle_trees).unwrap();
+
+        assert_eq!(batches.len(), 4);
+        let trees_and_lens: Vec<_> = batches
+            .iter()
+            .map(|batch| {
+                assert_eq!(batch.changelogs.len(), 1);
+                let changelog_event = &batch.changelogs[0];
+                (
+                    changelog_event.merkle_tree_pubkey,
+                    changelog_event.leaves.len(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            trees_and_lens,
+            vec![
+                ([0_u8; 32], 12),
+                ([1_u8; 32], 3),
+                ([2_u8; 32], 4),
+                ([3_u8; 32], 6)
+            ]
+        );
+        // Every leaf ends up in exactly one batch.
+        assert!(testing::batches_match_input(
+            &batches,
+            &leaves,
+            &merkle_trees
+        ));
+
+        assert!(matches!(
+            group_by_tree(&leaves, &merkle_trees[1..]),
+            Err(MyError::LeavesTreesNotEqual { .. })
+        ));
+    }
+
+    #[test]
+    fn test_append_leaves_validated() {
+        let reject_zero = |leaf: &[u8; 32]| {
+            if *leaf == [0_u8; 32] {
+                Err(MyError::InvalidLeaf("all-zero hash".to_owned()))
+            } else {
+                Ok(())
+            }
+        };
+
+        let (leaves, merkle_trees) = fixture::leaves_and_merkle_trees();
+        // The fixture starts with the leaf `[0; 32]`.
+        let err = append_leaves_validated(leaves.clone(), merkle_trees.clone(), 10, reject_zero)
+            .unwrap_err();
+        assert!(matches!(&err, MyError::InvalidLeaf(reason) if reason == "all-zero hash"));
+        assert_eq!(err.to_string(), "Invalid leaf: all-zero hash");
+
+        let clean_leaves: Vec<_> = leaves.iter().map(|leaf| [leaf[0] + 1; 32]).collect();
+        assert_eq!(
+            append_leaves_validated(clean_leaves.clone(), merkle_trees.clone(), 10, reject_zero)
+                .unwrap(),
+            append_leaves(clean_leaves, merkle_trees.clone(), 10).unwrap()
+        );
+
+        // The whole run stops at the first invalid leaf.
+        let validated = std::cell::Cell::new(0);
+        let reject_fifth = |_: &[u8; 32]| {
+            validated.set(validated.get() + 1);
+            if validated.get() == 5 {
+                Err(MyError::InvalidLeaf("fifth".to_owned()))
+            } else {
+                Ok(())
+            }
+        };
+        assert!(
+            append_leaves_validated(leaves.clone(), merkle_trees.clone(), 10, reject_fifth)
+                .is_err()
+        );
+        assert_eq!(validated.get(), 5);
+
+        // A zero batch size is rejected before validating any leaf.
+        validated.set(0);
+        assert!(matches!(
+            append_leaves_validated(leaves, merkle_trees, 0, reject_fifth),
+            Err(MyError::InvalidBatchSize)
+        ));
+        assert_eq!(validated.get(), 0);
+    }
 }
@@ -0,0 +1,123 @@
+use std::{cmp, collections::BTreeMap};
+
+use crate::{MyError, HASH_LEN};
+
+/// Limits on the size of the input, protecting the grouping from
+/// pathological inputs. `None` means no limit.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Limits {
+    /// Maximum number of distinct Merkle trees.
+    pub max_distinct_trees: Option<usize>,
+    /// Maximum number of leaves.
+    pub max_total_leaves: Option<usize>,
+}
+
+impl Limits {
+    /// Same as [`build_merkle_tree_map`](crate::build_merkle_tree_map), but
+    /// fails with [`MyError::TooManyLeaves`] before grouping if there are too
+    /// many leaves and with [`MyError::TooManyTrees`] as soon as one Merkle
+    /// tree too many is found.
+    pub fn build_merkle_tree_map(
+        &self,
+        leaves: &[[u8; HASH_LEN]],
+        merkle_trees: &[[u8; HASH_LEN]],
+    ) -> Result<BTreeMap<[u8; HASH_LEN], Vec<[u8; HASH_LEN]>>, MyError> {
+        if leaves.len() != merkle_trees.len() {
+            return Err(MyError::LeavesTreesNotEqual {
+                leaves: leaves.len(),
+                trees: merkle_trees.len(),
+                first_unpaired: cmp::min(leaves.len(), merkle_trees.len()),
+            });
+        }
+        if let Some(max_total_leaves) = self.max_total_leaves {
+            if leaves.len() > max_total_leaves {
+                return Err(MyError::TooManyLeaves {
+                    got: leaves.len(),
+                    max: max_total_leaves,
+                });
+            }
+        }
+        let max_distinct_trees = self.max_distinct_trees.unwrap_or(usize::MAX);
+
+        let mut merkle_tree_map: BTreeMap<_, Vec<_>> = BTreeMap::new();
+        for (merkle_tree, leaf) in merkle_trees.iter().zip(leaves) {
+            match merkle_tree_map.get_mut(merkle_tree) {
+                Some(tree_leaves) => tree_leaves.push(*leaf),
+                None => {
+                    if merkle_tree_map.len() == max_distinct_trees {
+                        return Err(MyError::TooManyTrees {
+                            got: merkle_tree_map.len() + 1,
+                            max: max_distinct_trees,
+                        });
+                    }
+                    merkle_tree_map.insert(*merkle_tree, vec![*leaf]);
+                }
+            }
+        }
+
+        Ok(merkle_tree_map)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{build_merkle_tree_map, fixture};
+
+    #[test]
+    fn test_limits_within() {
+        let (leaves, merkle_trees) = fixture::leaves_and_merkle_trees();
+        let expected = build_merkle_tree_map(&leaves, &merkle_trees).unwrap();
+
+        for limits in [
+            Limits::default(),
+            Limits {
+                max_distinct_trees: Some(4),
+                max_total_leaves: Some(25),
+            },
+        ] {
+            assert_eq!(
+                limits
+                    .build_merkle_tree_map(&leaves, &merkle_trees)
+                    .unwrap(),
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn test_limits_too_many_trees() {
+        // A million distinct trees, the grouping stops at the fourth one.
+        let merkle_trees: Vec<[u8; 32]> = (0..1_000_000_u32)
+            .map(|i| {
+                let mut merkle_tree = [0_u8; 32];
+                merkle_tree[..4].copy_from_slice(&i.to_le_bytes());
+                merkle_tree
+            })
+            .collect();
+        let leaves = vec![[1_u8; 32]; merkle_trees.len()];
+
+        let limits = Limits {
+            max_distinct_trees: Some(3),
+            max_total_leaves: None,
+        };
+        assert!(matches!(
+            limits.build_merkle_tree_map(&leaves, &merkle_trees),
+            Err(MyError::TooManyTrees { got: 4, max: 3 })
+        ));
+    }
+
+    #[test]
+    fn test_limits_too_many_leaves() {
+        let (leaves, merkle_trees) = fixture::leaves_and_merkle_trees();
+
+        let limits = Limits {
+            max_distinct_trees: None,
+            max_total_leaves: Some(24),
+        };
+        assert!(matches!(
+            limits.build_merkle_tree_map(&leaves, &merkle_trees),
+            Err(MyError::TooManyLeaves { got: 25, max: 24 })
+        ));
+    }
+}
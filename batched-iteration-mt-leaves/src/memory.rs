@@ -0,0 +1,118 @@
+//! Rough estimates of the heap memory used by batching.
+
+use std::mem;
+
+use crate::{ChangelogEvent, Changelogs, HASH_LEN};
+
+/// Maximum number of entries in a `BTreeMap` node (`2 * B - 1` with `B = 6`
+/// in the standard library).
+const BTREE_NODE_CAPACITY: usize = 11;
+/// Minimum number of entries in a non-root `BTreeMap` node (`B - 1`).
+const BTREE_NODE_MIN_LEN: usize = 5;
+/// Size of the node header: parent pointer, index in the parent and length.
+const BTREE_NODE_HEADER: usize = 16;
+
+impl Changelogs {
+    /// Returns the number of heap bytes owned by the batch, based on the
+    /// capacities of its vectors.
+    pub fn heap_size(&self) -> usize {
+        self.changelogs.capacity() * mem::size_of::<ChangelogEvent>()
+            + self
+                .changelogs
+                .iter()
+                .map(|changelog_event| changelog_event.leaves.capacity() * HASH_LEN)
+                .sum::<usize>()
+    }
+}
+
+/// Returns the number of heap bytes owned by the batches, not counting the
+/// slice itself.
+pub fn heap_size_of_batches(batches: &[Changelogs]) -> usize {
+    batches.iter().map(Changelogs::heap_size).sum()
+}
+
+/// Estimates the number of heap bytes used for grouping `num_leaves` leaves
+/// of `num_distinct_trees` Merkle trees, as in
+/// [`build_merkle_tree_map`](crate::build_merkle_tree_map).
+///
+/// The model assumes that:
+///
+/// * Each tree's leaf vector is allocated with the exact number of its
+///   leaves, so the leaves take `num_leaves * HASH_LEN` bytes regardless of
+///   their distribution.
+/// * `BTreeMap` nodes are filled to the minimum of 5 entries, which
+///   overestimates the number of nodes for maps filled in order.
+/// * The allocator adds no overhead.
+///
+/// The short-lived map counting the leaves per tree is not included.
+pub fn estimate_grouping_memory(num_leaves: usize, num_distinct_trees: usize) -> usize {
+    if num_distinct_trees == 0 {
+        return 0;
+    }
+
+    let leaves_size = num_leaves * HASH_LEN;
+
+    let entry_size = mem::size_of::<([u8; HASH_LEN], Vec<[u8; HASH_LEN]>)>();
+    let leaf_node_size = BTREE_NODE_HEADER + BTREE_NODE_CAPACITY * entry_size;
+    let internal_node_size = leaf_node_size + (BTREE_NODE_CAPACITY + 1) * mem::size_of::<usize>();
+
+    let mut nodes = num_distinct_trees.div_ceil(BTREE_NODE_MIN_LEN);
+    let mut nodes_size = nodes * leaf_node_size;
+    while nodes > 1 {
+        // Each internal node has at least `BTREE_NODE_MIN_LEN + 1` children.
+        nodes = nodes.div_ceil(BTREE_NODE_MIN_LEN + 1);
+        nodes_size += nodes * internal_node_size;
+    }
+
+    leaves_size + nodes_size
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{append_leaves, build_merkle_tree_map, fixture};
+
+    #[test]
+    fn test_heap_size() {
+        let (leaves, merkle_trees) = fixture::leaves_and_merkle_trees();
+        let batches = append_leaves(leaves, merkle_trees, 10).unwrap();
+
+        // The event lists and the leaf vectors are exact.
+        let expected = 6 * mem::size_of::<ChangelogEvent>() + 25 * HASH_LEN;
+        assert_eq!(heap_size_of_batches(&batches), expected);
+
+        let mut compacted = batches[1].clone();
+        compacted.shrink_to_fit();
+        assert_eq!(
+            compacted.heap_size(),
+            4 * mem::size_of::<ChangelogEvent>() + 10 * HASH_LEN
+        );
+        assert_eq!(heap_size_of_batches(&[]), 0);
+    }
+
+    #[test]
+    fn test_estimate_grouping_memory() {
+        let (leaves, merkle_trees) = fixture::leaves_and_merkle_trees();
+        let merkle_tree_map = build_merkle_tree_map(&leaves, &merkle_trees).unwrap();
+
+        // Manual calculation: the actual leaf vector capacities and a single
+        // `BTreeMap` node holding all 4 trees.
+        let leaves_size: usize = merkle_tree_map
+            .values()
+            .map(|leaves| leaves.capacity() * HASH_LEN)
+            .sum();
+        let node_size =
+            BTREE_NODE_HEADER + BTREE_NODE_CAPACITY * mem::size_of::<([u8; 32], Vec<[u8; 32]>)>();
+        let manual = leaves_size + node_size;
+
+        // The estimate is within 10% of the manual calculation.
+        let estimate = estimate_grouping_memory(25, 4);
+        assert!(
+            estimate.abs_diff(manual) * 10 <= manual,
+            "estimate {estimate}, manual {manual}"
+        );
+
+        assert_eq!(estimate_grouping_memory(0, 0), 0);
+        assert!(estimate_grouping_memory(1_000_000, 1000) > 1_000_000 * HASH_LEN);
+    }
+}
@@ -0,0 +1,92 @@
+use crate::{Changelogs, HASH_LEN};
+
+/// Returns the `page`-th page of `page_size` batches. The last page can be
+/// shorter. Returns `None` if the page starts past the end or `page_size` is
+/// zero.
+pub fn page_batches(
+    batches: &[Changelogs],
+    page: usize,
+    page_size: usize,
+) -> Option<&[Changelogs]> {
+    if page_size == 0 {
+        return None;
+    }
+    let start = page.checked_mul(page_size)?;
+    if start >= batches.len() {
+        return None;
+    }
+    let end = start.saturating_add(page_size).min(batches.len());
+    Some(&batches[start..end])
+}
+
+/// Returns at most `limit` `(merkle_tree, leaf)` pairs, starting at the
+/// `offset`-th leaf of all batches, in the order of batches and events. An
+/// offset past the end returns no leaves.
+pub fn page_leaves(
+    batches: &[Changelogs],
+    offset: usize,
+    limit: usize,
+) -> Vec<([u8; HASH_LEN], [u8; HASH_LEN])> {
+    let mut page = Vec::new();
+    let mut skip = offset;
+
+    for changelog_event in batches.iter().flat_map(|batch| &batch.changelogs) {
+        if page.len() == limit {
+            break;
+        }
+        // Skip whole events before the offset.
+        if skip >= changelog_event.leaves.len() {
+            skip -= changelog_event.leaves.len();
+            continue;
+        }
+
+        let take = (limit - page.len()).min(changelog_event.leaves.len() - skip);
+        page.extend(
+            changelog_event.leaves[skip..skip + take]
+                .iter()
+                .map(|leaf| (changelog_event.merkle_tree_pubkey, *leaf)),
+        );
+        skip = 0;
+    }
+
+    page
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixture;
+
+    #[test]
+    fn test_page_batches() {
+        let batches = fixture::batches();
+
+        assert_eq!(page_batches(&batches, 0, 2), Some(&batches[..2]));
+        assert_eq!(page_batches(&batches, 1, 2), Some(&batches[2..]));
+        assert_eq!(page_batches(&batches, 2, 2), None);
+        assert_eq!(page_batches(&batches, 0, 5), Some(&batches[..]));
+        assert_eq!(page_batches(&batches, 0, 0), None);
+        assert_eq!(page_batches(&batches, usize::MAX, 2), None);
+        assert_eq!(page_batches(&[], 0, 2), None);
+    }
+
+    #[test]
+    fn test_page_leaves() {
+        let (leaves, merkle_trees) = fixture::leaves_and_merkle_trees();
+        let batches = fixture::batches();
+        // The fixture leaves are sorted by their Merkle trees, so the batches
+        // contain them in the input order.
+        let pairs: Vec<_> = merkle_trees.into_iter().zip(leaves).collect();
+
+        // At a batch boundary.
+        assert_eq!(page_leaves(&batches, 10, 5), pairs[10..15]);
+        // Mid-event, across events and batches.
+        assert_eq!(page_leaves(&batches, 13, 10), pairs[13..23]);
+        assert_eq!(page_leaves(&batches, 0, 25), pairs);
+        // Past the end.
+        assert_eq!(page_leaves(&batches, 22, 10), pairs[22..]);
+        assert!(page_leaves(&batches, 25, 10).is_empty());
+        assert!(page_leaves(&batches, usize::MAX, 10).is_empty());
+        assert!(page_leaves(&batches, 0, 0).is_empty());
+    }
+}
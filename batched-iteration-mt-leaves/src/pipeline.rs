@@ -0,0 +1,170 @@
+use std::{
+    mem,
+    sync::mpsc::{Receiver, SyncSender},
+    thread::{self, JoinHandle},
+};
+
+use crate::{ChangelogEvent, Changelogs, MyError, HASH_LEN};
+
+/// Spawns a thread which batches `(merkle_tree, leaf)` pairs received from
+/// `rx` and sends every full batch of `batch_size` leaves to `tx`.
+///
+/// Within a batch, the leaves of each Merkle tree are grouped in one event,
+/// like in [`BatchCollector`](crate::BatchCollector). When all senders of
+/// `rx` are dropped, the last partial batch is sent and the thread exits,
+/// dropping `tx`.
+///
+/// A full `tx` blocks the batcher, which in turn fills up `rx` and blocks the
+/// producers, so a slow consumer slows down the whole pipeline instead of
+/// buffering the input. The batcher never waits on `rx` and `tx` at the same
+/// time, so closing the input while the output is full just delays the last
+/// batch until the consumer catches up. If the consumer drops its receiver,
+/// the thread exits with [`MyError::BatchReceiverDisconnected`].
+///
+/// # Panics
+///
+/// Panics if `batch_size` is 0.
+pub fn spawn_batcher(
+    rx: Receiver<([u8; HASH_LEN], [u8; HASH_LEN])>,
+    batch_size: usize,
+    tx: SyncSender<Changelogs>,
+) -> JoinHandle<Result<(), MyError>> {
+    assert!(batch_size != 0, "batch size must be non-zero");
+
+    thread::spawn(move || {
+        let mut batch_of_changelogs = Changelogs {
+            changelogs: Vec::new(),
+        };
+        let mut leaves_in_batch = 0;
+
+        for (merkle_tree, leaf) in rx {
+            let changelogs = &mut batch_of_changelogs.changelogs;
+            match changelogs
+                .iter_mut()
+                .find(|changelog_event| changelog_event.merkle_tree_pubkey == merkle_tree)
+            {
+                Some(changelog_event) => changelog_event.leaves.push(leaf),
+                None => changelogs.push(ChangelogEvent {
+                    merkle_tree_pubkey: merkle_tree,
+                    leaves: vec![leaf],
+                }),
+            }
+            leaves_in_batch += 1;
+
+            if leaves_in_batch == batch_size {
+                // We reached the batch limit.
+                let full_batch = mem::replace(
+                    &mut batch_of_changelogs,
+                    Changelogs {
+                        changelogs: Vec::new(),
+                    },
+                );
+                tx.send(full_batch)
+                    .map_err(|_| MyError::BatchReceiverDisconnected)?;
+                leaves_in_batch = 0;
+            }
+        }
+
+        // All the senders are dropped, flush the partial batch.
+        if leaves_in_batch > 0 {
+            tx.send(batch_of_changelogs)
+                .map_err(|_| MyError::BatchReceiverDisconnected)?;
+        }
+
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::BTreeMap, sync::mpsc};
+
+    use super::*;
+    use crate::{fixture, testing::check_invariants};
+
+    /// Interleaves the fixture leaves, so every batch contains several
+    /// Merkle trees.
+    fn interleaved_input() -> (Vec<[u8; HASH_LEN]>, Vec<[u8; HASH_LEN]>) {
+        let (leaves, merkle_trees) = fixture::leaves_and_merkle_trees();
+        let mut pairs: Vec<_> = merkle_trees.into_iter().zip(leaves).collect();
+        pairs.sort_by_key(|(_, leaf)| leaf[0] % 4);
+        pairs.into_iter().map(|(tree, leaf)| (leaf, tree)).unzip()
+    }
+
+    #[test]
+    fn test_spawn_batcher() {
+        let (leaves, merkle_trees) = interleaved_input();
+
+        let (input_tx, input_rx) = mpsc::sync_channel(2);
+        let (output_tx, output_rx) = mpsc::sync_channel(1);
+        let batcher = spawn_batcher(input_rx, 4, output_tx);
+
+        let producer = {
+            let (leaves, merkle_trees) = (leaves.clone(), merkle_trees.clone());
+            thread::spawn(move || {
+                for (leaf, merkle_tree) in leaves.into_iter().zip(merkle_trees) {
+                    input_tx.send((merkle_tree, leaf)).unwrap();
+                }
+            })
+        };
+        let consumer = thread::spawn(move || output_rx.into_iter().collect::<Vec<_>>());
+
+        producer.join().unwrap();
+        batcher.join().unwrap().unwrap();
+        let batches = consumer.join().unwrap();
+
+        assert_eq!(batches.len(), 7);
+        check_invariants(&leaves, &merkle_trees, 4, &batches).unwrap();
+    }
+
+    #[test]
+    fn test_spawn_batcher_output_full_on_close() {
+        let (leaves, merkle_trees) = fixture::leaves_and_merkle_trees();
+
+        let (input_tx, input_rx) = mpsc::sync_channel(32);
+        let (output_tx, output_rx) = mpsc::sync_channel(1);
+
+        // The whole input is sent and closed before anything is received, so
+        // the batcher blocks on the full output.
+        for (leaf, merkle_tree) in leaves.iter().zip(merkle_trees.iter()) {
+            input_tx.send((*merkle_tree, *leaf)).unwrap();
+        }
+        drop(input_tx);
+        let batcher = spawn_batcher(input_rx, 10, output_tx);
+
+        let mut leaves_per_tree: BTreeMap<[u8; HASH_LEN], usize> = BTreeMap::new();
+        let mut num_batches = 0;
+        for batch in output_rx {
+            num_batches += 1;
+            for changelog_event in batch.changelogs {
+                *leaves_per_tree
+                    .entry(changelog_event.merkle_tree_pubkey)
+                    .or_default() += changelog_event.leaves.len();
+            }
+        }
+        batcher.join().unwrap().unwrap();
+
+        assert_eq!(num_batches, 3);
+        assert_eq!(
+            leaves_per_tree.into_values().collect::<Vec<_>>(),
+            vec![12, 3, 4, 6]
+        );
+    }
+
+    #[test]
+    fn test_spawn_batcher_receiver_dropped() {
+        let (input_tx, input_rx) = mpsc::sync_channel(4);
+        let (output_tx, output_rx) = mpsc::sync_channel(1);
+        drop(output_rx);
+
+        let batcher = spawn_batcher(input_rx, 1, output_tx);
+        // The batcher may exit before all the pairs are sent.
+        let _ = input_tx.send(([0_u8; 32], [1_u8; 32]));
+        drop(input_tx);
+
+        assert!(matches!(
+            batcher.join().unwrap(),
+            Err(MyError::BatchReceiverDisconnected)
+        ));
+    }
+}
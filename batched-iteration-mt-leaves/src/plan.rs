@@ -0,0 +1,329 @@
+use std::{
+    cmp,
+    collections::{BTreeMap, BTreeSet},
+};
+
+use crate::{div_ceil_checked, BatchCursor, MyError, HASH_LEN};
+
+/// How the leaves of a Merkle tree are split when they don't fit in the rest
+/// of a batch.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SplitStrategy {
+    /// The batch is filled up, as in [`append_leaves`](crate::append_leaves).
+    #[default]
+    Greedy,
+    /// The tree is split at the largest power of two of leaves which fits and
+    /// the batch is closed, as with
+    /// [`BatchBuilder::align_to_pow2`](crate::BatchBuilder::align_to_pow2).
+    AlignToPow2,
+}
+
+/// Summary of a batching job, computed by [`plan`] without batching.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct BatchPlan {
+    /// Number of batches the job produces.
+    pub num_batches: usize,
+    /// Number of distinct Merkle trees.
+    pub distinct_trees: usize,
+    /// Number of `(merkle_tree, leaf)` pairs which repeat an earlier pair.
+    pub duplicate_pairs: usize,
+    /// Merkle trees with more leaves than the batch size, which can't be
+    /// appended without splitting them across batches, with their leaf
+    /// counts, ordered by pubkey.
+    pub oversized_trees: Vec<([u8; HASH_LEN], usize)>,
+    /// Merkle tree with the most leaves and the number of its leaves. Ties
+    /// are broken by the lowest pubkey.
+    pub largest_tree: Option<([u8; HASH_LEN], usize)>,
+}
+
+/// Computes a [`BatchPlan`] of batching `leaves` appended to `merkle_trees`
+/// into batches of `batch_size` leaves with the given `strategy`.
+///
+/// Only the leaf counts per Merkle tree are grouped, no batches are built.
+pub fn plan(
+    leaves: &[[u8; HASH_LEN]],
+    merkle_trees: &[[u8; HASH_LEN]],
+    batch_size: usize,
+    strategy: SplitStrategy,
+) -> Result<BatchPlan, MyError> {
+    if leaves.len() != merkle_trees.len() {
+        return Err(MyError::LeavesTreesNotEqual {
+            leaves: leaves.len(),
+            trees: merkle_trees.len(),
+            first_unpaired: cmp::min(leaves.len(), merkle_trees.len()),
+        });
+    }
+    let greedy_batches =
+        div_ceil_checked(leaves.len(), batch_size).ok_or(MyError::InvalidBatchSize)?;
+
+    let mut leaf_counts: BTreeMap<&[u8; HASH_LEN], usize> = BTreeMap::new();
+    let mut pairs = BTreeSet::new();
+    let mut duplicate_pairs = 0;
+    for (merkle_tree, leaf) in merkle_trees.iter().zip(leaves) {
+        *leaf_counts.entry(merkle_tree).or_default() += 1;
+        if !pairs.insert((merkle_tree, leaf)) {
+            duplicate_pairs += 1;
+        }
+    }
+
+    let num_batches = match strategy {
+        SplitStrategy::Greedy => greedy_batches,
+        SplitStrategy::AlignToPow2 => count_aligned_batches(leaf_counts.values(), batch_size),
+    };
+
+    let mut largest_tree: Option<([u8; HASH_LEN], usize)> = None;
+    for (merkle_tree, count) in leaf_counts.iter() {
+        match largest_tree {
+            Some((_, largest_count)) if largest_count >= *count => {}
+            _ => largest_tree = Some((**merkle_tree, *count)),
+        }
+    }
+
+    Ok(BatchPlan {
+        num_batches,
+        distinct_trees: leaf_counts.len(),
+        duplicate_pairs,
+        oversized_trees: leaf_counts
+            .iter()
+            .filter(|(_, count)| **count > batch_size)
+            .map(|(merkle_tree, count)| (**merkle_tree, *count))
+            .collect(),
+        largest_tree,
+    })
+}
+
+/// Counts the batches produced by [`SplitStrategy::AlignToPow2`] for Merkle
+/// trees with the given leaf counts, in the order of processing.
+fn count_aligned_batches<'a>(
+    leaf_counts: impl Iterator<Item = &'a usize>,
+    batch_size: usize,
+) -> usize {
+    let mut num_batches = 0;
+    let mut batch_len = 0;
+
+    for leaf_count in leaf_counts {
+        let mut leaves_remaining = *leaf_count;
+        while leaves_remaining > 0 {
+            let space = batch_size - batch_len;
+            let chunk_len = if leaves_remaining <= space {
+                leaves_remaining
+            } else {
+                1 << space.ilog2()
+            };
+            batch_len += chunk_len;
+            leaves_remaining -= chunk_len;
+
+            if leaves_remaining > 0 || batch_len == batch_size {
+                num_batches += 1;
+                batch_len = 0;
+            }
+        }
+    }
+
+    if batch_len > 0 {
+        num_batches += 1;
+    }
+    num_batches
+}
+
+/// Shape of one batch: the Merkle trees it contains and how many leaves each
+/// of them contributes.
+pub type BatchShape = Vec<([u8; HASH_LEN], usize)>;
+
+/// Computes only the shape of the batches [`append_leaves`](crate::append_leaves)
+/// would produce for leaves appended to `merkle_trees`.
+///
+/// Leaves themselves are not needed, nor copied. Fails with
+/// [`MyError::InvalidBatchSize`] if `batch_size` is 0.
+pub fn plan_batches(
+    merkle_trees: &[[u8; HASH_LEN]],
+    batch_size: usize,
+) -> Result<Vec<BatchShape>, MyError> {
+    let num_batches =
+        div_ceil_checked(merkle_trees.len(), batch_size).ok_or(MyError::InvalidBatchSize)?;
+
+    let mut leaf_counts = BTreeMap::new();
+    for merkle_tree in merkle_trees {
+        *leaf_counts.entry(*merkle_tree).or_insert(0) += 1;
+    }
+    let leaf_counts: Vec<([u8; HASH_LEN], usize)> = leaf_counts.into_iter().collect();
+
+    let mut cursor = BatchCursor::default();
+    let batches = (0..num_batches)
+        .map(|_| {
+            let mut batch = Vec::new();
+            cursor.next_batch(
+                batch_size,
+                |tree_index| leaf_counts.get(tree_index).map(|(_, count)| *count),
+                |tree_index, range| batch.push((leaf_counts[tree_index].0, range.len())),
+            );
+            batch
+        })
+        .collect();
+
+    Ok(batches)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{append_leaves, fixture, BatchBuilder};
+
+    #[test]
+    fn test_plan_batches() {
+        let (leaves, merkle_trees) = fixture::leaves_and_merkle_trees();
+
+        assert_eq!(
+            plan_batches(&merkle_trees, 10).unwrap(),
+            vec![
+                vec![([0_u8; 32], 10)],
+                vec![
+                    ([0_u8; 32], 2),
+                    ([1_u8; 32], 3),
+                    ([2_u8; 32], 4),
+                    ([3_u8; 32], 1)
+                ],
+                vec![([3_u8; 32], 5)],
+            ]
+        );
+
+        for batch_size in [1, 2, 3, 4, 7, 10, 12, 25, 26, 100] {
+            let batches = append_leaves(leaves.clone(), merkle_trees.clone(), batch_size).unwrap();
+            let shapes: Vec<BatchShape> = batches
+                .iter()
+                .map(|batch| {
+                    batch
+                        .changelogs
+                        .iter()
+                        .map(|changelog_event| {
+                            (
+                                changelog_event.merkle_tree_pubkey,
+                                changelog_event.leaves.len(),
+                            )
+                        })
+                        .collect()
+                })
+                .collect();
+
+            assert_eq!(plan_batches(&merkle_trees, batch_size).unwrap(), shapes);
+        }
+    }
+
+    /// Leaves and their Merkle trees.
+    type Input = (Vec<[u8; HASH_LEN]>, Vec<[u8; HASH_LEN]>);
+
+    /// Fixture, an interleaved input and an empty input.
+    fn plan_inputs() -> Vec<Input> {
+        let (leaves, merkle_trees) = fixture::leaves_and_merkle_trees();
+
+        let interleaved_leaves: Vec<[u8; HASH_LEN]> = (0..40).map(|i| [i % 37; 32]).collect();
+        let interleaved_trees = (0..40_u8).map(|i| [(i % 5) * 3 % 5; 32]).collect();
+
+        vec![
+            (leaves, merkle_trees),
+            (interleaved_leaves, interleaved_trees),
+            (Vec::new(), Vec::new()),
+        ]
+    }
+
+    #[test]
+    fn test_plan_num_batches() {
+        for (leaves, merkle_trees) in plan_inputs() {
+            for batch_size in [1, 2, 3, 5, 7, 10, 12, 25, 100] {
+                let greedy =
+                    plan(&leaves, &merkle_trees, batch_size, SplitStrategy::Greedy).unwrap();
+                assert_eq!(
+                    greedy.num_batches,
+                    append_leaves(leaves.clone(), merkle_trees.clone(), batch_size)
+                        .unwrap()
+                        .len()
+                );
+
+                let aligned = plan(
+                    &leaves,
+                    &merkle_trees,
+                    batch_size,
+                    SplitStrategy::AlignToPow2,
+                )
+                .unwrap();
+                assert_eq!(
+                    aligned.num_batches,
+                    BatchBuilder::new(batch_size)
+                        .align_to_pow2(true)
+                        .build(leaves.clone(), merkle_trees.clone())
+                        .unwrap()
+                        .len(),
+                    "batch size {batch_size}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_plan() {
+        let (leaves, merkle_trees) = fixture::leaves_and_merkle_trees();
+        assert_eq!(
+            plan(&leaves, &merkle_trees, 5, SplitStrategy::Greedy).unwrap(),
+            BatchPlan {
+                num_batches: 5,
+                distinct_trees: 4,
+                duplicate_pairs: 0,
+                oversized_trees: vec![([0_u8; 32], 12), ([3_u8; 32], 6)],
+                largest_tree: Some(([0_u8; 32], 12)),
+            }
+        );
+
+        // Leaves 37 to 39 repeat leaves 0 to 2, but in other trees.
+        let (leaves, merkle_trees) = plan_inputs().swap_remove(1);
+        let batch_plan = plan(&leaves, &merkle_trees, 10, SplitStrategy::Greedy).unwrap();
+        assert_eq!(batch_plan.distinct_trees, 5);
+        assert_eq!(batch_plan.duplicate_pairs, 0);
+        assert!(batch_plan.oversized_trees.is_empty());
+        assert_eq!(batch_plan.largest_tree, Some(([0_u8; 32], 8)));
+
+        let batch_plan = plan(
+            &[[1_u8; 32], [2_u8; 32], [1_u8; 32]],
+            &[[9_u8; 32]; 3],
+            2,
+            SplitStrategy::Greedy,
+        )
+        .unwrap();
+        assert_eq!(batch_plan.duplicate_pairs, 1);
+        assert_eq!(batch_plan.oversized_trees, vec![([9_u8; 32], 3)]);
+
+        assert_eq!(
+            plan(&[], &[], 10, SplitStrategy::Greedy).unwrap(),
+            BatchPlan::default()
+        );
+    }
+
+    #[test]
+    fn test_plan_errors() {
+        assert!(matches!(
+            plan(&[[0_u8; 32]], &[], 10, SplitStrategy::Greedy),
+            Err(MyError::LeavesTreesNotEqual { .. })
+        ));
+        assert!(matches!(
+            plan(&[[0_u8; 32]], &[[0_u8; 32]], 0, SplitStrategy::Greedy),
+            Err(MyError::InvalidBatchSize)
+        ));
+    }
+
+    #[test]
+    fn test_plan_batches_empty() {
+        assert!(plan_batches(&[], 10).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_plan_batches_zero_batch_size() {
+        let (_, merkle_trees) = fixture::leaves_and_merkle_trees();
+        assert!(matches!(
+            plan_batches(&merkle_trees, 0),
+            Err(MyError::InvalidBatchSize)
+        ));
+        assert!(matches!(
+            plan_batches(&[], 0),
+            Err(MyError::InvalidBatchSize)
+        ));
+    }
+}
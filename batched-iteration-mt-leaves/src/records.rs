@@ -0,0 +1,195 @@
+use std::{
+    cmp,
+    collections::BTreeMap,
+    io::{self, Read, Write},
+};
+
+use crate::{Changelogs, GroupedLeaves, MyError, HASH_LEN};
+
+/// Length of a leaf record: a Merkle tree pubkey followed by a leaf.
+pub const RECORD_LEN: usize = 2 * HASH_LEN;
+
+/// Number of bytes read from the source at once (64 KiB).
+const CHUNK_LEN: usize = 1024 * RECORD_LEN;
+
+/// Reads leaf records (see [`write_leaf_records`]) from `reader` and splits
+/// them into batches like [`append_leaves`](crate::append_leaves).
+///
+/// The records are read in chunks and grouped right away, so the input is
+/// never held in memory as a whole. Returns [`MyError::TruncatedInput`] if
+/// the input ends with a partial record.
+pub fn append_leaves_from_reader<R: Read>(
+    mut reader: R,
+    batch_size: usize,
+) -> Result<Vec<Changelogs>, MyError> {
+    if batch_size == 0 {
+        return Err(MyError::InvalidBatchSize);
+    }
+
+    let mut merkle_tree_map: BTreeMap<[u8; HASH_LEN], Vec<[u8; HASH_LEN]>> = BTreeMap::new();
+    let mut chunk = vec![0; CHUNK_LEN];
+    // Number of bytes in `chunk` which are not processed yet. It's always
+    // less than `RECORD_LEN` before a read.
+    let mut filled = 0;
+
+    loop {
+        let n = match reader.read(&mut chunk[filled..]) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.into()),
+        };
+        filled += n;
+
+        let complete = filled - filled % RECORD_LEN;
+        for record in chunk[..complete].chunks_exact(RECORD_LEN) {
+            let (merkle_tree, leaf) = record.split_at(HASH_LEN);
+            merkle_tree_map
+                .entry(merkle_tree.try_into().unwrap())
+                .or_default()
+                .push(leaf.try_into().unwrap());
+        }
+
+        // Move the partial record to the beginning of the chunk.
+        chunk.copy_within(complete..filled, 0);
+        filled -= complete;
+    }
+
+    if filled != 0 {
+        return Err(MyError::TruncatedInput(filled));
+    }
+
+    GroupedLeaves::from(merkle_tree_map).split_into_batches(batch_size)
+}
+
+/// Writes `leaves` and their `merkle_trees` as concatenated records of
+/// [`RECORD_LEN`] bytes, each being a Merkle tree pubkey followed by a leaf.
+pub fn write_leaf_records<W: Write>(
+    mut writer: W,
+    leaves: &[[u8; HASH_LEN]],
+    merkle_trees: &[[u8; HASH_LEN]],
+) -> Result<(), MyError> {
+    if leaves.len() != merkle_trees.len() {
+        return Err(MyError::LeavesTreesNotEqual {
+            leaves: leaves.len(),
+            trees: merkle_trees.len(),
+            first_unpaired: cmp::min(leaves.len(), merkle_trees.len()),
+        });
+    }
+
+    for (leaf, merkle_tree) in leaves.iter().zip(merkle_trees) {
+        writer.write_all(merkle_tree)?;
+        writer.write_all(leaf)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::{append_leaves, ChangelogEvent};
+
+    fn leaves_and_merkle_trees(n: usize) -> (Vec<[u8; HASH_LEN]>, Vec<[u8; HASH_LEN]>) {
+        let leaves = (0..n)
+            .map(|i| {
+                let mut leaf = [0_u8; HASH_LEN];
+                leaf[..8].copy_from_slice(&(i as u64).to_le_bytes());
+                leaf
+            })
+            .collect();
+        let merkle_trees = (0..n).map(|i| [(i % 7) as u8; HASH_LEN]).collect();
+        (leaves, merkle_trees)
+    }
+
+    #[test]
+    fn test_append_leaves_from_reader() {
+        // Spans several chunks.
+        let (leaves, merkle_trees) = leaves_and_merkle_trees(3000);
+
+        let mut records = Vec::new();
+        write_leaf_records(&mut records, &leaves, &merkle_trees).unwrap();
+        assert_eq!(records.len(), 3000 * RECORD_LEN);
+
+        let batches = append_leaves_from_reader(Cursor::new(records), 100).unwrap();
+        assert_eq!(batches, append_leaves(leaves, merkle_trees, 100).unwrap());
+    }
+
+    #[test]
+    fn test_append_leaves_from_reader_crafted() {
+        // Three records: MT 2 with leaf 1, MT 1 with leaf 2, MT 2 with leaf 3.
+        let mut records = Vec::new();
+        for (merkle_tree, leaf) in [(2_u8, 1_u8), (1, 2), (2, 3)] {
+            records.extend_from_slice(&[merkle_tree; 32]);
+            records.extend_from_slice(&[leaf; 32]);
+        }
+
+        let batches = append_leaves_from_reader(Cursor::new(&records), 2).unwrap();
+        assert_eq!(
+            batches,
+            vec![
+                Changelogs {
+                    changelogs: vec![
+                        ChangelogEvent {
+                            merkle_tree_pubkey: [1_u8; 32],
+                            leaves: vec![[2_u8; 32]],
+                        },
+                        ChangelogEvent {
+                            merkle_tree_pubkey: [2_u8; 32],
+                            leaves: vec![[1_u8; 32]],
+                        },
+                    ]
+                },
+                Changelogs {
+                    changelogs: vec![ChangelogEvent {
+                        merkle_tree_pubkey: [2_u8; 32],
+                        leaves: vec![[3_u8; 32]],
+                    }]
+                },
+            ]
+        );
+
+        // The last record lacks its leaf.
+        assert!(matches!(
+            append_leaves_from_reader(Cursor::new(&records[..2 * RECORD_LEN + HASH_LEN]), 2),
+            Err(MyError::TruncatedInput(32))
+        ));
+    }
+
+    #[test]
+    fn test_append_leaves_from_reader_truncated() {
+        let (leaves, merkle_trees) = leaves_and_merkle_trees(3000);
+
+        let mut records = Vec::new();
+        write_leaf_records(&mut records, &leaves, &merkle_trees).unwrap();
+        records.truncate(records.len() - 10);
+
+        assert!(matches!(
+            append_leaves_from_reader(Cursor::new(records), 100),
+            Err(MyError::TruncatedInput(54))
+        ));
+    }
+
+    #[test]
+    fn test_append_leaves_from_reader_empty() {
+        assert!(append_leaves_from_reader(Cursor::new(Vec::new()), 100)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn test_write_leaf_records_not_equal() {
+        let (leaves, merkle_trees) = leaves_and_merkle_trees(3);
+
+        assert!(matches!(
+            write_leaf_records(Vec::new(), &leaves, &merkle_trees[..2]),
+            Err(MyError::LeavesTreesNotEqual {
+                leaves: 3,
+                trees: 2,
+                first_unpaired: 2
+            })
+        ));
+    }
+}
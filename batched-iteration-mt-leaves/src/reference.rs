@@ -0,0 +1,176 @@
+//! Deliberately simple implementation of the batching, used as an oracle in
+//! differential tests of the optimized paths.
+//!
+//! **Not for production use.** The grouping is quadratic in the number of
+//! Merkle trees.
+
+use std::cmp;
+
+use crate::{
+    append_leaves, append_leaves_from_reader, append_leaves_into, append_leaves_iter, batch_map,
+    build_merkle_tree_map, canonical_eq_batches, write_leaf_records, BatchBuilder, ChangelogEvent,
+    Changelogs, GroupedLeaves, MyError, HASH_LEN,
+};
+
+/// Batches leaves like [`append_leaves`], in the most straightforward way.
+///
+/// **Not for production use**, see the [module documentation](self).
+pub fn append_leaves_reference(
+    leaves: &[[u8; HASH_LEN]],
+    merkle_trees: &[[u8; HASH_LEN]],
+    batch_size: usize,
+) -> Result<Vec<Changelogs>, MyError> {
+    if leaves.len() != merkle_trees.len() {
+        return Err(MyError::LeavesTreesNotEqual {
+            leaves: leaves.len(),
+            trees: merkle_trees.len(),
+            first_unpaired: cmp::min(leaves.len(), merkle_trees.len()),
+        });
+    }
+    assert!(batch_size != 0, "batch size must be non-zero");
+
+    // Group the leaves, looking up each tree with a linear search.
+    let mut groups: Vec<([u8; HASH_LEN], Vec<[u8; HASH_LEN]>)> = Vec::new();
+    for (merkle_tree, leaf) in merkle_trees.iter().zip(leaves) {
+        match groups
+            .iter_mut()
+            .find(|(group_tree, _)| group_tree == merkle_tree)
+        {
+            Some((_, group_leaves)) => group_leaves.push(*leaf),
+            None => groups.push((*merkle_tree, vec![*leaf])),
+        }
+    }
+    groups.sort_by_key(|(merkle_tree, _)| *merkle_tree);
+
+    // Add the leaves one by one, closing every full batch.
+    let mut batches = Vec::new();
+    let mut batch = Changelogs::default();
+    for (merkle_tree, group_leaves) in groups {
+        for leaf in group_leaves {
+            match batch.changelogs.last_mut() {
+                Some(changelog_event) if changelog_event.merkle_tree_pubkey == merkle_tree => {
+                    changelog_event.leaves.push(leaf);
+                }
+                _ => batch.changelogs.push(ChangelogEvent {
+                    merkle_tree_pubkey: merkle_tree,
+                    leaves: vec![leaf],
+                }),
+            }
+
+            let batch_len: usize = batch
+                .changelogs
+                .iter()
+                .map(|changelog_event| changelog_event.leaves.len())
+                .sum();
+            if batch_len == batch_size {
+                batches.push(batch);
+                batch = Changelogs::default();
+            }
+        }
+    }
+    if !batch.changelogs.is_empty() {
+        batches.push(batch);
+    }
+
+    Ok(batches)
+}
+
+/// Runs every optimized batching path on the input and panics unless all of
+/// them return the same batches as [`append_leaves_reference`], up to the
+/// order of events within each batch.
+///
+/// # Panics
+///
+/// Panics if `leaves` and `merkle_trees` have different lengths, if
+/// `batch_size` is zero or if any path disagrees with the reference.
+pub fn assert_equivalent(
+    leaves: &[[u8; HASH_LEN]],
+    merkle_trees: &[[u8; HASH_LEN]],
+    batch_size: usize,
+) {
+    let expected = append_leaves_reference(leaves, merkle_trees, batch_size).unwrap();
+
+    let mut into = Vec::new();
+    append_leaves_into(
+        leaves.to_vec(),
+        merkle_trees.to_vec(),
+        batch_size,
+        &mut into,
+    )
+    .unwrap();
+    let mut records = Vec::new();
+    write_leaf_records(&mut records, leaves, merkle_trees).unwrap();
+
+    let paths = [
+        (
+            "append_leaves",
+            append_leaves(leaves.to_vec(), merkle_trees.to_vec(), batch_size).unwrap(),
+        ),
+        ("append_leaves_into", into),
+        (
+            "append_leaves_iter",
+            append_leaves_iter(leaves.to_vec(), merkle_trees.to_vec(), batch_size)
+                .unwrap()
+                .collect(),
+        ),
+        (
+            "batch_map",
+            batch_map(
+                build_merkle_tree_map(leaves, merkle_trees).unwrap(),
+                batch_size,
+            )
+            .unwrap(),
+        ),
+        (
+            "GroupedLeaves",
+            GroupedLeaves::new(leaves, merkle_trees)
+                .unwrap()
+                .split_into_batches(batch_size)
+                .unwrap(),
+        ),
+        (
+            "BatchBuilder",
+            BatchBuilder::new(batch_size)
+                .build(leaves.to_vec(), merkle_trees.to_vec())
+                .unwrap(),
+        ),
+        (
+            "append_leaves_from_reader",
+            append_leaves_from_reader(records.as_slice(), batch_size).unwrap(),
+        ),
+    ];
+    for (name, batches) in paths {
+        assert!(
+            canonical_eq_batches(&batches, &expected),
+            "{name} differs from the reference implementation"
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixture;
+
+    #[test]
+    fn test_append_leaves_reference() {
+        let (leaves, merkle_trees) = fixture::leaves_and_merkle_trees();
+
+        for batch_size in [1, 3, 10, 25, 100] {
+            assert_eq!(
+                append_leaves_reference(&leaves, &merkle_trees, batch_size).unwrap(),
+                append_leaves(leaves.clone(), merkle_trees.clone(), batch_size).unwrap()
+            );
+            assert_equivalent(&leaves, &merkle_trees, batch_size);
+        }
+
+        assert!(matches!(
+            append_leaves_reference(&leaves, &merkle_trees[1..], 10),
+            Err(MyError::LeavesTreesNotEqual {
+                leaves: 25,
+                trees: 24,
+                first_unpaired: 24
+            })
+        ));
+    }
+}
@@ -0,0 +1,211 @@
+use std::{cmp, collections::BTreeSet};
+
+use crate::{append_leaves, Changelogs, MyError, HASH_LEN};
+
+/// What [`append_leaves_registered`] does with leaves of Merkle trees which
+/// aren't in the registry.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UnknownTreePolicy {
+    /// Fail with [`MyError::UnregisteredTree`] on the first such leaf.
+    Error,
+    /// Leave such leaves out of the batches and return them in
+    /// [`Registered::skipped`].
+    Skip,
+    /// Batch such leaves like the others.
+    Allow,
+}
+
+/// Result of [`append_leaves_registered`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Registered {
+    pub batches: Vec<Changelogs>,
+    /// `(merkle_tree, leaf)` pairs of unregistered Merkle trees left out with
+    /// [`UnknownTreePolicy::Skip`], in input order, so they can be retried
+    /// as they are.
+    pub skipped: Vec<([u8; HASH_LEN], [u8; HASH_LEN])>,
+}
+
+/// Same as [`append_leaves`], but checks every Merkle tree against the known
+/// set of trees in `registry`. Leaves of other trees are handled according to
+/// `unknown_policy`.
+pub fn append_leaves_registered(
+    leaves: Vec<[u8; HASH_LEN]>,
+    merkle_trees: Vec<[u8; HASH_LEN]>,
+    batch_size: usize,
+    registry: &BTreeSet<[u8; HASH_LEN]>,
+    unknown_policy: UnknownTreePolicy,
+) -> Result<Registered, MyError> {
+    if leaves.len() != merkle_trees.len() {
+        return Err(MyError::LeavesTreesNotEqual {
+            leaves: leaves.len(),
+            trees: merkle_trees.len(),
+            first_unpaired: cmp::min(leaves.len(), merkle_trees.len()),
+        });
+    }
+
+    match unknown_policy {
+        UnknownTreePolicy::Error => {
+            if let Some(index) = merkle_trees
+                .iter()
+                .position(|merkle_tree| !registry.contains(merkle_tree))
+            {
+                return Err(MyError::UnregisteredTree {
+                    pubkey: merkle_trees[index],
+                    index,
+                });
+            }
+            Ok(Registered {
+                batches: append_leaves(leaves, merkle_trees, batch_size)?,
+                skipped: Vec::new(),
+            })
+        }
+        UnknownTreePolicy::Skip => {
+            let mut known_leaves = Vec::with_capacity(leaves.len());
+            let mut known_trees = Vec::with_capacity(merkle_trees.len());
+            let mut skipped = Vec::new();
+            for (merkle_tree, leaf) in merkle_trees.into_iter().zip(leaves) {
+                if registry.contains(&merkle_tree) {
+                    known_trees.push(merkle_tree);
+                    known_leaves.push(leaf);
+                } else {
+                    skipped.push((merkle_tree, leaf));
+                }
+            }
+            Ok(Registered {
+                batches: append_leaves(known_leaves, known_trees, batch_size)?,
+                skipped,
+            })
+        }
+        UnknownTreePolicy::Allow => Ok(Registered {
+            batches: append_leaves(leaves, merkle_trees, batch_size)?,
+            skipped: Vec::new(),
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ChangelogEvent;
+
+    /// MT 1 and MT 3 are registered.
+    fn registry() -> BTreeSet<[u8; HASH_LEN]> {
+        BTreeSet::from([[1_u8; 32], [3_u8; 32]])
+    }
+
+    /// Leaves of MT 1 and MT 3 mixed with leaves of the unregistered MT 2 and
+    /// MT 4. All of them fall into the first batch window of 4 leaves.
+    fn input() -> (Vec<[u8; HASH_LEN]>, Vec<[u8; HASH_LEN]>) {
+        let leaves = vec![
+            [10_u8; 32],
+            [20_u8; 32],
+            [11_u8; 32],
+            [40_u8; 32],
+            [30_u8; 32],
+            [21_u8; 32],
+        ];
+        let merkle_trees = vec![
+            [1_u8; 32], [2_u8; 32], [1_u8; 32], [4_u8; 32], [3_u8; 32], [2_u8; 32],
+        ];
+        (leaves, merkle_trees)
+    }
+
+    #[test]
+    fn test_append_leaves_registered_error() {
+        let (leaves, merkle_trees) = input();
+        let registry = registry();
+        let err =
+            append_leaves_registered(leaves, merkle_trees, 4, &registry, UnknownTreePolicy::Error)
+                .unwrap_err();
+        assert!(matches!(
+            err,
+            MyError::UnregisteredTree { pubkey, index: 1 } if pubkey == [2_u8; 32]
+        ));
+
+        // Nothing to complain about when all the trees are registered.
+        let registered = append_leaves_registered(
+            vec![[10_u8; 32], [30_u8; 32]],
+            vec![[1_u8; 32], [3_u8; 32]],
+            4,
+            &registry,
+            UnknownTreePolicy::Error,
+        )
+        .unwrap();
+        assert_eq!(registered.batches.len(), 1);
+        assert!(registered.skipped.is_empty());
+    }
+
+    #[test]
+    fn test_append_leaves_registered_skip() {
+        let (leaves, merkle_trees) = input();
+        let registry = registry();
+        let registered =
+            append_leaves_registered(leaves, merkle_trees, 4, &registry, UnknownTreePolicy::Skip)
+                .unwrap();
+
+        assert_eq!(
+            registered.batches,
+            vec![Changelogs {
+                changelogs: vec![
+                    ChangelogEvent {
+                        merkle_tree_pubkey: [1_u8; 32],
+                        leaves: vec![[10_u8; 32], [11_u8; 32]],
+                    },
+                    ChangelogEvent {
+                        merkle_tree_pubkey: [3_u8; 32],
+                        leaves: vec![[30_u8; 32]],
+                    },
+                ],
+            }]
+        );
+        // In input order, not grouped per tree.
+        assert_eq!(
+            registered.skipped,
+            vec![
+                ([2_u8; 32], [20_u8; 32]),
+                ([4_u8; 32], [40_u8; 32]),
+                ([2_u8; 32], [21_u8; 32]),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_append_leaves_registered_allow() {
+        let (leaves, merkle_trees) = input();
+        let registry = registry();
+        let registered = append_leaves_registered(
+            leaves.clone(),
+            merkle_trees.clone(),
+            4,
+            &registry,
+            UnknownTreePolicy::Allow,
+        )
+        .unwrap();
+
+        assert_eq!(
+            registered.batches,
+            append_leaves(leaves, merkle_trees, 4).unwrap()
+        );
+        assert!(registered.skipped.is_empty());
+    }
+
+    #[test]
+    fn test_append_leaves_registered_unequal() {
+        let (leaves, _) = input();
+        let registry = registry();
+        assert!(matches!(
+            append_leaves_registered(
+                leaves,
+                vec![[1_u8; 32]],
+                4,
+                &registry,
+                UnknownTreePolicy::Skip,
+            ),
+            Err(MyError::LeavesTreesNotEqual {
+                leaves: 6,
+                trees: 1,
+                first_unpaired: 1,
+            })
+        ));
+    }
+}
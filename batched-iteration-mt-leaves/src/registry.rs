@@ -0,0 +1,233 @@
+use std::collections::BTreeMap;
+
+use crate::{ChangelogEvent, Changelogs, MyError, HASH_LEN};
+
+/// Assigns compact `u32` ids to Merkle tree pubkeys, in the order in which
+/// they are registered, starting from 0.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TreeRegistry {
+    pubkeys: Vec<[u8; HASH_LEN]>,
+    ids: BTreeMap<[u8; HASH_LEN], u32>,
+}
+
+impl TreeRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the id of `pubkey`, registering it first if it's not known
+    /// yet.
+    ///
+    /// # Panics
+    ///
+    /// Panics if more than `u32::MAX` Merkle trees are registered.
+    pub fn register(&mut self, pubkey: [u8; HASH_LEN]) -> u32 {
+        if let Some(id) = self.ids.get(&pubkey) {
+            return *id;
+        }
+        let id = u32::try_from(self.pubkeys.len()).expect("too many registered Merkle trees");
+        self.pubkeys.push(pubkey);
+        self.ids.insert(pubkey, id);
+        id
+    }
+
+    /// Returns the pubkey registered with `id`.
+    pub fn resolve(&self, id: u32) -> Option<&[u8; HASH_LEN]> {
+        self.pubkeys.get(id as usize)
+    }
+
+    /// Returns the id of `pubkey`, if it's registered.
+    pub fn id(&self, pubkey: &[u8; HASH_LEN]) -> Option<u32> {
+        self.ids.get(pubkey).copied()
+    }
+
+    /// Returns the number of registered Merkle trees.
+    pub fn len(&self) -> usize {
+        self.pubkeys.len()
+    }
+
+    /// Returns `true` if no Merkle tree is registered.
+    pub fn is_empty(&self) -> bool {
+        self.pubkeys.is_empty()
+    }
+}
+
+/// Changelog event for one Merkle tree, identified by its id in a
+/// [`TreeRegistry`] instead of the pubkey.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CompactChangelogEvent {
+    pub tree_id: u32,
+    pub leaves: Vec<[u8; HASH_LEN]>,
+}
+
+impl CompactChangelogEvent {
+    /// Converts `changelog_event`, registering its Merkle tree in `registry`
+    /// if needed.
+    pub fn from_event(changelog_event: &ChangelogEvent, registry: &mut TreeRegistry) -> Self {
+        Self {
+            tree_id: registry.register(changelog_event.merkle_tree_pubkey),
+            leaves: changelog_event.leaves.clone(),
+        }
+    }
+
+    /// Converts back to a full event. Fails with [`MyError::UnknownTreeId`]
+    /// if the id is not registered in `registry`.
+    pub fn to_event(&self, registry: &TreeRegistry) -> Result<ChangelogEvent, MyError> {
+        let merkle_tree_pubkey = registry
+            .resolve(self.tree_id)
+            .ok_or(MyError::UnknownTreeId(self.tree_id))?;
+        Ok(ChangelogEvent {
+            merkle_tree_pubkey: *merkle_tree_pubkey,
+            leaves: self.leaves.clone(),
+        })
+    }
+
+    /// Appends the encoded event to `out`: the tree id, the number of leaves
+    /// and the leaves, with integers in little endian.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the event has more than `u32::MAX` leaves.
+    pub fn encode(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.tree_id.to_le_bytes());
+        let num_leaves = u32::try_from(self.leaves.len()).expect("too many leaves in an event");
+        out.extend_from_slice(&num_leaves.to_le_bytes());
+        for leaf in self.leaves.iter() {
+            out.extend_from_slice(leaf);
+        }
+    }
+}
+
+/// Set of compact changelogs for different Merkle trees.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CompactChangelogs {
+    pub changelogs: Vec<CompactChangelogEvent>,
+}
+
+/// Replaces the Merkle tree pubkeys in `batches` with their ids in
+/// `registry`, registering the trees which are not known yet.
+pub fn compact_batches(
+    batches: &[Changelogs],
+    registry: &mut TreeRegistry,
+) -> Vec<CompactChangelogs> {
+    batches
+        .iter()
+        .map(|batch| CompactChangelogs {
+            changelogs: batch
+                .changelogs
+                .iter()
+                .map(|changelog_event| CompactChangelogEvent::from_event(changelog_event, registry))
+                .collect(),
+        })
+        .collect()
+}
+
+/// Inverse of [`compact_batches`]. Fails with [`MyError::UnknownTreeId`] for
+/// the first id which is not registered in `registry`.
+pub fn expand_batches(
+    batches: &[CompactChangelogs],
+    registry: &TreeRegistry,
+) -> Result<Vec<Changelogs>, MyError> {
+    batches
+        .iter()
+        .map(|batch| {
+            Ok(Changelogs {
+                changelogs: batch
+                    .changelogs
+                    .iter()
+                    .map(|changelog_event| changelog_event.to_event(registry))
+                    .collect::<Result<_, _>>()?,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{append_leaves, encode_versioned, fixture, ChangelogEventVersioned};
+
+    #[test]
+    fn test_tree_registry() {
+        let mut registry = TreeRegistry::new();
+        assert!(registry.is_empty());
+
+        assert_eq!(registry.register([5_u8; 32]), 0);
+        assert_eq!(registry.register([2_u8; 32]), 1);
+        assert_eq!(registry.register([5_u8; 32]), 0);
+        assert_eq!(registry.len(), 2);
+
+        assert_eq!(registry.resolve(0), Some(&[5_u8; 32]));
+        assert_eq!(registry.resolve(1), Some(&[2_u8; 32]));
+        assert_eq!(registry.resolve(2), None);
+        assert_eq!(registry.id(&[2_u8; 32]), Some(1));
+        assert_eq!(registry.id(&[3_u8; 32]), None);
+    }
+
+    #[test]
+    fn test_compact_batches_round_trip() {
+        let (leaves, merkle_trees) = fixture::leaves_and_merkle_trees();
+        let batches = append_leaves(leaves, merkle_trees, 10).unwrap();
+
+        let mut registry = TreeRegistry::new();
+        let compact = compact_batches(&batches, &mut registry);
+        assert_eq!(registry.len(), 4);
+        assert_eq!(
+            compact[1]
+                .changelogs
+                .iter()
+                .map(|changelog_event| changelog_event.tree_id)
+                .collect::<Vec<_>>(),
+            vec![0, 1, 2, 3]
+        );
+
+        assert_eq!(expand_batches(&compact, &registry).unwrap(), batches);
+    }
+
+    #[test]
+    fn test_expand_batches_unknown_id() {
+        let mut registry = TreeRegistry::new();
+        registry.register([1_u8; 32]);
+
+        let compact = vec![CompactChangelogs {
+            changelogs: vec![
+                CompactChangelogEvent {
+                    tree_id: 0,
+                    leaves: vec![[1_u8; 32]],
+                },
+                CompactChangelogEvent {
+                    tree_id: 7,
+                    leaves: vec![[2_u8; 32]],
+                },
+            ],
+        }];
+        assert!(matches!(
+            expand_batches(&compact, &registry),
+            Err(MyError::UnknownTreeId(7))
+        ));
+    }
+
+    #[test]
+    fn test_compact_encoded_size() {
+        let (leaves, merkle_trees) = fixture::leaves_and_merkle_trees();
+        let batches = append_leaves(leaves, merkle_trees, 10).unwrap();
+
+        let changelog_events: Vec<ChangelogEventVersioned> = batches
+            .iter()
+            .flat_map(|batch| batch.changelogs.iter().cloned())
+            .map(ChangelogEventVersioned::from)
+            .collect();
+        let full = encode_versioned(&changelog_events);
+
+        let mut compact = Vec::new();
+        for batch in compact_batches(&batches, &mut TreeRegistry::new()) {
+            for changelog_event in batch.changelogs {
+                changelog_event.encode(&mut compact);
+            }
+        }
+
+        // Every event saves the version tag and 28 bytes of the pubkey.
+        assert_eq!(full.len() - compact.len(), changelog_events.len() * 29);
+        assert_eq!(compact.len(), 6 * 8 + 25 * 32);
+    }
+}
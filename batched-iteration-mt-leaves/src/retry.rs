@@ -0,0 +1,357 @@
+use std::{
+    cmp,
+    collections::{BTreeMap, BTreeSet, HashSet},
+};
+
+use crate::{split_into_batches, ChangelogEvent, Changelogs, MyError, HASH_LEN};
+
+/// Merges the leaves of batches which failed to be submitted and splits them
+/// again into batches of `new_batch_size` leaves.
+///
+/// Merkle trees are processed in the order of their first appearance in
+/// `failed` and the leaves of each tree keep their order across the failed
+/// batches.
+pub fn rebatch_failed(
+    failed: Vec<Changelogs>,
+    new_batch_size: usize,
+) -> Result<Vec<Changelogs>, MyError> {
+    if new_batch_size == 0 {
+        return Err(MyError::InvalidBatchSize);
+    }
+
+    let mut tree_indices = BTreeMap::new();
+    let mut merkle_tree_leaves: Vec<([u8; HASH_LEN], Vec<[u8; HASH_LEN]>)> = Vec::new();
+    for changelog_event in failed
+        .into_iter()
+        .flat_map(|batch| batch.changelogs.into_iter())
+    {
+        let tree_index = *tree_indices
+            .entry(changelog_event.merkle_tree_pubkey)
+            .or_insert_with(|| {
+                merkle_tree_leaves.push((changelog_event.merkle_tree_pubkey, Vec::new()));
+                merkle_tree_leaves.len() - 1
+            });
+        merkle_tree_leaves[tree_index]
+            .1
+            .extend(changelog_event.leaves);
+    }
+
+    Ok(split_into_batches(merkle_tree_leaves, new_batch_size))
+}
+
+/// Result of [`subtract_submitted`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Unsubmitted {
+    /// Batches with the leaves which weren't submitted yet.
+    pub batches: Vec<Changelogs>,
+    /// Submitted `(merkle_tree, leaf)` pairs which aren't in the planned
+    /// batches, sorted.
+    pub unknown: Vec<([u8; HASH_LEN], [u8; HASH_LEN])>,
+}
+
+/// Removes the `submitted` `(merkle_tree, leaf)` pairs from the planned
+/// `batches`, e.g. to resume after a crash with the leaves confirmed
+/// on-chain. Events and batches which become empty are dropped, the others
+/// keep their order. A pair which appears in the plan more than once is
+/// removed everywhere.
+///
+/// With `recompact`, the remaining leaves are merged with
+/// [`rebatch_failed`] into full batches of the planned batch size, which is
+/// taken to be the number of leaves of the largest planned batch.
+pub fn subtract_submitted(
+    batches: &[Changelogs],
+    submitted: &HashSet<([u8; HASH_LEN], [u8; HASH_LEN])>,
+    recompact: bool,
+) -> Unsubmitted {
+    let mut planned = BTreeSet::new();
+    let mut batch_size = 0;
+    let mut remaining = Vec::new();
+
+    for batch in batches {
+        let mut leaves_in_batch = 0;
+        let mut changelogs = Vec::new();
+
+        for changelog_event in batch.changelogs.iter() {
+            leaves_in_batch += changelog_event.leaves.len();
+
+            let merkle_tree_pubkey = changelog_event.merkle_tree_pubkey;
+            let leaves: Vec<_> = changelog_event
+                .leaves
+                .iter()
+                .filter(|leaf| {
+                    let pair = (merkle_tree_pubkey, **leaf);
+                    planned.insert(pair);
+                    !submitted.contains(&pair)
+                })
+                .copied()
+                .collect();
+            if !leaves.is_empty() {
+                changelogs.push(ChangelogEvent {
+                    merkle_tree_pubkey,
+                    leaves,
+                });
+            }
+        }
+
+        batch_size = cmp::max(batch_size, leaves_in_batch);
+        if !changelogs.is_empty() {
+            remaining.push(Changelogs { changelogs });
+        }
+    }
+
+    if recompact && !remaining.is_empty() {
+        // Can't fail, the batches with remaining leaves aren't empty, so
+        // neither is the batch size.
+        remaining = rebatch_failed(remaining, batch_size).unwrap();
+    }
+
+    let mut unknown: Vec<_> = submitted
+        .iter()
+        .filter(|pair| !planned.contains(*pair))
+        .copied()
+        .collect();
+    unknown.sort_unstable();
+
+    Unsubmitted {
+        batches: remaining,
+        unknown,
+    }
+}
+
+/// Decides the batch size for retrying failed batches.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// Halve the batch size on each retry. Otherwise, decrease it by one.
+    pub halve_batch_size: bool,
+    /// Smallest batch size to retry with. 0 is treated as 1, a batch size is
+    /// never decreased to 0.
+    pub min_batch_size: usize,
+}
+
+impl RetryPolicy {
+    /// Returns the batch size to retry batches of `batch_size` leaves with.
+    /// Fails with [`MyError::RetriesExhausted`] when `batch_size` can't be
+    /// decreased without going below
+    /// [`min_batch_size`](Self::min_batch_size).
+    pub fn next_batch_size(&self, batch_size: usize) -> Result<usize, MyError> {
+        let min_batch_size = cmp::max(self.min_batch_size, 1);
+        if batch_size <= min_batch_size {
+            return Err(MyError::RetriesExhausted(batch_size, min_batch_size));
+        }
+
+        let next_batch_size = if self.halve_batch_size {
+            batch_size / 2
+        } else {
+            batch_size - 1
+        };
+        Ok(cmp::max(next_batch_size, min_batch_size))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{append_leaves, batches_to_map, fixture};
+
+    #[test]
+    fn test_rebatch_failed() {
+        let failed = vec![
+            Changelogs {
+                changelogs: vec![
+                    ChangelogEvent {
+                        merkle_tree_pubkey: [5_u8; 32],
+                        leaves: vec![[0_u8; 32], [1_u8; 32]],
+                    },
+                    ChangelogEvent {
+                        merkle_tree_pubkey: [1_u8; 32],
+                        leaves: vec![[2_u8; 32]],
+                    },
+                ],
+            },
+            Changelogs {
+                changelogs: vec![
+                    ChangelogEvent {
+                        merkle_tree_pubkey: [1_u8; 32],
+                        leaves: vec![[3_u8; 32], [4_u8; 32]],
+                    },
+                    ChangelogEvent {
+                        merkle_tree_pubkey: [5_u8; 32],
+                        leaves: vec![[5_u8; 32]],
+                    },
+                ],
+            },
+        ];
+
+        // MT 5 comes first, because it appeared first, and its leaves from
+        // both failed batches are merged.
+        assert_eq!(
+            rebatch_failed(failed, 2).unwrap(),
+            vec![
+                Changelogs {
+                    changelogs: vec![ChangelogEvent {
+                        merkle_tree_pubkey: [5_u8; 32],
+                        leaves: vec![[0_u8; 32], [1_u8; 32]],
+                    }]
+                },
+                Changelogs {
+                    changelogs: vec![
+                        ChangelogEvent {
+                            merkle_tree_pubkey: [5_u8; 32],
+                            leaves: vec![[5_u8; 32]],
+                        },
+                        ChangelogEvent {
+                            merkle_tree_pubkey: [1_u8; 32],
+                            leaves: vec![[2_u8; 32]],
+                        },
+                    ]
+                },
+                Changelogs {
+                    changelogs: vec![ChangelogEvent {
+                        merkle_tree_pubkey: [1_u8; 32],
+                        leaves: vec![[3_u8; 32], [4_u8; 32]],
+                    }]
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_rebatch_failed_invalid_batch_size() {
+        assert!(matches!(
+            rebatch_failed(Vec::new(), 0),
+            Err(MyError::InvalidBatchSize)
+        ));
+    }
+
+    #[test]
+    fn test_subtract_submitted() {
+        let (leaves, merkle_trees) = fixture::leaves_and_merkle_trees();
+        let batches = append_leaves(leaves, merkle_trees, 10).unwrap();
+
+        // All of batch 0, part of MT 0 and all of MT 1 in batch 1 and one
+        // unknown pair.
+        let mut submitted: HashSet<_> = (0..11).map(|i| ([0_u8; 32], [i; 32])).collect();
+        submitted.extend((12..15).map(|i| ([1_u8; 32], [i; 32])));
+        submitted.insert(([9_u8; 32], [0_u8; 32]));
+
+        let unsubmitted = subtract_submitted(&batches, &submitted, false);
+        assert_eq!(unsubmitted.unknown, vec![([9_u8; 32], [0_u8; 32])]);
+        assert_eq!(
+            unsubmitted.batches,
+            vec![
+                Changelogs {
+                    changelogs: vec![
+                        ChangelogEvent {
+                            merkle_tree_pubkey: [0_u8; 32],
+                            leaves: vec![[11_u8; 32]],
+                        },
+                        ChangelogEvent {
+                            merkle_tree_pubkey: [2_u8; 32],
+                            leaves: (15..19).map(|i| [i; 32]).collect(),
+                        },
+                        ChangelogEvent {
+                            merkle_tree_pubkey: [3_u8; 32],
+                            leaves: vec![[19_u8; 32]],
+                        },
+                    ],
+                },
+                batches[2].clone(),
+            ]
+        );
+
+        // The remaining 11 leaves are recompacted into batches of 10.
+        let unsubmitted = subtract_submitted(&batches, &submitted, true);
+        assert_eq!(
+            unsubmitted
+                .batches
+                .iter()
+                .map(|batch| batch.changelogs.len())
+                .collect::<Vec<_>>(),
+            vec![3, 1]
+        );
+        assert_eq!(
+            batches_to_map(&unsubmitted.batches)[&[3_u8; 32]],
+            (19..25).map(|i| [i; 32]).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_subtract_submitted_everything() {
+        let (leaves, merkle_trees) = fixture::leaves_and_merkle_trees();
+        let batches = append_leaves(leaves.clone(), merkle_trees.clone(), 10).unwrap();
+        let submitted: HashSet<_> = merkle_trees.into_iter().zip(leaves).collect();
+
+        for recompact in [false, true] {
+            assert_eq!(
+                subtract_submitted(&batches, &submitted, recompact),
+                Unsubmitted::default()
+            );
+        }
+        assert_eq!(
+            subtract_submitted(&batches, &HashSet::new(), false).batches,
+            batches
+        );
+    }
+
+    #[test]
+    fn test_retry_policy_halve() {
+        let policy = RetryPolicy {
+            halve_batch_size: true,
+            min_batch_size: 3,
+        };
+
+        let mut batch_sizes = vec![20];
+        while let Ok(batch_size) = policy.next_batch_size(*batch_sizes.last().unwrap()) {
+            batch_sizes.push(batch_size);
+        }
+        assert_eq!(batch_sizes, vec![20, 10, 5, 3]);
+    }
+
+    #[test]
+    fn test_retry_policy_decrement() {
+        let policy = RetryPolicy {
+            halve_batch_size: false,
+            min_batch_size: 8,
+        };
+
+        assert_eq!(policy.next_batch_size(10).unwrap(), 9);
+        assert_eq!(policy.next_batch_size(9).unwrap(), 8);
+    }
+
+    #[test]
+    fn test_retry_policy_exhausted() {
+        let policy = RetryPolicy {
+            halve_batch_size: true,
+            min_batch_size: 3,
+        };
+
+        assert!(matches!(
+            policy.next_batch_size(3),
+            Err(MyError::RetriesExhausted(3, 3))
+        ));
+        assert!(matches!(
+            policy.next_batch_size(2),
+            Err(MyError::RetriesExhausted(2, 3))
+        ));
+    }
+
+    #[test]
+    fn test_retry_policy_zero_min_batch_size() {
+        for halve_batch_size in [true, false] {
+            let policy = RetryPolicy {
+                halve_batch_size,
+                min_batch_size: 0,
+            };
+
+            assert_eq!(policy.next_batch_size(2).unwrap(), 1);
+            assert!(matches!(
+                policy.next_batch_size(1),
+                Err(MyError::RetriesExhausted(1, 1))
+            ));
+            assert!(matches!(
+                policy.next_batch_size(0),
+                Err(MyError::RetriesExhausted(0, 1))
+            ));
+        }
+    }
+}
@@ -0,0 +1,160 @@
+use std::collections::BTreeMap;
+
+use crate::{append_leaves, ChangelogEvent, MyError, HASH_LEN};
+
+/// Changelog event with a sequence number, which increases by one with
+/// each event emitted for its Merkle tree.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SequencedChangelogEvent {
+    pub seq: u64,
+    pub event: ChangelogEvent,
+}
+
+/// Set of sequenced changelogs for different Merkle trees.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SequencedChangelogs {
+    pub changelogs: Vec<SequencedChangelogEvent>,
+}
+
+/// Sequence number of the next event of each Merkle tree.
+pub type TreeSequences = BTreeMap<[u8; HASH_LEN], u64>;
+
+/// Batches leaves like [`append_leaves`] and assigns each event the next
+/// sequence number of its Merkle tree.
+///
+/// `sequences` maps Merkle trees to the sequence number of their next
+/// event. Trees missing from it start from 0. The returned map contains the
+/// sequence numbers to continue with in the subsequent call.
+///
+/// Fails with [`MyError::SequenceOverflow`] if the sequence number of a
+/// Merkle tree would exceed `u64::MAX`.
+pub fn append_leaves_sequenced(
+    leaves: Vec<[u8; HASH_LEN]>,
+    merkle_trees: Vec<[u8; HASH_LEN]>,
+    batch_size: usize,
+    mut sequences: TreeSequences,
+) -> Result<(Vec<SequencedChangelogs>, TreeSequences), MyError> {
+    let batches = append_leaves(leaves, merkle_trees, batch_size)?
+        .into_iter()
+        .map(|batch| {
+            Ok(SequencedChangelogs {
+                changelogs: batch
+                    .changelogs
+                    .into_iter()
+                    .map(|event| {
+                        let seq = sequences.entry(event.merkle_tree_pubkey).or_insert(0);
+                        let next_seq = seq
+                            .checked_add(1)
+                            .ok_or(MyError::SequenceOverflow(event.merkle_tree_pubkey))?;
+                        let sequenced_event = SequencedChangelogEvent { seq: *seq, event };
+                        *seq = next_seq;
+                        Ok(sequenced_event)
+                    })
+                    .collect::<Result<_, MyError>>()?,
+            })
+        })
+        .collect::<Result<_, MyError>>()?;
+
+    Ok((batches, sequences))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sequences_of(batches: &[SequencedChangelogs]) -> Vec<Vec<(u8, u64)>> {
+        batches
+            .iter()
+            .map(|batch| {
+                batch
+                    .changelogs
+                    .iter()
+                    .map(|sequenced_event| {
+                        (
+                            sequenced_event.event.merkle_tree_pubkey[0],
+                            sequenced_event.seq,
+                        )
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_append_leaves_sequenced() {
+        // MT 0 has 22 leaves, so it spans three batches. MT 1 has 3 leaves.
+        let leaves: Vec<[u8; HASH_LEN]> = (0..25).map(|i| [i; HASH_LEN]).collect();
+        let merkle_trees: Vec<[u8; HASH_LEN]> = (0..25)
+            .map(|i| {
+                if i < 22 {
+                    [0_u8; HASH_LEN]
+                } else {
+                    [1_u8; HASH_LEN]
+                }
+            })
+            .collect();
+
+        let sequences = BTreeMap::from([([0_u8; HASH_LEN], 7)]);
+        let (batches, sequences) =
+            append_leaves_sequenced(leaves.clone(), merkle_trees.clone(), 10, sequences).unwrap();
+
+        assert_eq!(
+            sequences_of(&batches),
+            vec![vec![(0, 7)], vec![(0, 8)], vec![(0, 9), (1, 0)]]
+        );
+        assert_eq!(
+            sequences,
+            BTreeMap::from([([0_u8; HASH_LEN], 10), ([1_u8; HASH_LEN], 1)])
+        );
+
+        // Leaves are the same as without sequence numbers.
+        let events: Vec<ChangelogEvent> = batches
+            .into_iter()
+            .flat_map(|batch| batch.changelogs)
+            .map(|sequenced_event| sequenced_event.event)
+            .collect();
+        let expected: Vec<ChangelogEvent> = append_leaves(leaves.clone(), merkle_trees, 10)
+            .unwrap()
+            .into_iter()
+            .flat_map(|batch| batch.changelogs)
+            .collect();
+        assert_eq!(events, expected);
+
+        // A subsequent call continues numbering only for the trees it touches.
+        let (batches, sequences) = append_leaves_sequenced(
+            leaves[..2].to_vec(),
+            vec![[1_u8; HASH_LEN], [2_u8; HASH_LEN]],
+            10,
+            sequences,
+        )
+        .unwrap();
+        assert_eq!(sequences_of(&batches), vec![vec![(1, 1), (2, 0)]]);
+        assert_eq!(
+            sequences,
+            BTreeMap::from([
+                ([0_u8; HASH_LEN], 10),
+                ([1_u8; HASH_LEN], 2),
+                ([2_u8; HASH_LEN], 1)
+            ])
+        );
+    }
+
+    #[test]
+    fn test_append_leaves_sequenced_overflow() {
+        let sequences = BTreeMap::from([([1_u8; HASH_LEN], u64::MAX - 1)]);
+        let (batches, sequences) = append_leaves_sequenced(
+            vec![[0_u8; HASH_LEN]],
+            vec![[1_u8; HASH_LEN]],
+            10,
+            sequences,
+        )
+        .unwrap();
+        assert_eq!(sequences_of(&batches), vec![vec![(1, u64::MAX - 1)]]);
+
+        // The sequence number to continue with after this event wouldn't fit.
+        assert!(matches!(
+            append_leaves_sequenced(vec![[0_u8; HASH_LEN]], vec![[1_u8; HASH_LEN]], 10, sequences),
+            Err(MyError::SequenceOverflow(tree)) if tree == [1_u8; HASH_LEN]
+        ));
+    }
+}
@@ -0,0 +1,115 @@
+use std::collections::BTreeMap;
+
+use crate::{build_merkle_tree_map, split_into_batches_into, Changelogs, MyError, HASH_LEN};
+
+/// Batches leaves like [`append_leaves`](crate::append_leaves), but never
+/// mixes Merkle trees of different shards in one batch. `shard_of` returns
+/// the shard of a Merkle tree.
+///
+/// Shards are processed in ascending order, and the trees of each shard in
+/// ascending order of their pubkeys. When the leaves of a shard are
+/// exhausted, its last batch is closed even if it's not full, so every shard
+/// starts with a new batch.
+pub fn append_leaves_sharded<F>(
+    leaves: Vec<[u8; HASH_LEN]>,
+    merkle_trees: Vec<[u8; HASH_LEN]>,
+    shard_of: F,
+    batch_size: usize,
+) -> Result<Vec<Changelogs>, MyError>
+where
+    F: Fn(&[u8; HASH_LEN]) -> u16,
+{
+    if batch_size == 0 {
+        return Err(MyError::InvalidBatchSize);
+    }
+    let merkle_tree_map = build_merkle_tree_map(&leaves, &merkle_trees)?;
+
+    let mut shards: BTreeMap<u16, Vec<_>> = BTreeMap::new();
+    for (merkle_tree, tree_leaves) in merkle_tree_map.iter() {
+        shards
+            .entry(shard_of(merkle_tree))
+            .or_default()
+            .push((*merkle_tree, tree_leaves.as_slice()));
+    }
+
+    let mut batches_of_changelogs = Vec::new();
+    for shard_trees in shards.into_values() {
+        split_into_batches_into(shard_trees, batch_size, &mut batches_of_changelogs);
+    }
+
+    Ok(batches_of_changelogs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{append_leaves, fixture, testing::batches_match_input};
+
+    #[test]
+    fn test_append_leaves_sharded() {
+        let (leaves, merkle_trees) = fixture::leaves_and_merkle_trees();
+        // MT 0 and MT 2 in shard 0 (16 leaves), MT 1 and MT 3 in shard 1 (9
+        // leaves).
+        let shard_of = |merkle_tree: &[u8; 32]| u16::from(merkle_tree[0] % 2);
+
+        let batches =
+            append_leaves_sharded(leaves.clone(), merkle_trees.clone(), shard_of, 10).unwrap();
+
+        let shards: Vec<Vec<u16>> = batches
+            .iter()
+            .map(|batch| {
+                batch
+                    .changelogs
+                    .iter()
+                    .map(|changelog_event| shard_of(&changelog_event.merkle_tree_pubkey))
+                    .collect()
+            })
+            .collect();
+        let leaves_per_batch: Vec<usize> = batches
+            .iter()
+            .map(|batch| {
+                batch
+                    .changelogs
+                    .iter()
+                    .map(|changelog_event| changelog_event.leaves.len())
+                    .sum()
+            })
+            .collect();
+        // Shard 0 closes its second batch with 6 leaves instead of pulling
+        // from shard 1.
+        assert_eq!(shards, vec![vec![0], vec![0, 0], vec![1, 1]]);
+        assert_eq!(leaves_per_batch, vec![10, 6, 9]);
+        assert!(batches_match_input(&batches, &leaves, &merkle_trees));
+
+        // With a single shard, it's the same as unsharded batching.
+        assert_eq!(
+            append_leaves_sharded(leaves.clone(), merkle_trees.clone(), |_| 7, 10).unwrap(),
+            append_leaves(leaves.clone(), merkle_trees.clone(), 10).unwrap()
+        );
+
+        assert!(matches!(
+            append_leaves_sharded(leaves, merkle_trees, shard_of, 0),
+            Err(MyError::InvalidBatchSize)
+        ));
+    }
+
+    #[test]
+    fn test_append_leaves_sharded_errors() {
+        assert!(matches!(
+            append_leaves_sharded(vec![[0_u8; 32]], vec![[1_u8; 32]], |_| 0, 0),
+            Err(MyError::InvalidBatchSize)
+        ));
+        assert!(matches!(
+            append_leaves_sharded(vec![[0_u8; 32]; 2], vec![[1_u8; 32]], |_| 0, 1),
+            Err(MyError::LeavesTreesNotEqual { .. })
+        ));
+        // The batch size is checked before the input is grouped.
+        assert!(matches!(
+            append_leaves_sharded(vec![[0_u8; 32]; 2], vec![[1_u8; 32]], |_| 0, 0),
+            Err(MyError::InvalidBatchSize)
+        ));
+        assert!(append_leaves_sharded(vec![], vec![], |_| 0, 1)
+            .unwrap()
+            .is_empty());
+    }
+}
@@ -0,0 +1,135 @@
+use std::ops::Deref;
+
+use smallvec::SmallVec;
+
+use crate::{
+    build_merkle_tree_map, div_ceil_checked, BatchCursor, ChangelogEvent, Changelogs, MyError,
+    HASH_LEN,
+};
+
+/// Events of a [`SmallChangelogs`]. Up to two of them are stored inline.
+pub type SmallChangelogEvents = SmallVec<[ChangelogEvent; 2]>;
+
+/// Same as [`Changelogs`], but stores up to two events inline, so batches
+/// with one or two Merkle trees don't allocate the list of events.
+///
+/// Dereferences to a slice of the events.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SmallChangelogs {
+    pub changelogs: SmallChangelogEvents,
+}
+
+impl Deref for SmallChangelogs {
+    type Target = [ChangelogEvent];
+
+    fn deref(&self) -> &Self::Target {
+        &self.changelogs
+    }
+}
+
+impl From<SmallChangelogs> for Changelogs {
+    fn from(batch: SmallChangelogs) -> Self {
+        Changelogs {
+            changelogs: batch.changelogs.into_vec(),
+        }
+    }
+}
+
+impl From<Changelogs> for SmallChangelogs {
+    fn from(batch: Changelogs) -> Self {
+        SmallChangelogs {
+            changelogs: SmallVec::from_vec(batch.changelogs),
+        }
+    }
+}
+
+/// Same as [`append_leaves`](crate::append_leaves), but returns
+/// [`SmallChangelogs`], saving an allocation for every batch with at most
+/// two events.
+pub fn append_leaves_small(
+    leaves: Vec<[u8; HASH_LEN]>,
+    merkle_trees: Vec<[u8; HASH_LEN]>,
+    batch_size: usize,
+) -> Result<Vec<SmallChangelogs>, MyError> {
+    let num_batches =
+        div_ceil_checked(leaves.len(), batch_size).ok_or(MyError::InvalidBatchSize)?;
+    let merkle_tree_leaves: Vec<_> = build_merkle_tree_map(&leaves, &merkle_trees)?
+        .into_iter()
+        .collect();
+
+    let mut cursor = BatchCursor::default();
+    let batches_of_changelogs = (0..num_batches)
+        .map(|_| {
+            let mut batch_of_changelogs = SmallChangelogs::default();
+            cursor.next_batch(
+                batch_size,
+                |tree_index| {
+                    merkle_tree_leaves
+                        .get(tree_index)
+                        .map(|(_, tree_leaves)| tree_leaves.len())
+                },
+                |tree_index, range| {
+                    let (merkle_tree_pubkey, tree_leaves) = &merkle_tree_leaves[tree_index];
+                    batch_of_changelogs.changelogs.push(ChangelogEvent {
+                        merkle_tree_pubkey: *merkle_tree_pubkey,
+                        leaves: tree_leaves[range].to_vec(),
+                    });
+                },
+            );
+            batch_of_changelogs
+        })
+        .collect();
+
+    Ok(batches_of_changelogs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{append_leaves, fixture};
+
+    #[test]
+    fn test_append_leaves_small() {
+        let (leaves, merkle_trees) = fixture::leaves_and_merkle_trees();
+
+        for batch_size in [1, 3, 7, 10, 25, 100] {
+            let small =
+                append_leaves_small(leaves.clone(), merkle_trees.clone(), batch_size).unwrap();
+            let expected = append_leaves(leaves.clone(), merkle_trees.clone(), batch_size).unwrap();
+            assert_eq!(small.len(), expected.len());
+            for (small, expected) in small.iter().zip(&expected) {
+                // Compared as slices.
+                assert_eq!(&small[..], &expected.changelogs[..]);
+            }
+            assert_eq!(
+                small.into_iter().map(Changelogs::from).collect::<Vec<_>>(),
+                expected
+            );
+        }
+
+        // Batches with at most two events are inline.
+        let small = append_leaves_small(leaves, merkle_trees, 10).unwrap();
+        assert!(!small[0].changelogs.spilled());
+        assert!(small[1].changelogs.spilled());
+        assert!(!small[2].changelogs.spilled());
+
+        assert!(matches!(
+            append_leaves_small(vec![], vec![], 0),
+            Err(MyError::InvalidBatchSize)
+        ));
+        assert!(append_leaves_small(vec![], vec![], 1).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_small_changelogs_conversion() {
+        let batch = Changelogs {
+            changelogs: vec![ChangelogEvent {
+                merkle_tree_pubkey: [1_u8; 32],
+                leaves: vec![[2_u8; 32]],
+            }],
+        };
+        let small = SmallChangelogs::from(batch.clone());
+        assert_eq!(small.len(), 1);
+        assert_eq!(Changelogs::from(small), batch);
+    }
+}
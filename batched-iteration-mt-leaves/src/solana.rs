@@ -0,0 +1,111 @@
+use solana_pubkey::Pubkey;
+
+use crate::{append_leaves, Changelogs, MyError, HASH_LEN};
+
+/// Merkle tree identifier which can be converted from and into a [`Pubkey`].
+///
+/// Both `[u8; HASH_LEN]` and `Pubkey` are foreign types, so the conversions between
+/// them have to go through this wrapper.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct MerkleTreePubkey(pub [u8; HASH_LEN]);
+
+impl From<Pubkey> for MerkleTreePubkey {
+    fn from(pubkey: Pubkey) -> Self {
+        Self(pubkey.to_bytes())
+    }
+}
+
+impl From<MerkleTreePubkey> for Pubkey {
+    fn from(merkle_tree_pubkey: MerkleTreePubkey) -> Self {
+        Pubkey::new_from_array(merkle_tree_pubkey.0)
+    }
+}
+
+impl From<[u8; HASH_LEN]> for MerkleTreePubkey {
+    fn from(merkle_tree_pubkey: [u8; HASH_LEN]) -> Self {
+        Self(merkle_tree_pubkey)
+    }
+}
+
+impl From<MerkleTreePubkey> for [u8; HASH_LEN] {
+    fn from(merkle_tree_pubkey: MerkleTreePubkey) -> Self {
+        merkle_tree_pubkey.0
+    }
+}
+
+/// Same as [`append_leaves`], but takes the Merkle trees as [`Pubkey`]s.
+pub fn append_leaves_pubkeys(
+    leaves: &[[u8; HASH_LEN]],
+    merkle_trees: &[Pubkey],
+    batch_size: usize,
+) -> Result<Vec<Changelogs>, MyError> {
+    let merkle_trees = merkle_trees.iter().map(Pubkey::to_bytes).collect();
+    append_leaves(leaves.to_vec(), merkle_trees, batch_size)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::*;
+    use crate::ChangelogEvent;
+
+    const PUBKEYS: [&str; 4] = [
+        "11111111111111111111111111111111",
+        "TokenkegQfeZyiNwAJbNbGKPFXCWuBvf9Ss623VQ5DA",
+        "cmtDvXumGCrqC1Age74AVPhSRVXJMd8PJS91L8KbNCK",
+        "noopb9bkMVfRPU8AsbpTUg8AQkHtKwMYZiFUjNRtMmV",
+    ];
+
+    #[test]
+    fn test_merkle_tree_pubkey_round_trip() {
+        for encoded in PUBKEYS {
+            let pubkey = Pubkey::from_str(encoded).unwrap();
+
+            let merkle_tree_pubkey = MerkleTreePubkey::from(pubkey);
+            assert_eq!(merkle_tree_pubkey.0, pubkey.to_bytes());
+
+            let bytes: [u8; HASH_LEN] = merkle_tree_pubkey.into();
+            let pubkey_back: Pubkey = MerkleTreePubkey::from(bytes).into();
+            assert_eq!(pubkey_back, pubkey);
+            assert_eq!(pubkey_back.to_string(), encoded);
+        }
+    }
+
+    #[test]
+    fn test_append_leaves_pubkeys() {
+        let merkle_trees: Vec<Pubkey> = [PUBKEYS[2], PUBKEYS[3], PUBKEYS[2]]
+            .iter()
+            .map(|encoded| Pubkey::from_str(encoded).unwrap())
+            .collect();
+        let leaves = [[1_u8; 32], [2_u8; 32], [3_u8; 32]];
+
+        let changelogs = append_leaves_pubkeys(&leaves, &merkle_trees, 2).unwrap();
+        assert_eq!(
+            changelogs,
+            append_leaves(
+                leaves.to_vec(),
+                merkle_trees.iter().map(Pubkey::to_bytes).collect(),
+                2
+            )
+            .unwrap()
+        );
+    }
+
+    #[test]
+    fn test_changelog_event_display_base58() {
+        let pubkey = Pubkey::from_str(PUBKEYS[2]).unwrap();
+        let changelog_event = ChangelogEvent {
+            merkle_tree_pubkey: pubkey.to_bytes(),
+            leaves: vec![[1_u8; 32]],
+        };
+
+        assert_eq!(
+            changelog_event.to_string(),
+            format!(
+                "{}: 1 leaves, first 01010101.., last 01010101..",
+                PUBKEYS[2]
+            )
+        );
+    }
+}
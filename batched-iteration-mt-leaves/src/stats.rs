@@ -0,0 +1,248 @@
+use std::{
+    cmp,
+    collections::{BTreeMap, BTreeSet},
+};
+
+use crate::{Changelogs, HASH_LEN};
+
+/// Returns, for every Merkle tree, the index of the first batch containing
+/// any of its leaves.
+pub fn first_batch_index_per_tree(batches: &[Changelogs]) -> BTreeMap<[u8; HASH_LEN], usize> {
+    let mut first_batch_indices = BTreeMap::new();
+    for (i, batch) in batches.iter().enumerate() {
+        for changelog_event in batch.changelogs.iter() {
+            first_batch_indices
+                .entry(changelog_event.merkle_tree_pubkey)
+                .or_insert(i);
+        }
+    }
+    first_batch_indices
+}
+
+/// Returns, for every Merkle tree, the number of distinct batches containing
+/// any of its leaves, i.e. how many transactions lock it.
+pub fn batch_span_per_tree(batches: &[Changelogs]) -> BTreeMap<[u8; HASH_LEN], usize> {
+    let mut spans = BTreeMap::new();
+    for batch in batches {
+        // A tree with several events in the batch is counted once.
+        let merkle_trees: BTreeSet<_> = batch
+            .changelogs
+            .iter()
+            .map(|changelog_event| changelog_event.merkle_tree_pubkey)
+            .collect();
+        for merkle_tree in merkle_trees {
+            *spans.entry(merkle_tree).or_default() += 1;
+        }
+    }
+    spans
+}
+
+/// Returns, for every batch, the distinct Merkle trees it appends to, in
+/// the order of its events. These are the accounts which have to be writable
+/// in the transaction submitting the batch.
+pub fn writable_trees_per_batch(batches: &[Changelogs]) -> Vec<Vec<[u8; HASH_LEN]>> {
+    batches
+        .iter()
+        .map(|batch| {
+            // Batches produced by the batching functions have one event per
+            // tree, but the hand-constructed ones might not.
+            let mut merkle_trees: Vec<[u8; HASH_LEN]> = Vec::with_capacity(batch.changelogs.len());
+            for changelog_event in batch.changelogs.iter() {
+                if !merkle_trees.contains(&changelog_event.merkle_tree_pubkey) {
+                    merkle_trees.push(changelog_event.merkle_tree_pubkey);
+                }
+            }
+            merkle_trees
+        })
+        .collect()
+}
+
+/// Counts the leaves appended to every Merkle tree, without grouping them,
+/// so only the counts are kept in memory. Only `merkle_trees` is needed, the
+/// leaves themselves are never read.
+///
+/// `leaves_len_hint` is the number of leaves, if known. Leaves are paired
+/// with Merkle trees by index, so with a hint shorter than `merkle_trees`
+/// only the first `leaves_len_hint` trees have a leaf and are counted.
+pub fn tree_histogram(
+    leaves_len_hint: Option<usize>,
+    merkle_trees: &[[u8; HASH_LEN]],
+) -> BTreeMap<[u8; HASH_LEN], usize> {
+    let num_paired = leaves_len_hint.map_or(merkle_trees.len(), |leaves_len| {
+        cmp::min(leaves_len, merkle_trees.len())
+    });
+
+    let mut histogram = BTreeMap::new();
+    for merkle_tree in &merkle_trees[..num_paired] {
+        *histogram.entry(*merkle_tree).or_default() += 1;
+    }
+    histogram
+}
+
+/// Returns up to `k` Merkle trees with the most leaves in `histogram` (see
+/// [`tree_histogram`]), largest first. Trees with the same number of leaves
+/// are ordered by pubkey, ascending.
+pub fn top_k_trees(
+    histogram: &BTreeMap<[u8; HASH_LEN], usize>,
+    k: usize,
+) -> Vec<([u8; HASH_LEN], usize)> {
+    let mut trees: Vec<_> = histogram
+        .iter()
+        .map(|(merkle_tree, count)| (*merkle_tree, *count))
+        .collect();
+    // The map is ordered by pubkey and the sort is stable, so ties keep the
+    // ascending order of pubkeys.
+    trees.sort_by_key(|(_, count)| cmp::Reverse(*count));
+    trees.truncate(k);
+    trees
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{append_leaves, fixture};
+
+    #[test]
+    fn test_first_batch_index_per_tree() {
+        let (leaves, merkle_trees) = fixture::leaves_and_merkle_trees();
+        let batches = append_leaves(leaves, merkle_trees, 10).unwrap();
+
+        let first_batch_indices = first_batch_index_per_tree(&batches);
+        assert_eq!(first_batch_indices[&[3_u8; 32]], 1);
+        assert_eq!(
+            first_batch_indices,
+            BTreeMap::from([
+                ([0_u8; 32], 0),
+                ([1_u8; 32], 1),
+                ([2_u8; 32], 1),
+                ([3_u8; 32], 1),
+            ])
+        );
+        assert!(first_batch_index_per_tree(&[]).is_empty());
+    }
+
+    #[test]
+    fn test_batch_span_per_tree() {
+        let (leaves, merkle_trees) = fixture::leaves_and_merkle_trees();
+        let mut batches = append_leaves(leaves, merkle_trees, 10).unwrap();
+
+        assert_eq!(
+            batch_span_per_tree(&batches),
+            BTreeMap::from([
+                ([0_u8; 32], 2),
+                ([1_u8; 32], 1),
+                ([2_u8; 32], 1),
+                ([3_u8; 32], 2),
+            ])
+        );
+
+        // A repeated tree in a batch doesn't count twice.
+        let repeated = batches[1].changelogs[1].clone();
+        batches[1].changelogs.push(repeated);
+        assert_eq!(batch_span_per_tree(&batches)[&[1_u8; 32]], 1);
+
+        assert!(batch_span_per_tree(&[]).is_empty());
+    }
+
+    #[test]
+    fn test_writable_trees_per_batch() {
+        let (leaves, merkle_trees) = fixture::leaves_and_merkle_trees();
+        let mut batches = append_leaves(leaves, merkle_trees, 10).unwrap();
+
+        assert_eq!(
+            writable_trees_per_batch(&batches),
+            vec![
+                vec![[0_u8; 32]],
+                vec![[0_u8; 32], [1_u8; 32], [2_u8; 32], [3_u8; 32]],
+                vec![[3_u8; 32]],
+            ]
+        );
+
+        // Repeated trees are listed once.
+        let repeated = batches[1].changelogs[1].clone();
+        batches[1].changelogs.push(repeated);
+        assert_eq!(
+            writable_trees_per_batch(&batches)[1],
+            vec![[0_u8; 32], [1_u8; 32], [2_u8; 32], [3_u8; 32]]
+        );
+        assert!(writable_trees_per_batch(&[]).is_empty());
+    }
+
+    #[test]
+    fn test_tree_histogram() {
+        let (leaves, merkle_trees) = fixture::leaves_and_merkle_trees();
+
+        let histogram = tree_histogram(Some(leaves.len()), &merkle_trees);
+        assert_eq!(
+            histogram,
+            BTreeMap::from([
+                ([0_u8; 32], 12),
+                ([1_u8; 32], 3),
+                ([2_u8; 32], 4),
+                ([3_u8; 32], 6),
+            ])
+        );
+        assert_eq!(tree_histogram(None, &merkle_trees), histogram);
+        assert_eq!(
+            tree_histogram(None, &merkle_trees),
+            crate::build_merkle_tree_map(&leaves, &merkle_trees)
+                .unwrap()
+                .into_iter()
+                .map(|(merkle_tree, leaves)| (merkle_tree, leaves.len()))
+                .collect()
+        );
+
+        // Only the first 13 trees are paired with a leaf.
+        assert_eq!(
+            tree_histogram(Some(13), &merkle_trees),
+            BTreeMap::from([([0_u8; 32], 12), ([1_u8; 32], 1)])
+        );
+        assert!(tree_histogram(Some(0), &merkle_trees).is_empty());
+        assert!(tree_histogram(None, &[]).is_empty());
+    }
+
+    #[test]
+    fn test_top_k_trees() {
+        let (_, merkle_trees) = fixture::leaves_and_merkle_trees();
+        let histogram = tree_histogram(None, &merkle_trees);
+
+        assert_eq!(
+            top_k_trees(&histogram, 2),
+            vec![([0_u8; 32], 12), ([3_u8; 32], 6)]
+        );
+        assert!(top_k_trees(&histogram, 0).is_empty());
+        // Larger `k` than the number of trees returns all of them.
+        assert_eq!(
+            top_k_trees(&histogram, 10),
+            vec![
+                ([0_u8; 32], 12),
+                ([3_u8; 32], 6),
+                ([2_u8; 32], 4),
+                ([1_u8; 32], 3),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_top_k_trees_ties() {
+        // MT 5, MT 2 and MT 9 have 2 leaves each, MT 7 has 1.
+        let merkle_trees = [
+            [9_u8; 32], [5; 32], [7; 32], [2; 32], [9; 32], [2; 32], [5; 32],
+        ];
+        let histogram = tree_histogram(None, &merkle_trees);
+
+        assert_eq!(
+            top_k_trees(&histogram, 2),
+            vec![([2_u8; 32], 2), ([5_u8; 32], 2)]
+        );
+        assert_eq!(
+            top_k_trees(&histogram, 4),
+            vec![
+                ([2_u8; 32], 2),
+                ([5_u8; 32], 2),
+                ([9_u8; 32], 2),
+                ([7_u8; 32], 1),
+            ]
+        );
+    }
+}
@@ -0,0 +1,89 @@
+use std::future::Future;
+
+use crate::{append_leaves_iter, Changelogs, MyError, HASH_LEN};
+
+/// Batches the leaves like [`append_leaves`](crate::append_leaves), but
+/// instead of collecting the batches, passes each of them to `submit` as soon
+/// as it's produced. The next batch is produced only after the future returned
+/// by `submit` completes. The first error returned by `submit` stops the
+/// batching and is returned to the caller.
+pub async fn append_leaves_stream<F, Fut>(
+    leaves: Vec<[u8; HASH_LEN]>,
+    merkle_trees: Vec<[u8; HASH_LEN]>,
+    batch_size: usize,
+    mut submit: F,
+) -> Result<(), MyError>
+where
+    F: FnMut(Changelogs) -> Fut,
+    Fut: Future<Output = Result<(), MyError>>,
+{
+    for batch_of_changelogs in append_leaves_iter(leaves, merkle_trees, batch_size)? {
+        submit(batch_of_changelogs).await?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io,
+        sync::{Arc, Mutex},
+    };
+
+    use super::*;
+    use crate::{append_leaves, fixture};
+
+    #[tokio::test]
+    async fn test_append_leaves_stream() {
+        let (leaves, merkle_trees) = fixture::leaves_and_merkle_trees();
+        let expected = append_leaves(leaves.clone(), merkle_trees.clone(), 10).unwrap();
+
+        let submitted = Arc::new(Mutex::new(Vec::new()));
+        append_leaves_stream(leaves, merkle_trees, 10, |batch| {
+            let submitted = Arc::clone(&submitted);
+            async move {
+                submitted.lock().unwrap().push(batch);
+                Ok(())
+            }
+        })
+        .await
+        .unwrap();
+
+        assert_eq!(*submitted.lock().unwrap(), expected);
+    }
+
+    #[tokio::test]
+    async fn test_append_leaves_stream_zero_batch_size() {
+        let (leaves, merkle_trees) = fixture::leaves_and_merkle_trees();
+
+        let res = append_leaves_stream(leaves, merkle_trees, 0, |_| async {
+            panic!("no batch should be submitted")
+        })
+        .await;
+        assert!(matches!(res, Err(MyError::InvalidBatchSize)));
+    }
+
+    #[tokio::test]
+    async fn test_append_leaves_stream_submit_error() {
+        let (leaves, merkle_trees) = fixture::leaves_and_merkle_trees();
+
+        let submitted = Arc::new(Mutex::new(Vec::new()));
+        let res = append_leaves_stream(leaves, merkle_trees, 10, |batch| {
+            let submitted = Arc::clone(&submitted);
+            async move {
+                let mut submitted = submitted.lock().unwrap();
+                if !submitted.is_empty() {
+                    return Err(MyError::Io(io::Error::other("submission failed")));
+                }
+                submitted.push(batch);
+                Ok(())
+            }
+        })
+        .await;
+
+        assert!(matches!(res, Err(MyError::Io(_))));
+        // The batching stopped after the second submission failed.
+        assert_eq!(submitted.lock().unwrap().len(), 1);
+    }
+}
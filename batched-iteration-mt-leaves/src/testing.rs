@@ -0,0 +1,421 @@
+//! Helpers for checking the output of the batching functions, shared by the
+//! unit tests and the fuzz targets.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::{hex, Changelogs, HASH_LEN};
+
+/// Input for fuzzing [`append_leaves`](crate::append_leaves). The leaves and
+/// Merkle trees always have the same length and the batch size is never zero.
+#[cfg(feature = "arbitrary")]
+#[derive(Clone, Debug)]
+pub struct FuzzInput {
+    pub leaves: Vec<[u8; HASH_LEN]>,
+    pub merkle_trees: Vec<[u8; HASH_LEN]>,
+    pub batch_size: usize,
+}
+
+#[cfg(feature = "arbitrary")]
+impl<'a> arbitrary::Arbitrary<'a> for FuzzInput {
+    fn arbitrary(u: &mut arbitrary::Unstructured<'a>) -> arbitrary::Result<Self> {
+        let batch_size = u.int_in_range(1..=64)?;
+
+        // Pick the Merkle trees from a small pool, otherwise nearly every leaf
+        // would end up in a separate tree.
+        let num_trees = u.int_in_range(1..=8)?;
+        let pool = (0..num_trees)
+            .map(|_| u.arbitrary())
+            .collect::<arbitrary::Result<Vec<[u8; HASH_LEN]>>>()?;
+
+        let len = u.arbitrary_len::<[u8; HASH_LEN + 1]>()?;
+        let mut leaves = Vec::with_capacity(len);
+        let mut merkle_trees = Vec::with_capacity(len);
+        for _ in 0..len {
+            leaves.push(u.arbitrary()?);
+            merkle_trees.push(*u.choose(&pool)?);
+        }
+
+        Ok(Self {
+            leaves,
+            merkle_trees,
+            batch_size,
+        })
+    }
+}
+
+/// Checks that `batches` is a valid result of batching `leaves` appended to
+/// `merkle_trees` with `batch_size`:
+///
+/// * No leaf is dropped or duplicated and every leaf stays with its Merkle
+///   tree, in the original order.
+/// * Every batch has at least one and at most `batch_size` leaves, and every
+///   batch except the last one is full.
+/// * No event is empty and no Merkle tree has more than one event in a batch.
+///
+/// Returns a description of the first violated invariant.
+pub fn check_invariants(
+    leaves: &[[u8; HASH_LEN]],
+    merkle_trees: &[[u8; HASH_LEN]],
+    batch_size: usize,
+    batches: &[Changelogs],
+) -> Result<(), String> {
+    let mut expected: BTreeMap<[u8; HASH_LEN], Vec<[u8; HASH_LEN]>> = BTreeMap::new();
+    for (leaf, merkle_tree) in leaves.iter().zip(merkle_trees) {
+        expected.entry(*merkle_tree).or_default().push(*leaf);
+    }
+
+    let mut actual: BTreeMap<[u8; HASH_LEN], Vec<[u8; HASH_LEN]>> = BTreeMap::new();
+    for (i, batch) in batches.iter().enumerate() {
+        let mut leaves_in_batch = 0;
+        let mut merkle_trees_in_batch = Vec::with_capacity(batch.changelogs.len());
+
+        for changelog_event in batch.changelogs.iter() {
+            if changelog_event.leaves.is_empty() {
+                return Err(format!("batch {i} contains an empty event"));
+            }
+            if merkle_trees_in_batch.contains(&changelog_event.merkle_tree_pubkey) {
+                return Err(format!(
+                    "batch {i} contains more than one event for the same Merkle tree"
+                ));
+            }
+            merkle_trees_in_batch.push(changelog_event.merkle_tree_pubkey);
+
+            leaves_in_batch += changelog_event.leaves.len();
+            actual
+                .entry(changelog_event.merkle_tree_pubkey)
+                .or_default()
+                .extend_from_slice(&changelog_event.leaves);
+        }
+
+        if leaves_in_batch == 0 {
+            return Err(format!("batch {i} is empty"));
+        }
+        if leaves_in_batch > batch_size {
+            return Err(format!(
+                "batch {i} has {leaves_in_batch} leaves, more than the batch size {batch_size}"
+            ));
+        }
+        if i + 1 < batches.len() && leaves_in_batch < batch_size {
+            return Err(format!(
+                "batch {i} has {leaves_in_batch} leaves, but only the last batch can have \
+                 less than {batch_size}"
+            ));
+        }
+    }
+
+    if actual != expected {
+        return Err("leaves were dropped, duplicated or reordered".to_owned());
+    }
+
+    Ok(())
+}
+
+/// Returns `true` if `batches` contain exactly the `(merkle_tree, leaf)`
+/// pairs of `expected_leaves` and `expected_trees`, compared as multisets.
+///
+/// Unlike [`check_invariants`], the order of leaves and the batch sizes are
+/// not checked, so recorded inputs can be compared with the output of any
+/// batching configuration. Unbalanced expected input never matches.
+pub fn batches_match_input(
+    batches: &[Changelogs],
+    expected_leaves: &[[u8; HASH_LEN]],
+    expected_trees: &[[u8; HASH_LEN]],
+) -> bool {
+    if expected_leaves.len() != expected_trees.len() {
+        return false;
+    }
+
+    let mut actual: Vec<_> = batches
+        .iter()
+        .flat_map(|batch| &batch.changelogs)
+        .flat_map(|changelog_event| {
+            changelog_event
+                .leaves
+                .iter()
+                .map(|leaf| (changelog_event.merkle_tree_pubkey, *leaf))
+        })
+        .collect();
+    let mut expected: Vec<_> = expected_trees
+        .iter()
+        .copied()
+        .zip(expected_leaves.iter().copied())
+        .collect();
+
+    actual.sort_unstable();
+    expected.sort_unstable();
+    actual == expected
+}
+
+/// Describes the differences between two sets of batches, one line per
+/// difference, in the order of batches. Returns an empty list if they are
+/// equal, ignoring the order of events within batches.
+///
+/// Reported are batches present only in one of the sets, batches with a
+/// different number of leaves and, per Merkle tree, trees present in only one
+/// of the batches or with different leaves.
+pub fn diff_batches(a: &[Changelogs], b: &[Changelogs]) -> Vec<String> {
+    fn leaves_per_tree(batch: &Changelogs) -> BTreeMap<[u8; HASH_LEN], Vec<[u8; HASH_LEN]>> {
+        let mut leaves_per_tree: BTreeMap<_, Vec<_>> = BTreeMap::new();
+        for changelog_event in batch.changelogs.iter() {
+            leaves_per_tree
+                .entry(changelog_event.merkle_tree_pubkey)
+                .or_default()
+                .extend_from_slice(&changelog_event.leaves);
+        }
+        leaves_per_tree
+    }
+
+    let mut diff = Vec::new();
+    for i in 0..a.len().max(b.len()) {
+        let (batch_a, batch_b) = match (a.get(i), b.get(i)) {
+            (Some(batch_a), Some(batch_b)) => (batch_a, batch_b),
+            (Some(_), None) => {
+                diff.push(format!("batch {i}: missing in b"));
+                continue;
+            }
+            (None, Some(_)) => {
+                diff.push(format!("batch {i}: missing in a"));
+                continue;
+            }
+            (None, None) => unreachable!(),
+        };
+
+        let leaves_a = leaves_per_tree(batch_a);
+        let leaves_b = leaves_per_tree(batch_b);
+
+        let count_a: usize = leaves_a.values().map(Vec::len).sum();
+        let count_b: usize = leaves_b.values().map(Vec::len).sum();
+        if count_a != count_b {
+            diff.push(format!(
+                "batch {i}: {count_a} leaves in a, {count_b} leaves in b"
+            ));
+        }
+
+        let merkle_trees: BTreeSet<_> = leaves_a.keys().chain(leaves_b.keys()).collect();
+        for merkle_tree in merkle_trees {
+            let tree = hex::encode(merkle_tree);
+            match (leaves_a.get(merkle_tree), leaves_b.get(merkle_tree)) {
+                (Some(_), None) => diff.push(format!("batch {i}: tree {tree} only in a")),
+                (None, Some(_)) => diff.push(format!("batch {i}: tree {tree} only in b")),
+                (Some(tree_a), Some(tree_b)) if tree_a.len() != tree_b.len() => diff.push(format!(
+                    "batch {i}: tree {tree} has {} leaves in a, {} leaves in b",
+                    tree_a.len(),
+                    tree_b.len()
+                )),
+                (Some(tree_a), Some(tree_b)) if tree_a != tree_b => {
+                    diff.push(format!("batch {i}: tree {tree} has different leaves"))
+                }
+                _ => {}
+            }
+        }
+    }
+
+    diff
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+
+    use super::*;
+    use crate::{append_leaves, fixture, reference};
+
+    /// Generates leaves appended to up to 8 Merkle trees and a batch size,
+    /// which can be larger than the whole input. Each leaf is described by
+    /// the index of its Merkle tree, so the input shrinks towards fewer leaves
+    /// and fewer trees. Leaves are unique, so reordering is always detected.
+    fn batching_input() -> impl Strategy<Value = (Vec<[u8; HASH_LEN]>, Vec<[u8; HASH_LEN]>, usize)>
+    {
+        (prop::collection::vec(0..8_u8, 0..200), 1..300_usize).prop_map(
+            |(tree_indices, batch_size)| {
+                let leaves = (0..tree_indices.len())
+                    .map(|i| {
+                        let mut leaf = [0_u8; HASH_LEN];
+                        leaf[..8].copy_from_slice(&(i as u64).to_le_bytes());
+                        leaf
+                    })
+                    .collect();
+                let merkle_trees = tree_indices
+                    .into_iter()
+                    .map(|tree_index| [tree_index; HASH_LEN])
+                    .collect();
+                (leaves, merkle_trees, batch_size)
+            },
+        )
+    }
+
+    proptest! {
+        #[test]
+        fn prop_append_leaves_invariants(
+            (leaves, merkle_trees, batch_size) in batching_input()
+        ) {
+            let batches = append_leaves(leaves.clone(), merkle_trees.clone(), batch_size).unwrap();
+
+            let num_leaves: usize = batches
+                .iter()
+                .flat_map(|batch| &batch.changelogs)
+                .map(|changelog_event| changelog_event.leaves.len())
+                .sum();
+            prop_assert_eq!(num_leaves, leaves.len());
+
+            let check = check_invariants(&leaves, &merkle_trees, batch_size, &batches);
+            prop_assert!(check.is_ok(), "{}", check.unwrap_err());
+        }
+
+        #[test]
+        fn prop_equivalent_to_reference(
+            (leaves, merkle_trees, batch_size) in batching_input()
+        ) {
+            reference::assert_equivalent(&leaves, &merkle_trees, batch_size);
+        }
+    }
+
+    #[test]
+    fn test_check_invariants() {
+        let (leaves, merkle_trees) = fixture::leaves_and_merkle_trees();
+
+        for batch_size in [1, 2, 3, 7, 10, 12, 25, 100] {
+            let batches = append_leaves(leaves.clone(), merkle_trees.clone(), batch_size).unwrap();
+            check_invariants(&leaves, &merkle_trees, batch_size, &batches).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_check_invariants_violations() {
+        let (leaves, merkle_trees) = fixture::leaves_and_merkle_trees();
+        let batches = append_leaves(leaves.clone(), merkle_trees.clone(), 10).unwrap();
+
+        let mut dropped = batches.clone();
+        dropped[2].changelogs[0].leaves.pop();
+        assert_eq!(
+            check_invariants(&leaves, &merkle_trees, 10, &dropped),
+            Err("leaves were dropped, duplicated or reordered".to_owned())
+        );
+
+        let mut reordered = batches.clone();
+        reordered[0].changelogs[0].leaves.swap(0, 1);
+        assert!(check_invariants(&leaves, &merkle_trees, 10, &reordered).is_err());
+
+        assert_eq!(
+            check_invariants(&leaves, &merkle_trees, 9, &batches),
+            Err("batch 0 has 10 leaves, more than the batch size 9".to_owned())
+        );
+
+        let mut under_full = batches;
+        let leaf = under_full[0].changelogs[0].leaves.pop().unwrap();
+        under_full[2].changelogs[0].leaves.insert(0, leaf);
+        assert_eq!(
+            check_invariants(&leaves, &merkle_trees, 10, &under_full),
+            Err("batch 0 has 9 leaves, but only the last batch can have less than 10".to_owned())
+        );
+    }
+
+    #[test]
+    fn test_batches_match_input() {
+        let (leaves, merkle_trees) = fixture::leaves_and_merkle_trees();
+        let batches = append_leaves(leaves.clone(), merkle_trees.clone(), 10).unwrap();
+        assert!(batches_match_input(&batches, &leaves, &merkle_trees));
+
+        // The order of the expected input doesn't matter.
+        let mut reversed_leaves = leaves.clone();
+        reversed_leaves.reverse();
+        let mut reversed_trees = merkle_trees.clone();
+        reversed_trees.reverse();
+        assert!(batches_match_input(
+            &batches,
+            &reversed_leaves,
+            &reversed_trees
+        ));
+
+        // A leaf moved to another Merkle tree.
+        let mut moved = batches.clone();
+        let leaf = moved[0].changelogs[0].leaves.pop().unwrap();
+        moved[1].changelogs[1].leaves.push(leaf);
+        assert!(!batches_match_input(&moved, &leaves, &merkle_trees));
+
+        // A duplicated leaf.
+        let mut duplicated = batches.clone();
+        duplicated[2].changelogs[0].leaves.push([24_u8; 32]);
+        assert!(!batches_match_input(&duplicated, &leaves, &merkle_trees));
+
+        // A dropped leaf.
+        assert!(!batches_match_input(&batches[..2], &leaves, &merkle_trees));
+        assert!(!batches_match_input(&batches, &leaves, &merkle_trees[..24]));
+    }
+
+    #[test]
+    fn test_diff_batches() {
+        let (leaves, merkle_trees) = fixture::leaves_and_merkle_trees();
+        let batches = append_leaves(leaves.clone(), merkle_trees.clone(), 10).unwrap();
+        assert!(diff_batches(&batches, &batches).is_empty());
+
+        // Moving MT 3 to MT 4 and dropping the last leaf.
+        let mut merkle_trees_b = merkle_trees;
+        for merkle_tree in merkle_trees_b
+            .iter_mut()
+            .filter(|tree| **tree == [3_u8; 32])
+        {
+            *merkle_tree = [4_u8; 32];
+        }
+        let batches_b =
+            append_leaves(leaves[..24].to_vec(), merkle_trees_b[..24].to_vec(), 10).unwrap();
+
+        let mt3 = "03".repeat(32);
+        let mt4 = "04".repeat(32);
+        assert_eq!(
+            diff_batches(&batches, &batches_b),
+            vec![
+                format!("batch 1: tree {mt3} only in a"),
+                format!("batch 1: tree {mt4} only in b"),
+                "batch 2: 5 leaves in a, 4 leaves in b".to_owned(),
+                format!("batch 2: tree {mt3} only in a"),
+                format!("batch 2: tree {mt4} only in b"),
+            ]
+        );
+
+        assert_eq!(
+            diff_batches(&batches, &batches[..1]),
+            vec!["batch 1: missing in b", "batch 2: missing in b"]
+        );
+
+        let mut reordered = batches.clone();
+        reordered[0].changelogs[0].leaves.swap(0, 1);
+        assert_eq!(
+            diff_batches(&batches, &reordered),
+            vec![format!(
+                "batch 0: tree {} has different leaves",
+                "00".repeat(32)
+            )]
+        );
+    }
+
+    #[cfg(feature = "arbitrary")]
+    #[test]
+    fn test_fuzz_input() {
+        use arbitrary::{Arbitrary, Unstructured};
+
+        let data: Vec<u8> = (0..4096_u32)
+            .map(|i| (i.wrapping_mul(2_654_435_761) >> 13) as u8)
+            .collect();
+        let mut u = Unstructured::new(&data);
+        while !u.is_empty() {
+            let input = FuzzInput::arbitrary(&mut u).unwrap();
+            assert_eq!(input.leaves.len(), input.merkle_trees.len());
+            assert!(input.batch_size > 0);
+
+            let batches = append_leaves(
+                input.leaves.clone(),
+                input.merkle_trees.clone(),
+                input.batch_size,
+            )
+            .unwrap();
+            check_invariants(
+                &input.leaves,
+                &input.merkle_trees,
+                input.batch_size,
+                &batches,
+            )
+            .unwrap();
+        }
+    }
+}
@@ -0,0 +1,407 @@
+//! Minimal append-only Merkle tree, for checking that replaying the batches
+//! gives the same tree as appending the leaves directly.
+
+use std::marker::PhantomData;
+
+use crate::{ChangelogEvent, Changelogs, MyError, HASH_LEN};
+
+/// Hash function of a [`MerkleTree`].
+pub trait Hasher {
+    /// Hashes two child nodes into their parent.
+    fn hash_pair(left: &[u8; HASH_LEN], right: &[u8; HASH_LEN]) -> [u8; HASH_LEN];
+}
+
+/// SHA-256 of the concatenated child nodes.
+#[cfg(feature = "sha2")]
+#[derive(Clone, Copy, Debug)]
+pub struct Sha256;
+
+#[cfg(feature = "sha2")]
+impl Hasher for Sha256 {
+    fn hash_pair(left: &[u8; HASH_LEN], right: &[u8; HASH_LEN]) -> [u8; HASH_LEN] {
+        use sha2::Digest;
+
+        let mut hasher = sha2::Sha256::new();
+        hasher.update(left);
+        hasher.update(right);
+        hasher.finalize().into()
+    }
+}
+
+/// Merkle proof of inclusion of a leaf.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Proof {
+    pub leaf: [u8; HASH_LEN],
+    pub leaf_index: u64,
+    /// Siblings of the nodes on the path from the leaf to the root, starting
+    /// from the leaf's sibling.
+    pub siblings: Vec<[u8; HASH_LEN]>,
+}
+
+/// Checks whether `proof` proves the inclusion of its leaf in the tree with
+/// `root`.
+pub fn verify_proof<H: Hasher>(root: &[u8; HASH_LEN], proof: &Proof) -> bool {
+    let mut node = proof.leaf;
+    let mut index = proof.leaf_index;
+    for sibling in proof.siblings.iter() {
+        node = if index.is_multiple_of(2) {
+            H::hash_pair(&node, sibling)
+        } else {
+            H::hash_pair(sibling, &node)
+        };
+        index /= 2;
+    }
+    // A leaf index beyond the tree's capacity can't be proven.
+    index == 0 && node == *root
+}
+
+/// Applies the events of `tree` from `batch` and returns the proofs of all
+/// leaves appended by them, against the root after the batch.
+pub fn proofs_for_batch<H: Hasher>(
+    tree: &mut MerkleTree<H>,
+    batch: &Changelogs,
+) -> Result<Vec<Proof>, MyError> {
+    let start = tree.len();
+    tree.apply_batches(std::slice::from_ref(batch))?;
+    (start..tree.len())
+        .map(|leaf_index| tree.prove(leaf_index as u64))
+        .collect()
+}
+
+/// Append-only Merkle tree of a fixed height. Empty leaves are all zeros.
+#[derive(Clone, Debug)]
+pub struct MerkleTree<H: Hasher> {
+    merkle_tree_pubkey: [u8; HASH_LEN],
+    height: u32,
+    leaves: Vec<[u8; HASH_LEN]>,
+    _hasher: PhantomData<H>,
+}
+
+impl<H: Hasher> MerkleTree<H> {
+    /// Creates an empty tree with `2^height` leaves. Its pubkey is all zeros
+    /// until set with [`with_pubkey`](Self::with_pubkey).
+    pub fn new(height: u32) -> Self {
+        Self {
+            merkle_tree_pubkey: [0_u8; HASH_LEN],
+            height,
+            leaves: Vec::new(),
+            _hasher: PhantomData,
+        }
+    }
+
+    /// Sets the pubkey of the tree, which the events applied to it have to
+    /// match.
+    pub fn with_pubkey(mut self, merkle_tree_pubkey: [u8; HASH_LEN]) -> Self {
+        self.merkle_tree_pubkey = merkle_tree_pubkey;
+        self
+    }
+
+    pub fn merkle_tree_pubkey(&self) -> &[u8; HASH_LEN] {
+        &self.merkle_tree_pubkey
+    }
+
+    /// Returns the maximum number of leaves, `None` if it doesn't fit in
+    /// `usize`.
+    pub fn capacity(&self) -> Option<usize> {
+        1_usize.checked_shl(self.height)
+    }
+
+    /// Returns the number of appended leaves.
+    pub fn len(&self) -> usize {
+        self.leaves.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.leaves.is_empty()
+    }
+
+    /// Appends a leaf. Fails with [`MyError::TreeFull`] if the tree already
+    /// has `2^height` leaves.
+    pub fn append(&mut self, leaf: [u8; HASH_LEN]) -> Result<(), MyError> {
+        if let Some(capacity) = self.capacity() {
+            if self.leaves.len() == capacity {
+                return Err(MyError::TreeFull { capacity });
+            }
+        }
+        self.leaves.push(leaf);
+        Ok(())
+    }
+
+    /// Appends the leaves of the event. Fails with [`MyError::TreeMismatch`]
+    /// if it belongs to a different tree and with [`MyError::TreeFull`] if its
+    /// leaves don't fit, without appending any of them.
+    pub fn apply_event(&mut self, changelog_event: &ChangelogEvent) -> Result<(), MyError> {
+        if changelog_event.merkle_tree_pubkey != self.merkle_tree_pubkey {
+            return Err(MyError::TreeMismatch {
+                expected: self.merkle_tree_pubkey,
+                got: changelog_event.merkle_tree_pubkey,
+            });
+        }
+        if let Some(capacity) = self.capacity() {
+            if changelog_event.leaves.len() > capacity - self.leaves.len() {
+                return Err(MyError::TreeFull { capacity });
+            }
+        }
+        self.leaves.extend_from_slice(&changelog_event.leaves);
+        Ok(())
+    }
+
+    /// Applies the events of this tree from `batches`, skipping the events
+    /// of other trees. Returns the root after each batch which contains an
+    /// event of this tree.
+    pub fn apply_batches(
+        &mut self,
+        batches: &[Changelogs],
+    ) -> Result<Vec<[u8; HASH_LEN]>, MyError> {
+        let mut roots = Vec::new();
+        for batch in batches {
+            let mut applied = false;
+            for changelog_event in batch.changelogs.iter() {
+                if changelog_event.merkle_tree_pubkey == self.merkle_tree_pubkey {
+                    self.apply_event(changelog_event)?;
+                    applied = true;
+                }
+            }
+            if applied {
+                roots.push(self.root());
+            }
+        }
+        Ok(roots)
+    }
+
+    /// Returns the proof of the leaf at `leaf_index` against the current
+    /// root. Fails with [`MyError::LeafIndexOutOfRange`] if there is no such
+    /// leaf.
+    pub fn prove(&self, leaf_index: u64) -> Result<Proof, MyError> {
+        let out_of_range = MyError::LeafIndexOutOfRange {
+            leaf_index,
+            len: self.leaves.len(),
+        };
+        let Ok(mut index) = usize::try_from(leaf_index) else {
+            return Err(out_of_range);
+        };
+        let Some(leaf) = self.leaves.get(index) else {
+            return Err(out_of_range);
+        };
+
+        let mut siblings = Vec::with_capacity(self.height as usize);
+        let mut zero = [0_u8; HASH_LEN];
+        let mut level = self.leaves.clone();
+        for _ in 0..self.height {
+            if level.len() % 2 == 1 {
+                level.push(zero);
+            }
+            siblings.push(level[index ^ 1]);
+            level = level
+                .chunks_exact(2)
+                .map(|pair| H::hash_pair(&pair[0], &pair[1]))
+                .collect();
+            zero = H::hash_pair(&zero, &zero);
+            index /= 2;
+        }
+
+        Ok(Proof {
+            leaf: *leaf,
+            leaf_index,
+            siblings,
+        })
+    }
+
+    /// Computes the root. Only the subtrees with appended leaves are hashed,
+    /// the empty ones are replaced by precomputed zero hashes.
+    pub fn root(&self) -> [u8; HASH_LEN] {
+        let mut zero = [0_u8; HASH_LEN];
+        let mut level = self.leaves.clone();
+        for _ in 0..self.height {
+            if level.len() % 2 == 1 {
+                level.push(zero);
+            }
+            level = level
+                .chunks_exact(2)
+                .map(|pair| H::hash_pair(&pair[0], &pair[1]))
+                .collect();
+            zero = H::hash_pair(&zero, &zero);
+        }
+        level.first().copied().unwrap_or(zero)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+
+    use super::*;
+    use crate::{append_leaves, fixture};
+
+    /// Cheap, non-commutative mixing of the child nodes.
+    struct TestHasher;
+
+    impl Hasher for TestHasher {
+        fn hash_pair(left: &[u8; HASH_LEN], right: &[u8; HASH_LEN]) -> [u8; HASH_LEN] {
+            let mut parent = [0_u8; HASH_LEN];
+            for (i, byte) in parent.iter_mut().enumerate() {
+                *byte = left[i]
+                    .wrapping_mul(31)
+                    .wrapping_add(right[(i + 1) % HASH_LEN])
+                    .rotate_left(3)
+                    ^ i as u8;
+            }
+            parent
+        }
+    }
+
+    #[test]
+    fn test_apply_batches() {
+        let (leaves, merkle_trees) = fixture::leaves_and_merkle_trees();
+        let unique_trees: BTreeSet<_> = merkle_trees.iter().copied().collect();
+
+        for batch_size in [1, 3, 10, 25] {
+            let batches = append_leaves(leaves.clone(), merkle_trees.clone(), batch_size).unwrap();
+
+            for merkle_tree in unique_trees.iter() {
+                let mut direct = MerkleTree::<TestHasher>::new(4).with_pubkey(*merkle_tree);
+                for (leaf, _) in leaves
+                    .iter()
+                    .zip(merkle_trees.iter())
+                    .filter(|(_, leaf_tree)| *leaf_tree == merkle_tree)
+                {
+                    direct.append(*leaf).unwrap();
+                }
+
+                let mut batched = MerkleTree::<TestHasher>::new(4).with_pubkey(*merkle_tree);
+                let roots = batched.apply_batches(&batches).unwrap();
+
+                assert_eq!(batched.len(), direct.len());
+                assert_eq!(batched.root(), direct.root());
+                assert_eq!(roots.last(), Some(&direct.root()));
+            }
+        }
+    }
+
+    #[test]
+    fn test_root() {
+        let mut tree = MerkleTree::<TestHasher>::new(2);
+        let zero = [0_u8; 32];
+        let zero_1 = TestHasher::hash_pair(&zero, &zero);
+        assert_eq!(tree.root(), TestHasher::hash_pair(&zero_1, &zero_1));
+
+        tree.append([1_u8; 32]).unwrap();
+        tree.append([2_u8; 32]).unwrap();
+        tree.append([3_u8; 32]).unwrap();
+        assert_eq!(
+            tree.root(),
+            TestHasher::hash_pair(
+                &TestHasher::hash_pair(&[1_u8; 32], &[2_u8; 32]),
+                &TestHasher::hash_pair(&[3_u8; 32], &zero),
+            )
+        );
+
+        assert_eq!(MerkleTree::<TestHasher>::new(0).root(), zero);
+    }
+
+    #[test]
+    fn test_tree_full() {
+        let mut tree = MerkleTree::<TestHasher>::new(1);
+        tree.append([1_u8; 32]).unwrap();
+        tree.append([2_u8; 32]).unwrap();
+        assert!(matches!(
+            tree.append([3_u8; 32]),
+            Err(MyError::TreeFull { capacity: 2 })
+        ));
+
+        // MT 0 of the fixture has 12 leaves.
+        let (leaves, merkle_trees) = fixture::leaves_and_merkle_trees();
+        let batches = append_leaves(leaves, merkle_trees, 10).unwrap();
+        let mut tree = MerkleTree::<TestHasher>::new(3).with_pubkey([0_u8; 32]);
+        assert!(matches!(
+            tree.apply_batches(&batches),
+            Err(MyError::TreeFull { capacity: 8 })
+        ));
+    }
+
+    #[test]
+    fn test_tree_mismatch() {
+        let mut tree = MerkleTree::<TestHasher>::new(4).with_pubkey([1_u8; 32]);
+        let changelog_event = ChangelogEvent {
+            merkle_tree_pubkey: [2_u8; 32],
+            leaves: vec![[3_u8; 32]],
+        };
+
+        assert!(matches!(
+            tree.apply_event(&changelog_event),
+            Err(MyError::TreeMismatch { expected, got })
+                if expected == [1_u8; 32] && got == [2_u8; 32]
+        ));
+        assert!(tree.is_empty());
+    }
+
+    #[cfg(feature = "sha2")]
+    #[test]
+    fn test_sha256() {
+        let mut tree = MerkleTree::<Sha256>::new(1);
+        tree.append([1_u8; 32]).unwrap();
+        assert_eq!(
+            tree.root(),
+            <Sha256 as Hasher>::hash_pair(&[1_u8; 32], &[0_u8; 32])
+        );
+        assert_ne!(tree.root(), MerkleTree::<Sha256>::new(1).root());
+    }
+
+    #[test]
+    fn test_proofs_for_batch() {
+        let (leaves, merkle_trees) = fixture::leaves_and_merkle_trees();
+        let batches = append_leaves(leaves, merkle_trees, 10).unwrap();
+
+        // MT 0 gets 10 leaves in batch 0 and 2 in batch 1.
+        let mut tree = MerkleTree::<TestHasher>::new(4).with_pubkey([0_u8; 32]);
+        for (batch, expected_indices) in batches.iter().zip([0..10, 10..12, 12..12]) {
+            let proofs = proofs_for_batch(&mut tree, batch).unwrap();
+            let indices: Vec<u64> = proofs.iter().map(|proof| proof.leaf_index).collect();
+            assert_eq!(indices, expected_indices.collect::<Vec<_>>());
+
+            let root = tree.root();
+            for proof in proofs.iter() {
+                assert_eq!(proof.siblings.len(), 4);
+                assert!(verify_proof::<TestHasher>(&root, proof));
+
+                let mut tampered = proof.clone();
+                tampered.siblings[2][0] ^= 1;
+                assert!(!verify_proof::<TestHasher>(&root, &tampered));
+            }
+        }
+
+        // Proofs of earlier leaves are valid against the latest root as well.
+        let root = tree.root();
+        for leaf_index in 0..tree.len() as u64 {
+            assert!(verify_proof::<TestHasher>(
+                &root,
+                &tree.prove(leaf_index).unwrap()
+            ));
+        }
+    }
+
+    #[test]
+    fn test_prove_invalid() {
+        let mut tree = MerkleTree::<TestHasher>::new(2);
+        tree.append([1_u8; 32]).unwrap();
+        tree.append([2_u8; 32]).unwrap();
+
+        assert!(matches!(
+            tree.prove(2),
+            Err(MyError::LeafIndexOutOfRange {
+                leaf_index: 2,
+                len: 2
+            })
+        ));
+
+        // A different leaf or index doesn't verify.
+        let root = tree.root();
+        let proof = tree.prove(1).unwrap();
+        let mut wrong_leaf = proof.clone();
+        wrong_leaf.leaf = [3_u8; 32];
+        assert!(!verify_proof::<TestHasher>(&root, &wrong_leaf));
+        let mut wrong_index = proof;
+        wrong_index.leaf_index = 5;
+        assert!(!verify_proof::<TestHasher>(&root, &wrong_index));
+    }
+}
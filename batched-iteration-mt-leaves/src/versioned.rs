@@ -0,0 +1,401 @@
+//! Versioned changelog events and their binary encoding.
+//!
+//! Every encoded event starts with a version byte, followed by the fields of
+//! that version. All integers are little-endian:
+//!
+//! * V1: `merkle_tree_pubkey`, `u32` leaf count, leaves.
+//! * V2: `merkle_tree_pubkey`, `u64` `start_index`, `u64` `seq`, `u32` leaf
+//!   count, leaves.
+
+use std::collections::BTreeMap;
+
+use crate::{append_leaves, ChangelogEvent, MyError, HASH_LEN};
+
+/// Version of the changelog events.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum EventVersion {
+    /// [`ChangelogEvent`].
+    #[default]
+    V1,
+    /// [`ChangelogEventV2`].
+    V2,
+}
+
+impl EventVersion {
+    /// Returns the tag of the version in the encoded events.
+    pub fn tag(self) -> u8 {
+        match self {
+            EventVersion::V1 => 1,
+            EventVersion::V2 => 2,
+        }
+    }
+
+    pub fn from_tag(tag: u8) -> Result<Self, MyError> {
+        match tag {
+            1 => Ok(EventVersion::V1),
+            2 => Ok(EventVersion::V2),
+            tag => Err(MyError::UnknownVersion(tag)),
+        }
+    }
+}
+
+/// Changelog event with the position of its leaves.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ChangelogEventV2 {
+    pub merkle_tree_pubkey: [u8; HASH_LEN],
+    /// Index of the first leaf among all leaves appended to the Merkle tree.
+    pub start_index: u64,
+    /// Number of the previous events of the Merkle tree.
+    pub seq: u64,
+    pub leaves: Vec<[u8; HASH_LEN]>,
+}
+
+/// Changelog event of any version.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ChangelogEventVersioned {
+    V1(ChangelogEvent),
+    V2(ChangelogEventV2),
+}
+
+/// Set of versioned changelogs for different Merkle trees.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ChangelogsVersioned {
+    pub changelogs: Vec<ChangelogEventVersioned>,
+}
+
+impl ChangelogEventVersioned {
+    pub fn version(&self) -> EventVersion {
+        match self {
+            ChangelogEventVersioned::V1(_) => EventVersion::V1,
+            ChangelogEventVersioned::V2(_) => EventVersion::V2,
+        }
+    }
+
+    pub fn merkle_tree_pubkey(&self) -> &[u8; HASH_LEN] {
+        match self {
+            ChangelogEventVersioned::V1(changelog_event) => &changelog_event.merkle_tree_pubkey,
+            ChangelogEventVersioned::V2(changelog_event) => &changelog_event.merkle_tree_pubkey,
+        }
+    }
+
+    pub fn leaves(&self) -> &[[u8; HASH_LEN]] {
+        match self {
+            ChangelogEventVersioned::V1(changelog_event) => &changelog_event.leaves,
+            ChangelogEventVersioned::V2(changelog_event) => &changelog_event.leaves,
+        }
+    }
+
+    /// Converts the event to V2. V1 events don't carry `start_index` and
+    /// `seq`, so both are set to 0.
+    pub fn upgrade(self) -> ChangelogEventV2 {
+        match self {
+            ChangelogEventVersioned::V1(changelog_event) => ChangelogEventV2 {
+                merkle_tree_pubkey: changelog_event.merkle_tree_pubkey,
+                start_index: 0,
+                seq: 0,
+                leaves: changelog_event.leaves,
+            },
+            ChangelogEventVersioned::V2(changelog_event) => changelog_event,
+        }
+    }
+
+    /// Converts the event to V1, dropping the fields added in V2.
+    pub fn downgrade(self) -> ChangelogEvent {
+        match self {
+            ChangelogEventVersioned::V1(changelog_event) => changelog_event,
+            ChangelogEventVersioned::V2(changelog_event) => ChangelogEvent {
+                merkle_tree_pubkey: changelog_event.merkle_tree_pubkey,
+                leaves: changelog_event.leaves,
+            },
+        }
+    }
+
+    /// Appends the encoded event to `out`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the event has more than `u32::MAX` leaves.
+    pub fn encode(&self, out: &mut Vec<u8>) {
+        out.push(self.version().tag());
+        out.extend_from_slice(self.merkle_tree_pubkey());
+        if let ChangelogEventVersioned::V2(changelog_event) = self {
+            out.extend_from_slice(&changelog_event.start_index.to_le_bytes());
+            out.extend_from_slice(&changelog_event.seq.to_le_bytes());
+        }
+        let leaves = self.leaves();
+        let num_leaves = u32::try_from(leaves.len()).expect("too many leaves in an event");
+        out.extend_from_slice(&num_leaves.to_le_bytes());
+        for leaf in leaves {
+            out.extend_from_slice(leaf);
+        }
+    }
+
+    /// Decodes an event from the beginning of `bytes`. Returns the event and
+    /// the number of bytes it took.
+    pub fn decode(bytes: &[u8]) -> Result<(Self, usize), MyError> {
+        let mut decoder = Decoder::new(bytes);
+
+        let version = EventVersion::from_tag(decoder.take::<1>()?[0])?;
+        let merkle_tree_pubkey = decoder.take::<HASH_LEN>()?;
+        let (start_index, seq) = match version {
+            EventVersion::V1 => (0, 0),
+            EventVersion::V2 => (
+                u64::from_le_bytes(decoder.take()?),
+                u64::from_le_bytes(decoder.take()?),
+            ),
+        };
+        let num_leaves = u32::from_le_bytes(decoder.take()?) as usize;
+        // Don't trust the leaf count for the allocation.
+        let mut leaves = Vec::with_capacity(num_leaves.min(decoder.remaining() / HASH_LEN));
+        for _ in 0..num_leaves {
+            leaves.push(decoder.take::<HASH_LEN>()?);
+        }
+
+        let changelog_event = match version {
+            EventVersion::V1 => ChangelogEventVersioned::V1(ChangelogEvent {
+                merkle_tree_pubkey,
+                leaves,
+            }),
+            EventVersion::V2 => ChangelogEventVersioned::V2(ChangelogEventV2 {
+                merkle_tree_pubkey,
+                start_index,
+                seq,
+                leaves,
+            }),
+        };
+        Ok((changelog_event, decoder.offset))
+    }
+}
+
+impl From<ChangelogEvent> for ChangelogEventVersioned {
+    fn from(changelog_event: ChangelogEvent) -> Self {
+        ChangelogEventVersioned::V1(changelog_event)
+    }
+}
+
+impl From<ChangelogEventV2> for ChangelogEventVersioned {
+    fn from(changelog_event: ChangelogEventV2) -> Self {
+        ChangelogEventVersioned::V2(changelog_event)
+    }
+}
+
+/// Reads fixed-size fields from the beginning of a byte slice.
+pub(crate) struct Decoder<'a> {
+    bytes: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Decoder<'a> {
+    pub(crate) fn new(bytes: &'a [u8]) -> Self {
+        Self { bytes, offset: 0 }
+    }
+
+    pub(crate) fn remaining(&self) -> usize {
+        self.bytes.len() - self.offset
+    }
+
+    pub(crate) fn take<const N: usize>(&mut self) -> Result<[u8; N], MyError> {
+        let Some(bytes) = self.bytes.get(self.offset..self.offset + N) else {
+            return Err(MyError::TruncatedInput(self.remaining()));
+        };
+        self.offset += N;
+        // The slice has exactly `N` bytes.
+        Ok(bytes.try_into().unwrap())
+    }
+}
+
+/// Encodes the events one after another.
+pub fn encode_versioned(changelog_events: &[ChangelogEventVersioned]) -> Vec<u8> {
+    let mut out = Vec::new();
+    for changelog_event in changelog_events {
+        changelog_event.encode(&mut out);
+    }
+    out
+}
+
+/// Decodes events encoded one after another, possibly of different
+/// versions.
+pub fn decode_versioned(mut bytes: &[u8]) -> Result<Vec<ChangelogEventVersioned>, MyError> {
+    let mut changelog_events = Vec::new();
+    while !bytes.is_empty() {
+        let (changelog_event, len) = ChangelogEventVersioned::decode(bytes)?;
+        changelog_events.push(changelog_event);
+        bytes = &bytes[len..];
+    }
+    Ok(changelog_events)
+}
+
+/// Batches leaves like [`append_leaves`] and emits the events in the given
+/// `version`.
+///
+/// In V2 events, `start_index` and `seq` count the leaves and events of each
+/// Merkle tree within this call, starting from 0.
+pub fn append_leaves_versioned(
+    leaves: Vec<[u8; HASH_LEN]>,
+    merkle_trees: Vec<[u8; HASH_LEN]>,
+    batch_size: usize,
+    version: EventVersion,
+) -> Result<Vec<ChangelogsVersioned>, MyError> {
+    // Number of leaves and events of each Merkle tree emitted so far.
+    let mut emitted: BTreeMap<[u8; HASH_LEN], (u64, u64)> = BTreeMap::new();
+
+    let batches = append_leaves(leaves, merkle_trees, batch_size)?
+        .into_iter()
+        .map(|batch| ChangelogsVersioned {
+            changelogs: batch
+                .changelogs
+                .into_iter()
+                .map(|changelog_event| match version {
+                    EventVersion::V1 => ChangelogEventVersioned::V1(changelog_event),
+                    EventVersion::V2 => {
+                        let (start_index, seq) = emitted
+                            .entry(changelog_event.merkle_tree_pubkey)
+                            .or_default();
+                        let changelog_event_v2 = ChangelogEventV2 {
+                            merkle_tree_pubkey: changelog_event.merkle_tree_pubkey,
+                            start_index: *start_index,
+                            seq: *seq,
+                            leaves: changelog_event.leaves,
+                        };
+                        *start_index += changelog_event_v2.leaves.len() as u64;
+                        *seq += 1;
+                        ChangelogEventVersioned::V2(changelog_event_v2)
+                    }
+                })
+                .collect(),
+        })
+        .collect();
+
+    Ok(batches)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixture;
+
+    fn v1_event() -> ChangelogEvent {
+        ChangelogEvent {
+            merkle_tree_pubkey: [7_u8; 32],
+            leaves: vec![[1_u8; 32], [2_u8; 32]],
+        }
+    }
+
+    #[test]
+    fn test_decode_v1_upgrade_encode_v2() {
+        let mut v1_bytes = vec![1];
+        v1_bytes.extend_from_slice(&[7_u8; 32]);
+        v1_bytes.extend_from_slice(&2_u32.to_le_bytes());
+        v1_bytes.extend_from_slice(&[1_u8; 32]);
+        v1_bytes.extend_from_slice(&[2_u8; 32]);
+
+        let (changelog_event, len) = ChangelogEventVersioned::decode(&v1_bytes).unwrap();
+        assert_eq!(len, v1_bytes.len());
+        assert_eq!(changelog_event, ChangelogEventVersioned::V1(v1_event()));
+
+        let mut upgraded = changelog_event.upgrade();
+        assert_eq!((upgraded.start_index, upgraded.seq), (0, 0));
+        upgraded.start_index = 5;
+        upgraded.seq = 3;
+
+        let mut v2_bytes = Vec::new();
+        ChangelogEventVersioned::V2(upgraded.clone()).encode(&mut v2_bytes);
+        assert_eq!(v2_bytes[0], 2);
+        assert_eq!(v2_bytes.len(), v1_bytes.len() + 16);
+
+        let (decoded, _) = ChangelogEventVersioned::decode(&v2_bytes).unwrap();
+        assert_eq!(decoded, ChangelogEventVersioned::V2(upgraded));
+        assert_eq!(decoded.downgrade(), v1_event());
+    }
+
+    #[test]
+    fn test_decode_versioned_mixed() {
+        let changelog_events = vec![
+            ChangelogEventVersioned::V1(v1_event()),
+            ChangelogEventVersioned::V2(ChangelogEventV2 {
+                merkle_tree_pubkey: [8_u8; 32],
+                start_index: 10,
+                seq: 1,
+                leaves: vec![[3_u8; 32]],
+            }),
+            ChangelogEventVersioned::V1(ChangelogEvent {
+                merkle_tree_pubkey: [9_u8; 32],
+                leaves: Vec::new(),
+            }),
+        ];
+
+        let bytes = encode_versioned(&changelog_events);
+        assert_eq!(decode_versioned(&bytes).unwrap(), changelog_events);
+        assert!(decode_versioned(&[]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_decode_versioned_invalid() {
+        let bytes = encode_versioned(&[ChangelogEventVersioned::V1(v1_event())]);
+
+        assert!(matches!(
+            decode_versioned(&bytes[..bytes.len() - 1]),
+            Err(MyError::TruncatedInput(31))
+        ));
+
+        let mut unknown = bytes;
+        unknown[0] = 3;
+        assert!(matches!(
+            decode_versioned(&unknown),
+            Err(MyError::UnknownVersion(3))
+        ));
+    }
+
+    #[test]
+    fn test_append_leaves_versioned() {
+        let (leaves, merkle_trees) = fixture::leaves_and_merkle_trees();
+        let batches = append_leaves(leaves.clone(), merkle_trees.clone(), 10).unwrap();
+
+        let v1 =
+            append_leaves_versioned(leaves.clone(), merkle_trees.clone(), 10, EventVersion::V1)
+                .unwrap();
+        let v2 = append_leaves_versioned(leaves, merkle_trees, 10, EventVersion::V2).unwrap();
+
+        for (batch, (v1_batch, v2_batch)) in batches.iter().zip(v1.into_iter().zip(v2.clone())) {
+            let v1_events: Vec<_> = v1_batch
+                .changelogs
+                .into_iter()
+                .map(ChangelogEventVersioned::downgrade)
+                .collect();
+            let v2_events: Vec<_> = v2_batch
+                .changelogs
+                .into_iter()
+                .map(ChangelogEventVersioned::downgrade)
+                .collect();
+            assert_eq!(v1_events, batch.changelogs);
+            assert_eq!(v2_events, batch.changelogs);
+        }
+
+        // MT 0 is split between batches 0 and 1, MT 3 between batches 1 and 2.
+        let positions: Vec<Vec<(u8, u64, u64)>> = v2
+            .into_iter()
+            .map(|batch| {
+                batch
+                    .changelogs
+                    .into_iter()
+                    .map(|changelog_event| {
+                        let changelog_event = changelog_event.upgrade();
+                        (
+                            changelog_event.merkle_tree_pubkey[0],
+                            changelog_event.start_index,
+                            changelog_event.seq,
+                        )
+                    })
+                    .collect()
+            })
+            .collect();
+        assert_eq!(
+            positions,
+            vec![
+                vec![(0, 0, 0)],
+                vec![(0, 10, 1), (1, 0, 0), (2, 0, 0), (3, 0, 0)],
+                vec![(3, 1, 1)],
+            ]
+        );
+    }
+}
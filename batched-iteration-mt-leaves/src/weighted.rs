@@ -0,0 +1,449 @@
+use std::{collections::BTreeMap, mem};
+
+use crate::{build_merkle_tree_map, generic, ChangelogEvent, Changelogs, MyError, HASH_LEN};
+
+/// Batches leaves so that the sum of their weights in each batch doesn't
+/// exceed `max_weight`. Each item is a `(merkle_tree, leaf, weight)` triple.
+///
+/// Leaves are grouped per Merkle tree in the same way as in
+/// [`append_leaves`](crate::append_leaves) and a batch is closed before the
+/// next leaf would push its total weight over `max_weight`.
+///
+/// Returns the batches together with the total weight of each of them.
+pub fn append_weighted_leaves(
+    items: &[([u8; HASH_LEN], [u8; HASH_LEN], u32)],
+    max_weight: u64,
+) -> Result<(Vec<Changelogs>, Vec<u64>), MyError> {
+    let mut merkle_tree_map: BTreeMap<[u8; HASH_LEN], Vec<([u8; HASH_LEN], u64)>> = BTreeMap::new();
+    for (merkle_tree, leaf, weight) in items {
+        let weight = u64::from(*weight);
+        if weight > max_weight {
+            return Err(MyError::LeafWeightExceeded(weight, max_weight));
+        }
+        merkle_tree_map
+            .entry(*merkle_tree)
+            .or_default()
+            .push((*leaf, weight));
+    }
+
+    let mut batches_of_changelogs = Vec::new();
+    let mut batch_weights = Vec::new();
+
+    let mut batch_of_changelogs = Changelogs {
+        changelogs: Vec::new(),
+    };
+    let mut batch_weight = 0;
+
+    for (merkle_tree_pubkey, leaves) in merkle_tree_map {
+        for (leaf, weight) in leaves {
+            // `batch_weight` never exceeds `max_weight`, so the subtraction
+            // can't underflow.
+            if weight > max_weight - batch_weight {
+                // The leaf doesn't fit. Close the batch.
+                batches_of_changelogs.push(mem::replace(
+                    &mut batch_of_changelogs,
+                    Changelogs {
+                        changelogs: Vec::new(),
+                    },
+                ));
+                batch_weights.push(batch_weight);
+                batch_weight = 0;
+            }
+
+            match batch_of_changelogs.changelogs.last_mut() {
+                Some(changelog_event)
+                    if changelog_event.merkle_tree_pubkey == merkle_tree_pubkey =>
+                {
+                    changelog_event.leaves.push(leaf)
+                }
+                _ => batch_of_changelogs.changelogs.push(ChangelogEvent {
+                    merkle_tree_pubkey,
+                    leaves: vec![leaf],
+                }),
+            }
+            batch_weight += weight;
+        }
+    }
+
+    if !batch_of_changelogs.changelogs.is_empty() {
+        batches_of_changelogs.push(batch_of_changelogs);
+        batch_weights.push(batch_weight);
+    }
+
+    Ok((batches_of_changelogs, batch_weights))
+}
+
+/// Batches leaves so that the serialized size of each batch doesn't exceed
+/// `max_bytes`, where every event costs `per_event_overhead` bytes and every
+/// leaf [`HASH_LEN`] bytes.
+///
+/// Leaves are grouped per Merkle tree in the same way as in
+/// [`append_leaves`](crate::append_leaves). A batch is closed before the next
+/// leaf would push its size over `max_bytes`, so the leaves of a Merkle tree
+/// can be split over several batches. Fails with
+/// [`MyError::LeafWeightExceeded`] if a single leaf in its own event doesn't
+/// fit.
+pub fn append_leaves_by_bytes(
+    leaves: Vec<[u8; HASH_LEN]>,
+    merkle_trees: Vec<[u8; HASH_LEN]>,
+    max_bytes: u64,
+    per_event_overhead: u64,
+) -> Result<Vec<Changelogs>, MyError> {
+    let merkle_tree_map = build_merkle_tree_map(&leaves, &merkle_trees)?;
+
+    split_by_cost(
+        merkle_tree_map,
+        max_bytes,
+        per_event_overhead,
+        HASH_LEN as u64,
+    )
+}
+
+/// Compute unit cost model of a batch, see [`append_leaves_by_compute`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ComputeBudget {
+    /// Cost of every event, i.e. of every Merkle tree in a batch.
+    pub per_event: u32,
+    /// Cost of every leaf.
+    pub per_leaf: u32,
+    /// Maximum cost of a batch.
+    pub max_total: u64,
+}
+
+impl ComputeBudget {
+    /// Maximum number of compute units of a Solana transaction.
+    pub const MAX_TRANSACTION_UNITS: u64 = 1_400_000;
+}
+
+/// Batches leaves so that the compute cost of each batch doesn't exceed
+/// `budget.max_total`, where every event costs `budget.per_event` and every
+/// leaf `budget.per_leaf`. The first leaf of a Merkle tree in a batch costs
+/// both.
+///
+/// Leaves are grouped per Merkle tree in the same way as in
+/// [`append_leaves`](crate::append_leaves) and batched in that order. A batch
+/// is closed as soon as the next leaf doesn't fit, even if a leaf of a tree
+/// already in the batch would. Fails with [`MyError::LeafWeightExceeded`] if
+/// an event with a single leaf doesn't fit.
+pub fn append_leaves_by_compute(
+    leaves: Vec<[u8; HASH_LEN]>,
+    merkle_trees: Vec<[u8; HASH_LEN]>,
+    budget: ComputeBudget,
+) -> Result<Vec<Changelogs>, MyError> {
+    let merkle_tree_map = build_merkle_tree_map(&leaves, &merkle_trees)?;
+
+    split_by_cost(
+        merkle_tree_map,
+        budget.max_total,
+        budget.per_event.into(),
+        budget.per_leaf.into(),
+    )
+}
+
+/// Splits leaves grouped per Merkle tree into batches costing at most
+/// `max_cost`, where every event costs `per_event_cost` and every leaf
+/// `per_leaf_cost`. The first leaf of a Merkle tree in a batch opens an event,
+/// so it costs both. See [`append_leaves_by_bytes`].
+pub(crate) fn split_by_cost<L>(
+    merkle_tree_map: BTreeMap<[u8; HASH_LEN], Vec<L>>,
+    max_cost: u64,
+    per_event_cost: u64,
+    per_leaf_cost: u64,
+) -> Result<Vec<generic::Changelogs<[u8; HASH_LEN], L>>, MyError> {
+    let min_event_cost = per_event_cost.saturating_add(per_leaf_cost);
+    if !merkle_tree_map.is_empty() && min_event_cost > max_cost {
+        return Err(MyError::LeafWeightExceeded(min_event_cost, max_cost));
+    }
+
+    let mut batches_of_changelogs = Vec::new();
+
+    let mut batch_of_changelogs = generic::Changelogs::default();
+    let mut batch_cost = 0;
+
+    for (merkle_tree_pubkey, leaves) in merkle_tree_map {
+        for leaf in leaves {
+            let continues_event = matches!(
+                batch_of_changelogs.changelogs.last(),
+                Some(changelog_event) if changelog_event.merkle_tree_pubkey == merkle_tree_pubkey
+            );
+            let cost = if continues_event {
+                per_leaf_cost
+            } else {
+                min_event_cost
+            };
+
+            // `batch_cost` never exceeds `max_cost`, so the subtraction
+            // can't underflow.
+            if cost > max_cost - batch_cost {
+                // The leaf doesn't fit. Close the batch, the leaf starts a new
+                // event in the next one.
+                batches_of_changelogs.push(mem::take(&mut batch_of_changelogs));
+                batch_cost = 0;
+            }
+
+            match batch_of_changelogs.changelogs.last_mut() {
+                Some(changelog_event)
+                    if changelog_event.merkle_tree_pubkey == merkle_tree_pubkey =>
+                {
+                    changelog_event.leaves.push(leaf);
+                    batch_cost += per_leaf_cost;
+                }
+                _ => {
+                    batch_of_changelogs
+                        .changelogs
+                        .push(generic::ChangelogEvent {
+                            merkle_tree_pubkey,
+                            leaves: vec![leaf],
+                        });
+                    batch_cost += min_event_cost;
+                }
+            }
+        }
+    }
+
+    if !batch_of_changelogs.changelogs.is_empty() {
+        batches_of_changelogs.push(batch_of_changelogs);
+    }
+
+    Ok(batches_of_changelogs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{append_leaves, fixture};
+
+    /// Returns the number of leaves of every event, per batch.
+    fn event_lens(batches: &[Changelogs]) -> Vec<Vec<usize>> {
+        batches
+            .iter()
+            .map(|batch| {
+                batch
+                    .changelogs
+                    .iter()
+                    .map(|changelog_event| changelog_event.leaves.len())
+                    .collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_append_weighted_leaves() {
+        let items = [
+            ([0_u8; 32], [0_u8; 32], 3),
+            ([1_u8; 32], [1_u8; 32], 1),
+            ([0_u8; 32], [2_u8; 32], 5),
+            ([1_u8; 32], [3_u8; 32], 4),
+            ([0_u8; 32], [4_u8; 32], 1),
+            ([2_u8; 32], [5_u8; 32], 2),
+        ];
+
+        let (changelogs, weights) = append_weighted_leaves(&items, 8).unwrap();
+        assert_eq!(
+            changelogs,
+            vec![
+                // MT 0 leaves weigh 3 + 5, the last one (1) doesn't fit.
+                Changelogs {
+                    changelogs: vec![ChangelogEvent {
+                        merkle_tree_pubkey: [0_u8; 32],
+                        leaves: vec![[0_u8; 32], [2_u8; 32]],
+                    }]
+                },
+                Changelogs {
+                    changelogs: vec![
+                        ChangelogEvent {
+                            merkle_tree_pubkey: [0_u8; 32],
+                            leaves: vec![[4_u8; 32]],
+                        },
+                        ChangelogEvent {
+                            merkle_tree_pubkey: [1_u8; 32],
+                            leaves: vec![[1_u8; 32], [3_u8; 32]],
+                        },
+                        ChangelogEvent {
+                            merkle_tree_pubkey: [2_u8; 32],
+                            leaves: vec![[5_u8; 32]],
+                        },
+                    ]
+                },
+            ]
+        );
+        assert_eq!(weights, vec![8, 8]);
+    }
+
+    #[test]
+    fn test_append_weighted_leaves_exact_limit() {
+        let items = [
+            ([0_u8; 32], [0_u8; 32], 2),
+            ([0_u8; 32], [1_u8; 32], 2),
+            ([0_u8; 32], [2_u8; 32], 2),
+            ([0_u8; 32], [3_u8; 32], 1),
+        ];
+
+        // The first three leaves fill the batch exactly.
+        let (changelogs, weights) = append_weighted_leaves(&items, 6).unwrap();
+        assert_eq!(
+            changelogs,
+            vec![
+                Changelogs {
+                    changelogs: vec![ChangelogEvent {
+                        merkle_tree_pubkey: [0_u8; 32],
+                        leaves: vec![[0_u8; 32], [1_u8; 32], [2_u8; 32]],
+                    }]
+                },
+                Changelogs {
+                    changelogs: vec![ChangelogEvent {
+                        merkle_tree_pubkey: [0_u8; 32],
+                        leaves: vec![[3_u8; 32]],
+                    }]
+                },
+            ]
+        );
+        assert_eq!(weights, vec![6, 1]);
+    }
+
+    #[test]
+    fn test_append_weighted_leaves_leaf_too_heavy() {
+        let items = [([0_u8; 32], [0_u8; 32], 2), ([1_u8; 32], [1_u8; 32], 11)];
+
+        let res = append_weighted_leaves(&items, 10);
+        assert!(matches!(res, Err(MyError::LeafWeightExceeded(11, 10))));
+    }
+
+    #[test]
+    fn test_append_leaves_by_bytes() {
+        let (leaves, merkle_trees) = fixture::leaves_and_merkle_trees();
+
+        // 256 bytes fit 8 leaves of a single event, but every additional
+        // event costs another 32 bytes.
+        let batches =
+            append_leaves_by_bytes(leaves.clone(), merkle_trees.clone(), 256, 32).unwrap();
+        assert_eq!(
+            event_lens(&batches),
+            vec![vec![7], vec![5, 1], vec![2, 4], vec![6]]
+        );
+        for batch in batches.iter() {
+            let bytes: usize = batch
+                .changelogs
+                .iter()
+                .map(|changelog_event| 32 + 32 * changelog_event.leaves.len())
+                .sum();
+            assert!(bytes <= 256);
+        }
+
+        let by_count = append_leaves(leaves.clone(), merkle_trees.clone(), 8).unwrap();
+        assert_eq!(
+            event_lens(&by_count),
+            vec![vec![8], vec![4, 3, 1], vec![3, 5], vec![1]]
+        );
+
+        // Without the overhead, the bytes limit is the same as the leaf
+        // count.
+        assert_eq!(
+            append_leaves_by_bytes(leaves, merkle_trees, 256, 0).unwrap(),
+            by_count
+        );
+    }
+
+    #[test]
+    fn test_append_leaves_by_bytes_event_too_large() {
+        let (leaves, merkle_trees) = fixture::leaves_and_merkle_trees();
+
+        let res = append_leaves_by_bytes(leaves, merkle_trees, 63, 32);
+        assert!(matches!(res, Err(MyError::LeafWeightExceeded(64, 63))));
+
+        // Nothing to batch, so nothing is too large.
+        assert_eq!(
+            append_leaves_by_bytes(Vec::new(), Vec::new(), 63, 32).unwrap(),
+            vec![]
+        );
+    }
+
+    #[test]
+    fn test_append_leaves_by_compute() {
+        let (leaves, merkle_trees) = fixture::leaves_and_merkle_trees();
+        let budget = ComputeBudget {
+            per_event: 100,
+            per_leaf: 10,
+            max_total: 250,
+        };
+
+        // An event with 15 leaves costs 250, but MT 0 has only 12 (220),
+        // which leaves 30 units. That's not enough for the first leaf of MT 1
+        // (110), so the batch is closed.
+        let batches =
+            append_leaves_by_compute(leaves.clone(), merkle_trees.clone(), budget).unwrap();
+        assert_eq!(
+            event_lens(&batches),
+            vec![vec![12], vec![3, 2], vec![2, 3], vec![3]]
+        );
+        for batch in batches.iter() {
+            let cost: usize = batch
+                .changelogs
+                .iter()
+                .map(|changelog_event| 100 + 10 * changelog_event.leaves.len())
+                .sum();
+            assert!(cost <= 250);
+        }
+
+        // 10 more units fit another leaf of MT 2 in the second batch and of
+        // MT 3 in the third one.
+        let budget = ComputeBudget {
+            max_total: 260,
+            ..budget
+        };
+        let batches = append_leaves_by_compute(leaves, merkle_trees, budget).unwrap();
+        assert_eq!(
+            event_lens(&batches),
+            vec![vec![12], vec![3, 3], vec![1, 5], vec![1]]
+        );
+    }
+
+    #[test]
+    fn test_append_leaves_by_compute_new_tree_does_not_fit() {
+        // 130 units are used by MT 1, 105 are left. Another leaf of MT 1 (10)
+        // would fit, but MT 1 has no more leaves and the first leaf of MT 2
+        // (110) doesn't fit, so the batch is closed.
+        let leaves = vec![[0_u8; 32]; 5];
+        let merkle_trees = vec![[1_u8; 32], [1_u8; 32], [1_u8; 32], [2_u8; 32], [2_u8; 32]];
+        let budget = ComputeBudget {
+            per_event: 100,
+            per_leaf: 10,
+            max_total: 235,
+        };
+
+        let batches =
+            append_leaves_by_compute(leaves.clone(), merkle_trees.clone(), budget).unwrap();
+        assert_eq!(event_lens(&batches), vec![vec![3], vec![2]]);
+        // Deterministic.
+        assert_eq!(
+            append_leaves_by_compute(leaves, merkle_trees, budget).unwrap(),
+            batches
+        );
+    }
+
+    #[test]
+    fn test_append_leaves_by_compute_event_too_expensive() {
+        let budget = ComputeBudget {
+            per_event: 100,
+            per_leaf: 10,
+            max_total: 109,
+        };
+        assert!(matches!(
+            append_leaves_by_compute(vec![[0_u8; 32]], vec![[1_u8; 32]], budget),
+            Err(MyError::LeafWeightExceeded(110, 109))
+        ));
+        assert!(append_leaves_by_compute(vec![], vec![], budget)
+            .unwrap()
+            .is_empty());
+
+        let budget = ComputeBudget {
+            per_event: u32::MAX,
+            per_leaf: u32::MAX,
+            max_total: ComputeBudget::MAX_TRANSACTION_UNITS,
+        };
+        assert!(matches!(
+            append_leaves_by_compute(vec![[0_u8; 32]], vec![[1_u8; 32]], budget),
+            Err(MyError::LeafWeightExceeded(_, 1_400_000))
+        ));
+    }
+}
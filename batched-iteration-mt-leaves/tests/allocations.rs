@@ -0,0 +1,93 @@
+//! Counts the allocations made by `append_leaves`. Lives in its own test
+//! binary, because it replaces the global allocator.
+
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    cell::Cell,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use batched_iteration_mt_leaves::append_leaves;
+
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+    /// Only the allocations of the thread running the measured code are
+    /// counted, the test harness allocates on other threads.
+    static COUNTING: Cell<bool> = const { Cell::new(false) };
+}
+
+fn count() {
+    if COUNTING.with(Cell::get) {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        count();
+        System.alloc(layout)
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        count();
+        System.alloc_zeroed(layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        count();
+        System.realloc(ptr, layout, new_size)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+/// Returns the number of allocations (including reallocations) made by `f`.
+fn count_allocations<T>(f: impl FnOnce() -> T) -> (T, usize) {
+    ALLOCATIONS.store(0, Ordering::Relaxed);
+    COUNTING.with(|counting| counting.set(true));
+    let res = f();
+    COUNTING.with(|counting| counting.set(false));
+    (res, ALLOCATIONS.load(Ordering::Relaxed))
+}
+
+#[test]
+fn test_append_leaves_allocations() {
+    const NUM_TREES: usize = 16;
+    const NUM_LEAVES: usize = 10_000;
+    const BATCH_SIZE: usize = 100;
+
+    // Leaves interleaved over all the trees.
+    let leaves: Vec<[u8; 32]> = (0..NUM_LEAVES)
+        .map(|i| {
+            let mut leaf = [0_u8; 32];
+            leaf[..8].copy_from_slice(&(i as u64).to_le_bytes());
+            leaf
+        })
+        .collect();
+    let merkle_trees: Vec<[u8; 32]> = (0..NUM_LEAVES)
+        .map(|i| [(i % NUM_TREES) as u8 + 1; 32])
+        .collect();
+
+    let (batches, allocations) =
+        count_allocations(|| append_leaves(leaves, merkle_trees, BATCH_SIZE).unwrap());
+    let num_batches = batches.len();
+    let num_events: usize = batches.iter().map(|batch| batch.changelogs.len()).sum();
+    assert_eq!(num_batches, NUM_LEAVES / BATCH_SIZE);
+
+    // One allocation per tree in the grouping map, per batch and per event,
+    // plus a handful for the maps' nodes and the list of batches. Nothing
+    // grows per leaf.
+    let bound = 2 * NUM_TREES + num_batches + num_events + 16;
+    assert!(
+        allocations <= bound,
+        "{allocations} allocations, expected at most {bound}"
+    );
+}